@@ -0,0 +1,107 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use ez80::*;
+use ez80::cpm::*;
+
+static ZEXALL: &[u8] = include_bytes!("../tests/res/zexall.com");
+
+const INSTRUCTIONS: u64 = 1_000_000;
+
+fn zexall(c: &mut Criterion) {
+    let mut group = c.benchmark_group("zexall");
+    group.throughput(Throughput::Elements(INSTRUCTIONS));
+    group.sample_size(20);
+    for (name, decode_cache, block_cache) in [
+        ("z80", false, false),
+        ("z80_decode_cache", true, false),
+        ("z80_blocks", false, true),
+    ] {
+        group.bench_function(name, |b| {
+            let mut machine = Box::new(PlainMachine::new());
+            let mut cpu = Cpu::new_z80();
+            cpu.set_decode_cache(decode_cache);
+            cpu.set_block_cache(block_cache);
+            let mut cpm = Cpm::new(BufferConsole::new(&[]));
+            cpm.load(&mut cpu, machine.as_mut(), ZEXALL, "");
+            b.iter(|| cpm.run_instructions(&mut cpu, machine.as_mut(), INSTRUCTIONS));
+        });
+    }
+    group.finish();
+}
+
+fn ez80_loop(c: &mut Criterion) {
+    // ADL mode memory copy and checksum loop
+    let code = [
+        0x21, 0x00, 0x00, 0x01,  // LD HL, $010000
+        0x11, 0x00, 0x00, 0x02,  // LD DE, $020000
+        0x01, 0x00, 0x10, 0x00,  // LD BC, $001000
+        0xaf,                    // XOR A
+        // loop:
+        0x86,                    // ADD A, (HL)
+        0xed, 0xa0,              // LDI
+        0xea, 0x0d, 0x00, 0x00,  // JP PE, loop
+        0xc3, 0x00, 0x00, 0x00,  // JP $000000
+    ];
+
+    let mut group = c.benchmark_group("ez80");
+    group.throughput(Throughput::Elements(INSTRUCTIONS));
+    group.sample_size(20);
+    group.bench_function("adl_loop", |b| {
+        let mut machine = Box::new(PlainMachine::new());
+        let mut cpu = Cpu::new_ez80();
+        for (i, byte) in code.iter().enumerate() {
+            machine.poke(i as u32, *byte);
+        }
+        cpu.set_adl(true);
+        cpu.state.set_pc(0);
+        b.iter(|| {
+            for _ in 0..INSTRUCTIONS {
+                cpu.execute_instruction(machine.as_mut());
+            }
+        });
+    });
+    group.bench_function("adl_loop_decode_cache", |b| {
+        let mut machine = Box::new(PlainMachine::new());
+        let mut cpu = Cpu::new_ez80();
+        cpu.set_decode_cache(true);
+        for (i, byte) in code.iter().enumerate() {
+            machine.poke(i as u32, *byte);
+        }
+        cpu.set_adl(true);
+        cpu.state.set_pc(0);
+        b.iter(|| {
+            for _ in 0..INSTRUCTIONS {
+                cpu.execute_instruction(machine.as_mut());
+            }
+        });
+    });
+    group.bench_function("adl_loop_blocks", |b| {
+        let mut machine = Box::new(PlainMachine::new());
+        let mut cpu = Cpu::new_ez80();
+        cpu.set_block_cache(true);
+        for (i, byte) in code.iter().enumerate() {
+            machine.poke(i as u32, *byte);
+        }
+        cpu.set_adl(true);
+        cpu.state.set_pc(0);
+        b.iter(|| cpu.execute_instructions(machine.as_mut(), INSTRUCTIONS));
+    });
+    group.bench_function("adl_loop_generic", |b| {
+        let mut machine = Box::new(PlainMachine::new());
+        let mut cpu = GenericCpu::<PlainMachine>::new_ez80();
+        for (i, byte) in code.iter().enumerate() {
+            machine.poke(i as u32, *byte);
+        }
+        cpu.set_adl(true);
+        cpu.state.set_pc(0);
+        b.iter(|| {
+            for _ in 0..INSTRUCTIONS {
+                cpu.execute_instruction(machine.as_mut());
+            }
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(benches, zexall, ez80_loop);
+criterion_main!(benches);
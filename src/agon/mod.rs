@@ -0,0 +1,278 @@
+//! Agon Light machine
+//!
+//! An eZ80F92 at 18.432 MHz with the memory map of the Agon Light, the
+//! on-chip peripherals and a stand-in of the VDP on UART0:
+//!
+//! ```text
+//! $000000 - $01FFFF  on-chip flash, MOS
+//! $040000 - $0BFFFF  external RAM, 512 KiB on CS0
+//! $0BE000 - $0BFFFF  on-chip RAM, over the external RAM
+//! ```
+//!
+//! The memory controller starts with this map, as MOS sets it on boot. The
+//! SD card is on the SPI port with the chip select on PB4. PD3 is the CTS
+//! line of UART0, kept low, and PB1 gets the vertical blank pulses of the
+//! VDP at 60 Hz.
+//!
+//!# Example
+//! ```no_run
+//!use ez80::agon::*;
+//!
+//!let mut agon = Agon::new(true);
+//!agon.load_mos_file("MOS.bin").unwrap();
+//!loop {
+//!    agon.run_cycles(CLOCK as u64 / 100);
+//!}
+//! ```
+
+use std::fs::File;
+use std::io;
+use std::cell::Cell;
+
+use crate::cpu::Cpu;
+use crate::ez80f92::*;
+use crate::machine::Machine;
+
+mod mos;
+mod vdp;
+
+pub use mos::{Mos, EXIT_ADDRESS, PROGRAM_ADDRESS, SYSVARS};
+pub use vdp::{Vdp, COLUMNS, ROWS};
+
+/// Frequency of the system clock
+pub const CLOCK: u32 = 18_432_000;
+/// Start of the external RAM
+pub const EXTERNAL_RAM: u32 = 0x040000;
+/// Size of the external RAM
+pub const EXTERNAL_RAM_SIZE: usize = 0x80000;
+/// Start of the on-chip RAM
+pub const ONCHIP_RAM: u32 = 0x0be000;
+
+const SD_CS_PIN: u8 = 4;
+const VBLANK_PIN: u8 = 1;
+const CTS_PIN: u8 = 3;
+const VBLANK_CYCLES: u64 = CLOCK as u64 / 60;
+
+/// The external bus of the Agon Light: the RAM on CS0 and the on-chip
+/// peripherals on the internal I/O ports
+pub struct AgonBus {
+    /// External RAM
+    pub ram: Vec<u8>,
+    pub timers: Timers,
+    /// UART0, connected to the VDP
+    pub uart0: Uart<Vdp>,
+    /// UART1, on the GPIO header
+    pub uart1: Uart<BufferBackend>,
+    /// Ports B, C and D
+    pub gpio: [GpioPort; 3],
+    /// SPI, with the SD card
+    pub spi: Spi<Option<SdCard<File>>>,
+    pub i2c: I2c,
+    pub rtc: Rtc,
+    pub watchdog: Watchdog,
+    pub intc: InterruptController,
+    cycles: Cell<u64>,
+}
+
+impl AgonBus {
+    fn new(vdp: Vdp) -> AgonBus {
+        let mut gpio = [GpioPort::new(0), GpioPort::new(1), GpioPort::new(2)];
+        gpio[2].set_input(CTS_PIN, false);
+        AgonBus {
+            ram: vec![0; EXTERNAL_RAM_SIZE],
+            timers: Timers::new(),
+            uart0: Uart::new(0, vdp),
+            uart1: Uart::new(1, BufferBackend::new()),
+            gpio,
+            spi: Spi::new(None),
+            i2c: I2c::new(),
+            rtc: Rtc::new(CLOCK),
+            watchdog: Watchdog::new(CLOCK),
+            intc: InterruptController::new(),
+            cycles: Cell::new(0),
+        }
+    }
+
+    /// Returns the cycles of the system clock used
+    pub fn cycles(&self) -> u64 {
+        self.cycles.get()
+    }
+
+    fn peripherals(&mut self) -> [&mut dyn Peripheral; 11] {
+        let [pb, pc, pd] = &mut self.gpio;
+        [
+            &mut self.timers, &mut self.uart0, &mut self.uart1, pb, pc, pd,
+            &mut self.spi, &mut self.i2c, &mut self.rtc, &mut self.watchdog,
+            &mut self.intc,
+        ]
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        for peripheral in self.peripherals().iter_mut() {
+            peripheral.tick(cycles);
+        }
+        let [pb, pc, pd] = &self.gpio;
+        self.intc.sample(&[
+            &self.timers, &self.uart0, &self.uart1, pb, pc, pd,
+            &self.spi, &self.i2c, &self.rtc,
+        ]);
+    }
+
+    fn ram_offset(address: u32) -> Option<usize> {
+        let offset = address.wrapping_sub(EXTERNAL_RAM) as usize;
+        if offset < EXTERNAL_RAM_SIZE { Some(offset) } else { None }
+    }
+}
+
+impl Machine for AgonBus {
+    fn peek(&self, address: u32) -> u8 {
+        self.use_cycles(1);
+        self.peek_debug(address)
+    }
+
+    fn peek_debug(&self, address: u32) -> u8 {
+        AgonBus::ram_offset(address).map_or(0xff, |offset| self.ram[offset])
+    }
+
+    fn poke(&mut self, address: u32, value: u8) {
+        self.use_cycles(1);
+        if let Some(offset) = AgonBus::ram_offset(address) {
+            self.ram[offset] = value;
+        }
+    }
+
+    fn use_cycles(&self, cycles: i32) {
+        self.cycles.set(self.cycles.get().wrapping_add(cycles as u64));
+    }
+
+    fn port_in(&mut self, address: u16) -> u8 {
+        self.use_cycles(1);
+        if address & 0xff00 == 0 {
+            for peripheral in self.peripherals().iter_mut() {
+                if let Some(value) = peripheral.port_in(address) {
+                    return value;
+                }
+            }
+        }
+        0xff
+    }
+
+    fn port_out(&mut self, address: u16, value: u8) {
+        self.use_cycles(1);
+        if address & 0xff00 == 0 {
+            for peripheral in self.peripherals().iter_mut() {
+                if peripheral.port_out(address, value) {
+                    break;
+                }
+            }
+            // The chip select of the SD card is active low
+            let selected = self.gpio[0].pins() & (1 << SD_CS_PIN) == 0;
+            self.spi.select(selected);
+        }
+    }
+}
+
+/// An Agon Light, the CPU and the machine
+pub struct Agon {
+    pub cpu: Cpu,
+    /// The memory controller, on top of the external bus
+    pub sys: MemoryController<AgonBus>,
+    next_vblank: u64,
+}
+
+impl Agon {
+    /// Returns the machine after a reset, with the flash erased and no SD
+    /// card. With [echo] the text rendered by the VDP is also written to
+    /// stdout.
+    pub fn new(echo: bool) -> Agon {
+        let mut cpu = Cpu::new_ez80();
+        cpu.set_adl(true);
+
+        let mut sys = MemoryController::new(AgonBus::new(Vdp::new(echo)));
+        sys.cs[0] = ChipSelect { lbr: 0x04, ubr: 0x0b, ctl: 0x08, bmc: 0x02 };
+        sys.ram_addr_u = (ONCHIP_RAM >> 16) as u8;
+        sys.flash_ctrl = 0x28;
+
+        Agon {
+            cpu,
+            sys,
+            next_vblank: VBLANK_CYCLES,
+        }
+    }
+
+    /// Copies a MOS image to the flash
+    pub fn load_mos(&mut self, image: &[u8]) {
+        let size = image.len().min(FLASH_SIZE);
+        self.sys.load_flash(0, &image[..size]);
+    }
+
+    /// Copies a MOS image file to the flash
+    pub fn load_mos_file(&mut self, path: &str) -> io::Result<()> {
+        let image = std::fs::read(path)?;
+        self.load_mos(&image);
+        Ok(())
+    }
+
+    /// Inserts an SD card, or removes it with None
+    pub fn insert_sdcard(&mut self, card: Option<SdCard<File>>) {
+        self.sys.bus.spi.device = card;
+    }
+
+    /// Returns the VDP
+    pub fn vdp(&mut self) -> &mut Vdp {
+        &mut self.sys.bus.uart0.backend
+    }
+
+    /// Returns the cycles of the system clock used
+    pub fn cycles(&self) -> u64 {
+        self.sys.bus.cycles()
+    }
+
+    /// Executes an instruction, advances the peripherals and delivers the
+    /// interrupts
+    pub fn step(&mut self) {
+        let cycles = self.cycles();
+        if self.cpu.is_halted() {
+            // The clock keeps running
+            self.sys.bus.use_cycles(4);
+        }
+        self.cpu.execute_instruction(&mut self.sys);
+        let elapsed = self.cycles() - cycles;
+        self.sys.bus.tick(elapsed as u32);
+
+        if self.cycles() >= self.next_vblank {
+            self.next_vblank += VBLANK_CYCLES;
+            let pb = &mut self.sys.bus.gpio[0];
+            pb.set_input(VBLANK_PIN, false);
+            pb.set_input(VBLANK_PIN, true);
+        }
+
+        self.sys.bus.watchdog.signal(&mut self.cpu);
+        let mut intc = std::mem::take(&mut self.sys.bus.intc);
+        intc.deliver(&mut self.cpu, &mut self.sys);
+        self.sys.bus.intc = intc;
+    }
+
+    /// Runs at least [cycles] of the system clock
+    pub fn run_cycles(&mut self, cycles: u64) {
+        let end = self.cycles() + cycles;
+        while self.cycles() < end {
+            self.step();
+        }
+    }
+
+    /// Runs until [condition] is true or up to [max_cycles] of the system
+    /// clock. Returns true if the condition was met.
+    pub fn run_until<F: FnMut(&mut Agon) -> bool>(&mut self, max_cycles: u64, mut condition: F) -> bool {
+        let end = self.cycles() + max_cycles;
+        while self.cycles() < end {
+            for _ in 0..1000 {
+                self.step();
+            }
+            if condition(self) {
+                return true;
+            }
+        }
+        false
+    }
+}
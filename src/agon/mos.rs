@@ -0,0 +1,648 @@
+//! High level emulation of the MOS API
+//!
+//! Runs Agon `.bin` programs without a MOS image: the calls to MOS are
+//! trapped and serviced on the host. The files are on a host directory,
+//! the root of the SD card, and the text goes to a `Console`.
+//!
+//! ```text
+//! RST.LIL 08h  MOS API, function in A
+//! RST.LIL 10h  output the character in A
+//! RST.LIL 18h  output BC bytes at HL, or up to the delimiter in A if BC is 0
+//! ```
+//!
+//! The functions of the API return the same registers as MOS. These are
+//! emulated:
+//!
+//! ```text
+//! $00 mos_getkey     $08 mos_sysvars    $0F mos_getError
+//! $01 mos_load       $09 mos_editline   $1A mos_fread
+//! $02 mos_save       $0A mos_fopen      $1B mos_fwrite
+//! $03 mos_cd         $0B mos_fclose     $1C mos_flseek
+//! $04 mos_dir        $0C mos_fgetc
+//! $05 mos_del        $0D mos_fputc
+//! $06 mos_ren        $0E mos_feof
+//! $07 mos_mkdir
+//! ```
+//!
+//! The rest return A = 23, not implemented. The programs run at $040000 in
+//! ADL mode and return to MOS with RET, with the exit code in HL.
+//!
+//!# Example
+//! ```no_run
+//!use ez80::agon::*;
+//!use ez80::cpm::BufferConsole;
+//!
+//!let program = std::fs::read("hello.bin").unwrap();
+//!let mut agon = Agon::new(false);
+//!let mos = Mos::new(BufferConsole::new(b""));
+//!mos.map_directory(".");
+//!mos.install(&mut agon.cpu);
+//!mos.load(&mut agon.cpu, &mut agon.sys, &program, "");
+//!mos.run(&mut agon.cpu, &mut agon.sys);
+//!print!("{}", mos.console().output_string());
+//! ```
+
+use std::cell::{RefCell, RefMut};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::time::Instant;
+
+use crate::cpm::Console;
+use crate::cpu::Cpu;
+use crate::machine::Machine;
+use crate::registers::*;
+use crate::trap::{Trap, TrapAction};
+
+use super::{COLUMNS, ONCHIP_RAM, ROWS};
+
+/// Address where the programs are loaded and started
+pub const PROGRAM_ADDRESS: u32 = 0x040000;
+/// Address of the system variables, returned by mos_sysvars
+pub const SYSVARS: u32 = ONCHIP_RAM;
+/// Address the programs return to
+pub const EXIT_ADDRESS: u32 = ONCHIP_RAM + 0x100;
+
+const ARGS_ADDRESS: u32 = ONCHIP_RAM + 0x200;
+const ARGS_SIZE: usize = 256;
+const STACK_TOP: u32 = 0x0c0000;
+const MAX_OPEN_FILES: usize = 8;
+const MAX_STRING: u32 = 256;
+
+const KEY_ESCAPE: u8 = 0x1b;
+
+// Offsets of the system variables
+const SYSVAR_TIME: u32 = 0x00;
+const SYSVAR_KEYASCII: u32 = 0x05;
+const SYSVAR_SCR_WIDTH: u32 = 0x0f;
+const SYSVAR_SCR_HEIGHT: u32 = 0x11;
+const SYSVAR_SCR_COLS: u32 = 0x13;
+const SYSVAR_SCR_ROWS: u32 = 0x14;
+const SYSVAR_SCR_COLOURS: u32 = 0x15;
+const SYSVAR_VKEYCOUNT: u32 = 0x19;
+
+// Modes of mos_fopen
+const FA_WRITE: u8 = 0x02;
+const FA_CREATE_NEW: u8 = 0x04;
+const FA_CREATE_ALWAYS: u8 = 0x08;
+const FA_OPEN_ALWAYS: u8 = 0x10;
+const FA_OPEN_APPEND: u8 = 0x30;
+
+// Status codes, the FatFs ones and the ones of MOS
+const FR_OK: u8 = 0;
+const FR_DISK_ERR: u8 = 1;
+const FR_NO_FILE: u8 = 4;
+const FR_NO_PATH: u8 = 5;
+const FR_DENIED: u8 = 7;
+const FR_EXIST: u8 = 8;
+const FR_INVALID_OBJECT: u8 = 9;
+const FR_INVALID_PARAMETER: u8 = 19;
+const MOS_OUT_OF_MEMORY: u8 = 22;
+const MOS_NOT_IMPLEMENTED: u8 = 23;
+
+const ERRORS: [&str; 26] = [
+    "OK",
+    "Error accessing SD card",
+    "Assertion failed",
+    "SD card failure",
+    "Could not find file",
+    "Could not find path",
+    "Invalid path name",
+    "Access denied or directory full",
+    "Access denied",
+    "Invalid file/directory object",
+    "SD card is write protected",
+    "Logical drive number is invalid",
+    "Volume has no work area",
+    "No valid FAT volume",
+    "Error occurred during mkfs",
+    "Volume timeout",
+    "Volume locked",
+    "LFN working buffer could not be allocated",
+    "Too many open files",
+    "Invalid parameter",
+    "Invalid command",
+    "Invalid executable",
+    "Out of memory",
+    "Not implemented",
+    "Load overlaps system area",
+    "Bad string",
+];
+
+struct MosState<C: Console> {
+    console: C,
+    root: Option<PathBuf>,
+    // Current directory, relative to the root
+    cwd: PathBuf,
+    files: [Option<File>; MAX_OPEN_FILES],
+    start: Instant,
+    terminated: bool,
+}
+
+/// The MOS API emulated on the host
+///
+/// `install()` sets the traps of the RST instructions and of the return
+/// address on a Cpu. The traps share the state with this handle, to use
+/// the console and the files while the program runs.
+pub struct Mos<C: Console> {
+    state: Rc<RefCell<MosState<C>>>,
+}
+
+impl<C: Console + 'static> Mos<C> {
+    /// Returns the MOS API with no directory mapped
+    pub fn new(console: C) -> Mos<C> {
+        Mos {
+            state: Rc::new(RefCell::new(MosState {
+                console,
+                root: None,
+                cwd: PathBuf::new(),
+                files: Default::default(),
+                start: Instant::now(),
+                terminated: false,
+            })),
+        }
+    }
+
+    /// Maps the root of the SD card onto a host directory
+    pub fn map_directory<P: Into<PathBuf>>(&self, path: P) {
+        let mut state = self.state.borrow_mut();
+        state.root = Some(path.into());
+        state.cwd = PathBuf::new();
+    }
+
+    /// Returns the console
+    pub fn console(&self) -> RefMut<'_, C> {
+        RefMut::map(self.state.borrow_mut(), |state| &mut state.console)
+    }
+
+    /// Returns true when the program has returned to MOS
+    pub fn is_terminated(&self) -> bool {
+        self.state.borrow().terminated
+    }
+
+    /// Sets the traps of RST 08h, 10h and 18h and of the return address on
+    /// the Cpu
+    pub fn install(&self, cpu: &mut Cpu) {
+        let state = Rc::clone(&self.state);
+        cpu.set_trap(Trap::Address { address: EXIT_ADDRESS, adl: Some(true) }, Some(Box::new(move |_, _| {
+            // The HALT stops the CPU
+            state.borrow_mut().terminated = true;
+            TrapAction::Execute
+        })));
+        let state = Rc::clone(&self.state);
+        cpu.set_trap(Trap::Rst(0x08), Some(Box::new(move |reg, sys| {
+            state.borrow_mut().api(reg, sys);
+            TrapAction::Skip
+        })));
+        let state = Rc::clone(&self.state);
+        cpu.set_trap(Trap::Rst(0x10), Some(Box::new(move |reg, _| {
+            state.borrow_mut().console.write(reg.a());
+            TrapAction::Skip
+        })));
+        let state = Rc::clone(&self.state);
+        cpu.set_trap(Trap::Rst(0x18), Some(Box::new(move |reg, sys| {
+            state.borrow_mut().write_string(reg, sys);
+            TrapAction::Skip
+        })));
+    }
+
+    /// Loads a program at $040000 and prepares the CPU to run it in ADL
+    /// mode, with HL pointing to the arguments
+    ///
+    /// # Arguments
+    ///
+    /// * `program` - Contents of the `.bin` file
+    /// * `args` - Command line after the name of the program
+    pub fn load(&self, cpu: &mut Cpu, sys: &mut dyn Machine, program: &[u8], args: &str) {
+        for (i, b) in program.iter().enumerate() {
+            sys.poke(PROGRAM_ADDRESS + i as u32, *b);
+        }
+        sys.poke(EXIT_ADDRESS, 0x76); // HALT
+        self.state.borrow_mut().terminated = false;
+
+        let args = &args.as_bytes()[..args.len().min(ARGS_SIZE - 1)];
+        for (i, b) in args.iter().chain([0].iter()).enumerate() {
+            sys.poke(ARGS_ADDRESS + i as u32, *b);
+        }
+
+        let sp = STACK_TOP - 3;
+        for (i, b) in EXIT_ADDRESS.to_le_bytes()[..3].iter().enumerate() {
+            sys.poke(sp + i as u32, *b);
+        }
+        cpu.set_adl(true);
+        let reg = cpu.registers();
+        reg.mbase = 0;
+        reg.set24(Reg24::SPL, sp);
+        reg.set24(Reg24::HL, ARGS_ADDRESS);
+        cpu.state.set_pc(PROGRAM_ADDRESS);
+        cpu.state.halted = false;
+        cpu.clear_decode_cache();
+    }
+
+    /// Executes a single instruction. Returns false once the program has
+    /// returned to MOS.
+    pub fn step(&self, cpu: &mut Cpu, sys: &mut dyn Machine) -> bool {
+        if self.is_terminated() {
+            return false;
+        }
+        cpu.execute_instruction(sys);
+        !self.is_terminated()
+    }
+
+    /// Runs the program until it returns to MOS, or halts, and returns
+    /// its exit code
+    pub fn run(&self, cpu: &mut Cpu, sys: &mut dyn Machine) -> u32 {
+        while !self.is_terminated() && !cpu.is_halted() {
+            cpu.execute_instructions(sys, u64::MAX);
+        }
+        cpu.registers().get24(Reg24::HL)
+    }
+}
+
+impl<C: Console> MosState<C> {
+    fn api(&mut self, reg: &mut Registers, sys: &mut dyn Machine) {
+        self.update_sysvars(sys);
+        match reg.a() {
+            0x00 => { // mos_getkey
+                let key = self.console.read().unwrap_or(KEY_ESCAPE);
+                sys.poke(SYSVARS + SYSVAR_KEYASCII, key);
+                reg.set_a(key);
+            }
+            0x01 => { // mos_load
+                let name = read_string(reg, sys, Reg24::HL);
+                let result = self.load_file(sys, &name, address(reg, Reg24::DE), count(reg, Reg24::BC));
+                reg.set_a(result);
+            }
+            0x02 => { // mos_save
+                let name = read_string(reg, sys, Reg24::HL);
+                let (start, size) = (address(reg, Reg24::DE), count(reg, Reg24::BC));
+                let data: Vec<u8> = (0..size).map(|i| sys.peek(start + i)).collect();
+                let result = self.host_path(&name).map(|path| fs::write(path, data));
+                reg.set_a(status(result));
+            }
+            0x03 => { // mos_cd
+                let name = read_string(reg, sys, Reg24::HL);
+                reg.set_a(self.change_directory(&name));
+            }
+            0x04 => { // mos_dir
+                let name = read_string(reg, sys, Reg24::HL);
+                reg.set_a(self.list_directory(&name));
+            }
+            0x05 => { // mos_del
+                let name = read_string(reg, sys, Reg24::HL);
+                let result = self.host_path(&name).map(|path| {
+                    if path.is_dir() { fs::remove_dir(path) } else { fs::remove_file(path) }
+                });
+                reg.set_a(status(result));
+            }
+            0x06 => { // mos_ren
+                let from = read_string(reg, sys, Reg24::HL);
+                let to = read_string(reg, sys, Reg24::DE);
+                let result = match (self.host_path(&from), self.host_path(&to)) {
+                    (Ok(_), Ok(to)) if to.exists() => Err(io::ErrorKind::AlreadyExists.into()),
+                    (Ok(from), Ok(to)) => Ok(fs::rename(from, to)),
+                    (Err(e), _) | (_, Err(e)) => Err(e),
+                };
+                reg.set_a(status(result));
+            }
+            0x07 => { // mos_mkdir
+                let name = read_string(reg, sys, Reg24::HL);
+                reg.set_a(status(self.host_path(&name).map(fs::create_dir)));
+            }
+            0x08 => { // mos_sysvars
+                reg.set24(Reg24::IX, SYSVARS);
+            }
+            0x09 => { // mos_editline
+                let key = self.edit_line(sys, address(reg, Reg24::HL), count(reg, Reg24::BC),
+                    reg.get8(Reg8::E) & 1 != 0);
+                reg.set_a(key);
+            }
+            0x0a => { // mos_fopen
+                let name = read_string(reg, sys, Reg24::HL);
+                let handle = self.open_file(&name, reg.get8(Reg8::C));
+                reg.set_a(handle);
+            }
+            0x0b => { // mos_fclose
+                match reg.get8(Reg8::C) {
+                    0 => self.files.iter_mut().for_each(|file| *file = None),
+                    handle => if let Some(file) = self.files.get_mut(handle as usize - 1) {
+                        *file = None;
+                    }
+                }
+                let open = self.files.iter().filter(|file| file.is_some()).count();
+                reg.set_a(open as u8);
+            }
+            0x0c => { // mos_fgetc
+                let mut data = [0];
+                // Carry on the last byte of the file too, like MOS does
+                let last = self.file(reg.get8(Reg8::C)).map_or(true, |file| {
+                    !file.read(&mut data).is_ok_and(|n| n == 1) || at_end(file)
+                });
+                reg.set_a(data[0]);
+                reg.put_flag(Flag::C, last);
+            }
+            0x0d => { // mos_fputc
+                let value = reg.get8(Reg8::B);
+                if let Some(file) = self.file(reg.get8(Reg8::C)) {
+                    let _ = file.write_all(&[value]);
+                }
+            }
+            0x0e => { // mos_feof
+                let eof = self.file(reg.get8(Reg8::C)).map_or(true, at_end);
+                reg.set_a(eof as u8);
+            }
+            0x0f => { // mos_getError
+                let message = ERRORS.get(reg.get8(Reg8::E) as usize).copied().unwrap_or("");
+                let (buffer, size) = (address(reg, Reg24::HL), count(reg, Reg24::BC));
+                write_string(sys, buffer, size, message.as_bytes());
+            }
+            0x1a => { // mos_fread
+                let (buffer, size) = (address(reg, Reg24::HL), count(reg, Reg24::DE));
+                let mut data = vec![0; size as usize];
+                let read = self.file(reg.get8(Reg8::C)).map_or(0, |file| read_all(file, &mut data));
+                for (i, b) in data[..read].iter().enumerate() {
+                    sys.poke(buffer + i as u32, *b);
+                }
+                set_count(reg, Reg24::DE, read as u32);
+            }
+            0x1b => { // mos_fwrite
+                let (buffer, size) = (address(reg, Reg24::HL), count(reg, Reg24::DE));
+                let data: Vec<u8> = (0..size).map(|i| sys.peek(buffer + i)).collect();
+                let written = self.file(reg.get8(Reg8::C))
+                    .map_or(0, |file| if file.write_all(&data).is_ok() { size } else { 0 });
+                set_count(reg, Reg24::DE, written);
+            }
+            0x1c => { // mos_flseek
+                let offset = reg.get24(Reg24::HL) as u64 | (reg.get8(Reg8::E) as u64) << 24;
+                let result = match self.file(reg.get8(Reg8::C)) {
+                    Some(file) => status(Ok(file.seek(SeekFrom::Start(offset)).map(|_| ()))),
+                    None => FR_INVALID_OBJECT,
+                };
+                reg.set_a(result);
+            }
+            _ => reg.set_a(MOS_NOT_IMPLEMENTED),
+        }
+    }
+
+    fn write_string(&mut self, reg: &Registers, sys: &mut dyn Machine) {
+        let start = address(reg, Reg24::HL);
+        let length = count(reg, Reg24::BC);
+        if length > 0 {
+            for i in 0..length {
+                self.console.write(sys.peek(start + i));
+            }
+        } else {
+            let delimiter = reg.a();
+            let mut address = start;
+            loop {
+                let c = sys.peek(address);
+                if c == delimiter {
+                    break;
+                }
+                self.console.write(c);
+                address += 1;
+            }
+        }
+    }
+
+    fn update_sysvars(&mut self, sys: &mut dyn Machine) {
+        let centiseconds = (self.start.elapsed().as_millis() / 10) as u32;
+        for (i, b) in centiseconds.to_le_bytes().iter().enumerate() {
+            sys.poke(SYSVARS + SYSVAR_TIME + i as u32, *b);
+        }
+        for (i, b) in ((COLUMNS * 8) as u16).to_le_bytes().iter().enumerate() {
+            sys.poke(SYSVARS + SYSVAR_SCR_WIDTH + i as u32, *b);
+        }
+        for (i, b) in ((ROWS * 8) as u16).to_le_bytes().iter().enumerate() {
+            sys.poke(SYSVARS + SYSVAR_SCR_HEIGHT + i as u32, *b);
+        }
+        sys.poke(SYSVARS + SYSVAR_SCR_COLS, COLUMNS as u8);
+        sys.poke(SYSVARS + SYSVAR_SCR_ROWS, ROWS as u8);
+        sys.poke(SYSVARS + SYSVAR_SCR_COLOURS, 16);
+        if self.console.status() {
+            // A key is waiting, as if the VDP had sent it
+            let count = sys.peek(SYSVARS + SYSVAR_VKEYCOUNT);
+            sys.poke(SYSVARS + SYSVAR_VKEYCOUNT, count.wrapping_add(1));
+        }
+    }
+
+    /// Returns the host path of a MOS path, absolute or relative to the
+    /// current directory. The path can't go out of the root.
+    fn host_path(&self, name: &str) -> io::Result<PathBuf> {
+        let root = self.root.as_ref().ok_or(io::ErrorKind::NotFound)?;
+        let mut relative = if name.starts_with('/') { PathBuf::new() } else { self.cwd.clone() };
+        for component in Path::new(name).components() {
+            match component {
+                Component::Normal(part) => relative.push(part),
+                Component::ParentDir => {
+                    relative.pop();
+                }
+                _ => {}
+            }
+        }
+
+        // The names are not case sensitive, like on FAT
+        let mut path = root.clone();
+        for part in relative.iter() {
+            let found = fs::read_dir(&path).ok().and_then(|entries| entries
+                .filter_map(|entry| entry.ok())
+                .find(|entry| entry.file_name().to_string_lossy()
+                    .eq_ignore_ascii_case(&part.to_string_lossy())));
+            match found {
+                Some(entry) => path.push(entry.file_name()),
+                None => path.push(part),
+            }
+        }
+        Ok(path)
+    }
+
+    fn load_file(&mut self, sys: &mut dyn Machine, name: &str, start: u32, size: u32) -> u8 {
+        let data = match self.host_path(name).and_then(fs::read) {
+            Ok(data) => data,
+            Err(e) => return error_status(&e),
+        };
+        if data.len() > size as usize {
+            return MOS_OUT_OF_MEMORY;
+        }
+        for (i, b) in data.iter().enumerate() {
+            sys.poke(start + i as u32, *b);
+        }
+        FR_OK
+    }
+
+    fn change_directory(&mut self, name: &str) -> u8 {
+        let path = match self.host_path(name) {
+            Ok(path) => path,
+            Err(e) => return error_status(&e),
+        };
+        if !path.is_dir() {
+            return FR_NO_PATH;
+        }
+        let root = self.root.as_ref().map_or(Path::new(""), |root| root.as_path());
+        self.cwd = path.strip_prefix(root).map_or(PathBuf::new(), |cwd| cwd.to_path_buf());
+        FR_OK
+    }
+
+    fn list_directory(&mut self, name: &str) -> u8 {
+        let entries = match self.host_path(name).and_then(fs::read_dir) {
+            Ok(entries) => entries,
+            Err(e) => return error_status(&e),
+        };
+        let mut lines: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                match entry.metadata() {
+                    Ok(metadata) if metadata.is_dir() => format!("   <DIR> {}", name),
+                    Ok(metadata) => format!("{:8} {}", metadata.len(), name),
+                    Err(_) => name,
+                }
+            })
+            .collect();
+        lines.sort_by_key(|line| line[9.min(line.len())..].to_ascii_lowercase());
+        for line in lines {
+            for c in line.bytes().chain(b"\r\n".iter().copied()) {
+                self.console.write(c);
+            }
+        }
+        FR_OK
+    }
+
+    /// Reads a line from the console to a buffer of [size] bytes, zero
+    /// terminated. Returns the key that ended it, Return or Escape.
+    fn edit_line(&mut self, sys: &mut dyn Machine, buffer: u32, size: u32, clear: bool) -> u8 {
+        let mut line: Vec<u8> = Vec::new();
+        if !clear {
+            line.extend((0..size).map(|i| sys.peek(buffer + i)).take_while(|c| *c != 0));
+            line.iter().for_each(|c| self.console.write(*c));
+        }
+        let key = loop {
+            match self.console.read().unwrap_or(KEY_ESCAPE) {
+                13 => break 13,
+                KEY_ESCAPE => break KEY_ESCAPE,
+                8 | 127 if line.pop().is_some() => self.console.write(127),
+                c if c >= 32 && (line.len() as u32) + 1 < size => {
+                    line.push(c);
+                    self.console.write(c);
+                }
+                _ => {}
+            }
+        };
+        write_string(sys, buffer, size, &line);
+        self.console.write(b'\r');
+        self.console.write(b'\n');
+        key
+    }
+
+    /// Opens a file with the mode of FatFs. Returns the handle, or 0 if
+    /// it can't be opened.
+    fn open_file(&mut self, name: &str, mode: u8) -> u8 {
+        let slot = match self.files.iter().position(|file| file.is_none()) {
+            Some(slot) => slot,
+            None => return 0,
+        };
+        let mut options = OpenOptions::new();
+        options.read(true).write(mode & FA_WRITE != 0);
+        if mode & FA_OPEN_APPEND == FA_OPEN_APPEND || mode & FA_OPEN_ALWAYS != 0 {
+            options.create(true);
+        } else if mode & FA_CREATE_ALWAYS != 0 {
+            options.create(true).truncate(true);
+        } else if mode & FA_CREATE_NEW != 0 {
+            options.create_new(true);
+        }
+        match self.host_path(name).and_then(|path| options.open(path)) {
+            Ok(mut file) => {
+                if mode & FA_OPEN_APPEND == FA_OPEN_APPEND {
+                    let _ = file.seek(SeekFrom::End(0));
+                }
+                self.files[slot] = Some(file);
+                slot as u8 + 1
+            }
+            Err(_) => 0,
+        }
+    }
+
+    fn file(&mut self, handle: u8) -> Option<&mut File> {
+        let slot = (handle as usize).checked_sub(1)?;
+        self.files.get_mut(slot)?.as_mut()
+    }
+}
+
+/// Returns the address in a register, on the page of MBASE in Z80 mode
+fn address(reg: &Registers, rr: Reg24) -> u32 {
+    let value = reg.get24(rr);
+    if reg.adl { value } else { ((reg.mbase as u32) << 16) | (value & 0xffff) }
+}
+
+/// Returns the count in a register, of 16 bits in Z80 mode
+fn count(reg: &Registers, rr: Reg24) -> u32 {
+    let value = reg.get24(rr);
+    if reg.adl { value } else { value & 0xffff }
+}
+
+fn set_count(reg: &mut Registers, rr: Reg24, value: u32) {
+    if reg.adl {
+        reg.set24(rr, value);
+    } else {
+        let upper = reg.get24(rr) & 0xff0000;
+        reg.set24(rr, upper | (value & 0xffff));
+    }
+}
+
+/// Reads a string terminated by 0 or CR
+fn read_string(reg: &Registers, sys: &dyn Machine, rr: Reg24) -> String {
+    let start = address(reg, rr);
+    let bytes: Vec<u8> = (0..MAX_STRING)
+        .map(|i| sys.peek(start + i))
+        .take_while(|c| *c != 0 && *c != 13)
+        .collect();
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+/// Writes a zero terminated string to a buffer of [size] bytes
+fn write_string(sys: &mut dyn Machine, buffer: u32, size: u32, text: &[u8]) {
+    if size == 0 {
+        return;
+    }
+    let length = text.len().min(size as usize - 1);
+    for (i, b) in text[..length].iter().chain([0].iter()).enumerate() {
+        sys.poke(buffer + i as u32, *b);
+    }
+}
+
+/// Returns true if the position of [file] is at its end
+fn at_end(file: &mut File) -> bool {
+    let position = file.stream_position().unwrap_or(0);
+    let size = file.metadata().map_or(0, |metadata| metadata.len());
+    position >= size
+}
+
+fn read_all(file: &mut File, data: &mut [u8]) -> usize {
+    let mut read = 0;
+    while read < data.len() {
+        match file.read(&mut data[read..]) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => read += n
+        }
+    }
+    read
+}
+
+fn error_status(e: &io::Error) -> u8 {
+    match e.kind() {
+        io::ErrorKind::NotFound => FR_NO_FILE,
+        io::ErrorKind::PermissionDenied => FR_DENIED,
+        io::ErrorKind::AlreadyExists => FR_EXIST,
+        io::ErrorKind::InvalidInput => FR_INVALID_PARAMETER,
+        _ => FR_DISK_ERR,
+    }
+}
+
+/// Returns the status code of an operation on a host path
+fn status(result: io::Result<io::Result<()>>) -> u8 {
+    match result.and_then(|result| result) {
+        Ok(()) => FR_OK,
+        Err(e) => error_status(&e),
+    }
+}
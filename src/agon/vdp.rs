@@ -0,0 +1,245 @@
+//! Stand-in of the VDP, the ESP32 that renders the video of the Agon Light
+//!
+//! It receives the VDU byte stream sent by MOS on UART0 and renders the
+//! text on a character frame buffer, optionally copying it to stdout. The
+//! graphics commands are parsed and ignored. It answers the system
+//! commands, VDU 23, 0, n, used by MOS:
+//!
+//! ```text
+//! $80  general poll, echoes the byte
+//! $82  cursor position
+//! $83  character at a position
+//! $86  screen mode information
+//! $87  real time clock, the time of the host
+//! ```
+//!
+//! The keys are sent to MOS as keyboard packets, a key down and a key up.
+
+use std::collections::VecDeque;
+use std::io::Write;
+
+use crate::ez80f92::{RtcTime, SerialBackend};
+
+/// Columns of the text screen, mode 0 of the VDP
+pub const COLUMNS: usize = 80;
+/// Rows of the text screen, mode 0 of the VDP
+pub const ROWS: usize = 60;
+
+const PACKET_GP: u8 = 0x80;
+const PACKET_KEYCODE: u8 = 0x81;
+const PACKET_CURSOR: u8 = 0x82;
+const PACKET_SCRCHAR: u8 = 0x83;
+const PACKET_SCRPIXEL: u8 = 0x84;
+const PACKET_MODE: u8 = 0x86;
+const PACKET_RTC: u8 = 0x87;
+
+/// A VDP rendering text, a SerialBackend for UART0
+pub struct Vdp {
+    screen: Vec<u8>,
+    cursor: (usize, usize),
+    // Bytes of the VDU command being received
+    command: Vec<u8>,
+    // Packets to MOS
+    output: VecDeque<u8>,
+    echo: bool,
+}
+
+impl Vdp {
+    /// Returns the VDP with the screen cleared. With [echo], the text is
+    /// also written to stdout.
+    pub fn new(echo: bool) -> Vdp {
+        Vdp {
+            screen: vec![b' '; COLUMNS * ROWS],
+            cursor: (0, 0),
+            command: Vec::new(),
+            output: VecDeque::new(),
+            echo,
+        }
+    }
+
+    /// Returns the text of a row, without the trailing spaces
+    pub fn line(&self, row: usize) -> String {
+        let line = &self.screen[row * COLUMNS..(row + 1) * COLUMNS];
+        String::from_utf8_lossy(line).trim_end().to_string()
+    }
+
+    /// Returns the text of the screen, a line for each row up to the last
+    /// one not empty
+    pub fn text(&self) -> String {
+        let lines: Vec<String> = (0..ROWS).map(|row| self.line(row)).collect();
+        let used = lines.iter().rposition(|line| !line.is_empty()).map_or(0, |row| row + 1);
+        lines[..used].join("\n")
+    }
+
+    /// Returns the column and row of the text cursor
+    pub fn cursor(&self) -> (usize, usize) {
+        self.cursor
+    }
+
+    /// Sends the key with the ASCII code [key] to MOS
+    pub fn press_key(&mut self, key: u8) {
+        for down in [1, 0].iter() {
+            self.packet(PACKET_KEYCODE, &[key, 0, 0, *down]);
+        }
+    }
+
+    /// Sends the keys of a text to MOS, with the line feeds as Return
+    pub fn type_text(&mut self, text: &str) {
+        for key in text.bytes() {
+            self.press_key(if key == b'\n' { b'\r' } else { key });
+        }
+    }
+
+    fn packet(&mut self, command: u8, data: &[u8]) {
+        self.output.push_back(command);
+        self.output.push_back(data.len() as u8);
+        self.output.extend(data.iter());
+    }
+
+    /// Returns the length of the VDU command being received
+    fn command_length(&self) -> usize {
+        match self.command[0] {
+            1 | 17 | 22 | 27 => 2,
+            18 | 31 => 3,
+            28 | 29 => 5,
+            19 | 25 => 6,
+            24 => 9,
+            23 => match (self.command.get(1), self.command.get(2)) {
+                (None, _) | (Some(0), None) => 3,
+                (Some(0), Some(command)) => 3 + match *command {
+                    PACKET_GP | PACKET_KEYCODE => 1,
+                    PACKET_SCRCHAR | PACKET_SCRPIXEL => 4,
+                    PACKET_RTC => match self.command.get(3) {
+                        Some(1) => 7,
+                        _ => 1,
+                    },
+                    0x88 => 5,
+                    0x8a | 0xc0 => 1,
+                    _ => 0,
+                },
+                // VDU 23, n and 8 bytes
+                _ => 10,
+            },
+            _ => 1,
+        }
+    }
+
+    fn execute(&mut self, command: &[u8]) {
+        match command[0] {
+            8 | 127 => {
+                if self.cursor.0 > 0 {
+                    self.cursor.0 -= 1;
+                } else if self.cursor.1 > 0 {
+                    self.cursor = (COLUMNS - 1, self.cursor.1 - 1);
+                }
+                if command[0] == 127 {
+                    self.screen[self.cursor.1 * COLUMNS + self.cursor.0] = b' ';
+                }
+            }
+            9 => self.advance(),
+            10 => self.line_feed(),
+            11 => self.cursor.1 = self.cursor.1.saturating_sub(1),
+            12 | 22 => {
+                self.screen.iter_mut().for_each(|c| *c = b' ');
+                self.cursor = (0, 0);
+            }
+            13 => self.cursor.0 = 0,
+            23 if command[1] == 0 => self.system_command(&command[2..]),
+            30 => self.cursor = (0, 0),
+            31 => {
+                let (x, y) = (command[1] as usize, command[2] as usize);
+                if x < COLUMNS && y < ROWS {
+                    self.cursor = (x, y);
+                }
+            }
+            c if c >= 32 => {
+                self.screen[self.cursor.1 * COLUMNS + self.cursor.0] = c;
+                self.advance();
+            }
+            _ => {}
+        }
+    }
+
+    fn system_command(&mut self, command: &[u8]) {
+        match command[0] {
+            PACKET_GP => self.packet(PACKET_GP, &[command[1]]),
+            PACKET_CURSOR => {
+                let (x, y) = self.cursor;
+                self.packet(PACKET_CURSOR, &[x as u8, y as u8]);
+            }
+            PACKET_SCRCHAR => {
+                let x = u16::from_le_bytes([command[1], command[2]]) as usize / 8;
+                let y = u16::from_le_bytes([command[3], command[4]]) as usize / 8;
+                let c = if x < COLUMNS && y < ROWS { self.screen[y * COLUMNS + x] } else { 0 };
+                self.packet(PACKET_SCRCHAR, &[c]);
+            }
+            PACKET_SCRPIXEL => self.packet(PACKET_SCRPIXEL, &[0, 0, 0, 0]),
+            PACKET_MODE => {
+                let (width, height) = ((COLUMNS * 8) as u16, (ROWS * 8) as u16);
+                let mut data = Vec::new();
+                data.extend(&width.to_le_bytes());
+                data.extend(&height.to_le_bytes());
+                data.extend(&[COLUMNS as u8, ROWS as u8, 16, 0]);
+                self.packet(PACKET_MODE, &data);
+            }
+            PACKET_RTC if command[1] == 0 => {
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|duration| duration.as_secs())
+                    .unwrap_or(0);
+                let time = RtcTime::from_unix(now);
+                self.packet(PACKET_RTC, &[
+                    (time.year.saturating_sub(1980)) as u8, time.month - 1, time.day,
+                    time.hour, time.minute, time.second,
+                ]);
+            }
+            _ => {}
+        }
+    }
+
+    fn advance(&mut self) {
+        self.cursor.0 += 1;
+        if self.cursor.0 == COLUMNS {
+            self.cursor.0 = 0;
+            self.line_feed();
+        }
+    }
+
+    fn line_feed(&mut self) {
+        if self.cursor.1 + 1 < ROWS {
+            self.cursor.1 += 1;
+        } else {
+            // Scroll up
+            self.screen.copy_within(COLUMNS.., 0);
+            let last = (ROWS - 1) * COLUMNS;
+            self.screen[last..].iter_mut().for_each(|c| *c = b' ');
+        }
+    }
+
+    fn echo(&self, command: &[u8]) {
+        let c = command[0];
+        if c == 8 || c == 10 || c == 13 || (32..127).contains(&c) {
+            let mut stdout = std::io::stdout();
+            let _ = stdout.write_all(&[c]);
+            let _ = stdout.flush();
+        }
+    }
+}
+
+impl SerialBackend for Vdp {
+    fn receive(&mut self) -> Option<u8> {
+        self.output.pop_front()
+    }
+
+    fn send(&mut self, value: u8) {
+        self.command.push(value);
+        if self.command.len() < self.command_length() {
+            return;
+        }
+        let command = std::mem::take(&mut self.command);
+        if self.echo {
+            self.echo(&command);
+        }
+        self.execute(&command);
+    }
+}
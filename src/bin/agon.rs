@@ -0,0 +1,115 @@
+/*
+Agon Light emulator on the terminal.
+
+Boots a MOS flash image with the text of the VDP on stdout and the keys
+typed on stdin. The SD card is a disk image file.
+*/
+use std::io::Read;
+use std::process::exit;
+use std::sync::mpsc;
+use std::thread;
+
+use ez80::agon::*;
+use ez80::ez80f92::SdCard;
+
+const USAGE: &str = "\
+Usage: agon [OPTIONS] MOS_IMAGE
+
+Boots the MOS flash image MOS_IMAGE on an emulated Agon Light.
+
+Options:
+  --sdcard IMAGE       Disk image of the SD card
+  --max-cycles N       Stop after N cycles of the 18.432 MHz clock
+  --trace              Trace each instruction executed
+  -h, --help           Show this help";
+
+struct Options {
+    mos: String,
+    sdcard: Option<String>,
+    max_cycles: Option<u64>,
+    trace: bool,
+}
+
+fn main() {
+    let options = parse_args().unwrap_or_else(|msg| {
+        eprintln!("agon: {}", msg);
+        eprintln!("{}", USAGE);
+        exit(2);
+    });
+
+    let mut agon = Agon::new(true);
+    if let Err(e) = agon.load_mos_file(&options.mos) {
+        eprintln!("agon: can't read {}: {}", options.mos, e);
+        exit(1);
+    }
+    if let Some(path) = options.sdcard.as_ref() {
+        match SdCard::open(path) {
+            Ok(card) => agon.insert_sdcard(Some(card)),
+            Err(e) => {
+                eprintln!("agon: can't open {}: {}", path, e);
+                exit(1);
+            }
+        }
+    }
+    agon.cpu.set_trace(options.trace);
+
+    // Stdin is read on a separate thread
+    let (tx, rx) = mpsc::channel::<u8>();
+    thread::spawn(move || {
+        let mut buffer = [0u8; 1];
+        while let Ok(1) = std::io::stdin().read(&mut buffer) {
+            let key = if buffer[0] == 10 { 13 } else { buffer[0] };
+            if tx.send(key).is_err() {
+                break;
+            }
+        }
+    });
+
+    loop {
+        agon.run_cycles(CLOCK as u64 / 100);
+        while let Ok(key) = rx.try_recv() {
+            agon.vdp().press_key(key);
+        }
+        if options.max_cycles.is_some_and(|max| agon.cycles() >= max) {
+            break;
+        }
+    }
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        mos: String::new(),
+        sdcard: None,
+        max_cycles: None,
+        trace: false,
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("missing value for {}", name));
+        match arg.as_str() {
+            "--sdcard" => options.sdcard = Some(value(&arg)?),
+            "--max-cycles" => {
+                let text = value(&arg)?;
+                options.max_cycles = Some(text.parse().map_err(|_| format!("invalid number {}", text))?);
+            }
+            "--trace" => options.trace = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                exit(0);
+            },
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ => {
+                if !options.mos.is_empty() {
+                    return Err("only one MOS image can be booted".to_string());
+                }
+                options.mos = arg;
+            }
+        }
+    }
+
+    if options.mos.is_empty() {
+        return Err("missing MOS image".to_string());
+    }
+    Ok(options)
+}
@@ -0,0 +1,851 @@
+/*
+Interactive monitor and debugger for 8080, 8085, Z80, Z80N and eZ80 programs.
+
+Type "help" at the prompt for the list of commands.
+*/
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, BufRead, Write};
+
+use ez80::*;
+use ez80::disassembler::*;
+use ez80::z80_mem_tools::*;
+
+const HELP: &str = "\
+Commands (addresses and values can be hex with 0x or $ prefix, decimal
+with a # prefix, plain hex, or symbol names):
+  s, step [N]               Execute N instructions (default 1)
+  n, next                   Step over CALL and RST
+  bs, back [N]              Undo the last N instructions (default 1)
+  who ADDRESS               Show the last instruction that wrote ADDRESS
+  g, go [ADDRESS]           Run, optionally stopping at ADDRESS
+  u, until ADDRESS          Run until PC reaches ADDRESS
+  b, break [ADDRESS]        Set a breakpoint, or list them
+  bd ADDRESS                Delete a breakpoint
+  w, watch ADDRESS [r|w|rw] Set a watchpoint (default w), or list them
+  wd ADDRESS                Delete a watchpoint
+  r, regs [REG VALUE]       Show registers, or set one. Registers: A F B C D E
+                            H L I R IXH IXL IYH IYL AF BC DE HL IX IY SPS SPL
+                            PC MBASE ADL MADL
+  m, mem ADDRESS [LEN]      Hex dump memory
+  e, edit ADDRESS BYTE...   Write bytes to memory
+  d, dis [ADDRESS] [N]      Disassemble N instructions (default around PC)
+  l, load FILE [ADDRESS]    Load a binary or Intel HEX file
+  sym FILE                  Load a symbol file (lines like 'name = $1234',
+                            'name equ 1234h' or '001234 name')
+  reset                     Signal reset
+  nmi                       Signal NMI
+  h, help                   Show this help
+  q, quit                   Exit
+An empty line repeats the last step or next command.";
+
+// Stop runs after this many instructions, so the prompt comes back
+const RUN_LIMIT: u64 = 100_000_000;
+
+// Instructions kept for back and who
+const HISTORY_SIZE: usize = 100_000;
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let mut model = "ez80".to_string();
+    let mut adl = false;
+    let mut load_address = 0u32;
+    let mut program: Option<String> = None;
+    let mut symbol_file: Option<String> = None;
+    let mut history_size = HISTORY_SIZE;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--cpu" => model = args.next().unwrap_or_default().to_ascii_lowercase(),
+            "--load" => load_address = parse_number(&args.next().unwrap_or_default()).unwrap_or_else(|| {
+                eprintln!("ez80-debug: invalid load address");
+                std::process::exit(2);
+            }),
+            "--sym" => symbol_file = args.next(),
+            "--history" => history_size = args.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| {
+                eprintln!("ez80-debug: invalid history size");
+                std::process::exit(2);
+            }),
+            "-h" | "--help" => {
+                println!("Usage: ez80-debug [--cpu 8080|8085|z80|z80n|ez80|ez80-adl] [--load ADDRESS] [--sym FILE] [--history N] [PROGRAM]");
+                println!();
+                println!("{}", HELP);
+                return;
+            },
+            _ => program = Some(arg),
+        }
+    }
+
+    let cpu = match model.as_str() {
+        "8080" => Cpu::new_8080(),
+        "8085" => Cpu::new_8085(),
+        "z80" => Cpu::new_z80(),
+        "z80n" => Cpu::new_z80n(),
+        "ez80" => Cpu::new_ez80(),
+        "ez80-adl" => {
+            adl = true;
+            Cpu::new_ez80()
+        },
+        _ => {
+            eprintln!("ez80-debug: unknown cpu model {}", model);
+            std::process::exit(2);
+        }
+    };
+
+    let mut debugger = Debugger::new(cpu);
+    debugger.cpu.set_adl(adl);
+    debugger.cpu.set_history(history_size);
+    debugger.cpu.state.set_pc(load_address);
+    if let Some(file) = program {
+        debugger.load(&file, load_address);
+    }
+    if let Some(file) = symbol_file {
+        debugger.load_symbols(&file);
+    }
+
+    debugger.show_location();
+    let stdin = io::stdin();
+    let mut last_command = String::new();
+    loop {
+        print!("> ");
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        let mut line = line.trim().to_string();
+        if line.is_empty() {
+            line = last_command.clone();
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        if !debugger.command(&words) {
+            break;
+        }
+        if matches!(words[0], "s" | "step" | "n" | "next" | "bs" | "back") {
+            last_command = line;
+        } else {
+            last_command.clear();
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+/// A command of the prompt, with its arguments parsed
+#[derive(Debug, PartialEq)]
+enum Command {
+    Step(u32),
+    Next,
+    Back(u32),
+    Who(u32),
+    Go(Option<u32>),
+    Break(Option<u32>),
+    BreakDelete(u32),
+    Watch(Option<(u32, Access)>),
+    WatchDelete(u32),
+    Registers(Option<(String, u32)>),
+    Memory(u32, u32),
+    Edit(u32, Vec<u8>),
+    Disassemble(Option<(u32, usize)>),
+    Load(String, Option<u32>),
+    Symbols(String),
+    Reset,
+    Nmi,
+    Help,
+    Quit,
+}
+
+/// Parses the words of a command line. The addresses and values can be
+/// symbol names.
+fn parse_command(words: &[&str], symbols: &BTreeMap<u32, String>) -> Result<Command, String> {
+    let address = |text: &str| -> Result<u32, String> {
+        if let Some((address, _)) = symbols.iter().find(|(_, name)| name.as_str() == text) {
+            return Ok(*address);
+        }
+        parse_number(text).ok_or(format!("Invalid address or value {}", text))
+    };
+    let count = |text: Option<&&str>| match text {
+        Some(n) => parse_number(n).ok_or(format!("Invalid count {}", n)),
+        None => Ok(1)
+    };
+    let args = &words[1..];
+    let command = match words[0] {
+        "s" | "step" => Command::Step(count(args.first())?),
+        "n" | "next" => Command::Next,
+        "bs" | "back" => Command::Back(count(args.first())?),
+        "who" => Command::Who(address(args.first().ok_or("who ADDRESS")?)?),
+        "g" | "go" => Command::Go(args.first().map(|a| address(a)).transpose()?),
+        "u" | "until" => match args {
+            [a] => Command::Go(Some(address(a)?)),
+            _ => return Err("until ADDRESS".to_string())
+        },
+        "b" | "break" => Command::Break(args.first().map(|a| address(a)).transpose()?),
+        "bd" => Command::BreakDelete(address(args.first().ok_or("bd ADDRESS")?)?),
+        "w" | "watch" => match args.first() {
+            Some(a) => {
+                let access = match args.get(1) {
+                    Some(&"r") => Access::Read,
+                    Some(&"w") | None => Access::Write,
+                    Some(&"rw") => Access::ReadWrite,
+                    Some(mode) => return Err(format!("Invalid access {}", mode))
+                };
+                Command::Watch(Some((address(a)?, access)))
+            },
+            None => Command::Watch(None)
+        },
+        "wd" => Command::WatchDelete(address(args.first().ok_or("wd ADDRESS")?)?),
+        "r" | "regs" => match args {
+            [] => Command::Registers(None),
+            [name, value] => Command::Registers(Some((name.to_string(), address(value)?))),
+            _ => return Err("regs REG VALUE".to_string())
+        },
+        "m" | "mem" => {
+            let start = address(args.first().ok_or("mem ADDRESS [LEN]")?)?;
+            let len = match args.get(1) {
+                Some(n) => address(n)?,
+                None => 0x80
+            };
+            Command::Memory(start, len)
+        },
+        "e" | "edit" => {
+            if args.len() < 2 {
+                return Err("edit ADDRESS BYTE...".to_string());
+            }
+            let bytes = args[1..].iter()
+                .map(|b| address(b).map(|value| value as u8))
+                .collect::<Result<Vec<u8>, String>>()?;
+            Command::Edit(address(args[0])?, bytes)
+        },
+        "d" | "dis" => match args.first() {
+            Some(a) => {
+                let count = match args.get(1) {
+                    Some(n) => address(n)? as usize,
+                    None => 16
+                };
+                Command::Disassemble(Some((address(a)?, count)))
+            },
+            None => Command::Disassemble(None)
+        },
+        "l" | "load" => match args {
+            [file] => Command::Load(file.to_string(), None),
+            [file, a] => Command::Load(file.to_string(), Some(address(a)?)),
+            _ => return Err("load FILE [ADDRESS]".to_string())
+        },
+        "sym" => match args {
+            [file] => Command::Symbols(file.to_string()),
+            _ => return Err("sym FILE".to_string())
+        },
+        "reset" => Command::Reset,
+        "nmi" => Command::Nmi,
+        "h" | "help" | "?" => Command::Help,
+        "q" | "quit" => Command::Quit,
+        _ => return Err(format!("Unknown command {}. Type help for the list of commands", words[0]))
+    };
+    Ok(command)
+}
+
+/// Returns the address after the instruction at pc of [len] bytes, where
+/// a call returns
+fn return_address(pc: u32, len: u32, adl: bool) -> u32 {
+    if adl {
+        (pc + len) & 0xffffff
+    } else {
+        (pc & 0xff0000) + ((pc + len) & 0xffff)
+    }
+}
+
+/// Machine with 16 MiB of RAM and 64K ports that reports watched accesses
+struct DebugMachine {
+    mem: Vec<u8>,
+    io: Vec<u8>,
+    watching: bool,
+    watchpoints: BTreeMap<u32, Access>,
+    hits: RefCell<Vec<(u32, Access, u8)>>,
+}
+
+impl DebugMachine {
+    fn new() -> DebugMachine {
+        DebugMachine {
+            mem: vec![0; 0x1000000],
+            io: vec![0; 0x10000],
+            watching: false,
+            watchpoints: BTreeMap::new(),
+            hits: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn check_watch(&self, address: u32, access: Access, value: u8) {
+        if self.watching {
+            if let Some(watch) = self.watchpoints.get(&address) {
+                if *watch == Access::ReadWrite || *watch == access {
+                    self.hits.borrow_mut().push((address, access, value));
+                }
+            }
+        }
+    }
+}
+
+impl Machine for DebugMachine {
+    fn peek(&self, address: u32) -> u8 {
+        let value = self.mem[address as usize & 0xffffff];
+        self.check_watch(address & 0xffffff, Access::Read, value);
+        value
+    }
+
+    fn peek_debug(&self, address: u32) -> u8 {
+        self.mem[address as usize & 0xffffff]
+    }
+
+    fn poke(&mut self, address: u32, value: u8) {
+        self.check_watch(address & 0xffffff, Access::Write, value);
+        self.mem[address as usize & 0xffffff] = value;
+    }
+
+    fn port_in(&mut self, address: u16) -> u8 {
+        self.io[address as usize]
+    }
+
+    fn port_out(&mut self, address: u16, value: u8) {
+        self.io[address as usize] = value;
+    }
+
+    fn use_cycles(&self, _cycles: i32) {
+    }
+}
+
+/// A point where a step over finishes: the return address, in the same
+/// ADL mode, with the stack unwound to where it was at the call.
+struct ReturnPoint {
+    pc: u32,
+    adl: bool,
+    sps: u16,
+    spl: u32,
+}
+
+struct Debugger {
+    cpu: Cpu,
+    machine: DebugMachine,
+    breakpoints: HashSet<u32>,
+    symbols: BTreeMap<u32, String>,
+}
+
+impl Debugger {
+    fn new(cpu: Cpu) -> Debugger {
+        Debugger {
+            cpu,
+            machine: DebugMachine::new(),
+            breakpoints: HashSet::new(),
+            symbols: BTreeMap::new(),
+        }
+    }
+
+    /// Runs a command. Returns false to quit.
+    fn command(&mut self, words: &[&str]) -> bool {
+        let result = match parse_command(words, &self.symbols) {
+            Ok(Command::Quit) => return false,
+            Ok(command) => self.execute_command(command),
+            Err(msg) => Err(msg)
+        };
+        if let Err(msg) = result {
+            println!("{}", msg);
+        }
+        true
+    }
+
+    fn execute_command(&mut self, command: Command) -> Result<(), String> {
+        match command {
+            Command::Step(count) => self.cmd_step(count),
+            Command::Next => self.cmd_next(),
+            Command::Back(count) => self.cmd_back(count),
+            Command::Who(address) => self.cmd_who(address),
+            Command::Go(address) => self.cmd_go(address),
+            Command::Break(address) => self.cmd_break(address),
+            Command::BreakDelete(address) => self.cmd_break_delete(address),
+            Command::Watch(watch) => self.cmd_watch(watch),
+            Command::WatchDelete(address) => self.cmd_watch_delete(address),
+            Command::Registers(set) => self.cmd_registers(set),
+            Command::Memory(start, len) => self.cmd_memory(start, len),
+            Command::Edit(start, bytes) => self.cmd_edit(start, &bytes),
+            Command::Disassemble(range) => self.cmd_disassemble(range),
+            Command::Load(file, address) => {
+                let address = address.unwrap_or(self.cpu.state.pc());
+                self.load(&file, address);
+                Ok(())
+            },
+            Command::Symbols(file) => { self.load_symbols(&file); Ok(()) },
+            Command::Reset => { self.cpu.signal_reset(); Ok(()) },
+            Command::Nmi => { self.cpu.signal_nmi(); Ok(()) },
+            Command::Help => { println!("{}", HELP); Ok(()) },
+            Command::Quit => Ok(()),
+        }
+    }
+
+    fn cmd_step(&mut self, count: u32) -> Result<(), String> {
+        for _ in 0..count {
+            if self.execute() {
+                break;
+            }
+        }
+        self.show_location();
+        Ok(())
+    }
+
+    fn cmd_next(&mut self) -> Result<(), String> {
+        let pc = self.cpu.state.pc();
+        let (asm, len) = self.decode(pc);
+        if !(asm.starts_with("CALL") || asm.starts_with("RST")) {
+            return self.cmd_step(1);
+        }
+
+        // Mixed-ADL calls return to the caller mode, so the return address
+        // is computed in the current mode
+        let adl = self.cpu.state.reg.adl;
+        let target = ReturnPoint {
+            pc: return_address(pc, len, adl),
+            adl,
+            sps: self.cpu.registers().get16(Reg16::SP),
+            spl: self.cpu.registers().get24(Reg24::SPL),
+        };
+        self.run(Some(target));
+        Ok(())
+    }
+
+    fn cmd_back(&mut self, count: u32) -> Result<(), String> {
+        for _ in 0..count {
+            if !self.cpu.step_back(&mut self.machine) {
+                println!("No more history");
+                break;
+            }
+        }
+        self.show_location();
+        Ok(())
+    }
+
+    fn cmd_who(&mut self, address: u32) -> Result<(), String> {
+        let history = self.cpu.history().ok_or("History disabled")?;
+        let writer = history.last_writer(address)
+            .map(|entry| (entry.pc, entry.instructions_executed()));
+        match writer {
+            Some((pc, index)) => {
+                let ago = self.cpu.state.instructions_executed - index;
+                println!("Written by the instruction at {}, {} instructions ago", self.describe(pc), ago);
+                self.list(pc, 1);
+            },
+            None => println!("Not written in the last {} instructions", history.len())
+        }
+        Ok(())
+    }
+
+    fn cmd_go(&mut self, temporary: Option<u32>) -> Result<(), String> {
+        let added = match temporary {
+            Some(address) => self.breakpoints.insert(address),
+            None => false
+        };
+        // Run past a breakpoint at the current PC
+        if !self.execute() && !self.at_breakpoint() {
+            self.run(None);
+        } else {
+            self.show_location();
+        }
+        if added {
+            self.breakpoints.remove(&temporary.unwrap());
+        }
+        Ok(())
+    }
+
+    fn cmd_break(&mut self, address: Option<u32>) -> Result<(), String> {
+        match address {
+            Some(address) => {
+                self.breakpoints.insert(address);
+            },
+            None => {
+                let mut list: Vec<&u32> = self.breakpoints.iter().collect();
+                list.sort();
+                for address in list {
+                    println!("{}", self.describe(*address));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn cmd_break_delete(&mut self, address: u32) -> Result<(), String> {
+        if !self.breakpoints.remove(&address) {
+            return Err("No breakpoint at that address".to_string());
+        }
+        Ok(())
+    }
+
+    fn cmd_watch(&mut self, watch: Option<(u32, Access)>) -> Result<(), String> {
+        match watch {
+            Some((address, access)) => {
+                self.machine.watchpoints.insert(address, access);
+            },
+            None => {
+                for (address, access) in &self.machine.watchpoints {
+                    println!("{} {}", self.describe(*address), match access {
+                        Access::Read => "r",
+                        Access::Write => "w",
+                        Access::ReadWrite => "rw",
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn cmd_watch_delete(&mut self, address: u32) -> Result<(), String> {
+        if self.machine.watchpoints.remove(&address).is_none() {
+            return Err("No watchpoint at that address".to_string());
+        }
+        Ok(())
+    }
+
+    fn cmd_registers(&mut self, set: Option<(String, u32)>) -> Result<(), String> {
+        let (name, value) = match set {
+            Some(set) => set,
+            None => {
+                self.show_registers();
+                return Ok(());
+            }
+        };
+        let reg = self.cpu.registers();
+        match name.to_ascii_uppercase().as_str() {
+            "A" => reg.set8(Reg8::A, value as u8),
+            "F" => reg.set8(Reg8::F, value as u8),
+            "B" => reg.set8(Reg8::B, value as u8),
+            "C" => reg.set8(Reg8::C, value as u8),
+            "D" => reg.set8(Reg8::D, value as u8),
+            "E" => reg.set8(Reg8::E, value as u8),
+            "H" => reg.set8(Reg8::H, value as u8),
+            "L" => reg.set8(Reg8::L, value as u8),
+            "I" => reg.set8(Reg8::I, value as u8),
+            "R" => reg.set8(Reg8::R, value as u8),
+            "IXH" => reg.set8(Reg8::IXH, value as u8),
+            "IXL" => reg.set8(Reg8::IXL, value as u8),
+            "IYH" => reg.set8(Reg8::IYH, value as u8),
+            "IYL" => reg.set8(Reg8::IYL, value as u8),
+            "AF" => reg.set16(Reg16::AF, value as u16),
+            "BC" => reg.set24(Reg24::BC, value & 0xffffff),
+            "DE" => reg.set24(Reg24::DE, value & 0xffffff),
+            "HL" => reg.set24(Reg24::HL, value & 0xffffff),
+            "IX" => reg.set24(Reg24::IX, value & 0xffffff),
+            "IY" => reg.set24(Reg24::IY, value & 0xffffff),
+            "SPS" => reg.set16(Reg16::SP, value as u16),
+            "SPL" => reg.set24(Reg24::SPL, value & 0xffffff),
+            "PC" => reg.pc = value & 0xffffff,
+            "MBASE" | "MB" => reg.mbase = value as u8,
+            "ADL" => reg.adl = value != 0,
+            "MADL" => reg.madl = value != 0,
+            _ => return Err(format!("Unknown register {}", name))
+        }
+        self.show_registers();
+        Ok(())
+    }
+
+    fn cmd_memory(&mut self, start: u32, len: u32) -> Result<(), String> {
+        // Up to the end of the 24 bit address space
+        let end = start.saturating_add(len).min(0x1000000);
+        let mut line_start = start & !0xf;
+        while line_start < end {
+            let mut hex = String::new();
+            let mut ascii = String::new();
+            for address in line_start..line_start + 16 {
+                if address < start || address >= end {
+                    hex.push_str("   ");
+                    ascii.push(' ');
+                } else {
+                    let b = self.machine.peek(address);
+                    hex.push_str(&format!("{:02x} ", b));
+                    ascii.push(if (0x20..0x7f).contains(&b) { b as char } else { '.' });
+                }
+            }
+            println!("{:06x}: {} {}", line_start, hex, ascii);
+            line_start += 16;
+        }
+        Ok(())
+    }
+
+    fn cmd_edit(&mut self, start: u32, bytes: &[u8]) -> Result<(), String> {
+        for (i, value) in bytes.iter().enumerate() {
+            self.machine.poke(start.wrapping_add(i as u32) & 0xffffff, *value);
+        }
+        Ok(())
+    }
+
+    fn cmd_disassemble(&mut self, range: Option<(u32, usize)>) -> Result<(), String> {
+        let (start, count) = match range {
+            Some(range) => range,
+            None => {
+                let pc = self.cpu.state.pc();
+                (self.sync_before(pc, 5), 12)
+            }
+        };
+        self.list(start, count);
+        Ok(())
+    }
+
+    /// Returns an address before pc from where the disassembly of up to
+    /// `lines` instructions ends exactly at pc.
+    fn sync_before(&mut self, pc: u32, lines: usize) -> u32 {
+        for back in (1..=lines as u32 * 4).rev() {
+            if back > (pc & 0xffff) && !self.cpu.state.reg.adl {
+                continue;
+            }
+            let start = pc.wrapping_sub(back) & 0xffffff;
+            let dis = disassemble(&mut self.machine, &mut self.cpu, None, start, pc);
+            let ends_at_pc = dis.last().map(|d| d.loc + d.bytes.len() as u32 == pc).unwrap_or(false);
+            if ends_at_pc && dis.len() <= lines {
+                return start;
+            }
+        }
+        pc
+    }
+
+    fn list(&mut self, start: u32, count: usize) {
+        let pc = self.cpu.state.pc();
+        let mut address = start;
+        for _ in 0..count {
+            let dis = disassemble(&mut self.machine, &mut self.cpu, None, address, address + 1);
+            let d = match dis.first() {
+                Some(d) => d.clone(),
+                None => break
+            };
+            if let Some(name) = self.symbols.get(&d.loc) {
+                println!("{}:", name);
+            }
+            let bytes: Vec<String> = d.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            println!("{} {:06x}: {:15} {}",
+                if d.loc == pc { "=>" } else if self.breakpoints.contains(&d.loc) { " *" } else { "  " },
+                d.loc, bytes.join(" "), self.annotate(&d.asm, &d.comment));
+            address = d.loc + d.bytes.len().max(1) as u32;
+        }
+    }
+
+    /// Adds the symbol name of the addresses in an instruction and the
+    /// flags it uses as a comment
+    fn annotate(&self, asm: &str, flags: &str) -> String {
+        let mut comments = Vec::new();
+        if let Some(pos) = asm.find('$') {
+            let hex: String = asm[pos + 1..].chars().take_while(|c| c.is_ascii_hexdigit()).collect();
+            if let Ok(address) = u32::from_str_radix(&hex, 16) {
+                if let Some(name) = self.symbols.get(&address) {
+                    comments.push(name.as_str());
+                }
+            }
+        }
+        if !flags.is_empty() {
+            comments.push(flags);
+        }
+        if comments.is_empty() {
+            asm.to_string()
+        } else {
+            format!("{:24} ; {}", asm, comments.join(" "))
+        }
+    }
+
+    /// Executes one instruction. Returns true if a watchpoint was hit
+    fn execute(&mut self) -> bool {
+        self.machine.watching = true;
+        self.cpu.execute_instruction(&mut self.machine);
+        self.machine.watching = false;
+
+        let hits: Vec<(u32, Access, u8)> = self.machine.hits.borrow_mut().drain(..).collect();
+        for (address, access, value) in &hits {
+            println!("Watchpoint: {} {} {:02x}",
+                if *access == Access::Read { "read from" } else { "write to" },
+                self.describe(*address), value);
+        }
+        !hits.is_empty()
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        self.breakpoints.contains(&self.cpu.state.pc())
+    }
+
+    fn run(&mut self, target: Option<ReturnPoint>) {
+        for _ in 0..RUN_LIMIT {
+            if self.execute() {
+                break;
+            }
+            if self.at_breakpoint() {
+                println!("Breakpoint");
+                break;
+            }
+            if let Some(t) = &target {
+                let reg = self.cpu.registers();
+                if reg.adl == t.adl && self.cpu.state.pc() == t.pc
+                        && self.cpu.registers().get16(Reg16::SP) >= t.sps
+                        && self.cpu.registers().get24(Reg24::SPL) >= t.spl {
+                    break;
+                }
+            }
+            if self.cpu.is_halted() {
+                println!("Halted");
+                break;
+            }
+        }
+        self.show_location();
+    }
+
+    fn decode(&mut self, address: u32) -> (String, u32) {
+        match disassemble(&mut self.machine, &mut self.cpu, None, address, address + 1).first() {
+            Some(d) => (d.asm.clone(), d.bytes.len() as u32),
+            None => (String::new(), 1)
+        }
+    }
+
+    fn show_location(&mut self) {
+        self.show_registers();
+        let pc = self.cpu.state.pc();
+        self.list(pc, 1);
+    }
+
+    fn show_registers(&mut self) {
+        let reg = self.cpu.registers();
+        let flags = reg.flags_display();
+        println!("AF:{:04x} BC:{:06x} DE:{:06x} HL:{:06x} IX:{:06x} IY:{:06x} [{}]",
+            reg.get16(Reg16::AF), reg.get24(Reg24::BC), reg.get24(Reg24::DE),
+            reg.get24(Reg24::HL), reg.get24(Reg24::IX), reg.get24(Reg24::IY), flags);
+        println!("PC:{:06x} SPS:{:04x} SPL:{:06x} I:{:02x} R:{:02x} MBASE:{:02x} ADL:{} MADL:{} IFF1:{}",
+            reg.pc, reg.get16(Reg16::SP), reg.get24(Reg24::SPL), reg.get8(Reg8::I), reg.get8(Reg8::R),
+            reg.mbase, reg.adl as u8, reg.madl as u8, reg.get_iff1() as u8);
+    }
+
+    fn load(&mut self, file: &str, address: u32) {
+        let data = match std::fs::read(file) {
+            Ok(data) => data,
+            Err(e) => {
+                println!("Cannot read {}: {}", file, e);
+                return;
+            }
+        };
+        let lower = file.to_ascii_lowercase();
+        if lower.ends_with(".hex") || lower.ends_with(".ihx") {
+            match load_intel_hex(&mut self.machine, &String::from_utf8_lossy(&data)) {
+                Ok(Some(start)) => {
+                    self.cpu.state.set_pc(start);
+                    println!("Loaded {}, start at {:06x}", file, start);
+                },
+                Ok(None) => println!("Loaded {}", file),
+                Err(msg) => println!("{}", msg)
+            }
+        } else {
+            memcpy_to_z80(&mut self.machine, address, &data);
+            println!("Loaded {} bytes at {:06x}", data.len(), address);
+        }
+    }
+
+    fn load_symbols(&mut self, file: &str) {
+        let text = match std::fs::read_to_string(file) {
+            Ok(text) => text,
+            Err(e) => {
+                println!("Cannot read {}: {}", file, e);
+                return;
+            }
+        };
+        let mut count = 0;
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or("");
+            let tokens: Vec<&str> = line
+                .split(|c: char| c.is_whitespace() || c == '=' || c == ':' || c == ',')
+                .filter(|t| !t.is_empty() && !t.eq_ignore_ascii_case("equ") && !t.eq_ignore_ascii_case(".equ"))
+                .collect();
+            if tokens.len() != 2 {
+                continue;
+            }
+            let symbol = match (parse_number(tokens[0]), parse_number(tokens[1])) {
+                (Some(address), _) if is_identifier(tokens[1]) => Some((address, tokens[1])),
+                (_, Some(address)) if is_identifier(tokens[0]) => Some((address, tokens[0])),
+                _ => None
+            };
+            if let Some((address, name)) = symbol {
+                self.symbols.insert(address & 0xffffff, name.to_string());
+                count += 1;
+            }
+        }
+        println!("Loaded {} symbols", count);
+    }
+
+    fn describe(&self, address: u32) -> String {
+        match self.symbols.get(&address) {
+            Some(name) => format!("{:06x} ({})", address, name),
+            None => format!("{:06x}", address)
+        }
+    }
+}
+
+fn is_identifier(text: &str) -> bool {
+    text.chars().next().map(|c| c.is_ascii_alphabetic() || c == '_' || c == '.' || c == '@').unwrap_or(false)
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '@')
+}
+
+fn parse_number(text: &str) -> Option<u32> {
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix('$')) {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = text.strip_suffix('h').or_else(|| text.strip_suffix('H')) {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(decimal) = text.strip_prefix('#') {
+        decimal.parse::<u32>().ok()
+    } else {
+        u32::from_str_radix(text, 16).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<Command, String> {
+        let mut symbols = BTreeMap::new();
+        symbols.insert(0x4000, "start".to_string());
+        let words: Vec<&str> = line.split_whitespace().collect();
+        parse_command(&words, &symbols)
+    }
+
+    #[test]
+    fn test_parse_run_commands() {
+        assert_eq!(Ok(Command::Step(1)), parse("s"));
+        assert_eq!(Ok(Command::Step(0x10)), parse("step 10"));
+        assert_eq!(Ok(Command::Back(3)), parse("bs #3"));
+        assert_eq!(Ok(Command::Go(None)), parse("g"));
+        assert_eq!(Ok(Command::Go(Some(0x4000))), parse("until start"));
+        assert!(parse("until").is_err());
+        assert!(parse("s x").is_err());
+    }
+
+    #[test]
+    fn test_parse_watch_and_who() {
+        assert_eq!(Ok(Command::Watch(None)), parse("w"));
+        assert_eq!(Ok(Command::Watch(Some((0x1234, Access::Write)))), parse("watch $1234"));
+        assert_eq!(Ok(Command::Watch(Some((0x4000, Access::ReadWrite)))), parse("w start rw"));
+        assert!(parse("w 1234 x").is_err());
+        assert_eq!(Ok(Command::WatchDelete(0x1234)), parse("wd 0x1234"));
+        assert_eq!(Ok(Command::Who(0x50)), parse("who 50h"));
+        assert!(parse("who").is_err());
+    }
+
+    #[test]
+    fn test_parse_memory_commands() {
+        assert_eq!(Ok(Command::Memory(0xffffff, 0xffffffff)), parse("mem ffffff ffffffff"));
+        assert_eq!(Ok(Command::Memory(0x4000, 0x80)), parse("m start"));
+        assert_eq!(Ok(Command::Edit(0x100, vec![0x3e, 0x12])), parse("e 100 3e 12"));
+        assert!(parse("e 100").is_err());
+        assert_eq!(Ok(Command::Registers(Some(("hl".to_string(), 0x4000)))), parse("r hl start"));
+        assert!(parse("r hl").is_err());
+        assert!(parse("bogus").is_err());
+    }
+
+    #[test]
+    fn test_return_address() {
+        assert_eq!(0x01_0000, return_address(0x01_fffd, 3, false));
+        assert_eq!(0x02_0000, return_address(0x01_fffc, 4, true));
+    }
+}
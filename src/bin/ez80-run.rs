@@ -0,0 +1,372 @@
+/*
+General purpose runner for 8080, 8085, Z80, Z80N and eZ80 programs.
+
+Loads a binary, Intel HEX or CP/M .COM file and runs it, with the host
+stdin and stdout mapped to I/O ports or to a CP/M BDOS. The process exits
+with the code provided by the guest.
+*/
+use std::cell::Cell;
+use std::io::{self, Write};
+use std::process::exit;
+
+use ez80::*;
+use ez80::cpm::*;
+use ez80::trace::TextTrace;
+use ez80::z80_mem_tools::*;
+
+const USAGE: &str = "\
+Usage: ez80-run [OPTIONS] PROGRAM
+
+Loads PROGRAM (.bin, .hex/.ihx or .com) and runs it.
+
+Options:
+  --cpu MODEL          8080, 8085, z80, z80n, ez80 or ez80-adl (default z80)
+  --format FORMAT      bin, hex or com (default from the file extension)
+  --load ADDRESS       Load address of binary files (default 0)
+  --start ADDRESS      Initial PC (default the load address)
+  --sp ADDRESS         Initial stack pointer
+  --cpm                Run under the CP/M BDOS emulation (default for .com)
+  --drive DIR          Host directory used as CP/M drive A: (default .)
+  --in-port PORT       Reading PORT returns the next byte from stdin
+  --status-port PORT   Reading PORT returns 0xff if stdin has data, 0 otherwise
+  --out-port PORT      Bytes written to PORT go to stdout
+  --exit-port PORT     Writing to PORT exits with the value written as exit code
+  --trace              Trace each instruction executed to stderr
+  --max-cycles N       Stop with exit code 124 after N cycles
+  --dump-regs          Print the registers to stderr on exit
+  -h, --help           Show this help
+
+The program stops when it writes to the exit port, executes HALT (the exit
+code is then the value of A) or, under CP/M, returns to the system (exit
+code 0). Numbers can be decimal or hex with a 0x or $ prefix.";
+
+const EXIT_MAX_CYCLES: i32 = 124;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Binary,
+    IntelHex,
+    Com,
+}
+
+struct Options {
+    program: String,
+    cpu: String,
+    format: Option<Format>,
+    load: u32,
+    start: Option<u32>,
+    sp: Option<u32>,
+    cpm: bool,
+    drive: String,
+    in_port: Option<u8>,
+    status_port: Option<u8>,
+    out_port: Option<u8>,
+    exit_port: Option<u8>,
+    trace: bool,
+    max_cycles: Option<u64>,
+    dump_regs: bool,
+}
+
+fn main() {
+    let options = parse_args().unwrap_or_else(|msg| {
+        eprintln!("ez80-run: {}", msg);
+        eprintln!("{}", USAGE);
+        exit(2);
+    });
+
+    let mut cpu = match options.cpu.as_str() {
+        "8080" => Cpu::new_8080(),
+        "8085" => Cpu::new_8085(),
+        "z80" => Cpu::new_z80(),
+        "z80n" => Cpu::new_z80n(),
+        "ez80" | "ez80-adl" => Cpu::new_ez80(),
+        model => {
+            eprintln!("ez80-run: unknown cpu model {}", model);
+            exit(2);
+        }
+    };
+
+    let data = std::fs::read(&options.program).unwrap_or_else(|e| {
+        eprintln!("ez80-run: cannot read {}: {}", options.program, e);
+        exit(2);
+    });
+
+    let format = match options.format {
+        Some(format) => format,
+        None => {
+            let name = options.program.to_ascii_lowercase();
+            if name.ends_with(".hex") || name.ends_with(".ihx") {
+                Format::IntelHex
+            } else if name.ends_with(".com") {
+                Format::Com
+            } else {
+                Format::Binary
+            }
+        }
+    };
+
+    let mut machine = RunMachine::new(&options);
+    let code = if options.cpm || format == Format::Com {
+        let mut cpm = Cpm::new(StdioConsole::new());
+        cpm.map_drive(0, &options.drive);
+        let program = match format {
+            Format::IntelHex => {
+                // The image as loaded in the TPA
+                load(&mut machine, &data, &format, &options);
+                machine.mem[TPA_BASE as usize..BDOS_ENTRY as usize].to_vec()
+            },
+            _ => data
+        };
+        cpm.load(&mut cpu, &mut machine, &program, "");
+        set_trace(&mut cpu, options.trace);
+        loop {
+            if !cpm.step(&mut cpu, &mut machine) {
+                break 0;
+            }
+            if let Some(code) = check_stop(&cpu, &machine, &options) {
+                break code;
+            }
+        }
+    } else {
+        let start = load(&mut machine, &data, &format, &options);
+        if options.cpu == "ez80-adl" {
+            cpu.set_adl(true);
+        }
+        if let Some(sp) = options.sp {
+            if options.cpu == "ez80-adl" {
+                cpu.registers().set24(Reg24::SPL, sp);
+            } else {
+                cpu.registers().set16(Reg16::SP, sp as u16);
+            }
+        }
+        cpu.state.set_pc(options.start.or(start).unwrap_or(options.load));
+        set_trace(&mut cpu, options.trace);
+        loop {
+            cpu.execute_instruction(&mut machine);
+            if let Some(code) = check_stop(&cpu, &machine, &options) {
+                break code;
+            }
+        }
+    };
+
+    if options.dump_regs {
+        dump_registers(&mut cpu, &machine);
+    }
+    exit(code);
+}
+
+/// Loads the program, returning the start address found in the file, if any
+fn load(machine: &mut RunMachine, data: &[u8], format: &Format, options: &Options) -> Option<u32> {
+    match format {
+        Format::IntelHex => {
+            let text = String::from_utf8_lossy(data);
+            load_intel_hex(machine, &text).unwrap_or_else(|msg| {
+                eprintln!("ez80-run: {}", msg);
+                exit(2);
+            })
+        },
+        Format::Binary | Format::Com => {
+            let load = if *format == Format::Com { TPA_BASE as u32 } else { options.load };
+            memcpy_to_z80(machine, load, data);
+            Some(load)
+        }
+    }
+}
+
+fn check_stop(cpu: &Cpu, machine: &RunMachine, options: &Options) -> Option<i32> {
+    if let Some(code) = machine.exit_code {
+        return Some(code as i32);
+    }
+    if cpu.is_halted() {
+        return Some(cpu.state.reg.a() as i32);
+    }
+    if let Some(max_cycles) = options.max_cycles {
+        if machine.cycles.get() >= max_cycles {
+            eprintln!("ez80-run: maximum cycles reached at PC={:06x}", cpu.state.pc());
+            return Some(EXIT_MAX_CYCLES);
+        }
+    }
+    None
+}
+
+fn dump_registers(cpu: &mut Cpu, machine: &RunMachine) {
+    let pc = cpu.state.pc();
+    let reg = cpu.registers();
+    eprintln!("PC:{:06x} AF:{:04x} F:{} BC:{:06x} DE:{:06x} HL:{:06x} SPS:{:04x} SPL:{:06x} IX:{:06x} IY:{:06x} MB {:02x} ADL {:01x} MADL {:01x} cycles {}",
+        pc,
+        reg.get16(Reg16::AF),
+        reg.flags_display(),
+        reg.get24(Reg24::BC),
+        reg.get24(Reg24::DE),
+        reg.get24(Reg24::HL),
+        reg.get16(Reg16::SP),
+        reg.get24(Reg24::SPL),
+        reg.get24(Reg24::IX),
+        reg.get24(Reg24::IY),
+        reg.mbase,
+        reg.adl as i32,
+        reg.madl as i32,
+        machine.cycles.get(),
+    );
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        program: String::new(),
+        cpu: "z80".to_string(),
+        format: None,
+        load: 0,
+        start: None,
+        sp: None,
+        cpm: false,
+        drive: ".".to_string(),
+        in_port: None,
+        status_port: None,
+        out_port: None,
+        exit_port: None,
+        trace: false,
+        max_cycles: None,
+        dump_regs: false,
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("missing value for {}", name));
+        match arg.as_str() {
+            "--cpu" => options.cpu = value(&arg)?.to_ascii_lowercase(),
+            "--format" => options.format = Some(match value(&arg)?.as_str() {
+                "bin" => Format::Binary,
+                "hex" | "ihx" => Format::IntelHex,
+                "com" => Format::Com,
+                format => return Err(format!("unknown format {}", format))
+            }),
+            "--load" => options.load = parse_address(&value(&arg)?)?,
+            "--start" => options.start = Some(parse_address(&value(&arg)?)?),
+            "--sp" => options.sp = Some(parse_address(&value(&arg)?)?),
+            "--cpm" => options.cpm = true,
+            "--drive" => options.drive = value(&arg)?,
+            "--in-port" => options.in_port = Some(parse_port(&value(&arg)?)?),
+            "--status-port" => options.status_port = Some(parse_port(&value(&arg)?)?),
+            "--out-port" => options.out_port = Some(parse_port(&value(&arg)?)?),
+            "--exit-port" => options.exit_port = Some(parse_port(&value(&arg)?)?),
+            "--trace" => options.trace = true,
+            "--max-cycles" => options.max_cycles = Some(parse_number(&value(&arg)?)?),
+            "--dump-regs" => options.dump_regs = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                exit(0);
+            },
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ => {
+                if !options.program.is_empty() {
+                    return Err("only one program can be run".to_string());
+                }
+                options.program = arg;
+            }
+        }
+    }
+
+    if options.program.is_empty() {
+        return Err("missing program".to_string());
+    }
+    Ok(options)
+}
+
+fn parse_number(text: &str) -> Result<u64, String> {
+    let result = if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix('$')) {
+        u64::from_str_radix(hex, 16)
+    } else {
+        text.parse::<u64>()
+    };
+    result.map_err(|_| format!("invalid number {}", text))
+}
+
+fn parse_address(text: &str) -> Result<u32, String> {
+    let address = parse_number(text)?;
+    if address > 0xffffff {
+        return Err(format!("invalid address {}", text));
+    }
+    Ok(address as u32)
+}
+
+fn parse_port(text: &str) -> Result<u8, String> {
+    let port = parse_number(text)?;
+    if port > 0xff {
+        return Err(format!("invalid port {}", text));
+    }
+    Ok(port as u8)
+}
+
+/// Traces to stderr, apart from the output of the program on stdout
+fn set_trace(cpu: &mut Cpu, trace: bool) {
+    if trace {
+        cpu.set_trace_sink(Some(Box::new(TextTrace::new(io::stderr()))));
+    }
+}
+
+/// 16 MiB of memory with the console and exit ports
+struct RunMachine {
+    mem: Vec<u8>,
+    console: Option<StdioConsole>,
+    in_port: Option<u8>,
+    status_port: Option<u8>,
+    out_port: Option<u8>,
+    exit_port: Option<u8>,
+    exit_code: Option<u8>,
+    cycles: Cell<u64>,
+}
+
+impl RunMachine {
+    fn new(options: &Options) -> RunMachine {
+        let uses_stdin = options.in_port.is_some() || options.status_port.is_some();
+        RunMachine {
+            mem: vec![0; 0x1000000],
+            // Only read stdin when the program can access it
+            console: if uses_stdin && !options.cpm { Some(StdioConsole::new()) } else { None },
+            in_port: options.in_port,
+            status_port: options.status_port,
+            out_port: options.out_port,
+            exit_port: options.exit_port,
+            exit_code: None,
+            cycles: Cell::new(0),
+        }
+    }
+}
+
+impl Machine for RunMachine {
+    fn peek(&self, address: u32) -> u8 {
+        self.use_cycles(1);
+        self.mem[address as usize & 0xffffff]
+    }
+
+    fn poke(&mut self, address: u32, value: u8) {
+        self.use_cycles(1);
+        self.mem[address as usize & 0xffffff] = value;
+    }
+
+    fn port_in(&mut self, address: u16) -> u8 {
+        self.use_cycles(1);
+        let port = Some(address as u8);
+        match &mut self.console {
+            Some(console) if port == self.in_port => console.read().unwrap_or(0),
+            Some(console) if port == self.status_port => if console.status() { 0xff } else { 0 },
+            _ => 0xff
+        }
+    }
+
+    fn port_out(&mut self, address: u16, value: u8) {
+        self.use_cycles(1);
+        let port = Some(address as u8);
+        if port == self.exit_port {
+            self.exit_code = Some(value);
+        } else if port == self.out_port {
+            let mut stdout = std::io::stdout();
+            let _ = stdout.write_all(&[value]);
+            let _ = stdout.flush();
+        }
+    }
+
+    fn use_cycles(&self, cycles: i32) {
+        self.cycles.set(self.cycles.get().wrapping_add(cycles as u64));
+    }
+}
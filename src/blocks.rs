@@ -0,0 +1,338 @@
+//! Blocks of pre-decoded instructions
+//!
+//! A block is a run of straight-line instructions recorded the first time
+//! they are executed. It ends on a jump, call, return, repeated block
+//! instruction or any instruction that changes the interrupt or memory
+//! mode. I/O and unimplemented instructions are not stored, they are
+//! always executed with the decoder.
+//!
+//! The blocks are keyed by address, ADL mode and MBASE and are chained to
+//! the blocks executed after them. The writes done by the CPU invalidate
+//! the blocks with code in the lines they modify.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use alloc::collections::BTreeMap;
+
+use super::opcode::Action;
+use super::registers::Reg16;
+use super::state::SizePrefix;
+
+// The blocks are found by the 8 byte lines with their code
+const LINE_BITS: u32 = 3;
+const LINES: usize = 1 << (24 - LINE_BITS);
+const MAX_BLOCK_LEN: usize = 64;
+const MAX_LINKS: usize = 4;
+// The blocks are discarded when there are too many, most of them invalid
+const MAX_BLOCKS: usize = 16384;
+
+/// An instruction of a block, with the state left by the decoder
+#[derive(Copy, Clone)]
+pub(crate) struct BlockInstruction {
+    pub action: Action,
+    pub sz_prefix: SizePrefix,
+    pub index: Reg16,
+    pub displacement: i8,
+    /// Bytes of the prefixes, the opcode and the displacement
+    pub opcode_len: u8,
+    /// PC register after the decode, the immediate operands are next
+    pub decoded_pc: u32,
+    /// Address of the next instruction if there is no jump
+    pub next: u32,
+}
+
+impl BlockInstruction {
+    fn address(&self) -> u32 {
+        self.decoded_pc - self.opcode_len as u32
+    }
+}
+
+pub(crate) struct Block {
+    pub instructions: Vec<BlockInstruction>,
+    start: u32,
+    adl: bool,
+    mbase: u8,
+    valid: bool,
+    // Blocks executed after this one, with the key they were found with
+    links: Vec<((u32, bool, u8), usize)>,
+}
+
+impl Block {
+    pub fn new(start: u32, adl: bool, mbase: u8) -> Block {
+        Block {
+            instructions: Vec::new(),
+            start,
+            adl,
+            mbase,
+            valid: true,
+            links: Vec::new(),
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.instructions.len() >= MAX_BLOCK_LEN
+    }
+
+    /// Returns true if the address has a prefix, opcode or displacement
+    /// of the block
+    fn has_code(&self, address: u32) -> bool {
+        self.instructions.iter().any(|instruction| {
+            address >= instruction.address() && address < instruction.decoded_pc
+        })
+    }
+}
+
+/// Returns true if the action can change the flow or the execution mode.
+/// It is the last instruction of a block.
+pub(crate) fn ends_block(action: &Action) -> bool {
+    matches!(action,
+        Action::Halt
+        | Action::Slp
+        | Action::ConfInterrupts(_)
+        | Action::Im(_)
+        | Action::Stmix
+        | Action::Rsmix
+        | Action::CpBlock(_, true)
+        | Action::LdBlock(_, true)
+        | Action::LdBlockX(_, true)
+        | Action::Ldpirx
+        | Action::Djnz
+        | Action::JrUnconditional
+        | Action::JrEq(_, _)
+        | Action::JpUnconditional
+        | Action::JpEq(_, _)
+        | Action::JpHl
+        | Action::Call
+        | Action::CallEq(_, _)
+        | Action::Rst(_)
+        | Action::Trap(_)
+        | Action::Rstv
+        | Action::Ret
+        | Action::Reti
+        | Action::Retn
+        | Action::RetEq(_, _)
+        | Action::LdMbA)
+}
+
+/// Returns true if the action is always executed with the decoder
+pub(crate) fn excluded_from_blocks(action: &Action) -> bool {
+    matches!(action,
+        Action::LogUnimplemented(_)
+        | Action::OutCR(_)
+        | Action::OutC0
+        | Action::OutNA
+        | Action::Out0NR(_)
+        | Action::In0RN(_)
+        | Action::InRC(_)
+        | Action::In0C
+        | Action::InAN
+        | Action::InBlock(_, _)
+        | Action::OutBlock(_, _)
+        | Action::OtirxOrOtdrx(_)
+        | Action::TstIoN
+        | Action::Otim(_, _)
+        | Action::Rim
+        | Action::Sim
+        | Action::Outinb
+        | Action::Nextreg(_)
+        | Action::JpC)
+}
+
+/// Bytes of memory with the prefixes, opcodes and displacements of the
+/// instructions in blocks, a bit per byte. The immediate operands are read
+/// from memory when the block is executed, writing them is not tracked.
+pub(crate) struct CodeMap {
+    lines: Vec<u8>,
+    // Addresses of code written, until the blocks are invalidated
+    written: Vec<u32>,
+    marked: bool,
+}
+
+impl CodeMap {
+    fn new() -> CodeMap {
+        CodeMap {
+            lines: vec![0; LINES],
+            written: Vec::new(),
+            marked: false,
+        }
+    }
+
+    #[inline]
+    pub fn written(&mut self, address: u32) {
+        let address = address & 0xffffff;
+        let line = &mut self.lines[(address >> LINE_BITS) as usize];
+        let bit = 1 << (address & ((1 << LINE_BITS) - 1));
+        if *line & bit != 0 {
+            *line &= !bit;
+            self.written.push(address);
+        }
+    }
+
+    /// Returns true if code in a block has been written
+    #[inline]
+    pub fn has_writes(&self) -> bool {
+        !self.written.is_empty()
+    }
+
+    fn mark(&mut self, address: u32, len: u8) {
+        for i in 0..len as u32 {
+            let address = address + i;
+            self.lines[(address >> LINE_BITS) as usize] |= 1 << (address & ((1 << LINE_BITS) - 1));
+        }
+        self.marked = true;
+    }
+
+    fn clear(&mut self) {
+        if self.marked {
+            self.lines.iter_mut().for_each(|line| *line = 0);
+            self.marked = false;
+        }
+        self.written.clear();
+    }
+}
+
+pub(crate) struct BlockCache {
+    pub code: CodeMap,
+    blocks: Vec<Block>,
+    index: BTreeMap<(u32, bool, u8), usize>,
+    line_blocks: BTreeMap<u32, Vec<usize>>,
+    generation: u64,
+}
+
+impl BlockCache {
+    pub fn new() -> BlockCache {
+        BlockCache {
+            code: CodeMap::new(),
+            blocks: Vec::new(),
+            index: BTreeMap::new(),
+            line_blocks: BTreeMap::new(),
+            generation: 0,
+        }
+    }
+
+    /// Returns the block starting at `address`. Follows the link from the
+    /// previous block executed, or adds it.
+    #[inline]
+    pub fn find(&mut self, previous: Option<usize>, address: u32, adl: bool, mbase: u8) -> Option<usize> {
+        let key = (address, adl, mbase);
+        // The previous block can be gone if the cache has been cleared
+        let previous = previous.filter(|id| *id < self.blocks.len());
+        if let Some(previous) = previous {
+            let linked = self.blocks[previous].links.iter()
+                .find(|link| link.0 == key)
+                .map(|link| link.1);
+            if let Some(id) = linked {
+                let block = &self.blocks[id];
+                if block.valid && (block.start, block.adl, block.mbase) == key {
+                    return Some(id);
+                }
+            }
+        }
+
+        let id = *self.index.get(&key)?;
+        if let Some(previous) = previous {
+            let links = &mut self.blocks[previous].links;
+            if let Some(link) = links.iter_mut().find(|link| link.0 == key) {
+                link.1 = id;
+            } else if links.len() < MAX_LINKS {
+                links.push((key, id));
+            }
+        }
+        Some(id)
+    }
+
+    /// Returns a block with the map of code, to execute it
+    pub fn parts(&mut self, id: usize) -> (&Block, &mut CodeMap) {
+        (&self.blocks[id], &mut self.code)
+    }
+
+    /// Marks the code of an instruction being recorded, to detect if it
+    /// is modified before the block is stored
+    pub fn mark_instruction(&mut self, address: u32, opcode_len: u8) {
+        self.code.mark(address, opcode_len);
+    }
+
+    /// Stores a recorded block. Returns None if its code has been written
+    /// while it was recorded.
+    pub fn insert(&mut self, block: Block) -> Option<usize> {
+        let last = block.instructions.last()?;
+        if self.code.written.iter().any(|address| block.has_code(*address)) {
+            return None;
+        }
+        if self.blocks.len() >= MAX_BLOCKS {
+            self.clear();
+        }
+
+        // The instructions of a block don't wrap around
+        let id = self.blocks.len();
+        for line in (block.start >> LINE_BITS)..=((last.decoded_pc - 1) >> LINE_BITS) {
+            self.line_blocks.entry(line).or_default().push(id);
+        }
+        for instruction in block.instructions.iter() {
+            self.code.mark(instruction.address(), instruction.opcode_len);
+        }
+        self.index.insert((block.start, block.adl, block.mbase), id);
+        self.blocks.push(block);
+        Some(id)
+    }
+
+    /// Invalidates the blocks with code written. Returns true if there
+    /// were writes.
+    pub fn process_writes(&mut self) -> bool {
+        if self.code.written.is_empty() {
+            return false;
+        }
+        let written = core::mem::take(&mut self.code.written);
+        for address in written.iter() {
+            self.invalidate_address(*address);
+        }
+        true
+    }
+
+    /// Invalidates the blocks with code in the range of addresses
+    pub fn invalidate(&mut self, start: u32, end: u32) {
+        for address in start..end.min(0x1000000) {
+            self.invalidate_address(address);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.code.clear();
+        self.blocks.clear();
+        self.index.clear();
+        self.line_blocks.clear();
+    }
+
+    /// Clears the cache if the generation of the memory of the Machine
+    /// is not the one of the blocks
+    #[inline]
+    pub fn check_generation(&mut self, generation: u64) {
+        if generation != self.generation {
+            self.clear();
+            self.generation = generation;
+        }
+    }
+
+    fn invalidate_address(&mut self, address: u32) {
+        let ids = match self.line_blocks.get_mut(&(address >> LINE_BITS)) {
+            Some(ids) => ids,
+            None => return
+        };
+        let blocks = &mut self.blocks;
+        let index = &mut self.index;
+        ids.retain(|id| {
+            let block = &mut blocks[*id];
+            if block.valid && block.has_code(address) {
+                block.valid = false;
+                let key = (block.start, block.adl, block.mbase);
+                if index.get(&key) == Some(id) {
+                    index.remove(&key);
+                }
+            }
+            block.valid
+        });
+        if ids.is_empty() {
+            self.line_blocks.remove(&(address >> LINE_BITS));
+        }
+    }
+}
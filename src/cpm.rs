@@ -0,0 +1,988 @@
+//! CP/M 2.2 host emulation
+//!
+//! Provides enough of the CP/M 2.2 BDOS and BIOS to run `.COM` programs
+//! on the 8080, Z80 and eZ80 (non-ADL) CPUs. Programs are loaded at 0x100
+//! with the usual page zero setup. The BDOS and BIOS entry points are small
+//! stubs in high memory that are serviced by the host when the CPU reaches
+//! them. Disk files are mapped onto host directories, one per drive.
+//!
+//!# Example
+//! ```no_run
+//!use ez80::*;
+//!use ez80::cpm::*;
+//!
+//!let program = std::fs::read("HELLO.COM").unwrap();
+//!let mut machine = PlainMachine::new();
+//!let mut cpu = Cpu::new_z80();
+//!let mut cpm = Cpm::new(StdioConsole::new());
+//!cpm.map_drive(0, ".");
+//!cpm.load(&mut cpu, &mut machine, &program, "");
+//!cpm.run(&mut cpu, &mut machine);
+//! ```
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use crate::cpu::Cpu;
+use crate::machine::Machine;
+use crate::registers::*;
+
+/// Address where `.COM` programs are loaded and started
+pub const TPA_BASE: u16 = 0x0100;
+/// Default FCB filled from the first command line argument
+pub const DEFAULT_FCB: u16 = 0x005c;
+/// Default DMA buffer, initially holding the command tail
+pub const DEFAULT_DMA: u16 = 0x0080;
+/// BDOS entry point. Also the top of the TPA, as stored at 0x0006
+pub const BDOS_ENTRY: u16 = 0xfe06;
+/// Start of the BIOS jump table
+pub const BIOS_BASE: u16 = 0xff00;
+
+const IOBYTE_ADDRESS: u16 = 0x0003;
+const DRIVE_ADDRESS: u16 = 0x0004;
+const DPB_ADDRESS: u16 = 0xfe10;
+const ALV_ADDRESS: u16 = 0xfe20;
+const BIOS_STUBS: u16 = 0xff40;
+const BIOS_FUNCTIONS: u16 = 17;
+
+const RECORD_SIZE: usize = 128;
+const RECORDS_PER_EXTENT: u32 = 128;
+const EXTENTS_PER_MODULE: u32 = 32;
+const CTRL_Z: u8 = 0x1a;
+
+// Disk parameter block of an 8 MiB drive, reported by DRV_DPB
+const DPB: [u8; 15] = [
+    0x40, 0x00, // SPT
+    0x05,       // BSH
+    0x1f,       // BLM
+    0x01,       // EXM
+    0xff, 0x07, // DSM
+    0xff, 0x03, // DRM
+    0xff, 0x00, // AL0, AL1
+    0x00, 0x00, // CKS
+    0x00, 0x00, // OFF
+];
+
+/// Host side of the CP/M console device
+pub trait Console {
+    /// Returns true if a byte is waiting to be read
+    fn status(&mut self) -> bool;
+    /// Waits for a byte. Returns None when the input has ended
+    fn read(&mut self) -> Option<u8>;
+    /// Writes a byte to the console
+    fn write(&mut self, value: u8);
+}
+
+/// Console using the host stdin and stdout
+///
+/// Stdin is read on a separate thread so the console status can be polled
+/// without blocking. Line feeds are translated to carriage returns, as
+/// expected by CP/M programs.
+pub struct StdioConsole {
+    rx: Receiver<u8>,
+    waiting: Option<u8>,
+}
+
+impl StdioConsole {
+    pub fn new() -> StdioConsole {
+        let (tx, rx) = mpsc::channel::<u8>();
+        thread::spawn(move || {
+            let mut buffer = [0u8; 1];
+            while let Ok(1) = std::io::stdin().read(&mut buffer) {
+                let c = if buffer[0] == 10 { 13 } else { buffer[0] };
+                if tx.send(c).is_err() {
+                    break;
+                }
+            }
+        });
+        StdioConsole {
+            rx,
+            waiting: None
+        }
+    }
+}
+
+impl Default for StdioConsole {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Console for StdioConsole {
+    fn status(&mut self) -> bool {
+        if self.waiting.is_none() {
+            match self.rx.try_recv() {
+                Ok(c) => self.waiting = Some(c),
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => {}
+            }
+        }
+        self.waiting.is_some()
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        match self.waiting.take() {
+            Some(c) => Some(c),
+            None => self.rx.recv().ok()
+        }
+    }
+
+    fn write(&mut self, value: u8) {
+        let mut stdout = std::io::stdout();
+        let _ = stdout.write_all(&[value]);
+        let _ = stdout.flush();
+    }
+}
+
+/// Console backed by in-memory buffers, for tests and batch runs
+pub struct BufferConsole {
+    /// Bytes still to be read by the guest
+    pub input: VecDeque<u8>,
+    /// Bytes written by the guest
+    pub output: Vec<u8>,
+    /// Also copy the output to the host stdout
+    pub echo: bool,
+}
+
+impl BufferConsole {
+    pub fn new(input: &[u8]) -> BufferConsole {
+        BufferConsole {
+            input: input.iter().copied().collect(),
+            output: Vec::new(),
+            echo: false
+        }
+    }
+
+    /// Returns the output written so far as text
+    pub fn output_string(&self) -> String {
+        String::from_utf8_lossy(&self.output).to_string()
+    }
+}
+
+impl Console for BufferConsole {
+    fn status(&mut self) -> bool {
+        !self.input.is_empty()
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+
+    fn write(&mut self, value: u8) {
+        self.output.push(value);
+        if self.echo {
+            print!("{}", value as char);
+        }
+    }
+}
+
+/// CP/M 2.2 BDOS and BIOS emulation
+///
+/// Call `load()` to set up the memory of the machine and the registers of
+/// the CPU, then `step()` or `run()` instead of `Cpu::execute_instruction()`.
+pub struct Cpm<C: Console> {
+    /// The console device
+    pub console: C,
+    drives: [Option<PathBuf>; 16],
+    dma: u16,
+    search_results: VecDeque<[u8; 11]>,
+    search_drive: usize,
+    terminated: bool,
+    open_files: BTreeMap<u16, OpenFile>,
+}
+
+/// Host file open for the FCB at a given address, kept until the FCB is
+/// closed or the file deleted or renamed
+struct OpenFile {
+    drive: usize,
+    name: [u8; 11],
+    path: PathBuf,
+    file: fs::File,
+}
+
+impl OpenFile {
+    fn records(&self) -> u32 {
+        size_records(self.file.metadata().map(|m| m.len()).unwrap_or(0))
+    }
+}
+
+impl<C: Console> Cpm<C> {
+    /// Returns a CP/M environment with no drives mapped
+    pub fn new(console: C) -> Cpm<C> {
+        Cpm {
+            console,
+            drives: Default::default(),
+            dma: DEFAULT_DMA,
+            search_results: VecDeque::new(),
+            search_drive: 0,
+            terminated: false,
+            open_files: BTreeMap::new(),
+        }
+    }
+
+    /// Maps a CP/M drive (0 for A:, up to 15 for P:) onto a host directory
+    pub fn map_drive<P: Into<PathBuf>>(&mut self, drive: u8, path: P) {
+        self.drives[drive as usize & 0xf] = Some(path.into());
+        self.open_files.clear();
+    }
+
+    /// Returns true when the program has returned to CP/M
+    pub fn is_terminated(&self) -> bool {
+        self.terminated
+    }
+
+    /// Sets up page zero, the BDOS and BIOS stubs, loads the program at
+    /// 0x100 and prepares the CPU to run it.
+    ///
+    /// # Arguments
+    ///
+    /// * `program` - Contents of the `.COM` file
+    /// * `args` - Command line tail, used to fill the default FCBs
+    pub fn load(&mut self, cpu: &mut Cpu, sys: &mut dyn Machine, program: &[u8], args: &str) {
+        // Page zero
+        poke_jp(sys, 0x0000, BIOS_BASE + 3);
+        sys.poke(IOBYTE_ADDRESS as u32, 0);
+        sys.poke(DRIVE_ADDRESS as u32, 0);
+        poke_jp(sys, 0x0005, BDOS_ENTRY);
+        sys.poke(BDOS_ENTRY as u32, 0xc9); // RET
+
+        // BIOS jump table, each entry going to its own RET stub
+        for i in 0..BIOS_FUNCTIONS {
+            poke_jp(sys, BIOS_BASE + i * 3, BIOS_STUBS + i);
+            sys.poke((BIOS_STUBS + i) as u32, 0xc9); // RET
+        }
+
+        for (i, b) in DPB.iter().enumerate() {
+            sys.poke(DPB_ADDRESS as u32 + i as u32, *b);
+        }
+        for i in 0..32 {
+            sys.poke(ALV_ADDRESS as u32 + i, 0);
+        }
+
+        for (i, b) in program.iter().enumerate() {
+            sys.poke(TPA_BASE as u32 + i as u32, *b);
+        }
+
+        // Command tail and default FCBs
+        let tail = args.trim().to_ascii_uppercase();
+        let tail = if tail.is_empty() { tail } else { format!(" {}", tail) };
+        let tail = &tail.as_bytes()[..tail.len().min(127)];
+        sys.poke(DEFAULT_DMA as u32, tail.len() as u8);
+        for (i, b) in tail.iter().enumerate() {
+            sys.poke(DEFAULT_DMA as u32 + 1 + i as u32, *b);
+        }
+        sys.poke(DEFAULT_DMA as u32 + 1 + tail.len() as u32, 0);
+        let mut words = args.split_whitespace();
+        parse_fcb(sys, DEFAULT_FCB, words.next().unwrap_or(""));
+        parse_fcb(sys, DEFAULT_FCB + 16, words.next().unwrap_or(""));
+        sys.poke(DEFAULT_FCB as u32 + 32, 0); // CR
+
+        self.dma = DEFAULT_DMA;
+        self.terminated = false;
+        self.open_files.clear();
+
+        // Returning from the program goes to the warm boot
+        cpu.set_adl(false);
+        cpu.state.reg.mbase = 0;
+        cpu.registers().set16(Reg16::SP, BDOS_ENTRY - 6);
+        sys.poke((BDOS_ENTRY - 6) as u32, 0);
+        sys.poke((BDOS_ENTRY - 5) as u32, 0);
+        cpu.state.set_pc(TPA_BASE as u32);
+        cpu.clear_decode_cache();
+    }
+
+    /// Executes a single instruction and services any BDOS or BIOS call
+    /// reached. Returns false once the program has terminated, also with a
+    /// HALT, as there are no interrupts to leave it.
+    pub fn step(&mut self, cpu: &mut Cpu, sys: &mut dyn Machine) -> bool {
+        if self.terminated {
+            return false;
+        }
+
+        cpu.execute_instruction(sys);
+        self.service_calls(cpu, sys);
+        !self.terminated
+    }
+
+    /// Runs the program until it returns to CP/M or halts. The CPU stops
+    /// on breakpoints at the BDOS and BIOS entries, so the code in between
+    /// can run with `Cpu::set_block_cache()`. The breakpoints of the CPU
+    /// are restored at the end.
+    pub fn run(&mut self, cpu: &mut Cpu, sys: &mut dyn Machine) {
+        self.run_instructions(cpu, sys, u64::MAX);
+    }
+
+    /// Runs like `run()`, stopping after `max_instructions` if the program
+    /// has not terminated before. Returns the number of instructions
+    /// executed. It can be called again to continue.
+    pub fn run_instructions(&mut self, cpu: &mut Cpu, sys: &mut dyn Machine, max_instructions: u64) -> u64 {
+        let saved = cpu.breakpoints().to_vec();
+        let mut entries = vec![0x0000, BDOS_ENTRY as u32];
+        entries.extend((0..BIOS_FUNCTIONS).map(|i| (BIOS_STUBS + i) as u32));
+        entries.extend(saved.iter());
+        cpu.set_breakpoints(&entries);
+        let mut executed = 0;
+        while !self.terminated && executed < max_instructions {
+            executed += cpu.execute_instructions(sys, max_instructions - executed);
+            self.service_calls(cpu, sys);
+        }
+        cpu.set_breakpoints(&saved);
+        executed
+    }
+
+    fn service_calls(&mut self, cpu: &mut Cpu, sys: &mut dyn Machine) {
+        let pc = cpu.state.pc();
+        if cpu.is_halted() || pc == 0x0000 {
+            self.terminated = true;
+        } else if pc == BDOS_ENTRY as u32 {
+            self.bdos(cpu, sys);
+        } else if pc >= BIOS_STUBS as u32 && pc < (BIOS_STUBS + BIOS_FUNCTIONS) as u32 {
+            self.bios(cpu, (pc - BIOS_STUBS as u32) as u8);
+        }
+    }
+
+    fn bios(&mut self, cpu: &mut Cpu, function: u8) {
+        let reg = cpu.registers();
+        match function {
+            0 | 1 => { // BOOT, WBOOT
+                self.terminated = true;
+            },
+            2 => { // CONST
+                let ready = self.console.status();
+                reg.set_a(if ready { 0xff } else { 0 });
+            },
+            3 => { // CONIN
+                let c = self.console.read().unwrap_or(CTRL_Z);
+                reg.set_a(c);
+            },
+            4 => { // CONOUT
+                self.console.write(reg.get8(Reg8::C));
+            },
+            5 | 6 => {}, // LIST, PUNCH
+            7 => reg.set_a(CTRL_Z), // READER
+            8 | 10 | 11 => {}, // HOME, SETTRK, SETSEC
+            9 => reg.set16(Reg16::HL, 0), // SELDSK, no raw disks available
+            12 => self.dma = reg.get16(Reg16::BC), // SETDMA
+            13 | 14 => reg.set_a(1), // READ, WRITE
+            15 => reg.set_a(0xff), // LISTST
+            16 => { // SECTRAN
+                let bc = reg.get16(Reg16::BC);
+                reg.set16(Reg16::HL, bc);
+            },
+            _ => {}
+        }
+    }
+
+    fn bdos(&mut self, cpu: &mut Cpu, sys: &mut dyn Machine) {
+        let function = cpu.registers().get8(Reg8::C);
+        let e = cpu.registers().get8(Reg8::E);
+        let de = cpu.registers().get16(Reg16::DE);
+
+        let result: u16 = match function {
+            0 => { // P_TERMCPM
+                self.terminated = true;
+                0
+            },
+            1 => { // C_READ
+                let c = self.console.read().unwrap_or(CTRL_Z);
+                self.console.write(c);
+                c as u16
+            },
+            2 => { // C_WRITE
+                self.console.write(e);
+                0
+            },
+            3 => CTRL_Z as u16, // A_READ
+            4 | 5 => 0, // A_WRITE, L_WRITE
+            6 => match e { // C_RAWIO
+                0xff => if self.console.status() {
+                    self.console.read().unwrap_or(0) as u16
+                } else {
+                    0
+                },
+                0xfe => if self.console.status() { 0xff } else { 0 },
+                _ => {
+                    self.console.write(e);
+                    0
+                }
+            },
+            7 => sys.peek(IOBYTE_ADDRESS as u32) as u16, // A_GETIOB
+            8 => { // A_SETIOB
+                sys.poke(IOBYTE_ADDRESS as u32, e);
+                0
+            },
+            9 => { // C_WRITESTR
+                // Up to the whole memory if there is no terminator
+                for i in 0..0x10000 {
+                    let c = sys.peek(de.wrapping_add(i as u16) as u32);
+                    if c == b'$' {
+                        break;
+                    }
+                    self.console.write(c);
+                }
+                0
+            },
+            10 => { // C_READSTR
+                self.read_line(sys, de);
+                0
+            },
+            11 => if self.console.status() { 0xff } else { 0 }, // C_STAT
+            12 => 0x0022, // S_BDOSVER
+            13 => { // DRV_ALLRESET
+                self.dma = DEFAULT_DMA;
+                sys.poke(DRIVE_ADDRESS as u32, sys.peek(DRIVE_ADDRESS as u32) & 0xf0);
+                0
+            },
+            14 => { // DRV_SET
+                let user = sys.peek(DRIVE_ADDRESS as u32) & 0xf0;
+                sys.poke(DRIVE_ADDRESS as u32, user | (e & 0x0f));
+                if self.drives[e as usize & 0xf].is_some() { 0 } else { 0xff }
+            },
+            15 => self.file_open(sys, de), // F_OPEN
+            16 => self.file_close(sys, de), // F_CLOSE
+            17 => self.search_first(sys, de), // F_SFIRST
+            18 => self.search_next(sys), // F_SNEXT
+            19 => self.file_delete(sys, de), // F_DELETE
+            20 => self.read_sequential(sys, de), // F_READ
+            21 => self.write_sequential(sys, de), // F_WRITE
+            22 => self.file_make(sys, de), // F_MAKE
+            23 => self.file_rename(sys, de), // F_RENAME
+            24 => { // DRV_LOGINVEC
+                let mut vector = 0u16;
+                for (i, drive) in self.drives.iter().enumerate() {
+                    if drive.is_some() {
+                        vector |= 1 << i;
+                    }
+                }
+                vector
+            },
+            25 => (sys.peek(DRIVE_ADDRESS as u32) & 0x0f) as u16, // DRV_GET
+            26 => { // F_DMAOFF
+                self.dma = de;
+                0
+            },
+            27 => ALV_ADDRESS, // DRV_ALLOCVEC
+            28 => 0, // DRV_SETRO
+            29 => 0, // DRV_ROVEC
+            30 => if self.find_fcb_file(sys, de).is_some() { 0 } else { 0xff }, // F_ATTRIB
+            31 => DPB_ADDRESS, // DRV_DPB
+            32 => { // F_USERNUM
+                let drive = sys.peek(DRIVE_ADDRESS as u32);
+                if e == 0xff {
+                    (drive >> 4) as u16
+                } else {
+                    sys.poke(DRIVE_ADDRESS as u32, (drive & 0x0f) | (e << 4));
+                    0
+                }
+            },
+            33 => self.read_random(sys, de), // F_READRAND
+            34 | 40 => self.write_random(sys, de), // F_WRITERAND, F_WRITEZF
+            35 => self.file_size(sys, de), // F_SIZE
+            36 => { // F_RANDREC
+                let record = get_fcb_record(sys, de);
+                set_fcb_random_record(sys, de, record);
+                0
+            },
+            37 => 0, // DRV_RESET
+            _ => {
+                sys.diagnostic(format_args!("Unimplemented BDOS function {}", function));
+                0xff
+            }
+        };
+        if function == 20 || function == 33 {
+            // The record read may be code, like an overlay
+            let dma = self.dma as u32;
+            cpu.invalidate_decode_cache(dma, dma + RECORD_SIZE as u32);
+        }
+
+        // CP/M 2.2 returns values in HL, with A=L and B=H
+        let reg = cpu.registers();
+        reg.set16(Reg16::HL, result);
+        reg.set_a(result as u8);
+        reg.set8(Reg8::B, (result >> 8) as u8);
+    }
+
+    fn read_line(&mut self, sys: &mut dyn Machine, buffer: u16) {
+        let max = sys.peek(buffer as u32) as usize;
+        let mut line: Vec<u8> = Vec::new();
+        while let Some(c) = self.console.read() {
+            match c {
+                13 | 10 => {
+                    self.console.write(13);
+                    break;
+                },
+                8 | 127 => {
+                    if line.pop().is_some() {
+                        self.console.write(8);
+                        self.console.write(b' ');
+                        self.console.write(8);
+                    }
+                },
+                _ => {
+                    if line.len() < max {
+                        line.push(c);
+                        self.console.write(c);
+                    }
+                    if line.len() == max {
+                        break;
+                    }
+                }
+            }
+        }
+        sys.poke(buffer as u32 + 1, line.len() as u8);
+        for (i, c) in line.iter().enumerate() {
+            sys.poke(buffer as u32 + 2 + i as u32, *c);
+        }
+    }
+
+    fn fcb_drive(&self, sys: &dyn Machine, fcb: u16) -> usize {
+        match sys.peek(fcb as u32) & 0x1f {
+            0 => (sys.peek(DRIVE_ADDRESS as u32) & 0x0f) as usize,
+            dr => (dr - 1) as usize & 0xf,
+        }
+    }
+
+    fn find_fcb_file(&self, sys: &dyn Machine, fcb: u16) -> Option<PathBuf> {
+        let dir = self.drives[self.fcb_drive(sys, fcb)].as_ref()?;
+        let name = get_fcb_name(sys, fcb + 1);
+        find_file(dir, &name)
+    }
+
+    /// Returns the host file of an FCB, opening it the first time or when
+    /// the FCB now names another file
+    fn open_fcb_file(&mut self, sys: &dyn Machine, fcb: u16) -> Option<&mut OpenFile> {
+        let drive = self.fcb_drive(sys, fcb);
+        let name = get_fcb_name(sys, fcb + 1);
+        let cached = matches!(self.open_files.get(&fcb),
+            Some(open) if open.drive == drive && open.name == name);
+        if !cached {
+            self.open_files.remove(&fcb);
+            let path = self.find_fcb_file(sys, fcb)?;
+            // Read-only files can still be read
+            let file = OpenOptions::new().read(true).write(true).open(&path)
+                .or_else(|_| fs::File::open(&path))
+                .ok()?;
+            self.open_files.insert(fcb, OpenFile { drive, name, path, file });
+        }
+        self.open_files.get_mut(&fcb)
+    }
+
+    /// Drops the cached handles of a host file that is replaced or removed
+    fn forget_file(&mut self, path: &Path) {
+        self.open_files.retain(|_, open| open.path != path);
+    }
+
+    fn file_open(&mut self, sys: &mut dyn Machine, fcb: u16) -> u16 {
+        match self.open_fcb_file(sys, fcb) {
+            Some(open) => {
+                let records = open.records();
+                sys.poke(fcb as u32 + 14, 0); // S2
+                update_fcb_record_count(sys, fcb, records);
+                0
+            },
+            None => 0xff
+        }
+    }
+
+    fn file_close(&mut self, sys: &mut dyn Machine, fcb: u16) -> u16 {
+        if self.open_files.remove(&fcb).is_some() || self.find_fcb_file(sys, fcb).is_some() {
+            0
+        } else {
+            0xff
+        }
+    }
+
+    fn file_make(&mut self, sys: &mut dyn Machine, fcb: u16) -> u16 {
+        let dir = match &self.drives[self.fcb_drive(sys, fcb)] {
+            Some(dir) => dir.clone(),
+            None => return 0xff
+        };
+        let name = get_fcb_name(sys, fcb + 1);
+        let path = find_file(&dir, &name)
+            .unwrap_or_else(|| dir.join(name_to_host(&name)));
+        self.forget_file(&path);
+        match fs::File::create(&path) {
+            Ok(_) => {
+                sys.poke(fcb as u32 + 12, 0); // EX
+                sys.poke(fcb as u32 + 14, 0); // S2
+                sys.poke(fcb as u32 + 15, 0); // RC
+                0
+            },
+            Err(_) => 0xff
+        }
+    }
+
+    fn file_delete(&mut self, sys: &mut dyn Machine, fcb: u16) -> u16 {
+        let dir = match &self.drives[self.fcb_drive(sys, fcb)] {
+            Some(dir) => dir.clone(),
+            None => return 0xff
+        };
+        let pattern = get_fcb_name(sys, fcb + 1);
+        let mut result = 0xff;
+        for name in list_files(&dir, &pattern) {
+            if let Some(path) = find_file(&dir, &name) {
+                self.forget_file(&path);
+                if fs::remove_file(path).is_ok() {
+                    result = 0;
+                }
+            }
+        }
+        result
+    }
+
+    fn file_rename(&mut self, sys: &mut dyn Machine, fcb: u16) -> u16 {
+        let dir = match &self.drives[self.fcb_drive(sys, fcb)] {
+            Some(dir) => dir.clone(),
+            None => return 0xff
+        };
+        let old_name = get_fcb_name(sys, fcb + 1);
+        let new_name = get_fcb_name(sys, fcb + 17);
+        if find_file(&dir, &new_name).is_some() {
+            return 0xff;
+        }
+        match find_file(&dir, &old_name) {
+            Some(path) => {
+                self.forget_file(&path);
+                match fs::rename(path, dir.join(name_to_host(&new_name))) {
+                    Ok(_) => 0,
+                    Err(_) => 0xff
+                }
+            },
+            None => 0xff
+        }
+    }
+
+    fn search_first(&mut self, sys: &mut dyn Machine, fcb: u16) -> u16 {
+        self.search_results.clear();
+        self.search_drive = self.fcb_drive(sys, fcb);
+        if let Some(dir) = &self.drives[self.search_drive] {
+            let pattern = get_fcb_name(sys, fcb + 1);
+            self.search_results = list_files(dir, &pattern).into();
+        }
+        self.search_next(sys)
+    }
+
+    fn search_next(&mut self, sys: &mut dyn Machine) -> u16 {
+        let name = match self.search_results.pop_front() {
+            Some(name) => name,
+            None => return 0xff
+        };
+        let records = match &self.drives[self.search_drive] {
+            Some(dir) => find_file(dir, &name).map(|path| file_records(&path)).unwrap_or(0),
+            None => 0
+        };
+        let extent = if records == 0 { 0 } else { (records - 1) / RECORDS_PER_EXTENT };
+
+        // Directory entry in the first slot of the DMA buffer
+        let entry = self.dma as u32;
+        sys.poke(entry, sys.peek(DRIVE_ADDRESS as u32) >> 4);
+        for (i, c) in name.iter().enumerate() {
+            sys.poke(entry + 1 + i as u32, *c);
+        }
+        sys.poke(entry + 12, (extent % EXTENTS_PER_MODULE) as u8);
+        sys.poke(entry + 13, 0);
+        sys.poke(entry + 14, (extent / EXTENTS_PER_MODULE) as u8);
+        sys.poke(entry + 15, (records - extent * RECORDS_PER_EXTENT) as u8);
+        for i in 16..32 {
+            sys.poke(entry + i, 0);
+        }
+        0
+    }
+
+    fn read_sequential(&mut self, sys: &mut dyn Machine, fcb: u16) -> u16 {
+        let record = get_fcb_record(sys, fcb);
+        let open = match self.open_fcb_file(sys, fcb) {
+            Some(open) => open,
+            None => return 9 // Invalid FCB
+        };
+        let data = read_record(&mut open.file, record);
+        let records = open.records();
+        match data {
+            Some(data) => {
+                self.poke_dma(sys, &data);
+                set_fcb_record(sys, fcb, record + 1);
+                update_fcb_record_count(sys, fcb, records);
+                0
+            },
+            None => 1 // End of file
+        }
+    }
+
+    fn write_sequential(&mut self, sys: &mut dyn Machine, fcb: u16) -> u16 {
+        let record = get_fcb_record(sys, fcb);
+        let data = self.peek_dma(sys);
+        let open = match self.open_fcb_file(sys, fcb) {
+            Some(open) => open,
+            None => return 9 // Invalid FCB
+        };
+        if write_record(&mut open.file, record, &data) {
+            let records = open.records();
+            set_fcb_record(sys, fcb, record + 1);
+            update_fcb_record_count(sys, fcb, records);
+            0
+        } else {
+            2 // Disk full
+        }
+    }
+
+    fn read_random(&mut self, sys: &mut dyn Machine, fcb: u16) -> u16 {
+        let record = match get_fcb_random_record(sys, fcb) {
+            Some(record) => record,
+            None => return 6 // Record out of range
+        };
+        let open = match self.open_fcb_file(sys, fcb) {
+            Some(open) => open,
+            None => return 9 // Invalid FCB
+        };
+        let data = read_record(&mut open.file, record);
+        let records = open.records();
+        // The sequential position is set to the record read
+        set_fcb_record(sys, fcb, record);
+        update_fcb_record_count(sys, fcb, records);
+        match data {
+            Some(data) => {
+                self.poke_dma(sys, &data);
+                0
+            },
+            None => 1 // Reading unwritten data
+        }
+    }
+
+    fn write_random(&mut self, sys: &mut dyn Machine, fcb: u16) -> u16 {
+        let record = match get_fcb_random_record(sys, fcb) {
+            Some(record) => record,
+            None => return 6 // Record out of range
+        };
+        let data = self.peek_dma(sys);
+        let open = match self.open_fcb_file(sys, fcb) {
+            Some(open) => open,
+            None => return 9 // Invalid FCB
+        };
+        if write_record(&mut open.file, record, &data) {
+            let records = open.records();
+            set_fcb_record(sys, fcb, record);
+            update_fcb_record_count(sys, fcb, records);
+            0
+        } else {
+            2 // Disk full
+        }
+    }
+
+    fn file_size(&mut self, sys: &mut dyn Machine, fcb: u16) -> u16 {
+        match self.find_fcb_file(sys, fcb) {
+            Some(path) => {
+                set_fcb_random_record(sys, fcb, file_records(&path));
+                0
+            },
+            None => 0xff
+        }
+    }
+
+    fn peek_dma(&self, sys: &dyn Machine) -> [u8; RECORD_SIZE] {
+        let mut data = [0u8; RECORD_SIZE];
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = sys.peek(self.dma.wrapping_add(i as u16) as u32);
+        }
+        data
+    }
+
+    fn poke_dma(&self, sys: &mut dyn Machine, data: &[u8; RECORD_SIZE]) {
+        for (i, b) in data.iter().enumerate() {
+            sys.poke(self.dma.wrapping_add(i as u16) as u32, *b);
+        }
+    }
+}
+
+fn poke_jp(sys: &mut dyn Machine, address: u16, target: u16) {
+    sys.poke(address as u32, 0xc3); // JP nn
+    sys.poke(address as u32 + 1, target as u8);
+    sys.poke(address as u32 + 2, (target >> 8) as u8);
+}
+
+/// Fills an FCB from a `[D:]NAME.TYP` string, expanding `*` wildcards
+fn parse_fcb(sys: &mut dyn Machine, fcb: u16, arg: &str) {
+    let arg = arg.to_ascii_uppercase();
+    let bytes = arg.as_bytes();
+    let (drive, rest) = if bytes.len() >= 2 && bytes[1] == b':' {
+        (bytes[0].wrapping_sub(b'A').wrapping_add(1) & 0x1f, &arg[2..])
+    } else {
+        (0, &arg[..])
+    };
+    let (name, ext) = match rest.find('.') {
+        Some(pos) => (&rest[..pos], &rest[pos + 1..]),
+        None => (rest, "")
+    };
+
+    sys.poke(fcb as u32, drive);
+    for (offset, field, size) in [(1u32, name, 8usize), (9, ext, 3)] {
+        let mut chars = field.bytes();
+        let mut i = 0;
+        while i < size {
+            match chars.next() {
+                Some(b'*') => {
+                    while i < size {
+                        sys.poke(fcb as u32 + offset + i as u32, b'?');
+                        i += 1;
+                    }
+                },
+                Some(c) => {
+                    sys.poke(fcb as u32 + offset + i as u32, c);
+                    i += 1;
+                },
+                None => {
+                    sys.poke(fcb as u32 + offset + i as u32, b' ');
+                    i += 1;
+                }
+            }
+        }
+    }
+    for i in 12..16 {
+        sys.poke(fcb as u32 + i, 0);
+    }
+}
+
+fn get_fcb_name(sys: &dyn Machine, address: u16) -> [u8; 11] {
+    let mut name = [0u8; 11];
+    for (i, c) in name.iter_mut().enumerate() {
+        // The high bits are used as attributes
+        *c = (sys.peek(address as u32 + i as u32) & 0x7f).to_ascii_uppercase();
+    }
+    name
+}
+
+/// Sequential record number from the EX, S2 and CR fields
+fn get_fcb_record(sys: &dyn Machine, fcb: u16) -> u32 {
+    let ex = sys.peek(fcb as u32 + 12) as u32 & 0x1f;
+    let s2 = sys.peek(fcb as u32 + 14) as u32 & 0x3f;
+    let cr = sys.peek(fcb as u32 + 32) as u32 & 0x7f;
+    (s2 * EXTENTS_PER_MODULE + ex) * RECORDS_PER_EXTENT + cr
+}
+
+fn set_fcb_record(sys: &mut dyn Machine, fcb: u16, record: u32) {
+    let extent = record / RECORDS_PER_EXTENT;
+    sys.poke(fcb as u32 + 32, (record % RECORDS_PER_EXTENT) as u8);
+    sys.poke(fcb as u32 + 12, (extent % EXTENTS_PER_MODULE) as u8);
+    sys.poke(fcb as u32 + 14, (extent / EXTENTS_PER_MODULE) as u8);
+}
+
+/// Random record number from R0, R1. None if R2 is set.
+fn get_fcb_random_record(sys: &dyn Machine, fcb: u16) -> Option<u32> {
+    let r0 = sys.peek(fcb as u32 + 33) as u32;
+    let r1 = sys.peek(fcb as u32 + 34) as u32;
+    let r2 = sys.peek(fcb as u32 + 35);
+    if r2 != 0 {
+        None
+    } else {
+        Some(r0 + (r1 << 8))
+    }
+}
+
+fn set_fcb_random_record(sys: &mut dyn Machine, fcb: u16, record: u32) {
+    sys.poke(fcb as u32 + 33, record as u8);
+    sys.poke(fcb as u32 + 34, (record >> 8) as u8);
+    sys.poke(fcb as u32 + 35, (record >> 16) as u8);
+}
+
+/// Sets RC to the number of records of the file in the current extent
+fn update_fcb_record_count(sys: &mut dyn Machine, fcb: u16, records: u32) {
+    let ex = sys.peek(fcb as u32 + 12) as u32 & 0x1f;
+    let s2 = sys.peek(fcb as u32 + 14) as u32 & 0x3f;
+    let extent_start = (s2 * EXTENTS_PER_MODULE + ex) * RECORDS_PER_EXTENT;
+    let records = records.saturating_sub(extent_start).min(RECORDS_PER_EXTENT);
+    sys.poke(fcb as u32 + 15, records as u8);
+}
+
+/// Converts a host file name to the 8.3 FCB format, if possible
+fn host_to_name(host: &str) -> Option<[u8; 11]> {
+    let (name, ext) = match host.rfind('.') {
+        Some(pos) => (&host[..pos], &host[pos + 1..]),
+        None => (host, "")
+    };
+    if name.is_empty() || name.len() > 8 || ext.len() > 3 {
+        return None;
+    }
+    let valid = |c: u8| c.is_ascii_graphic() && c != b'.' && c != b'?' && c != b'*';
+    if !name.bytes().chain(ext.bytes()).all(valid) {
+        return None;
+    }
+    let mut result = [b' '; 11];
+    for (i, c) in name.bytes().enumerate() {
+        result[i] = c.to_ascii_uppercase();
+    }
+    for (i, c) in ext.bytes().enumerate() {
+        result[8 + i] = c.to_ascii_uppercase();
+    }
+    Some(result)
+}
+
+fn name_to_host(name: &[u8; 11]) -> String {
+    let base = String::from_utf8_lossy(&name[0..8]).trim_end().to_string();
+    let ext = String::from_utf8_lossy(&name[8..11]).trim_end().to_string();
+    if ext.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, ext)
+    }
+}
+
+fn name_matches(pattern: &[u8; 11], name: &[u8; 11]) -> bool {
+    pattern.iter().zip(name.iter()).all(|(p, n)| *p == b'?' || p == n)
+}
+
+/// Returns the CP/M names of the files in a host directory matching a pattern
+fn list_files(dir: &Path, pattern: &[u8; 11]) -> Vec<[u8; 11]> {
+    let mut names: Vec<[u8; 11]> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().map(|t| t.is_file()).unwrap_or(false))
+            .filter_map(|entry| host_to_name(&entry.file_name().to_string_lossy()))
+            .filter(|name| name_matches(pattern, name))
+            .collect(),
+        Err(_) => vec![]
+    };
+    names.sort_unstable();
+    names.dedup();
+    names
+}
+
+/// Finds a host file by its CP/M name, ignoring case
+fn find_file(dir: &Path, name: &[u8; 11]) -> Option<PathBuf> {
+    fs::read_dir(dir).ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().map(|t| t.is_file()).unwrap_or(false))
+        .find(|entry| host_to_name(&entry.file_name().to_string_lossy()).as_ref() == Some(name))
+        .map(|entry| entry.path())
+}
+
+fn file_records(path: &Path) -> u32 {
+    size_records(fs::metadata(path).map(|m| m.len()).unwrap_or(0))
+}
+
+fn size_records(size: u64) -> u32 {
+    ((size + RECORD_SIZE as u64 - 1) / RECORD_SIZE as u64) as u32
+}
+
+fn read_record(file: &mut fs::File, record: u32) -> Option<[u8; RECORD_SIZE]> {
+    file.seek(SeekFrom::Start(record as u64 * RECORD_SIZE as u64)).ok()?;
+    let mut data = [CTRL_Z; RECORD_SIZE];
+    let mut read = 0;
+    while read < RECORD_SIZE {
+        match file.read(&mut data[read..]) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => read += n
+        }
+    }
+    if read == 0 {
+        None
+    } else {
+        Some(data)
+    }
+}
+
+fn write_record(file: &mut fs::File, record: u32, data: &[u8; RECORD_SIZE]) -> bool {
+    file.seek(SeekFrom::Start(record as u64 * RECORD_SIZE as u64)).is_ok()
+        && file.write_all(data).is_ok()
+}
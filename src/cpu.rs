@@ -1,66 +1,193 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+
+use super::blocks::*;
+use super::decode_cache::*;
 use super::decoder_ez80::*;
 use super::decoder_z80::*;
+use super::decoder_z80n::*;
+use super::decoder_z180::*;
 use super::decoder_8080::*;
+use super::decoder_8085::*;
 use super::environment::*;
+use super::history::*;
+use super::i8085;
+use super::i8085::I8085Io;
 use super::machine::*;
 use super::opcode::*;
 use super::registers::*;
 use super::state::*;
+use super::trace::*;
+use super::trap::*;
+use super::z180;
+use super::z180::Z180Io;
 
 const NMI_ADDRESS: u32 = 0x0066;
 
 /// The Z80 cpu emulator.
 /// 
 /// Executes Z80 instructions changing the cpu State and Machine
-pub struct Cpu {
+pub type Cpu = GenericCpu<dyn Machine>;
+
+/// The Z80 cpu emulator for a specific Machine type.
+///
+/// With a concrete Machine, like `GenericCpu<PlainMachine>`, the memory and
+/// port accesses are resolved at compile time and can be inlined. `Cpu` is
+/// the version for any `&mut dyn Machine`.
+pub struct GenericCpu<M: Machine + ?Sized> {
     pub state: State,
-    trace: bool,
-    decoder: Box<dyn Decoder>,
+    trace_sink: Option<Box<dyn TraceSink>>,
+    history: Option<History>,
+    decode_cache: Option<Box<DecodeCache>>,
+    block_cache: Option<Box<BlockCache>>,
+    breakpoints: Vec<u32>,
+    traps: Traps,
+    decoder: CpuDecoder,
+    machine: PhantomData<fn(&mut M)>,
 }
 
 pub(crate) trait Decoder {
-    fn decode(&self, env: &mut Environment) -> &Opcode;
+    fn decode<M: Machine + ?Sized>(&self, env: &mut Environment<M>) -> &Opcode;
 }
 
-impl Cpu {
+enum CpuDecoder {
+    Z80(Box<DecoderZ80>),
+    EZ80(Box<DecoderEZ80>),
+    Z80N(Box<DecoderZ80N>),
+    Z180(Box<DecoderZ180>),
+    I8080(Box<Decoder8080>),
+    I8085(Box<Decoder8085>),
+}
+
+impl CpuDecoder {
+    #[inline]
+    fn decode<M: Machine + ?Sized>(&self, env: &mut Environment<M>) -> &Opcode {
+        match self {
+            CpuDecoder::Z80(decoder) => decoder.decode(env),
+            CpuDecoder::EZ80(decoder) => decoder.decode(env),
+            CpuDecoder::Z80N(decoder) => decoder.decode(env),
+            CpuDecoder::Z180(decoder) => decoder.decode(env),
+            CpuDecoder::I8080(decoder) => decoder.decode(env),
+            CpuDecoder::I8085(decoder) => decoder.decode(env),
+        }
+    }
+}
+
+impl<M: Machine + ?Sized> GenericCpu<M> {
 
     /// Returns a Z80 Cpu instance. Alias of new_z80()
-    pub fn new() -> Cpu {
+    pub fn new() -> GenericCpu<M> {
         Self::new_z80()
     }
 
     /// Returns a Z80 Cpu instance
-    pub fn new_z80() -> Cpu {
-        Cpu {
+    pub fn new_z80() -> GenericCpu<M> {
+        GenericCpu {
+            state: State::new(),
+            trace_sink: None,
+            history: None,
+            decode_cache: None,
+            block_cache: None,
+            breakpoints: Vec::new(),
+            traps: Traps::new(),
+            decoder: CpuDecoder::Z80(Box::new(DecoderZ80::new())),
+            machine: PhantomData,
+        }
+    }
+
+    pub fn new_ez80() -> GenericCpu<M> {
+        GenericCpu {
             state: State::new(),
-            trace: false,
-            decoder: Box::new(DecoderZ80::new())
+            trace_sink: None,
+            history: None,
+            decode_cache: None,
+            block_cache: None,
+            breakpoints: Vec::new(),
+            traps: Traps::new(),
+            decoder: CpuDecoder::EZ80(Box::new(DecoderEZ80::new())),
+            machine: PhantomData,
         }
     }
 
-    pub fn new_ez80() -> Cpu {
-        Cpu {
+    /// Returns a Z80N Cpu instance, the Z80 of the ZX Spectrum Next with
+    /// its extended instructions. NEXTREG writes with `Machine::nextreg()`.
+    pub fn new_z80n() -> GenericCpu<M> {
+        GenericCpu {
             state: State::new(),
-            trace: false,
-            decoder: Box::new(DecoderEZ80::new())
+            trace_sink: None,
+            history: None,
+            decode_cache: None,
+            block_cache: None,
+            breakpoints: Vec::new(),
+            traps: Traps::new(),
+            decoder: CpuDecoder::Z80N(Box::new(DecoderZ80N::new())),
+            machine: PhantomData,
         }
     }
 
+    /// Returns a Z180 Cpu instance, with the MMU and the internal I/O
+    /// registers in `state.z180`. The memory accesses go to the Machine
+    /// with the physical addresses.
+    pub fn new_z180() -> GenericCpu<M> {
+        let mut cpu = GenericCpu {
+            state: State::new(),
+            trace_sink: None,
+            history: None,
+            decode_cache: None,
+            block_cache: None,
+            breakpoints: Vec::new(),
+            traps: Traps::new(),
+            decoder: CpuDecoder::Z180(Box::new(DecoderZ180::new())),
+            machine: PhantomData,
+        };
+
+        cpu.state.z180 = Some(Z180Io::new());
+        cpu
+    }
+
     /// Returns an Intel 8080 Cpu instance
-    pub fn new_8080() -> Cpu {
-        let mut cpu = Cpu {
+    pub fn new_8080() -> GenericCpu<M> {
+        let mut cpu = GenericCpu {
             state: State::new(),
-            trace: false,
-            decoder: Box::new(Decoder8080::new())
+            trace_sink: None,
+            history: None,
+            decode_cache: None,
+            block_cache: None,
+            breakpoints: Vec::new(),
+            traps: Traps::new(),
+            decoder: CpuDecoder::I8080(Box::new(Decoder8080::new())),
+            machine: PhantomData,
         };
 
         cpu.state.reg.set_8080();
         cpu
     }
 
+    /// Returns an Intel 8085 Cpu instance, with the interrupt inputs and
+    /// the serial lines in `state.i8085`
+    pub fn new_8085() -> GenericCpu<M> {
+        let mut cpu = GenericCpu {
+            state: State::new(),
+            trace_sink: None,
+            history: None,
+            decode_cache: None,
+            block_cache: None,
+            breakpoints: Vec::new(),
+            traps: Traps::new(),
+            decoder: CpuDecoder::I8085(Box::new(Decoder8085::new())),
+            machine: PhantomData,
+        };
+
+        cpu.state.reg.set_8085();
+        cpu.state.i8085 = Some(I8085Io::new());
+        cpu
+    }
+
 }
 
-impl Default for Cpu {
+impl<M: Machine + ?Sized> Default for GenericCpu<M> {
     fn default() -> Self {
         Self::new()
     }
@@ -74,12 +201,366 @@ impl Cpu {
     /// * `sys` - A representation of the emulated machine that has the Machine trait
     ///
     pub fn execute_instruction(&mut self, sys: &mut dyn Machine) {
+        self.execute(sys)
+    }
+
+    /// Returns the instrction in PC disassembled. PC is advanced.
+    /// 
+    /// # Arguments
+    /// 
+    /// * `sys` - A representation of the emulated machine that has the Machine trait
+    ///  
+    pub fn disasm_instruction(&mut self, sys: &mut dyn Machine) -> String {
+        self.disasm(sys)
+    }
+
+    /// Undoes the last instruction in the history, restoring the CPU state
+    /// and the memory written. Returns false if the history is empty.
+    ///
+    /// # Arguments
+    ///
+    /// * `sys` - The Machine the instruction was executed on
+    pub fn step_back(&mut self, sys: &mut dyn Machine) -> bool {
+        self.undo(sys)
+    }
+
+    /// Executes instructions until `max_instructions` are executed, the
+    /// CPU halts or PC reaches a breakpoint. With set_block_cache(true)
+    /// the straight-line code runs from blocks of pre-decoded instructions.
+    /// Returns the number of instructions executed.
+    ///
+    /// # Arguments
+    ///
+    /// * `sys` - A representation of the emulated machine that has the Machine trait
+    /// * `max_instructions` - Limit of instructions to execute
+    pub fn execute_instructions(&mut self, sys: &mut dyn Machine, max_instructions: u64) -> u64 {
+        self.run(sys, max_instructions)
+    }
+
+    /// Requests a maskable vectored interrupt, like the eZ80 on-chip
+    /// peripherals do. With the interrupts enabled, the interrupt is
+    /// accepted: the Cpu leaves HALT, pushes PC and jumps to the address
+    /// stored at I * 256 + vector. Returns true if it was accepted.
+    ///
+    /// # Arguments
+    ///
+    /// * `sys` - A representation of the emulated machine that has the Machine trait
+    /// * `vector` - Offset of the interrupt on the vector table
+    pub fn interrupt(&mut self, sys: &mut dyn Machine, vector: u8) -> bool {
+        self.accept_interrupt(sys, vector)
+    }
+}
+
+impl<M: Machine> GenericCpu<M> {
+    /// Executes a single instruction
+    ///
+    /// # Arguments
+    ///
+    /// * `sys` - The emulated machine
+    ///
+    pub fn execute_instruction(&mut self, sys: &mut M) {
+        self.execute(sys)
+    }
+
+    /// Returns the instruction in PC disassembled. PC is advanced.
+    pub fn disasm_instruction(&mut self, sys: &mut M) -> String {
+        self.disasm(sys)
+    }
+
+    /// Undoes the last instruction in the history. See `Cpu::step_back()`.
+    pub fn step_back(&mut self, sys: &mut M) -> bool {
+        self.undo(sys)
+    }
+
+    /// Executes up to `max_instructions`. See `Cpu::execute_instructions()`.
+    pub fn execute_instructions(&mut self, sys: &mut M, max_instructions: u64) -> u64 {
+        self.run(sys, max_instructions)
+    }
+
+    /// Requests a maskable vectored interrupt. See `Cpu::interrupt()`.
+    pub fn interrupt(&mut self, sys: &mut M, vector: u8) -> bool {
+        self.accept_interrupt(sys, vector)
+    }
+}
+
+impl<M: Machine + ?Sized> GenericCpu<M> {
+    fn execute<N: AsDynMachine + ?Sized>(&mut self, sys: &mut N) {
         if self.is_halted() {
             // The CPU is in HALT state. Only interrupts can execute.
             return
         }
 
+        if !self.traps.is_empty() && !self.has_pending_interrupt() {
+            let ez80 = self.is_ez80();
+            if let Some((index, len)) = self.traps.find(&self.state, ez80, sys) {
+                let pc = self.state.reg.pc;
+                let saved_state = self.history.as_ref().map(|_| self.state.clone());
+                let mut machine = TrapMachine::new(sys.as_dyn_machine(),
+                    self.decode_cache.as_deref_mut(),
+                    self.block_cache.as_deref_mut().map(|cache| &mut cache.code),
+                    self.state.z180.is_some(), saved_state.is_some());
+                let action = self.traps.call(index, &mut self.state.reg, &mut machine);
+                let (undo, remapped) = (machine.undo, machine.remapped);
+                if remapped {
+                    self.clear_decode_cache();
+                }
+                // Kept on the history to undo the writes of the handler
+                if let (Some(history), Some(state)) = (self.history.as_mut(), saved_state) {
+                    if action != TrapAction::Execute || !undo.is_empty() {
+                        history.push(pc, state, undo);
+                    }
+                }
+                match action {
+                    TrapAction::Execute => {}
+                    TrapAction::Skip => {
+                        // The instruction trapped by the address is decoded
+                        // for its length
+                        self.state.reg.pc = if len == 0 {
+                            self.next_instruction(sys)
+                        } else {
+                            let mask = if self.state.reg.adl { 0xffffff } else { 0xffff };
+                            (pc & !mask) | (pc.wrapping_add(len) & mask)
+                        };
+                        return
+                    }
+                    TrapAction::Emulated => return,
+                    TrapAction::Return => {
+                        // Counted as the RET executed, with the cycles of
+                        // its opcode fetch
+                        let mut env = Environment::new(&mut self.state, sys);
+                        let pc = env.state.pc();
+                        env.use_fetch_cycles(1);
+                        Action::Ret.execute(&mut env);
+                        Self::end_instruction(&mut env, pc, &Action::Ret);
+                        return
+                    }
+                }
+            }
+        }
+
+        if self.trace_sink.is_some() || self.history.is_some() {
+            self.execute_traced(sys);
+            return
+        }
+
+        let mut env = Environment::new(&mut self.state, sys);
+        env.decode_cache = self.decode_cache.as_deref_mut();
+        env.code_map = self.block_cache.as_deref_mut().map(|cache| &mut cache.code);
+        Self::start_instruction(&mut env);
+        let pc = env.state.pc();
+        let action = if env.decode_cache.is_some() {
+            Self::decode_cached(&self.decoder, &mut env, pc)
+        } else {
+            self.decoder.decode(&mut env).action
+        };
+        action.execute(&mut env);
+        Self::end_instruction(&mut env, pc, &action);
+    }
+
+    /// Decodes the instruction at pc using the decode cache. Leaves the
+    /// state as the decoder does, with the immediate operands ready to be
+    /// read with advance_pc().
+    fn decode_cached<N: Machine + ?Sized>(decoder: &CpuDecoder, env: &mut Environment<N>, pc: u32) -> Action {
+        let generation = env.memory_generation();
+        let adl = env.state.reg.adl;
+        let mbase = env.state.reg.mbase;
+        let mask = if adl { 0xffffff } else { 0xffff };
+
+        if let Some(cache) = env.decode_cache.as_deref_mut() {
+            cache.check_generation(generation);
+            if let Some(entry) = cache.get(pc, adl, mbase).copied() {
+                env.state.sz_prefix = entry.sz_prefix;
+                env.state.index = entry.index;
+                env.state.displacement = entry.displacement;
+                env.state.set_pc((pc & !mask) | (pc.wrapping_add(entry.opcode_len as u32) & mask));
+                env.use_fetch_cycles(entry.len());
+                env.set_operands(entry.operands, entry.operand_len);
+                return entry.action;
+            }
+        }
+
+        // Not cached. Decode and read the operands to store them.
+        let opcode = decoder.decode(env);
+        let decoded_pc = env.state.reg.pc;
+        let opcode_len = env.state.pc().wrapping_sub(pc) & mask;
+        let operand_len = opcode.operand_len(env.state);
+        let mut operands = [0; 3];
+        for operand in operands.iter_mut().take(operand_len as usize) {
+            *operand = env.advance_pc();
+        }
+        env.state.reg.pc = decoded_pc;
+        env.set_operands(operands, operand_len);
+
+        if opcode_len + operand_len as u32 <= MAX_INSTRUCTION_LEN {
+            let instruction = CachedInstruction {
+                address: pc,
+                adl,
+                mbase,
+                action: opcode.action,
+                sz_prefix: env.state.sz_prefix,
+                index: env.state.index,
+                displacement: env.state.displacement,
+                opcode_len: opcode_len as u8,
+                operands,
+                operand_len,
+            };
+            if let Some(cache) = env.decode_cache.as_deref_mut() {
+                cache.insert(instruction);
+            }
+        }
+        opcode.action
+    }
+
+    fn run<N: AsDynMachine + ?Sized>(&mut self, sys: &mut N, max_instructions: u64) -> u64 {
+        let mut executed = 0;
+        let mut previous = None;
+        while executed < max_instructions && !self.is_halted() {
+            let pc = self.state.pc();
+            if executed > 0 && self.breakpoints.binary_search(&pc).is_ok() {
+                break;
+            }
+
+            // The traces, the history, the interrupts and the traps need
+            // the execution instruction by instruction
+            let single_step = self.trace_sink.is_some() || self.history.is_some()
+                || self.has_pending_interrupt()
+                || self.traps.find(&self.state, self.is_ez80(), sys).is_some();
+            let cache = match self.block_cache.as_deref_mut() {
+                Some(cache) if !single_step => cache,
+                _ => {
+                    self.execute(sys);
+                    executed += 1;
+                    previous = None;
+                    continue;
+                }
+            };
+
+            cache.check_generation(memory_generation(&self.state, sys));
+            cache.process_writes();
+            let left = max_instructions - executed;
+            match cache.find(previous, pc, self.state.reg.adl, self.state.reg.mbase) {
+                Some(id) => {
+                    executed += self.execute_block(sys, id, left);
+                    previous = Some(id);
+                }
+                None => {
+                    let (count, id) = self.record_block(sys, left);
+                    executed += count;
+                    previous = id;
+                }
+            }
+        }
+        executed
+    }
+
+    /// Executes the instructions of a block. Stops before the end of the
+    /// block if there is a jump or if the code or the memory mapping are
+    /// modified. Returns the number of instructions executed.
+    fn execute_block<N: Machine + ?Sized>(&mut self, sys: &mut N, id: usize, max_instructions: u64) -> u64 {
+        let cache = match self.block_cache.as_deref_mut() {
+            Some(cache) => cache,
+            None => return 0
+        };
+        let generation = memory_generation(&self.state, sys);
+        let (block, code) = cache.parts(id);
+        let mut env = Environment::new(&mut self.state, sys);
+        env.decode_cache = self.decode_cache.as_deref_mut();
+        env.code_map = Some(code);
+
+        let mut executed = 0;
+        for instruction in block.instructions.iter() {
+            let pc = env.state.pc();
+            // The same reads as the decoder, the instructions don't wrap
+            for i in 0..instruction.opcode_len as u32 {
+                env.peek(pc + i);
+            }
+            env.state.sz_prefix = instruction.sz_prefix;
+            env.state.index = instruction.index;
+            env.state.displacement = instruction.displacement;
+            env.state.reg.pc = instruction.decoded_pc;
+            instruction.action.execute(&mut env);
+            Self::end_instruction(&mut env, pc, &instruction.action);
+            executed += 1;
+
+            if executed == max_instructions
+                    || env.state.pc() != instruction.next
+                    || env.code_map.as_deref().is_some_and(|code| code.has_writes())
+                    || env.memory_generation() != generation {
+                break;
+            }
+        }
+        executed
+    }
+
+    /// Executes instructions with the decoder, recording them in a new
+    /// block. Returns the number of instructions executed and the block,
+    /// if it could be stored.
+    fn record_block<N: Machine + ?Sized>(&mut self, sys: &mut N, max_instructions: u64) -> (u64, Option<usize>) {
+        let ez80 = self.is_ez80();
+        let cache = match self.block_cache.as_deref_mut() {
+            Some(cache) => cache,
+            None => return (0, None)
+        };
+        let generation = memory_generation(&self.state, sys);
+        let mut block = Block::new(self.state.pc(), self.state.reg.adl, self.state.reg.mbase);
+
+        let mut executed = 0;
+        loop {
+            let pc = self.state.pc();
+            let mut env = Environment::new(&mut self.state, sys);
+            env.decode_cache = self.decode_cache.as_deref_mut();
+            let opcode = self.decoder.decode(&mut env);
+            let mut instruction = BlockInstruction {
+                action: opcode.action,
+                sz_prefix: env.state.sz_prefix,
+                index: env.state.index,
+                displacement: env.state.displacement,
+                opcode_len: env.state.reg.pc.wrapping_sub(pc) as u8,
+                decoded_pc: env.state.reg.pc,
+                next: 0,
+            };
+            // Marked before the execution to detect writes on itself
+            let wraps = instruction.decoded_pc <= pc;
+            if !wraps {
+                cache.mark_instruction(pc, instruction.opcode_len);
+            }
+            env.code_map = Some(&mut cache.code);
+            opcode.execute(&mut env);
+            Self::end_instruction(&mut env, pc, &opcode.action);
+            executed += 1;
+            instruction.next = env.state.pc();
+            let remapped = env.memory_generation() != generation;
+
+            if wraps || remapped || excluded_from_blocks(&instruction.action) {
+                break;
+            }
+            block.instructions.push(instruction);
+            if ends_block(&instruction.action)
+                    || block.is_full()
+                    || executed == max_instructions
+                    || !(instruction.decoded_pc..=instruction.decoded_pc + 3).contains(&instruction.next)
+                    || cache.code.has_writes()
+                    || self.breakpoints.binary_search(&instruction.next).is_ok()
+                    || self.traps.find(&self.state, ez80, sys).is_some() {
+                break;
+            }
+        }
+        (executed, cache.insert(block))
+    }
+
+    fn accept_interrupt<N: Machine + ?Sized>(&mut self, sys: &mut N, vector: u8) -> bool {
+        if !self.state.reg.get_iff1() {
+            return false;
+        }
+        self.state.halted = false;
         let mut env = Environment::new(&mut self.state, sys);
+        env.decode_cache = self.decode_cache.as_deref_mut();
+        env.code_map = self.block_cache.as_deref_mut().map(|cache| &mut cache.code);
+        env.interrupt(vector as u32);
+        true
+    }
+
+    fn start_instruction<N: Machine + ?Sized>(env: &mut Environment<N>) {
         if env.state.reset_pending {
             env.state.reset_pending = false;
             env.state.nmi_pending = false;
@@ -89,6 +570,12 @@ impl Cpu {
             env.state.reg.set8(Reg8::R, 0x00);
             env.state.reg.set_interrupts(false);
             env.state.reg.set_interrupt_mode(0);
+            if let Some(z180) = env.state.z180.as_mut() {
+                z180.reset();
+            }
+            if let Some(i8085) = env.state.i8085.as_mut() {
+                i8085.reset();
+            }
         }
         else if env.state.nmi_pending {
             env.state.nmi_pending = false;
@@ -96,49 +583,109 @@ impl Cpu {
             env.state.reg.start_nmi();
             env.subroutine_call(NMI_ADDRESS);
         }
+        else if let Some(i8085) = env.state.i8085.as_mut() {
+            if let Some(address) = i8085.acknowledge(env.state.reg.get_iff1()) {
+                env.state.halted = false;
+                env.state.reg.set_interrupts(false);
+                env.subroutine_call(address);
+            }
+        }
+    }
 
-        let pc = env.state.pc();
-        let opcode = self.decoder.decode(&mut env);
-        if self.trace {
-            print!("==> {:06x}: {:20}", pc, opcode.disasm(&env).0);
+    fn end_instruction<N: Machine + ?Sized>(env: &mut Environment<N>, pc: u32, action: &Action) {
+        if env.has_cycle_table() {
+            // The bus accesses used a cycle each, the rest is added
+            let cycles = if env.state.z180.is_some() {
+                z180::cycles(action, env.is_alt_index(), pc, env.state.pc())
+            } else {
+                i8085::cycles(action, pc, env.state.pc())
+            };
+            env.sys.use_cycles(cycles - env.bus_accesses() as i32);
         }
-        opcode.execute(&mut env);
         env.state.cached_instruction = env.state.pc() == pc;
         env.clear_index();
         env.state.clear_sz_prefix();
         env.state.instructions_executed += 1;
         env.state.reg.set8(Reg8::R, env.state.reg.get8(Reg8::R).wrapping_add(1));
+    }
+
+    /// Executes an instruction recording its accesses for the trace sink
+    /// and the history
+    fn execute_traced<N: Machine + ?Sized>(&mut self, sys: &mut N) {
+        let saved_state = self.history.as_ref().map(|_| self.state.clone());
+        let mut tracer = TracingMachine::new(sys, saved_state.is_some());
+        let mut env = Environment::new(&mut self.state, &mut tracer);
+        env.decode_cache = self.decode_cache.as_deref_mut();
+        env.code_map = self.block_cache.as_deref_mut().map(|cache| &mut cache.code);
+        Self::start_instruction(&mut env);
+        let before = env.state.reg.clone();
+        let pc = env.state.pc();
+        let opcode = self.decoder.decode(&mut env);
+        let (instruction, operands) = opcode.disasm(&env);
+        let decoded = env.state.pc();
+        let mut addresses = Vec::new();
+        let mut address = pc;
+        while address != decoded {
+            addresses.push(address);
+            address = env.wrap_address(address, 1);
+        }
+        for _ in 0..operands {
+            addresses.push(address);
+            address = env.wrap_address(address, 1);
+        }
+        opcode.execute(&mut env);
+        Self::end_instruction(&mut env, pc, &opcode.action);
+        let next_pc = env.state.pc();
 
-        if self.trace {
-            print!(" PC:{:06x} AF:{:04x} BC:{:06x} DE:{:06x} HL:{:06x} SPS:{:04x} SPL:{:06x} IX:{:06x} IY:{:06x} MB {:02x} ADL {:01x} MADL {:01x} tick {}",
-                self.state.pc(),
-                self.state.reg.get16(Reg16::AF),
-                self.state.reg.get24(Reg16::BC),
-                self.state.reg.get24(Reg16::DE),
-                self.state.reg.get24(Reg16::HL),
-                self.state.reg.get16(Reg16::SP),
-                self.state.reg.get24(Reg16::SP),
-                self.state.reg.get24(Reg16::IX),
-                self.state.reg.get24(Reg16::IY),
-                self.state.reg.mbase,
-                self.state.reg.adl as i32,
-                self.state.reg.madl as i32,
-                self.state.instructions_executed,
-            );
-            println!(" [{:02x} {:02x} {:02x} {:02x}]", sys.peek(pc),
-                sys.peek(pc.wrapping_add(1)),
-                sys.peek(pc.wrapping_add(2)),
-                sys.peek(pc.wrapping_add(3)));
+        if let (Some(history), Some(state)) = (self.history.as_mut(), saved_state) {
+            history.push(pc, state, core::mem::take(&mut tracer.undo));
+        }
+        if self.trace_sink.is_none() {
+            return;
+        }
+
+        // The instruction bytes are the first reads of their addresses
+        let extra_cycles = tracer.extra_cycles.get();
+        let accesses = tracer.accesses.into_inner();
+        if let Some(z180) = self.state.z180.as_ref() {
+            addresses.iter_mut().for_each(|a| *a = z180.translate(*a));
+        }
+        let bytes = addresses.iter().map(|a| {
+            accesses.iter()
+                .find(|access| access.kind == AccessKind::MemoryRead && access.address == *a)
+                .map(|access| access.value)
+                .unwrap_or_else(|| sys.peek(*a))
+        }).collect();
+        let record = TraceRecord {
+            pc,
+            next_pc,
+            bytes,
+            instruction,
+            before,
+            after: self.state.reg.clone(),
+            cycles: accesses.len() as i32 + extra_cycles,
+            accesses,
+            instructions_executed: self.state.instructions_executed,
+        };
+        if let Some(sink) = self.trace_sink.as_mut() {
+            sink.record(&record);
         }
     }
 
-    /// Returns the instrction in PC disassembled. PC is advanced.
-    /// 
-    /// # Arguments
-    /// 
-    /// * `sys` - A representation of the emulated machine that has the Machine trait
-    ///  
-    pub fn disasm_instruction(&mut self, sys: &mut dyn Machine) -> String {
+    /// Returns the address of the instruction after the one at pc. It is
+    /// decoded on a copy of the state, without side effects on the Machine.
+    fn next_instruction<N: Machine + ?Sized>(&self, sys: &N) -> u32 {
+        let mut state = self.state.clone();
+        let mut debug = DebugMachine(sys);
+        let mut env = Environment::new(&mut state, &mut debug);
+        let opcode = self.decoder.decode(&mut env);
+        let operand_len = opcode.operand_len(env.state) as u32;
+        let pc = env.state.reg.pc;
+        let mask = if env.state.reg.adl { 0xffffff } else { 0xffff };
+        (pc & !mask) | (pc.wrapping_add(operand_len) & mask)
+    }
+
+    fn disasm<N: Machine + ?Sized>(&mut self, sys: &mut N) -> String {
         let mut env = Environment::new(&mut self.state, sys);
         let opcode = self.decoder.decode(&mut env);
         let (asm, pc_inc) = opcode.disasm(&env);
@@ -147,13 +694,180 @@ impl Cpu {
     }
 
     /// Activates or deactivates traces of the instruction executed and
-    /// the state of the registers.
+    /// the state of the registers, written to stdout. Needs the `std`
+    /// feature, see set_trace_sink() otherwise.
     /// 
     /// # Arguments
     /// 
     /// * `trace` - A bool defining the trace state to set
+    #[cfg(feature = "std")]
     pub fn set_trace(&mut self, trace: bool) {
-        self.trace = trace;
+        if trace {
+            self.set_trace_sink(Some(Box::new(TextTrace::stdout())));
+        } else {
+            self.set_trace_sink(None);
+        }
+    }
+
+    /// Sets the receiver of a TraceRecord for each instruction executed.
+    /// Replaces the trace activated with set_trace().
+    ///
+    /// # Arguments
+    ///
+    /// * `sink` - The TraceSink, or None to stop tracing
+    pub fn set_trace_sink(&mut self, sink: Option<Box<dyn TraceSink>>) {
+        self.trace_sink = sink;
+    }
+
+    /// Removes the trace sink and returns it
+    pub fn take_trace_sink(&mut self) -> Option<Box<dyn TraceSink>> {
+        self.trace_sink.take()
+    }
+
+    /// Keeps the history of the last instructions executed, to undo them
+    /// with step_back(). The previous history is discarded.
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - Number of instructions to keep, 0 to disable the history
+    pub fn set_history(&mut self, capacity: usize) {
+        self.history = if capacity > 0 {
+            Some(History::new(capacity))
+        } else {
+            None
+        };
+    }
+
+    /// Returns the history of the last instructions executed, if enabled
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    fn undo<N: Machine + ?Sized>(&mut self, sys: &mut N) -> bool {
+        let (state, memory_writes) = match self.history.as_mut().and_then(|h| h.pop()) {
+            Some(entry) => entry,
+            None => return false
+        };
+        // The writes of the Z180 are on physical addresses, the caches
+        // use the logical ones
+        if self.state.z180.is_some() {
+            self.clear_decode_cache();
+        }
+        for (address, value) in memory_writes.iter().rev() {
+            if let Some(cache) = self.decode_cache.as_deref_mut() {
+                cache.written(*address);
+            }
+            if let Some(cache) = self.block_cache.as_deref_mut() {
+                cache.code.written(*address);
+            }
+            sys.poke(*address, *value);
+        }
+        self.state = state;
+        true
+    }
+
+    /// Activates or deactivates the cache of decoded instructions. With
+    /// it, the prefixes, opcode and immediate operands of an instruction
+    /// already executed are not decoded again.
+    ///
+    /// The writes done by the CPU invalidate the cached instructions.
+    /// Memory changed by other means, like loading a program directly on
+    /// the Machine, has to be invalidated with invalidate_decode_cache()
+    /// or by incrementing `Machine::memory_generation()`. The cycles of the
+    /// instruction fetch are counted with a single `use_cycles()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - A bool defining the state of the cache to set
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.decode_cache = if enabled {
+            Some(Box::new(DecodeCache::new()))
+        } else {
+            None
+        };
+    }
+
+    /// Invalidates the cached instructions and blocks that use memory in a
+    /// range of addresses, after it is modified without the CPU
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - First address of the range
+    /// * `end` - Address after the last address of the range
+    pub fn invalidate_decode_cache(&mut self, start: u32, end: u32) {
+        if let Some(cache) = self.decode_cache.as_deref_mut() {
+            cache.invalidate(start, end);
+        }
+        if let Some(cache) = self.block_cache.as_deref_mut() {
+            cache.invalidate(start, end);
+        }
+    }
+
+    /// Invalidates all the cached instructions and blocks
+    pub fn clear_decode_cache(&mut self) {
+        if let Some(cache) = self.decode_cache.as_deref_mut() {
+            cache.clear();
+        }
+        if let Some(cache) = self.block_cache.as_deref_mut() {
+            cache.clear();
+        }
+    }
+
+    /// Activates or deactivates the execution of blocks of pre-decoded
+    /// instructions in execute_instructions(). A block is a run of
+    /// straight-line code recorded the first time it is executed and
+    /// chained to the blocks that follow it.
+    ///
+    /// The state of the CPU and the calls to the Machine are the same as
+    /// with execute_instruction(). I/O instructions, interrupts, traces
+    /// and the history use the execution instruction by instruction. The
+    /// blocks are invalidated like the decode cache, see set_decode_cache().
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - A bool defining the state of the blocks to set
+    pub fn set_block_cache(&mut self, enabled: bool) {
+        self.block_cache = if enabled {
+            Some(Box::new(BlockCache::new()))
+        } else {
+            None
+        };
+    }
+
+    /// Sets the addresses, including MBASE, where execute_instructions()
+    /// stops before executing the instruction. Replaces the previous ones.
+    ///
+    /// # Arguments
+    ///
+    /// * `addresses` - The breakpoints, empty to remove them
+    pub fn set_breakpoints(&mut self, addresses: &[u32]) {
+        self.breakpoints = addresses.to_vec();
+        self.breakpoints.sort_unstable();
+        self.breakpoints.dedup();
+        // The blocks end before the breakpoints
+        if let Some(cache) = self.block_cache.as_deref_mut() {
+            cache.clear();
+        }
+    }
+
+    /// Returns the addresses of the breakpoints, sorted
+    pub fn breakpoints(&self) -> &[u32] {
+        &self.breakpoints
+    }
+
+    /// Sets the handler of a trap, replacing the previous one, or removes
+    /// it with None. See the `trap` module.
+    ///
+    /// # Arguments
+    ///
+    /// * `trap` - The instructions trapped
+    /// * `handler` - The handler called before their execution
+    pub fn set_trap(&mut self, trap: Trap, handler: Option<TrapHandler>) {
+        self.traps.set(trap, handler);
+        // The blocks end before the traps
+        if let Some(cache) = self.block_cache.as_deref_mut() {
+            cache.clear();
+        }
     }
 
     /// Set eZ80 ADL state
@@ -169,6 +883,23 @@ impl Cpu {
     /// Returns if the Cpu has executed a HALT
     pub fn is_halted(&self) -> bool {
         self.state.halted && !self.state.nmi_pending && !self.state.reset_pending
+            && !self.has_8085_interrupt()
+    }
+
+    /// Returns true if an interrupt, a NMI or a reset will be accepted
+    /// before the next instruction
+    fn has_pending_interrupt(&self) -> bool {
+        self.state.nmi_pending || self.state.reset_pending || self.has_8085_interrupt()
+    }
+
+    fn is_ez80(&self) -> bool {
+        matches!(self.decoder, CpuDecoder::EZ80(_))
+    }
+
+    /// Returns true if an 8085 interrupt will be accepted
+    fn has_8085_interrupt(&self) -> bool {
+        self.state.i8085.as_ref()
+            .is_some_and(|i8085| i8085.pending(self.state.reg.get_iff1()).is_some())
     }
 
     /// Non maskable interrupt request
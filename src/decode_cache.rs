@@ -0,0 +1,136 @@
+//! Cache of decoded instructions
+//!
+//! Stores, for the instructions executed, the action with the prefixes, the
+//! index register, the displacement and the immediate operands already
+//! decoded. The entries are keyed by address, ADL mode and MBASE.
+//!
+//! The writes done by the CPU invalidate the entries of the code they
+//! modify. Memory changed by other means has to be invalidated with
+//! `Cpu::invalidate_decode_cache()` or with `Machine::memory_generation()`.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use super::opcode::Action;
+use super::registers::Reg16;
+use super::state::SizePrefix;
+
+// Direct mapped, indexed by the low bits of the address
+const ENTRIES: usize = 8192;
+// Writes are checked against a bitmap of the 8 byte lines with cached code
+const LINE_BITS: u32 = 3;
+const LINES: usize = 1 << (24 - LINE_BITS);
+// Longest instruction: size prefix, index prefix, CB, displacement, opcode
+// or size prefix, index prefix, opcode, displacement, immediate
+pub(crate) const MAX_INSTRUCTION_LEN: u32 = 6;
+
+#[derive(Copy, Clone)]
+pub(crate) struct CachedInstruction {
+    pub address: u32,
+    pub adl: bool,
+    pub mbase: u8,
+    pub action: Action,
+    pub sz_prefix: SizePrefix,
+    pub index: Reg16,
+    pub displacement: i8,
+    /// Bytes of the prefixes, the opcode and the displacement
+    pub opcode_len: u8,
+    /// Immediate operands, read by the action with advance_pc()
+    pub operands: [u8; 3],
+    pub operand_len: u8,
+}
+
+impl CachedInstruction {
+    pub fn len(&self) -> u32 {
+        (self.opcode_len + self.operand_len) as u32
+    }
+}
+
+pub(crate) struct DecodeCache {
+    entries: Vec<Option<CachedInstruction>>,
+    lines: Vec<u64>,
+    generation: u64,
+}
+
+impl DecodeCache {
+    pub fn new() -> DecodeCache {
+        DecodeCache {
+            entries: vec![None; ENTRIES],
+            lines: vec![0; LINES / 64],
+            generation: 0,
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, address: u32, adl: bool, mbase: u8) -> Option<&CachedInstruction> {
+        match &self.entries[address as usize & (ENTRIES - 1)] {
+            Some(entry) if entry.address == address && entry.adl == adl && entry.mbase == mbase => Some(entry),
+            _ => None
+        }
+    }
+
+    pub fn insert(&mut self, instruction: CachedInstruction) {
+        let start = instruction.address;
+        let end = start + instruction.len() - 1;
+        if end > 0xffffff || (!instruction.adl && (end & 0xffff) < (start & 0xffff)) {
+            // The instruction wraps around, not cached
+            return;
+        }
+        for line in (start >> LINE_BITS)..=(end >> LINE_BITS) {
+            self.lines[line as usize / 64] |= 1 << (line % 64);
+        }
+        self.entries[start as usize & (ENTRIES - 1)] = Some(instruction);
+    }
+
+    /// Invalidates the instructions that include `address`
+    #[inline]
+    pub fn written(&mut self, address: u32) {
+        let line = (address & 0xffffff) >> LINE_BITS;
+        if self.lines[line as usize / 64] & (1 << (line % 64)) != 0 {
+            self.invalidate_line(line);
+        }
+    }
+
+    /// Invalidates the instructions that include any address of the range
+    pub fn invalidate(&mut self, start: u32, end: u32) {
+        let end = end.min(0x1000000);
+        if start >= end {
+            return;
+        }
+        for line in (start >> LINE_BITS)..=((end - 1) >> LINE_BITS) {
+            if self.lines[line as usize / 64] & (1 << (line % 64)) != 0 {
+                self.invalidate_line(line);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.iter_mut().for_each(|entry| *entry = None);
+        self.lines.iter_mut().for_each(|bits| *bits = 0);
+    }
+
+    /// Clears the cache if the generation of the memory of the Machine
+    /// is not the one of the cached instructions
+    #[inline]
+    pub fn check_generation(&mut self, generation: u64) {
+        if generation != self.generation {
+            self.clear();
+            self.generation = generation;
+        }
+    }
+
+    fn invalidate_line(&mut self, line: u32) {
+        // Instructions starting on the previous line can extend into it
+        let line_start = line << LINE_BITS;
+        let start = line_start.saturating_sub(MAX_INSTRUCTION_LEN - 1);
+        let end = line_start + (1 << LINE_BITS);
+        for address in start..end {
+            let slot = &mut self.entries[address as usize & (ENTRIES - 1)];
+            if let Some(entry) = slot {
+                if entry.address == address && entry.address + entry.len() > line_start {
+                    *slot = None;
+                }
+            }
+        }
+        self.lines[line as usize / 64] &= !(1 << (line % 64));
+    }
+}
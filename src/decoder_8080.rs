@@ -9,6 +9,7 @@ use super::opcode_ld::*;
 use super::operators::*;
 use super::registers::*;
 use super::environment::*;
+use super::machine::Machine;
 
 /* See
     http://www.z80.info/decoding.htm
@@ -17,11 +18,11 @@ use super::environment::*;
 */
 
 pub struct Decoder8080 {
-    no_prefix: [Option<Opcode>; 256],
+    pub(crate) no_prefix: [Option<Opcode>; 256],
 }
 
 impl Decoder for Decoder8080 {
-    fn decode(&self, env: &mut Environment) -> &Opcode {
+    fn decode<M: Machine + ?Sized>(&self, env: &mut Environment<M>) -> &Opcode {
         let b0 = env.advance_pc();
         let opcode = &self.no_prefix[b0 as usize];
         match opcode {
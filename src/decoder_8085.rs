@@ -0,0 +1,64 @@
+use super::cpu::*;
+use super::decoder_8080::*;
+use super::opcode::*;
+use super::opcode_arith::*;
+use super::opcode_bits::*;
+use super::opcode_io::*;
+use super::opcode_jumps::*;
+use super::opcode_ld::*;
+use super::registers::*;
+use super::environment::*;
+use super::machine::Machine;
+
+/* See
+    Intel 8085AH datasheet
+    "Unspecified 8085 op codes enhance programming", W. Dehnhardt and V. M. Sorensen
+*/
+
+pub struct Decoder8085 {
+    no_prefix: [Option<Opcode>; 256],
+}
+
+impl Decoder for Decoder8085 {
+    fn decode<M: Machine + ?Sized>(&self, env: &mut Environment<M>) -> &Opcode {
+        let b0 = env.advance_pc();
+        let opcode = &self.no_prefix[b0 as usize];
+        match opcode {
+            Some(o) => o,
+            None => {
+                panic!("Opcode {:02x} not defined", b0);
+            }
+        }
+    }
+}
+
+impl Decoder8085 {
+    pub fn new() -> Decoder8085 {
+        // The 8080 opcodes, replacing its aliases of NOP, JP, CALL and RET
+        let mut decoder = Decoder8085 {
+            no_prefix: Decoder8080::new().no_prefix
+        };
+        decoder.load_no_prefix();
+        decoder
+    }
+
+    fn load_no_prefix(&mut self) {
+        let opcodes = [
+            (0x08, build_dsub()), // DSUB
+            (0x10, build_arhl()), // ARHL
+            (0x18, build_rdel()), // RDEL
+            (0x20, build_rim()), // RIM
+            (0x28, build_ld_de_rr_n(Reg16::HL)), // LDHI n
+            (0x30, build_sim()), // SIM
+            (0x38, build_ld_de_rr_n(Reg16::SP)), // LDSI n
+            (0xcb, build_rstv()), // RSTV
+            (0xd9, build_shlx()), // SHLX
+            (0xdd, build_jp_eq((Flag::K, false, "NK"))), // JNK nn
+            (0xed, build_lhlx()), // LHLX
+            (0xfd, build_jp_eq((Flag::K, true, "K"))), // JK nn
+        ];
+        for (c, opcode) in opcodes {
+            self.no_prefix[c] = Some(opcode);
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use alloc::string::ToString;
 use super::cpu::*;
 use super::opcode::*;
 use super::opcode_alu::*;
@@ -9,6 +10,7 @@ use super::opcode_ld::*;
 use super::operators::*;
 use super::registers::*;
 use super::environment::*;
+use super::machine::Machine;
 use super::state::*;
 
 /* See
@@ -30,7 +32,7 @@ pub struct DecoderEZ80 {
 }
 
 impl Decoder for DecoderEZ80 {
-    fn decode(&self, env: &mut Environment) -> &Opcode {
+    fn decode<M: Machine + ?Sized>(&self, env: &mut Environment<M>) -> &Opcode {
         let mut b0 = env.advance_pc();
 
         // Process prefixes even if reapeated
@@ -447,12 +449,12 @@ impl DecoderEZ80 {
                         _ => Some(build_out0_n_r(R[p.y])),
                     }
                     2 => match p.p {
-                        0 | 1 | 2 => Some(build_lea_rr_ind_offset(RP[p.p], Reg16::IX)),
+                        0..=2 => Some(build_lea_rr_ind_offset(RP[p.p], Reg16::IX)),
                         3 => Some(build_lea_rr_ind_offset(Reg16::IX, Reg16::IX)),
                         _ => Some(build_noni_nop()), // Invalid instruction NONI + NOP
                     },
                     3 => match p.p {
-                        0 | 1 | 2 => Some(build_lea_rr_ind_offset(RP[p.p], Reg16::IY)),
+                        0..=2 => Some(build_lea_rr_ind_offset(RP[p.p], Reg16::IY)),
                         3 => Some(build_lea_rr_ind_offset(Reg16::IY, Reg16::IY)),
                         _ => Some(build_noni_nop()), // Invalid instruction NONI + NOP
                     },
@@ -689,9 +691,7 @@ pub const BLI_A: [(bool, bool, &str); 4] = [
 pub fn build_log_unimplemented(name: &'static str) -> Opcode {
     Opcode {
         name: name.to_string(),
-        action: Box::new(move |_: &mut Environment| {
-            println!("Unimplemented opcode: {}", name);
-        })
+        action: Action::LogUnimplemented(name)
     }
 }
 
@@ -0,0 +1,210 @@
+use super::cpu::*;
+use super::decoder_z80::*;
+use super::opcode::*;
+use super::opcode_alu::*;
+use super::opcode_arith::*;
+use super::opcode_io::*;
+use super::opcode_bits::*;
+use super::opcode_jumps::*;
+use super::opcode_ld::*;
+use super::registers::*;
+use super::environment::*;
+use super::machine::Machine;
+
+/* See
+    Z8018x Family MPU User Manual (UM005003), Instruction Set and
+    Op Code Map
+*/
+
+/// Decoder of the Z180. The instructions are the ones of the Z80 without
+/// the undocumented ones, plus the ED instructions of the HD64180. The
+/// undefined opcodes execute a TRAP.
+pub struct DecoderZ180 {
+    z80: DecoderZ80,
+    prefix_ed: [Option<Opcode>; 256],
+    // Opcodes that can follow a DD or FD prefix
+    has_index: [bool; 256],
+    trap: Opcode,
+    trap_third_byte: Opcode,
+}
+
+impl Decoder for DecoderZ180 {
+    fn decode<M: Machine + ?Sized>(&self, env: &mut Environment<M>) -> &Opcode {
+        let mut b0 = env.advance_pc();
+
+        if b0 == 0xdd || b0 == 0xfd {
+            env.set_index(if b0 == 0xdd { Reg16::IX } else { Reg16::IY });
+            b0 = env.advance_pc();
+            if !self.has_index[b0 as usize] {
+                return &self.trap;
+            }
+        }
+
+        let opcode = match b0 {
+            0xcb => {
+                if env.is_alt_index() {
+                    env.load_displacement();
+                    let b1 = env.advance_pc();
+                    // Only the (IX+d) forms, without SLL
+                    if b1 & 0x07 != 0x06 || b1 == 0x36 {
+                        return &self.trap_third_byte;
+                    }
+                    &self.z80.prefix_cb_indexed[b1 as usize]
+                } else {
+                    let b1 = env.advance_pc();
+                    if b1 & 0xf8 == 0x30 {
+                        // SLL
+                        return &self.trap;
+                    }
+                    &self.z80.prefix_cb[b1 as usize]
+                }
+            },
+            0xed => &self.prefix_ed[env.advance_pc() as usize],
+            _ => {
+                if self.z80.has_displacement[b0 as usize] && env.is_alt_index() {
+                    env.load_displacement();
+                }
+                &self.z80.no_prefix[b0 as usize]
+            }
+        };
+        opcode.as_ref().unwrap_or(&self.trap)
+    }
+}
+
+impl DecoderZ180 {
+    pub fn new() -> DecoderZ180 {
+        let mut decoder = DecoderZ180 {
+            z80: DecoderZ80::new(),
+            prefix_ed: [
+                None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None,
+                None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None,
+                None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None,
+                None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None,
+                None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None,
+                None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None,
+                None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None,
+                None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None,
+                None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None,
+                None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None,
+                None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None,
+                None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None,
+                None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None,
+                None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None,
+                None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None,
+                None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None,
+            ],
+            has_index: [false; 256],
+            trap: build_trap(false),
+            trap_third_byte: build_trap(true),
+        };
+        decoder.load_prefix_ed();
+        decoder.load_has_index();
+        decoder
+    }
+
+    fn load_prefix_ed(&mut self) {
+        for c in 0..=255 {
+            let p = DecodingHelper::parts(c);
+            let opcode = match p.x {
+                0 => match p.z {
+                    0 if p.y != 6 => Some(build_in0_r_n(R[p.y])), // IN0 r, (n)
+                    1 if p.y != 6 => Some(build_out0_n_r(R[p.y])), // OUT0 (n), r
+                    4 => Some(build_tst_a_r(R[p.y])), // TST r
+                    _ => None
+                },
+                1 => match p.z {
+                    0 => match p.y {
+                        6 => Some(build_in_0_c()), // IN (C)
+                        _ => Some(build_in_r_c(R[p.y])), // IN r, (C)
+                    }
+                    1 => match p.y {
+                        6 => None,
+                        _ => Some(build_out_c_r(R[p.y])), // OUT (C), r
+                    }
+                    2 => match p.q {
+                        0 => Some(build_sbc_hl_rr(RP[p.p])), // SBC HL, rr
+                        1 => Some(build_adc_hl_rr(RP[p.p])), // ADC HL, rr
+                        _ => panic!("Unreachable")
+                    },
+                    3 => match p.q {
+                        0 => Some(build_ld_pnn_rr(RP[p.p], false)), // LD (nn), rr -- 16 bit loading
+                        1 => Some(build_ld_rr_pnn(RP[p.p], false)), // LD rr, (nn) -- 16 bit loading
+                        _ => panic!("Unreachable")
+                    },
+                    4 => match p.y {
+                        0 => Some(build_neg()), // NEG
+                        1 | 3 | 5 | 7 => Some(build_mlt_rr(RP[p.p])), // MLT rr
+                        4 => Some(build_tst_a_n()), // TST n
+                        6 => Some(build_tstio_n()), // TSTIO n
+                        _ => None
+                    },
+                    5 => match p.y {
+                        0 => Some(build_retn()), // RETN
+                        1 => Some(build_reti()), // RETI
+                        _ => None
+                    }
+                    6 => match p.y {
+                        0 | 2 | 3 => Some(build_im(IM[p.y])), // IM #
+                        6 => Some(build_slp()), // SLP
+                        _ => None
+                    }
+                    7 => match p.y {
+                        0 => Some(build_ld_r_r(Reg8::I, Reg8::A, true)), // LD I, A
+                        1 => Some(build_ld_r_r(Reg8::R, Reg8::A, true)), // LD R, A
+                        2 => Some(build_ld_r_r(Reg8::A, Reg8::I, true)), // LD A, I
+                        3 => Some(build_ld_r_r(Reg8::A, Reg8::R, true)), // LD A, R
+                        4 => Some(build_rxd(ShiftDir::Right, "RRD")), // RRD
+                        5 => Some(build_rxd(ShiftDir::Left, "RLD")),  // RLD
+                        _ => None
+                    },
+                    _ => panic!("Unreacheable")
+                },
+                2 => match p.z {
+                    0 if p.y >= 4 => Some(build_ld_block(BLI_A[p.y-4])), // Block LDxx
+                    1 if p.y >= 4 => Some(build_cp_block(BLI_A[p.y-4])), // Block CPxx
+                    2 if p.y >= 4 => Some(build_in_block(BLI_A[p.y-4])), // Block INxx
+                    3 if p.y >= 4 => Some(build_out_block(BLI_A[p.y-4])), // Block OUTxx
+                    3 => Some(build_otim(BLI_A[p.y])), // OTIM, OTDM, OTIMR, OTDMR
+                    _ => None
+                },
+                _ => None
+            };
+            self.prefix_ed[c as usize] = opcode;
+        }
+    }
+
+    fn load_has_index(&mut self) {
+        // The instructions with HL, (HL) or JP (HL). The ones with H and
+        // L, IXH and IXL on the Z80, are undefined.
+        for c in [0x09, 0x19, 0x21, 0x22, 0x23, 0x29, 0x2a, 0x2b, 0x39, 0xcb, 0xe1, 0xe3, 0xe5, 0xe9, 0xf9] {
+            self.has_index[c] = true;
+        }
+        for c in 0..256 {
+            if self.z80.has_displacement[c] {
+                self.has_index[c] = true;
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct DecodingHelper {
+    // See notation in http://www.z80.info/decoding.htm
+    x: usize,
+    y: usize,
+    z: usize,
+    p: usize,
+    q: usize
+}
+
+impl DecodingHelper {
+    fn parts(code: u8) -> DecodingHelper {
+        DecodingHelper {
+            x: (code >> 6) as usize,
+            y: ((code >> 3) & 7) as usize,
+            z: (code & 7) as usize,
+            p: ((code >> 4) & 3) as usize,
+            q: ((code >> 3) & 1) as usize,
+        }
+    }
+}
@@ -9,6 +9,7 @@ use super::opcode_ld::*;
 use super::operators::*;
 use super::registers::*;
 use super::environment::*;
+use super::machine::Machine;
 
 /* See
     http://www.z80.info/decoding.htm
@@ -17,15 +18,15 @@ use super::environment::*;
 */
 
 pub struct DecoderZ80 {
-    no_prefix: [Option<Opcode>; 256],
-    prefix_cb: [Option<Opcode>; 256],
-    prefix_cb_indexed: [Option<Opcode>; 256],
-    prefix_ed: [Option<Opcode>; 256],
-    has_displacement: [bool; 256],
+    pub(crate) no_prefix: [Option<Opcode>; 256],
+    pub(crate) prefix_cb: [Option<Opcode>; 256],
+    pub(crate) prefix_cb_indexed: [Option<Opcode>; 256],
+    pub(crate) prefix_ed: [Option<Opcode>; 256],
+    pub(crate) has_displacement: [bool; 256],
 }
 
 impl Decoder for DecoderZ80 {
-    fn decode(&self, env: &mut Environment) -> &Opcode {
+    fn decode<M: Machine + ?Sized>(&self, env: &mut Environment<M>) -> &Opcode {
         let mut b0 = env.advance_pc();
 
         // Process prefixes even if reapeated
@@ -0,0 +1,75 @@
+use super::cpu::*;
+use super::decoder_z80::*;
+use super::opcode::*;
+use super::opcode_alu::*;
+use super::opcode_arith::*;
+use super::opcode_io::*;
+use super::opcode_bits::*;
+use super::opcode_jumps::*;
+use super::opcode_ld::*;
+use super::registers::*;
+use super::environment::*;
+use super::machine::Machine;
+
+/* See
+    https://wiki.specnext.dev/Extended_Z80_instruction_set
+*/
+
+/// Decoder of the Z80N of the ZX Spectrum Next. The Z80 instructions with
+/// the Next extended ones on the ED table.
+pub struct DecoderZ80N {
+    z80: DecoderZ80,
+}
+
+impl Decoder for DecoderZ80N {
+    fn decode<M: Machine + ?Sized>(&self, env: &mut Environment<M>) -> &Opcode {
+        self.z80.decode(env)
+    }
+}
+
+impl DecoderZ80N {
+    pub fn new() -> DecoderZ80N {
+        let mut decoder = DecoderZ80N {
+            z80: DecoderZ80::new(),
+        };
+        decoder.load_prefix_ed();
+        decoder
+    }
+
+    fn load_prefix_ed(&mut self) {
+        let opcodes = [
+            (0x23, build_swapnib()), // SWAPNIB
+            (0x24, build_mirror()), // MIRROR A
+            (0x27, build_test_n()), // TEST n
+            (0x28, build_barrel_shift(0)), // BSLA DE, B
+            (0x29, build_barrel_shift(1)), // BSRA DE, B
+            (0x2a, build_barrel_shift(2)), // BSRL DE, B
+            (0x2b, build_barrel_shift(3)), // BSRF DE, B
+            (0x2c, build_barrel_shift(4)), // BRLC DE, B
+            (0x30, build_mul_d_e()), // MUL D, E
+            (0x31, build_add_rr_a(Reg16::HL)), // ADD HL, A
+            (0x32, build_add_rr_a(Reg16::DE)), // ADD DE, A
+            (0x33, build_add_rr_a(Reg16::BC)), // ADD BC, A
+            (0x34, build_add_rr_nn(Reg16::HL)), // ADD HL, nn
+            (0x35, build_add_rr_nn(Reg16::DE)), // ADD DE, nn
+            (0x36, build_add_rr_nn(Reg16::BC)), // ADD BC, nn
+            (0x8a, build_push_nn()), // PUSH nn, big endian
+            (0x90, build_outinb()), // OUTINB
+            (0x91, build_nextreg(true)), // NEXTREG n, n
+            (0x92, build_nextreg(false)), // NEXTREG n, A
+            (0x93, build_pixeldn()), // PIXELDN
+            (0x94, build_pixelad()), // PIXELAD
+            (0x95, build_setae()), // SETAE
+            (0x98, build_jp_c()), // JP (C)
+            (0xa4, build_ld_block_x((true, false, "IX"))), // LDIX
+            (0xa5, build_ldws()), // LDWS
+            (0xac, build_ld_block_x((false, false, "DX"))), // LDDX
+            (0xb4, build_ld_block_x((true, true, "IRX"))), // LDIRX
+            (0xb7, build_ldpirx()), // LDPIRX
+            (0xbc, build_ld_block_x((false, true, "DRX"))), // LDDRX
+        ];
+        for (c, opcode) in opcodes {
+            self.z80.prefix_ed[c] = Some(opcode);
+        }
+    }
+}
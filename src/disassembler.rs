@@ -1,13 +1,20 @@
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
 use crate::machine::Machine;
 use crate::cpu::Cpu;
 use crate::environment::Environment;
+use crate::flags::{Flags, FlagsLayout};
 use crate::registers::*;
 
 #[derive(Clone, Debug)]
 pub struct Disasm {
     pub loc: u32,
     pub asm: String,
-    pub bytes: Vec<u8>
+    pub bytes: Vec<u8>,
+    /// The flags affected or tested by the instruction, with the names of
+    /// the CPU model, or empty if it doesn't use them
+    pub comment: String,
 }
 
 /**
@@ -42,10 +49,17 @@ pub fn disassemble(machine: &mut dyn Machine, cpu: &mut Cpu, adl_override: Optio
             }
         }
 
+        let layout = cpu.state.reg.flags_layout();
+        let flags = instruction_flags(&opcode_asm, layout);
         dis.push(Disasm {
             loc: opcode_start,
             asm: opcode_asm,
-            bytes: instruction_bytes
+            bytes: instruction_bytes,
+            comment: if flags == Flags::empty() {
+                String::new()
+            } else {
+                flags.display(layout).to_string()
+            },
         });
 
         cpu.state.clear_sz_prefix();
@@ -62,3 +76,74 @@ pub fn disassemble(machine: &mut dyn Machine, cpu: &mut Cpu, adl_override: Optio
 
     dis
 }
+
+/// Returns the flags affected or tested by the disassembled instruction
+/// [asm], with the bits of [layout]. The 8080 has no N flag nor the
+/// undocumented bits 5 and 3, the 8085 has its K and V flags on the bits 5
+/// and 1.
+pub fn instruction_flags(asm: &str, layout: FlagsLayout) -> Flags {
+    let (mnemonic, operands) = asm.split_once(' ').unwrap_or((asm, ""));
+    // Without the size suffix of the eZ80
+    let mnemonic = mnemonic.split('.').next().unwrap_or(mnemonic);
+    let mut operands = operands.split(", ");
+    let first = operands.next().unwrap_or("");
+    let second = operands.next().unwrap_or("");
+    let pair = matches!(first, "BC" | "DE" | "HL" | "SP" | "IX" | "IY");
+
+    let all = Flags::all();
+    let undocumented = Flags::Y | Flags::X;
+    let flags = match mnemonic {
+        "ADD" if first == "A" => all,
+        // The Z80N additions to a pair don't change the flags
+        "ADD" if second == "A" || second.starts_with('$') => Flags::empty(),
+        "ADD" if layout != FlagsLayout::Z80 => Flags::C,
+        "ADD" => undocumented | Flags::H | Flags::N | Flags::C,
+        "ADC" | "SBC" | "SUB" | "AND" | "OR" | "XOR" | "CP" | "NEG" | "DAA"
+            | "TST" | "TSTIO" | "TEST" => all,
+        "INC" | "DEC" if pair => Flags::empty(),
+        "INC" | "DEC" | "BIT" | "RLD" | "RRD" | "IN0" | "CPI" | "CPD" | "CPIR" | "CPDR"
+            | "LDWS" => all & !Flags::C,
+        "IN" if first == "(C)" || second == "(C)" => all & !Flags::C,
+        "CPL" => undocumented | Flags::H | Flags::N,
+        "SCF" | "CCF" | "RLCA" | "RRCA" | "RLA" | "RRA" => undocumented | Flags::H | Flags::N | Flags::C,
+        // ARHL and RDEL of the 8085
+        "SRA" | "RL" if pair => Flags::C,
+        "RLC" | "RRC" | "RL" | "RR" | "SLA" | "SRA" | "SLL" | "SRL" => all,
+        "LDI" | "LDD" | "LDIR" | "LDDR" => undocumented | Flags::H | Flags::PV | Flags::N,
+        "INI" | "IND" | "INIR" | "INDR" | "OUTI" | "OUTD" | "OTIR" | "OTDR"
+            | "INI2" | "IND2" | "INI2R" | "IND2R" | "OUTI2" | "OUTD2" | "OTI2R" | "OTD2R"
+            | "INIM" | "INDM" | "INIMR" | "INDMR" | "OTIM" | "OTDM" | "OTIMR" | "OTDMR"
+            | "INIRX" | "INDRX" | "OTIRX" | "OTDRX" => all,
+        "LD" if first == "A" && (second == "I" || second == "R") => all & !Flags::C,
+        "POP" | "EX" if first == "AF" => all,
+        "JP" | "JR" | "CALL" | "RET" => match first {
+            "Z" | "NZ" => Flags::Z,
+            "C" | "NC" => Flags::C,
+            "PE" | "PO" => Flags::PV,
+            "P" | "M" | "N" => Flags::S,
+            "K" | "NK" => Flags::Y,
+            _ => Flags::empty(),
+        },
+        "RST" if first == "V" => Flags::N,
+        _ => Flags::empty(),
+    };
+
+    match layout {
+        FlagsLayout::Z80 => flags,
+        FlagsLayout::I8080 => flags & !(undocumented | Flags::N),
+        FlagsLayout::I8085 => {
+            // K and V, set by the arithmetic with carry, INX and DCX, RDEL
+            // and tested by JK, JNK and RSTV
+            let k_v = match mnemonic {
+                "ADD" | "ADC" | "SUB" | "SBC" | "CP" if first == "A" => Flags::Y | Flags::N,
+                "SUB" => Flags::Y | Flags::N,
+                "INC" | "DEC" if pair => Flags::Y,
+                "RL" if pair => Flags::N,
+                "JP" | "CALL" | "RET" if matches!(first, "K" | "NK") => Flags::Y,
+                "RST" if first == "V" => Flags::N,
+                _ => Flags::empty(),
+            };
+            (flags & !(undocumented | Flags::N)) | k_v
+        }
+    }
+}
@@ -1,20 +1,97 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use core::cell::Cell;
 use super::machine::*;
 use super::registers::*;
+use super::blocks::CodeMap;
+use super::decode_cache::DecodeCache;
 use super::state::{ State, SizePrefix };
 
-pub struct Environment<'a> {
+/// The CPU state and the Machine during the execution of an instruction.
+/// Generic over the Machine so the memory accesses can be inlined.
+pub struct Environment<'a, M: Machine + ?Sized = dyn Machine> {
     pub state: &'a mut State,
-    pub sys: &'a mut dyn Machine
+    pub sys: &'a mut M,
+    // Decoded instructions to invalidate on writes
+    pub(crate) decode_cache: Option<&'a mut DecodeCache>,
+    // Code in blocks, to invalidate them on writes
+    pub(crate) code_map: Option<&'a mut CodeMap>,
+    // Immediate operands of a cached instruction, returned by advance_pc()
+    operands: [u8; 3],
+    operand_pos: u8,
+    operand_len: u8,
+    // Accesses to the Machine of a Z180 instruction, for its timing
+    bus_accesses: Cell<u32>,
 }
 
-impl <'a> Environment<'_> {
-    pub fn new(state: &'a mut State, sys: &'a mut dyn Machine) -> Environment<'a> {
+impl <'a, M: Machine + ?Sized> Environment<'a, M> {
+    pub fn new(state: &'a mut State, sys: &'a mut M) -> Environment<'a, M> {
         Environment {
             state,
-            sys
+            sys,
+            decode_cache: None,
+            code_map: None,
+            operands: [0; 3],
+            operand_pos: 0,
+            operand_len: 0,
+            bus_accesses: Cell::new(0),
         }
     }
 
+    /// Sets the operands of a cached instruction, to be used by
+    /// advance_pc() instead of reading them again from memory
+    pub(crate) fn set_operands(&mut self, operands: [u8; 3], len: u8) {
+        self.operands = operands;
+        self.operand_pos = 0;
+        self.operand_len = len;
+    }
+
+    /// Returns the address on the bus of a memory access, counted for the
+    /// instruction timing. On the Z180 it is translated by the MMU.
+    #[inline]
+    fn bus_address(&self, address: u32) -> u32 {
+        self.bus_accesses.set(self.bus_accesses.get() + 1);
+        match self.state.z180.as_ref() {
+            Some(z180) => z180.translate(address),
+            None => address
+        }
+    }
+
+    /// Returns the number of accesses to the Machine of the instruction
+    pub(crate) fn bus_accesses(&self) -> u32 {
+        self.bus_accesses.get()
+    }
+
+    /// Uses the cycles of the reads of a cached instruction, that are not
+    /// done again
+    pub(crate) fn use_fetch_cycles(&self, reads: u32) {
+        self.sys.use_cycles(reads as i32);
+        self.bus_accesses.set(self.bus_accesses.get() + reads);
+    }
+
+    /// Returns true if the cycles of the whole instruction are used at its
+    /// end, with a table for the Z180 or the 8085
+    #[inline]
+    pub(crate) fn has_cycle_table(&self) -> bool {
+        self.state.z180.is_some() || self.state.i8085.is_some()
+    }
+
+    /// Uses the cycles of the internal operations of an instruction. The
+    /// Z180 and the 8085 use the cycles of the whole instruction at its
+    /// end instead.
+    #[inline]
+    pub fn use_cycles(&self, cycles: i32) {
+        if !self.has_cycle_table() {
+            self.sys.use_cycles(cycles);
+        }
+    }
+
+    /// Returns the generation of the memory seen by the CPU, changed by
+    /// the Machine or by the Z180 MMU
+    pub fn memory_generation(&self) -> u64 {
+        memory_generation(self.state, self.sys)
+    }
+
     pub fn wrap_address24(&self, address: u32, increment: i32) -> u32 {
         address.wrapping_add(increment as u32)
     }
@@ -32,7 +109,7 @@ impl <'a> Environment<'_> {
         }
     }
 
-    pub fn interrupt(&mut self, number: u32) -> () {
+    pub fn interrupt(&mut self, number: u32) {
         if self.state.reg.get_iff1() {
             let vector_address = ((self.state.reg.get8(Reg8::I) as u32) << 8) + number;
             let vector = self.peek16(vector_address) as u32;
@@ -43,7 +120,7 @@ impl <'a> Environment<'_> {
             // 2 for interrupt vector
             // = 6
             // Measured interrupt entry cost on EZ80F92 is 11 cycles, so...
-            self.sys.use_cycles(5);
+            self.use_cycles(5);
 
             self.state.reg.set_interrupts(false);
             if self.state.reg.madl {
@@ -65,47 +142,62 @@ impl <'a> Environment<'_> {
         }
     }
 
+    #[inline]
     pub fn peek(&self, address: u32) -> u8 {
-        self.sys.peek(address)
+        self.sys.peek(self.bus_address(address))
     }
 
     /// Sets the memory content to [value] in [address]
+    #[inline]
     pub fn poke(&mut self, address: u32, value: u8) {
+        if let Some(cache) = self.decode_cache.as_deref_mut() {
+            cache.written(address);
+        }
+        if let Some(code) = self.code_map.as_deref_mut() {
+            code.written(address);
+        }
+        let address = self.bus_address(address);
         self.sys.poke(address, value);
     }
 
     /// Returns the memory contents in [address] as word
     pub fn peek16(&self, address: u32) -> u16 {
-        self.sys.peek(address) as u16
-        + ((self.sys.peek(self.wrap_address(address, 1)) as u16) << 8)
+        self.peek(address) as u16
+        + ((self.peek(self.wrap_address(address, 1)) as u16) << 8)
     }
 
     /// Sets the memory content to the word [value] in [address]
     pub fn poke16(&mut self, address: u32, value: u16) {
-        self.sys.poke(address, value as u8 );
-        self.sys.poke(self.wrap_address(address, 1), (value >> 8) as u8);
+        self.poke(address, value as u8 );
+        self.poke(self.wrap_address(address, 1), (value >> 8) as u8);
     }
 
     pub fn peek24(&self, address: u32) -> u32 {
-        self.sys.peek(address) as u32
-        + ((self.sys.peek(self.wrap_address(address, 1)) as u32) << 8)
-        + ((self.sys.peek(self.wrap_address(address, 2)) as u32) << 16)
+        self.peek(address) as u32
+        + ((self.peek(self.wrap_address(address, 1)) as u32) << 8)
+        + ((self.peek(self.wrap_address(address, 2)) as u32) << 16)
     }
 
     pub fn poke24(&mut self, address: u32, value: u32) {
-        self.sys.poke(address, value as u8 );
-        self.sys.poke(self.wrap_address(address, 1), (value >> 8) as u8);
-        self.sys.poke(self.wrap_address(address, 2), (value >> 16) as u8);
+        self.poke(address, value as u8 );
+        self.poke(self.wrap_address(address, 1), (value >> 8) as u8);
+        self.poke(self.wrap_address(address, 2), (value >> 16) as u8);
     }
 
     pub fn peek_pc(&self) -> u8 {
         let pc = self.state.pc();
-        self.sys.peek(pc)
+        self.peek(pc)
     }
 
+    #[inline]
     pub fn advance_pc(&mut self) -> u8 {
         let pc = self.state.pc();
-        let value = self.sys.peek(pc);
+        let value = if self.operand_pos < self.operand_len {
+            self.operand_pos += 1;
+            self.operands[self.operand_pos as usize - 1]
+        } else {
+            self.peek(pc)
+        };
         if self.state.reg.adl {
             self.state.set_pc(self.wrap_address24(pc, 1));
         } else {
@@ -161,27 +253,27 @@ impl <'a> Environment<'_> {
 
     pub fn push_byte_sps(&mut self, value: u8) {
         let sps = self.wrap_address16( self.state.reg.get16_mbase(Reg16::SP), -1);
-        self.sys.poke(sps, value);
+        self.poke(sps, value);
         self.state.reg.set16(Reg16::SP, sps as u16);
     }
 
     pub fn pop_byte_sps(&mut self) -> u8 {
         let sps = self.state.reg.get16_mbase(Reg16::SP);
-        let l = self.sys.peek(sps);
+        let l = self.peek(sps);
         self.state.reg.set16(Reg16::SP, self.wrap_address16(sps, 1) as u16);
         l
     }
 
     pub fn push_byte_spl(&mut self, value: u8) {
-        let spl = self.wrap_address24( self.state.reg.get24(Reg16::SP), -1);
-        self.sys.poke(spl, value);
-        self.state.reg.set24(Reg16::SP, spl);
+        let spl = self.wrap_address24( self.state.reg.get24(Reg24::SPL), -1);
+        self.poke(spl, value);
+        self.state.reg.set24(Reg24::SPL, spl);
     }
 
     pub fn pop_byte_spl(&mut self) -> u8 {
-        let spl = self.state.reg.get24(Reg16::SP);
-        let l = self.sys.peek(spl);
-        self.state.reg.set24(Reg16::SP, self.wrap_address24(spl, 1));
+        let spl = self.state.reg.get24(Reg24::SPL);
+        let l = self.peek(spl);
+        self.state.reg.set24(Reg24::SPL, self.wrap_address24(spl, 1));
         l
     }
 
@@ -244,7 +336,7 @@ impl <'a> Environment<'_> {
                     }
                 }
                 prefix => {
-                    eprintln!("invalid size prefix {:?} to RET at PC=${:x}", prefix, self.state.pc());
+                    self.sys.diagnostic(format_args!("invalid size prefix {:?} to RET at PC=${:x}", prefix, self.state.pc()));
                     let pc = self.pop();
                     self.state.set_pc(pc);
                 }
@@ -273,7 +365,7 @@ impl <'a> Environment<'_> {
                     }
                 }
                 prefix => {
-                    eprintln!("invalid size prefix {:?} to RET at PC=${:x}", prefix, self.state.pc());
+                    self.sys.diagnostic(format_args!("invalid size prefix {:?} to RET at PC=${:x}", prefix, self.state.pc()));
                     let pc = self.pop();
                     self.state.set_pc(pc);
                 }
@@ -319,7 +411,7 @@ impl <'a> Environment<'_> {
 
     pub fn index_value(& self) -> u32 {
         if self.state.is_op_long() {
-            self.state.reg.get24(self.state.index)
+            self.state.reg.get24(self.state.index.to_reg24())
         } else {
             self.state.reg.get16_mbase(self.state.index)
         }
@@ -328,7 +420,7 @@ impl <'a> Environment<'_> {
     pub fn index_address(&self) -> u32 {
         // Pseudo register (HL), (IX+d), (IY+d)
         let address = if self.state.is_op_long() {
-            self.state.reg.get24(self.state.index)
+            self.state.reg.get24(self.state.index.to_reg24())
         } else {
             self.state.reg.get16_mbase(self.state.index)
         };
@@ -357,7 +449,7 @@ impl <'a> Environment<'_> {
 
     pub fn reg8_ext(& self, reg: Reg8) -> u8 {
         if reg == Reg8::_HL {
-            self.sys.peek(self.index_address())
+            self.peek(self.index_address())
         } else {
             self.state.reg.get8(self.translate_reg(reg))
         }
@@ -365,7 +457,7 @@ impl <'a> Environment<'_> {
 
     pub fn reg16mbase_or_24(&mut self, rr: Reg16) -> u32 {
         if self.state.is_op_long() {
-            self.state.reg.get24(rr)
+            self.state.reg.get24(rr.to_reg24())
         } else {
             self.state.reg.get16_mbase(rr)
         }
@@ -374,11 +466,11 @@ impl <'a> Environment<'_> {
     pub fn reg16or24_ext(& self, rr: Reg16) -> u32 {
         if self.state.is_op_long() {
             if rr == Reg16::HL {
-                self.state.reg.get24(self.state.index)
+                self.state.reg.get24(self.state.index.to_reg24())
             } else if rr == Reg16::AF {
                 self.state.reg.get16(rr) as u32
             } else {
-                self.state.reg.get24(rr)
+                self.state.reg.get24(rr.to_reg24())
             }
         } else {
             if rr == Reg16::HL {
@@ -391,7 +483,7 @@ impl <'a> Environment<'_> {
 
     pub fn set_reg(&mut self, reg: Reg8, value: u8) {
         if reg == Reg8::_HL {
-            self.sys.poke(self.index_address(), value);
+            self.poke(self.index_address(), value);
         } else {
             self.state.reg.set8(self.translate_reg(reg), value);
         }
@@ -423,17 +515,39 @@ impl <'a> Environment<'_> {
 
     pub fn set_reg24(&mut self, rr: Reg16, value: u32) {
         if rr == Reg16::HL {
-            self.state.reg.set24(self.state.index, value);
+            self.state.reg.set24(self.state.index.to_reg24(), value);
         } else {
-            self.state.reg.set24(rr, value);
+            self.state.reg.set24(rr.to_reg24(), value);
         }
     }
 
     pub fn port_in(&mut self, address: u16) -> u8 {
+        if let Some(z180) = self.state.z180.as_ref() {
+            if let Some(value) = z180.port_in(address) {
+                return value;
+            }
+        }
+        self.bus_accesses.set(self.bus_accesses.get() + 1);
         self.sys.port_in(address)
     }
 
     pub fn port_out(&mut self, address: u16, value: u8) {
+        if let Some(z180) = self.state.z180.as_mut() {
+            if z180.port_out(address, value) {
+                return;
+            }
+        }
+        self.bus_accesses.set(self.bus_accesses.get() + 1);
         self.sys.port_out(address, value);
     }
 }
+
+/// Returns the generation of the memory seen by the CPU, see
+/// `Machine::memory_generation()`. The Z180 adds the changes of the MMU.
+pub(crate) fn memory_generation<M: Machine + ?Sized>(state: &State, sys: &M) -> u64 {
+    let generation = sys.memory_generation();
+    match state.z180.as_ref() {
+        Some(z180) => generation.wrapping_add(z180.generation()),
+        None => generation
+    }
+}
@@ -0,0 +1,179 @@
+//! GPIO ports B, C and D
+//!
+//! Each pin is configured by its bits on the ALT2, ALT1 and DDR registers:
+//!
+//! ```text
+//! ALT2 ALT1 DDR  mode
+//!  0    0    0   1 output, drives DR
+//!  0    0    1   2 input
+//!  0    1    0   3 open drain output, drives low with DR = 0
+//!  0    1    1   4 open source output, drives high with DR = 1
+//!  1    0    0   5 reserved, high impedance
+//!  1    0    1   6 interrupt on both edges, cleared writing 1 on DR
+//!  1    1    0   7 alternate function
+//!  1    1    1   8 interrupt on level, low with DR = 0, high with DR = 1
+//! ```
+//!
+//! The registers of the port B are PB_DR, PB_DDR, PB_ALT1 and PB_ALT2 on the
+//! ports $9A to $9D, the port C follows on $9E and the port D on $A2. The
+//! reads of DR return the levels of the pins.
+//!
+//! The host sets the levels of the pins not driven by the eZ80 with
+//! `set_inputs()` and is told of the changes on the pins driven by the
+//! eZ80 with a callback. The pins on the alternate functions are not
+//! driven by the port.
+
+use alloc::boxed::Box;
+
+use super::{vectors, Peripheral};
+
+const BASE_PORT: u16 = 0x9a;
+
+/// Receives the mask of the pins driven by the eZ80 and their levels
+pub type GpioCallback = Box<dyn FnMut(u8, u8)>;
+
+/// A GPIO port of the eZ80F92
+pub struct GpioPort {
+    /// Px_DR
+    pub dr: u8,
+    /// Px_DDR
+    pub ddr: u8,
+    /// Px_ALT1
+    pub alt1: u8,
+    /// Px_ALT2
+    pub alt2: u8,
+    base: u16,
+    vector: u8,
+    // Levels set by the host on the pins not driven
+    inputs: u8,
+    // Edge interrupts waiting to be cleared
+    latched: u8,
+    callback: Option<GpioCallback>,
+    // Last mask and levels told to the callback
+    driven: (u8, u8),
+}
+
+impl GpioPort {
+    /// Returns the port [index] after a reset, 0 for the port B, 1 for C
+    /// and 2 for D. All the pins are inputs.
+    pub fn new(index: usize) -> GpioPort {
+        GpioPort {
+            dr: 0x00,
+            ddr: 0xff,
+            alt1: 0x00,
+            alt2: 0x00,
+            base: BASE_PORT + 4 * index as u16,
+            vector: vectors::PB0 + 0x10 * index as u8,
+            inputs: 0xff,
+            latched: 0,
+            callback: None,
+            driven: (0, 0),
+        }
+    }
+
+    /// Sets the function called when the pins driven by the eZ80 change.
+    /// It receives the mask of the pins driven and their levels.
+    pub fn set_callback(&mut self, callback: Option<GpioCallback>) {
+        self.callback = callback;
+        self.driven = (0, 0);
+        self.notify();
+    }
+
+    /// Returns the mode, 1 to 8, of a pin
+    pub fn mode(&self, pin: u8) -> u8 {
+        let bit = |r: u8| (r >> pin) & 1;
+        1 + (bit(self.alt2) << 2 | bit(self.alt1) << 1 | bit(self.ddr))
+    }
+
+    fn mode_mask(&self, mode: u8) -> u8 {
+        (0..8).filter(|pin| self.mode(*pin) == mode).fold(0, |mask, pin| mask | 1 << pin)
+    }
+
+    /// Returns the mask of the pins driven by the eZ80 and their levels
+    pub fn driven(&self) -> (u8, u8) {
+        let output = self.mode_mask(1);
+        let open_drain = self.mode_mask(3) & !self.dr;
+        let open_source = self.mode_mask(4) & self.dr;
+        let mask = output | open_drain | open_source;
+        (mask, self.dr & mask)
+    }
+
+    /// Returns the levels of the pins
+    pub fn pins(&self) -> u8 {
+        let (mask, levels) = self.driven();
+        levels | (self.inputs & !mask)
+    }
+
+    /// Sets the levels of the pins not driven by the eZ80
+    pub fn set_inputs(&mut self, levels: u8) {
+        let changed = (self.inputs ^ levels) & self.mode_mask(6);
+        self.latched |= changed;
+        self.inputs = levels;
+    }
+
+    /// Sets the level of a pin not driven by the eZ80
+    pub fn set_input(&mut self, pin: u8, level: bool) {
+        let levels = if level {
+            self.inputs | 1 << pin
+        } else {
+            self.inputs & !(1 << pin)
+        };
+        self.set_inputs(levels);
+    }
+
+    /// Returns the mask of the pins requesting an interrupt
+    pub fn pending(&self) -> u8 {
+        let edge = self.latched & self.mode_mask(6);
+        let level = !(self.pins() ^ self.dr) & self.mode_mask(8);
+        edge | level
+    }
+
+    fn notify(&mut self) {
+        let driven = self.driven();
+        if driven != self.driven {
+            self.driven = driven;
+            if let Some(callback) = self.callback.as_mut() {
+                callback(driven.0, driven.1);
+            }
+        }
+    }
+}
+
+impl Peripheral for GpioPort {
+    fn port_in(&mut self, port: u16) -> Option<u8> {
+        match port.checked_sub(self.base)? {
+            0 => Some(self.pins()),
+            1 => Some(self.ddr),
+            2 => Some(self.alt1),
+            3 => Some(self.alt2),
+            _ => None
+        }
+    }
+
+    fn port_out(&mut self, port: u16, value: u8) -> bool {
+        match port.checked_sub(self.base) {
+            Some(0) => {
+                self.latched &= !(value & self.mode_mask(6));
+                self.dr = value;
+            }
+            Some(1) => self.ddr = value,
+            Some(2) => self.alt1 = value,
+            Some(3) => self.alt2 = value,
+            _ => return false
+        }
+        self.latched &= self.mode_mask(6);
+        self.notify();
+        true
+    }
+
+    fn tick(&mut self, _cycles: u32) {}
+
+    fn interrupt(&self) -> Option<u8> {
+        let pending = self.pending();
+        if pending != 0 {
+            Some(self.vector + 2 * pending.trailing_zeros() as u8)
+        } else {
+            None
+        }
+    }
+}
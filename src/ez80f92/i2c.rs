@@ -0,0 +1,271 @@
+//! I2C controller
+//!
+//! The controller is driven by I2C_CTL. Setting STA sends a START, then
+//! each time IFLG is cleared the controller does the next step of the
+//! transfer: sends the address on I2C_DR, sends a byte from I2C_DR or
+//! receives a byte on I2C_DR, acknowledged if AAK is set. Setting STP
+//! sends a STOP. At the end of each step IFLG is set and I2C_SR has the
+//! status code.
+//!
+//! ```text
+//! I2C_SAR   $C8  slave address
+//! I2C_XSAR  $C9  extended slave address
+//! I2C_DR    $CA  data
+//! I2C_CTL   $CB  bit 7 IEN, bit 6 ENAB, bit 5 STA, bit 4 STP,
+//!                bit 3 IFLG, cleared writing 0, bit 2 AAK
+//! I2C_SR    $CC  read: status code
+//! I2C_CCR   $CC  write: clock control, bits 6-3 M, bits 2-0 N
+//! I2C_SRR   $CD  write: software reset
+//! ```
+//!
+//! Each step takes the time of its bits on the bus, the clock is the
+//! system clock divided by 10 * (M + 1) * 2^N. The devices on the bus are
+//! `I2cDevice`. Only the master mode with 7 bit addresses is emulated.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use super::{vectors, Peripheral};
+
+const BASE_PORT: u16 = 0xc8;
+
+const CTL_IEN: u8 = 0x80;
+const CTL_ENAB: u8 = 0x40;
+const CTL_STA: u8 = 0x20;
+const CTL_STP: u8 = 0x10;
+const CTL_IFLG: u8 = 0x08;
+const CTL_AAK: u8 = 0x04;
+
+const SR_START: u8 = 0x08;
+const SR_REPEATED_START: u8 = 0x10;
+const SR_ADDRESS_W_ACK: u8 = 0x18;
+const SR_ADDRESS_W_NACK: u8 = 0x20;
+const SR_DATA_W_ACK: u8 = 0x28;
+const SR_DATA_W_NACK: u8 = 0x30;
+const SR_ADDRESS_R_ACK: u8 = 0x40;
+const SR_ADDRESS_R_NACK: u8 = 0x48;
+const SR_DATA_R_ACK: u8 = 0x50;
+const SR_DATA_R_NACK: u8 = 0x58;
+const SR_IDLE: u8 = 0xf8;
+
+/// A slave device on the I2C bus
+pub trait I2cDevice {
+    /// Called after a START with the 7 bit address and the direction.
+    /// Returns true to acknowledge, the device is then addressed until the
+    /// next START or STOP.
+    fn start(&mut self, address: u8, read: bool) -> bool;
+    /// Receives a byte written by the master. Returns true to acknowledge.
+    fn write(&mut self, value: u8) -> bool;
+    /// Returns a byte read by the master. [ack] is false when the master
+    /// does not acknowledge it, on the last byte.
+    fn read(&mut self, ack: bool) -> u8;
+    /// Called on a STOP
+    fn stop(&mut self);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Step {
+    Start,
+    Address,
+    Write,
+    Read,
+}
+
+/// The I2C controller of the eZ80F92
+pub struct I2c {
+    /// I2C_SAR
+    pub sar: u8,
+    /// I2C_XSAR
+    pub xsar: u8,
+    /// I2C_DR
+    pub dr: u8,
+    /// I2C_CTL
+    pub ctl: u8,
+    /// I2C_SR
+    pub sr: u8,
+    /// I2C_CCR
+    pub ccr: u8,
+    devices: Vec<Box<dyn I2cDevice>>,
+    // Device that acknowledged the address
+    addressed: Option<usize>,
+    // Master of the bus, between the START and the STOP
+    busy: bool,
+    step: Option<Step>,
+    cycles: u32,
+}
+
+impl I2c {
+    /// Returns the I2C controller after a reset, with no devices
+    pub fn new() -> I2c {
+        I2c {
+            sar: 0,
+            xsar: 0,
+            dr: 0,
+            ctl: 0,
+            sr: SR_IDLE,
+            ccr: 0,
+            devices: Vec::new(),
+            addressed: None,
+            busy: false,
+            step: None,
+            cycles: 0,
+        }
+    }
+
+    /// Adds a device to the bus
+    pub fn attach(&mut self, device: Box<dyn I2cDevice>) {
+        self.devices.push(device);
+    }
+
+    /// Returns the cycles of the system clock of a bit on the bus
+    pub fn bit_cycles(&self) -> u32 {
+        let m = ((self.ccr >> 3) & 0x0f) as u32;
+        let n = (self.ccr & 0x07) as u32;
+        (10 * (m + 1)) << n
+    }
+
+    fn reset(&mut self) {
+        self.stop();
+        self.ctl = 0;
+        self.sr = SR_IDLE;
+    }
+
+    fn stop(&mut self) {
+        if self.busy {
+            for device in self.devices.iter_mut() {
+                device.stop();
+            }
+        }
+        self.addressed = None;
+        self.busy = false;
+        self.step = None;
+    }
+
+    /// Starts the next step of the transfer, when IFLG is cleared
+    fn next_step(&mut self) {
+        self.step = if self.ctl & CTL_STA != 0 {
+            Some(Step::Start)
+        } else {
+            match self.sr {
+                SR_START | SR_REPEATED_START => Some(Step::Address),
+                SR_ADDRESS_W_ACK | SR_DATA_W_ACK => Some(Step::Write),
+                SR_ADDRESS_R_ACK | SR_DATA_R_ACK => Some(Step::Read),
+                _ => None,
+            }
+        };
+        self.cycles = 0;
+    }
+
+    fn step_cycles(&self, step: Step) -> u32 {
+        match step {
+            Step::Start => self.bit_cycles(),
+            _ => 9 * self.bit_cycles(),
+        }
+    }
+
+    fn complete(&mut self, step: Step) {
+        self.sr = match step {
+            Step::Start => {
+                self.ctl &= !CTL_STA;
+                self.addressed = None;
+                if self.busy { SR_REPEATED_START } else { SR_START }
+            }
+            Step::Address => {
+                let address = self.dr >> 1;
+                let reading = self.dr & 1 != 0;
+                self.addressed = self.devices.iter_mut()
+                    .position(|device| device.start(address, reading));
+                match (reading, self.addressed.is_some()) {
+                    (false, true) => SR_ADDRESS_W_ACK,
+                    (false, false) => SR_ADDRESS_W_NACK,
+                    (true, true) => SR_ADDRESS_R_ACK,
+                    (true, false) => SR_ADDRESS_R_NACK,
+                }
+            }
+            Step::Write => {
+                let value = self.dr;
+                let ack = match self.addressed {
+                    Some(index) => self.devices[index].write(value),
+                    None => false,
+                };
+                if ack { SR_DATA_W_ACK } else { SR_DATA_W_NACK }
+            }
+            Step::Read => {
+                let ack = self.ctl & CTL_AAK != 0;
+                self.dr = match self.addressed {
+                    Some(index) => self.devices[index].read(ack),
+                    None => 0xff,
+                };
+                if ack { SR_DATA_R_ACK } else { SR_DATA_R_NACK }
+            }
+        };
+        self.busy = true;
+        self.ctl |= CTL_IFLG;
+    }
+}
+
+impl Default for I2c {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Peripheral for I2c {
+    fn port_in(&mut self, port: u16) -> Option<u8> {
+        match port.checked_sub(BASE_PORT)? {
+            0 => Some(self.sar),
+            1 => Some(self.xsar),
+            2 => Some(self.dr),
+            3 => Some(self.ctl),
+            4 => Some(self.sr),
+            5 => Some(0xff),
+            _ => None
+        }
+    }
+
+    fn port_out(&mut self, port: u16, value: u8) -> bool {
+        match port.checked_sub(BASE_PORT) {
+            Some(0) => self.sar = value,
+            Some(1) => self.xsar = value,
+            Some(2) => self.dr = value,
+            Some(3) => {
+                // IFLG is cleared writing 0, writing 1 does not set it
+                let flag = self.ctl & CTL_IFLG;
+                let cleared = flag != 0 && value & CTL_IFLG == 0;
+                self.ctl = (value & !CTL_IFLG) | (flag & value);
+                if self.ctl & CTL_ENAB == 0 {
+                    return true;
+                }
+                if self.ctl & CTL_STP != 0 && (cleared || flag == 0) {
+                    self.stop();
+                    self.ctl &= !(CTL_STP | CTL_IFLG);
+                    self.sr = SR_IDLE;
+                } else if cleared || (!self.busy && self.ctl & CTL_STA != 0 && self.step.is_none()) {
+                    self.next_step();
+                }
+            }
+            Some(4) => self.ccr = value & 0x7f,
+            Some(5) => self.reset(),
+            _ => return false
+        }
+        true
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if let Some(step) = self.step {
+            self.cycles += cycles;
+            if self.cycles >= self.step_cycles(step) {
+                self.step = None;
+                self.complete(step);
+            }
+        }
+    }
+
+    fn interrupt(&self) -> Option<u8> {
+        if self.ctl & (CTL_IEN | CTL_IFLG) == CTL_IEN | CTL_IFLG {
+            Some(vectors::I2C)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! Vectored interrupt controller
+//!
+//! Each interrupt source has a line and a vector, the offset on the vector
+//! table of the source. The source with the vector v has the bit v / 2 % 8
+//! of INT_P(v / 16) on the priority registers:
+//!
+//! ```text
+//! INT_P0  $0A  vectors $00 to $0E, PRT0 to PRT2
+//! INT_P1  $0B  vectors $10 to $1E, PRT3 to SPI
+//! INT_P2  $0C  vectors $20 to $2E
+//! INT_P3  $0D  vectors $30 to $3E, port B
+//! INT_P4  $0E  vectors $40 to $4E, port C
+//! INT_P5  $0F  vectors $50 to $5E, port D
+//! ```
+//!
+//! The sources with the priority bit set are served before the others,
+//! and on the same priority the lower vectors first. The controller keeps
+//! the interrupts accepted by the CPU as active until their RETI, for the
+//! debugging tools.
+
+use alloc::vec::Vec;
+
+use super::Peripheral;
+use crate::{Cpu, Machine};
+
+const BASE_PORT: u16 = 0x0a;
+const PRIORITY_REGISTERS: usize = 6;
+const SOURCES: u8 = 8 * PRIORITY_REGISTERS as u8;
+
+/// The interrupt controller of the eZ80F92
+#[derive(Clone, Debug, Default)]
+pub struct InterruptController {
+    /// INT_P0 to INT_P5
+    pub priority: [u8; PRIORITY_REGISTERS],
+    // Lines raised, the bit v / 2 for the vector v
+    lines: u64,
+    // Vectors accepted, the last one is being served
+    active: Vec<u8>,
+    // Value of the RETI count of the CPU on the last delivery
+    reti_executed: u64,
+}
+
+impl InterruptController {
+    /// Returns the controller after a reset, with all the lines cleared
+    pub fn new() -> InterruptController {
+        InterruptController::default()
+    }
+
+    fn source(vector: u8) -> u64 {
+        debug_assert!(vector / 2 < SOURCES, "Invalid interrupt vector");
+        1 << (vector / 2)
+    }
+
+    /// Raises the line of the source with [vector]
+    pub fn raise(&mut self, vector: u8) {
+        self.lines |= Self::source(vector);
+    }
+
+    /// Clears the line of the source with [vector]
+    pub fn clear(&mut self, vector: u8) {
+        self.lines &= !Self::source(vector);
+    }
+
+    /// Raises or clears the line of the source with [vector]
+    pub fn set_line(&mut self, vector: u8, raised: bool) {
+        if raised {
+            self.raise(vector);
+        } else {
+            self.clear(vector);
+        }
+    }
+
+    /// Clears all the lines
+    pub fn clear_all(&mut self) {
+        self.lines = 0;
+    }
+
+    /// Raises the lines of the interrupts requested by the peripherals and
+    /// clears the rest, for the peripherals that report their interrupts
+    /// with `Peripheral::interrupt()`
+    pub fn sample(&mut self, peripherals: &[&dyn Peripheral]) {
+        self.lines = 0;
+        for vector in peripherals.iter().filter_map(|peripheral| peripheral.interrupt()) {
+            self.raise(vector);
+        }
+    }
+
+    /// Returns true if the source with [vector] has high priority
+    pub fn is_high_priority(&self, vector: u8) -> bool {
+        let source = (vector / 2) as usize;
+        self.priority[source / 8] & (1 << (source % 8)) != 0
+    }
+
+    /// Returns the vectors of the lines raised, in the order they will be
+    /// served
+    pub fn pending(&self) -> Vec<u8> {
+        let mut vectors: Vec<u8> = (0..SOURCES)
+            .filter(|source| self.lines & (1 << source) != 0)
+            .map(|source| 2 * source)
+            .collect();
+        vectors.sort_by_key(|vector| (!self.is_high_priority(*vector), *vector));
+        vectors
+    }
+
+    /// Returns the vectors of the interrupts accepted and not returned
+    /// from, the last one is being served
+    pub fn active(&self) -> &[u8] {
+        &self.active
+    }
+
+    /// Records the interrupt [vector] as accepted by the CPU
+    pub fn acknowledge(&mut self, vector: u8) {
+        self.active.push(vector);
+    }
+
+    /// Records the RETI of the interrupt being served
+    pub fn end_of_interrupt(&mut self) {
+        self.active.pop();
+    }
+
+    /// Records the RETIs executed by the CPU since the previous call and
+    /// passes the interrupt with the higher priority to the CPU. It is
+    /// accepted with IFF1 set, with the entry of the ADL and MADL modes of
+    /// `Cpu::interrupt()`. Returns the vector of the interrupt accepted.
+    pub fn deliver(&mut self, cpu: &mut Cpu, sys: &mut dyn Machine) -> Option<u8> {
+        let returns = cpu.state.reti_executed.wrapping_sub(self.reti_executed);
+        self.reti_executed = cpu.state.reti_executed;
+        for _ in 0..returns {
+            self.end_of_interrupt();
+        }
+
+        let vector = self.interrupt()?;
+        if cpu.interrupt(sys, vector) {
+            self.acknowledge(vector);
+            Some(vector)
+        } else {
+            None
+        }
+    }
+}
+
+impl Peripheral for InterruptController {
+    fn port_in(&mut self, port: u16) -> Option<u8> {
+        let register = port.checked_sub(BASE_PORT).filter(|r| (*r as usize) < PRIORITY_REGISTERS)?;
+        Some(self.priority[register as usize])
+    }
+
+    fn port_out(&mut self, port: u16, value: u8) -> bool {
+        match port.checked_sub(BASE_PORT).filter(|r| (*r as usize) < PRIORITY_REGISTERS) {
+            Some(register) => {
+                self.priority[register as usize] = value;
+                true
+            }
+            None => false
+        }
+    }
+
+    fn tick(&mut self, _cycles: u32) {}
+
+    fn interrupt(&self) -> Option<u8> {
+        (0..SOURCES)
+            .filter(|source| self.lines & (1 << source) != 0)
+            .map(|source| 2 * source)
+            .min_by_key(|vector| (!self.is_high_priority(*vector), *vector))
+    }
+}
@@ -0,0 +1,297 @@
+//! Memory controller
+//!
+//! The 128 KiB of on-chip flash, the 8 KiB of on-chip RAM and the four chip
+//! selects of the external bus are located on the 16 MiB address space by
+//! registers. The on-chip RAM has priority, then the flash, then the chip
+//! selects from CS0 to CS3. The accesses not decoded read $FF.
+//!
+//! ```text
+//! CSx_LBR       $A8 + 3x  lower bound, address bits 23-16, or bits 15-8
+//!                         of the I/O address
+//! CSx_UBR       $A9 + 3x  upper bound, address bits 23-16
+//! CSx_CTL       $AA + 3x  bits 7-5 CS_WAIT, bit 4 CS_IO, bit 3 CS_EN
+//! RAM_CTL       $B4       bit 7 RAM_EN
+//! RAM_ADDR_U    $B5       the RAM is at {RAM_ADDR_U, $E000} to $FFFF
+//! CSx_BMC       $F0 + x   bus mode control, stored only
+//! FLASH_ADDR_U  $F7       the flash is at {FLASH_ADDR_U, $0000}
+//! FLASH_CTRL    $F8       bits 7-5 FLASH_WAIT, bit 3 FLASH_EN
+//! ```
+//!
+//! The wait states of the flash and of the chip selects are added to the
+//! cycles of each access. The flash is written by the host with
+//! `load_flash()`, the flash controller that programs it is not emulated.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::Machine;
+
+/// Size of the on-chip flash
+pub const FLASH_SIZE: usize = 0x20000;
+/// Size of the on-chip RAM
+pub const RAM_SIZE: usize = 0x2000;
+
+const CS_PORT: u16 = 0xa8;
+const RAM_CTL_PORT: u16 = 0xb4;
+const RAM_ADDR_U_PORT: u16 = 0xb5;
+const BMC_PORT: u16 = 0xf0;
+const FLASH_ADDR_U_PORT: u16 = 0xf7;
+const FLASH_CTRL_PORT: u16 = 0xf8;
+
+const CS_IO: u8 = 0x10;
+const CS_EN: u8 = 0x08;
+const RAM_EN: u8 = 0x80;
+const FLASH_EN: u8 = 0x08;
+
+/// A chip select of the external bus
+#[derive(Clone, Copy, Debug)]
+pub struct ChipSelect {
+    /// CSx_LBR
+    pub lbr: u8,
+    /// CSx_UBR
+    pub ubr: u8,
+    /// CSx_CTL
+    pub ctl: u8,
+    /// CSx_BMC
+    pub bmc: u8,
+}
+
+impl ChipSelect {
+    /// Returns the chip select [index], 0 to 3, after a reset. CS0 is
+    /// enabled on the whole address space with 7 wait states.
+    pub fn new(index: usize) -> ChipSelect {
+        if index == 0 {
+            ChipSelect { lbr: 0x00, ubr: 0xff, ctl: 0xe8, bmc: 0x02 }
+        } else {
+            ChipSelect { lbr: 0x00, ubr: 0x00, ctl: 0x00, bmc: 0x02 }
+        }
+    }
+
+    /// Returns the wait states added to the accesses
+    pub fn wait_states(&self) -> u8 {
+        self.ctl >> 5
+    }
+
+    /// Returns true if the chip select is asserted on the memory address
+    pub fn selects_memory(&self, address: u32) -> bool {
+        let upper = (address >> 16) as u8;
+        self.ctl & (CS_EN | CS_IO) == CS_EN && self.lbr <= upper && upper <= self.ubr
+    }
+
+    /// Returns true if the chip select is asserted on the I/O address
+    pub fn selects_io(&self, port: u16) -> bool {
+        self.ctl & (CS_EN | CS_IO) == CS_EN | CS_IO && (port >> 8) as u8 == self.lbr
+    }
+}
+
+/// Device of an address
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Target {
+    Ram(usize),
+    Flash(usize),
+    External(u8),
+    None,
+}
+
+/// The memory controller of the eZ80F92, a Machine on top of the Machine
+/// of the external bus
+///
+/// The external bus gets the memory accesses decoded by a chip select and
+/// all the port accesses but the ones of the controller registers.
+pub struct MemoryController<M: Machine> {
+    /// Machine of the external bus
+    pub bus: M,
+    /// Contents of the on-chip flash
+    pub flash: Vec<u8>,
+    /// Contents of the on-chip RAM
+    pub ram: Vec<u8>,
+    /// The chip selects CS0 to CS3
+    pub cs: [ChipSelect; 4],
+    /// RAM_CTL
+    pub ram_ctl: u8,
+    /// RAM_ADDR_U
+    pub ram_addr_u: u8,
+    /// FLASH_ADDR_U
+    pub flash_addr_u: u8,
+    /// FLASH_CTRL
+    pub flash_ctrl: u8,
+    // Incremented when the mapping changes, to clear the decoded code
+    generation: u64,
+}
+
+impl<M: Machine> MemoryController<M> {
+    /// Returns the controller after a reset, with the flash erased
+    pub fn new(bus: M) -> MemoryController<M> {
+        MemoryController {
+            bus,
+            flash: vec![0xff; FLASH_SIZE],
+            ram: vec![0; RAM_SIZE],
+            cs: [ChipSelect::new(0), ChipSelect::new(1), ChipSelect::new(2), ChipSelect::new(3)],
+            ram_ctl: 0x80,
+            ram_addr_u: 0xff,
+            flash_addr_u: 0x00,
+            flash_ctrl: 0x88,
+            generation: 0,
+        }
+    }
+
+    /// Copies [data] to the flash from [offset]
+    pub fn load_flash(&mut self, offset: usize, data: &[u8]) {
+        self.flash[offset..offset + data.len()].copy_from_slice(data);
+        self.generation += 1;
+    }
+
+    /// Returns the wait states of the flash
+    pub fn flash_wait_states(&self) -> u8 {
+        self.flash_ctrl >> 5
+    }
+
+    fn target(&self, address: u32) -> Target {
+        let address = address & 0xff_ffff;
+        if self.ram_ctl & RAM_EN != 0 && (address >> 16) as u8 == self.ram_addr_u
+                && address & 0xffff >= 0x10000 - RAM_SIZE as u32 {
+            return Target::Ram((address & (RAM_SIZE as u32 - 1)) as usize);
+        }
+        let offset = address.wrapping_sub((self.flash_addr_u as u32) << 16);
+        if self.flash_ctrl & FLASH_EN != 0 && offset < FLASH_SIZE as u32 {
+            return Target::Flash(offset as usize);
+        }
+        match self.cs.iter().find(|cs| cs.selects_memory(address)) {
+            Some(cs) => Target::External(cs.wait_states()),
+            None => Target::None,
+        }
+    }
+
+    fn register_in(&self, port: u16) -> Option<u8> {
+        let value = match port {
+            CS_PORT..=0xb3 => {
+                let cs = &self.cs[((port - CS_PORT) / 3) as usize];
+                match (port - CS_PORT) % 3 {
+                    0 => cs.lbr,
+                    1 => cs.ubr,
+                    _ => cs.ctl,
+                }
+            }
+            RAM_CTL_PORT => self.ram_ctl,
+            RAM_ADDR_U_PORT => self.ram_addr_u,
+            BMC_PORT..=0xf3 => self.cs[(port - BMC_PORT) as usize].bmc,
+            FLASH_ADDR_U_PORT => self.flash_addr_u,
+            FLASH_CTRL_PORT => self.flash_ctrl,
+            _ => return None
+        };
+        Some(value)
+    }
+
+    fn register_out(&mut self, port: u16, value: u8) -> bool {
+        let register = match port {
+            CS_PORT..=0xb3 => {
+                let cs = &mut self.cs[((port - CS_PORT) / 3) as usize];
+                match (port - CS_PORT) % 3 {
+                    0 => &mut cs.lbr,
+                    1 => &mut cs.ubr,
+                    _ => &mut cs.ctl,
+                }
+            }
+            RAM_CTL_PORT => &mut self.ram_ctl,
+            RAM_ADDR_U_PORT => &mut self.ram_addr_u,
+            BMC_PORT..=0xf3 => &mut self.cs[(port - BMC_PORT) as usize].bmc,
+            FLASH_ADDR_U_PORT => &mut self.flash_addr_u,
+            FLASH_CTRL_PORT => &mut self.flash_ctrl,
+            _ => return false
+        };
+        if *register != value {
+            *register = value;
+            self.generation += 1;
+        }
+        true
+    }
+
+    /// Returns the wait states of an access to an external I/O port
+    fn io_wait_states(&self, port: u16) -> u8 {
+        if (0x80..=0xff).contains(&port) {
+            // The on-chip peripherals
+            return 0;
+        }
+        self.cs.iter().find(|cs| cs.selects_io(port)).map_or(0, |cs| cs.wait_states())
+    }
+}
+
+impl<M: Machine> Machine for MemoryController<M> {
+    fn peek(&self, address: u32) -> u8 {
+        match self.target(address) {
+            Target::Ram(offset) => {
+                self.bus.use_cycles(1);
+                self.ram[offset]
+            }
+            Target::Flash(offset) => {
+                self.bus.use_cycles(1 + self.flash_wait_states() as i32);
+                self.flash[offset]
+            }
+            Target::External(wait_states) => {
+                self.bus.use_cycles(wait_states as i32);
+                self.bus.peek(address)
+            }
+            Target::None => {
+                self.bus.use_cycles(1);
+                0xff
+            }
+        }
+    }
+
+    fn poke(&mut self, address: u32, value: u8) {
+        match self.target(address) {
+            Target::Ram(offset) => {
+                self.bus.use_cycles(1);
+                self.ram[offset] = value;
+            }
+            Target::Flash(_) => {
+                self.bus.use_cycles(1 + self.flash_wait_states() as i32);
+            }
+            Target::External(wait_states) => {
+                self.bus.use_cycles(wait_states as i32);
+                self.bus.poke(address, value);
+            }
+            Target::None => self.bus.use_cycles(1),
+        }
+    }
+
+    fn peek_debug(&self, address: u32) -> u8 {
+        match self.target(address) {
+            Target::Ram(offset) => self.ram[offset],
+            Target::Flash(offset) => self.flash[offset],
+            Target::External(_) => self.bus.peek_debug(address),
+            Target::None => 0xff,
+        }
+    }
+
+    fn use_cycles(&self, cycles: i32) {
+        self.bus.use_cycles(cycles);
+    }
+
+    fn memory_generation(&self) -> u64 {
+        self.bus.memory_generation().wrapping_add(self.generation)
+    }
+
+    fn diagnostic(&self, message: fmt::Arguments) {
+        self.bus.diagnostic(message);
+    }
+
+    fn port_in(&mut self, address: u16) -> u8 {
+        if let Some(value) = self.register_in(address) {
+            self.bus.use_cycles(1);
+            return value;
+        }
+        self.bus.use_cycles(self.io_wait_states(address) as i32);
+        self.bus.port_in(address)
+    }
+
+    fn port_out(&mut self, address: u16, value: u8) {
+        if self.register_out(address, value) {
+            self.bus.use_cycles(1);
+            return;
+        }
+        self.bus.use_cycles(self.io_wait_states(address) as i32);
+        self.bus.port_out(address, value);
+    }
+}
@@ -0,0 +1,90 @@
+//! On-chip peripherals of the eZ80F92
+//!
+//! The peripherals are mapped on the internal I/O ports of the eZ80F92, the
+//! ones with the high byte 0. A Machine passes the accesses to these ports
+//! to the peripherals and tells them the cycles used by the CPU:
+//!
+//! ```
+//! use ez80::*;
+//! use ez80::ez80f92::*;
+//!
+//! let mut sys = PlainMachine::new();
+//! let mut cpu = Cpu::new_ez80();
+//! let mut timers = Timers::new();
+//!
+//! let cycles = sys.get_elapsed_cycles();
+//! cpu.execute_instruction(&mut sys);
+//! timers.tick((sys.get_elapsed_cycles() - cycles) as u32);
+//! if let Some(vector) = timers.interrupt() {
+//!     cpu.interrupt(&mut sys, vector);
+//! }
+//! ```
+//!
+//! The interrupts are requested with the offset of the source on the
+//! vector table of the eZ80F92, see the `vectors` module.
+
+mod gpio;
+mod i2c;
+mod intc;
+mod memory;
+mod prt;
+mod rtc;
+#[cfg(feature = "std")]
+mod sdcard;
+mod spi;
+mod uart;
+mod wdt;
+
+pub use gpio::{GpioCallback, GpioPort};
+pub use i2c::{I2c, I2cDevice};
+pub use intc::InterruptController;
+pub use memory::{ChipSelect, MemoryController, FLASH_SIZE, RAM_SIZE};
+pub use prt::{Prt, Timers};
+pub use rtc::{Rtc, RtcTime};
+#[cfg(feature = "std")]
+pub use sdcard::SdCard;
+pub use spi::{Spi, SpiDevice};
+pub use uart::{BufferBackend, SerialBackend, Uart};
+#[cfg(feature = "std")]
+pub use uart::StreamBackend;
+pub use wdt::{Watchdog, WatchdogTimeout};
+
+/// A peripheral on the internal I/O ports
+pub trait Peripheral {
+    /// Returns the value of a register of the peripheral. None if the port
+    /// is not one of the peripheral.
+    fn port_in(&mut self, port: u16) -> Option<u8>;
+
+    /// Writes a register of the peripheral. Returns false if the port is
+    /// not one of the peripheral.
+    fn port_out(&mut self, port: u16, value: u8) -> bool;
+
+    /// Advances the peripheral the cycles of the system clock used by the
+    /// CPU since the previous call
+    fn tick(&mut self, cycles: u32);
+
+    /// Returns the vector of the interrupt requested by the peripheral, the
+    /// one with the higher priority if there are several
+    fn interrupt(&self) -> Option<u8>;
+}
+
+/// Offsets on the vector table of the interrupt sources of the eZ80F92
+pub mod vectors {
+    pub const PRT0: u8 = 0x0a;
+    pub const PRT1: u8 = 0x0c;
+    pub const PRT2: u8 = 0x0e;
+    pub const PRT3: u8 = 0x10;
+    pub const PRT4: u8 = 0x12;
+    pub const PRT5: u8 = 0x14;
+    pub const RTC: u8 = 0x16;
+    pub const UART0: u8 = 0x18;
+    pub const UART1: u8 = 0x1a;
+    pub const I2C: u8 = 0x1c;
+    pub const SPI: u8 = 0x1e;
+    /// Port B pin 0, the pins 1 to 7 follow each 2 bytes
+    pub const PB0: u8 = 0x30;
+    /// Port C pin 0, the pins 1 to 7 follow each 2 bytes
+    pub const PC0: u8 = 0x40;
+    /// Port D pin 0, the pins 1 to 7 follow each 2 bytes
+    pub const PD0: u8 = 0x50;
+}
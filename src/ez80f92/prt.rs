@@ -0,0 +1,196 @@
+//! Programmable reload timers
+//!
+//! The six 16 bit timers count down the system clock divided by 4, 16, 64
+//! or 256. At the end of the count they set PRT_IRQ, request an interrupt
+//! if enabled and, in continuous mode, restart from the reload value. In
+//! single pass mode they stop.
+//!
+//! ```text
+//! TMRx_CTL   $80 + 3x  bit 7 PRT_IRQ, read only, cleared when read
+//!                      bit 6 IRQ_EN, bit 4 PRT_MODE (1 continuous),
+//!                      bits 3-2 CLK_DIV, bit 1 RST_EN, bit 0 PRT_EN
+//! TMRx_DR_L  $81 + 3x  read: counter, latches TMRx_DR_H
+//! TMRx_RR_L            write: reload value
+//! TMRx_DR_H  $82 + 3x  read: counter latched when TMRx_DR_L was read
+//! TMRx_RR_H            write: reload value
+//! ```
+//!
+//! A reload value of 0 counts 65536. Setting PRT_EN or writing RST_EN
+//! loads the counter with the reload value. The event counter inputs
+//! selected with TMR_ISS are not emulated.
+
+use super::{vectors, Peripheral};
+
+const CTL_IRQ: u8 = 0x80;
+const CTL_IRQ_EN: u8 = 0x40;
+const CTL_MODE: u8 = 0x10;
+const CTL_RST_EN: u8 = 0x02;
+const CTL_EN: u8 = 0x01;
+
+const BASE_PORT: u16 = 0x80;
+const TIMER_COUNT: usize = 6;
+
+/// A programmable reload timer
+#[derive(Clone, Copy, Debug)]
+pub struct Prt {
+    /// TMRx_CTL
+    pub ctl: u8,
+    /// Value of TMRx_RR_H and TMRx_RR_L
+    pub reload: u16,
+    /// Value of the down counter
+    pub counter: u16,
+    // Clock cycles not used yet by the divider
+    prescaler: u32,
+    // TMRx_DR_H latched when reading TMRx_DR_L
+    latched_high: u8,
+    vector: u8,
+}
+
+impl Prt {
+    /// Returns the timer [index], 0 to 5, after a reset
+    pub fn new(index: usize) -> Prt {
+        Prt {
+            ctl: 0,
+            reload: 0,
+            counter: 0,
+            prescaler: 0,
+            latched_high: 0,
+            vector: vectors::PRT0 + 2 * index as u8,
+        }
+    }
+
+    /// Returns the divider of the system clock
+    pub fn divider(&self) -> u32 {
+        4 << (2 * ((self.ctl >> 2) & 0x03))
+    }
+
+    /// Returns true if the timer is counting
+    pub fn is_enabled(&self) -> bool {
+        self.ctl & CTL_EN != 0
+    }
+
+    /// Returns true if the timer requests an interrupt
+    pub fn irq(&self) -> bool {
+        self.ctl & (CTL_IRQ | CTL_IRQ_EN) == CTL_IRQ | CTL_IRQ_EN
+    }
+
+    fn restart(&mut self) {
+        self.counter = self.reload;
+        self.prescaler = 0;
+    }
+
+    fn read(&mut self, register: u16) -> u8 {
+        match register {
+            0 => {
+                let value = self.ctl;
+                self.ctl &= !CTL_IRQ;
+                value
+            }
+            1 => {
+                self.latched_high = (self.counter >> 8) as u8;
+                self.counter as u8
+            }
+            _ => self.latched_high,
+        }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                let starting = value & CTL_EN != 0 && !self.is_enabled();
+                self.ctl = (self.ctl & CTL_IRQ) | (value & !CTL_IRQ);
+                if starting || value & CTL_RST_EN != 0 {
+                    self.restart();
+                }
+            }
+            1 => self.reload = (self.reload & 0xff00) | value as u16,
+            _ => self.reload = (self.reload & 0x00ff) | ((value as u16) << 8),
+        }
+    }
+
+    /// Advances the timer [cycles] of the system clock
+    pub fn tick(&mut self, cycles: u32) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.prescaler += cycles;
+        let divider = self.divider();
+        let ticks = self.prescaler / divider;
+        self.prescaler %= divider;
+
+        // A counter of 0 counts 65536
+        let count = if self.counter == 0 { 0x10000 } else { self.counter as u32 };
+        if ticks < count {
+            self.counter = (count - ticks) as u16;
+            return;
+        }
+        self.ctl |= CTL_IRQ;
+        if self.ctl & CTL_MODE != 0 {
+            let period = if self.reload == 0 { 0x10000 } else { self.reload as u32 };
+            self.counter = (period - (ticks - count) % period) as u16;
+        } else {
+            self.counter = 0;
+            self.ctl &= !CTL_EN;
+        }
+    }
+}
+
+/// The six programmable reload timers of the eZ80F92
+#[derive(Clone, Debug)]
+pub struct Timers {
+    pub prt: [Prt; TIMER_COUNT],
+}
+
+impl Timers {
+    /// Returns the timers after a reset
+    pub fn new() -> Timers {
+        Timers {
+            prt: [Prt::new(0), Prt::new(1), Prt::new(2), Prt::new(3), Prt::new(4), Prt::new(5)],
+        }
+    }
+
+    /// Returns the timer and its register for a port
+    fn register(port: u16) -> Option<(usize, u16)> {
+        let offset = port.checked_sub(BASE_PORT)?;
+        let index = (offset / 3) as usize;
+        if index < TIMER_COUNT {
+            Some((index, offset % 3))
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for Timers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Peripheral for Timers {
+    fn port_in(&mut self, port: u16) -> Option<u8> {
+        let (index, register) = Timers::register(port)?;
+        Some(self.prt[index].read(register))
+    }
+
+    fn port_out(&mut self, port: u16, value: u8) -> bool {
+        match Timers::register(port) {
+            Some((index, register)) => {
+                self.prt[index].write(register, value);
+                true
+            }
+            None => false
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        for prt in self.prt.iter_mut() {
+            prt.tick(cycles);
+        }
+    }
+
+    fn interrupt(&self) -> Option<u8> {
+        // The lower vectors have priority
+        self.prt.iter().find(|prt| prt.irq()).map(|prt| prt.vector)
+    }
+}
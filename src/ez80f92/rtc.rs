@@ -0,0 +1,277 @@
+//! Real time clock
+//!
+//! The clock counts the seconds on the cycles of the system clock. The
+//! counters are on binary or, with BCD_EN, on BCD.
+//!
+//! ```text
+//! RTC_SEC   $E0  seconds, 0 to 59      RTC_ASEC   $E8  alarm seconds
+//! RTC_MIN   $E1  minutes, 0 to 59      RTC_AMIN   $E9  alarm minutes
+//! RTC_HRS   $E2  hours, 0 to 23        RTC_AHRS   $EA  alarm hours
+//! RTC_DOW   $E3  day of week, 1 to 7   RTC_ADOW   $EB  alarm day of week
+//! RTC_DOM   $E4  day, 1 to 31          RTC_ACTRL  $EC  alarm enables, bit 3
+//! RTC_MON   $E5  month, 1 to 12                        ADOW to bit 0 ASEC
+//! RTC_YR    $E6  year, 0 to 99         RTC_CTRL   $ED  see below
+//! RTC_CEN   $E7  century, 0 to 99
+//!
+//! RTC_CTRL  bit 7 ALARM, cleared when read, bit 6 INT_EN, bit 5 BCD_EN,
+//!           bit 0 RTC_UNLOCK, the counters are stopped and writable
+//! ```
+//!
+//! The alarm is set when the enabled alarm registers match the counters.
+//! The clock source, the daylight saving and the sleep wake up bits are
+//! stored but have no effect.
+
+use super::{vectors, Peripheral};
+
+const BASE_PORT: u16 = 0xe0;
+
+const CTRL_ALARM: u8 = 0x80;
+const CTRL_INT_EN: u8 = 0x40;
+const CTRL_BCD_EN: u8 = 0x20;
+const CTRL_UNLOCK: u8 = 0x01;
+
+/// A date and time of the real time clock
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RtcTime {
+    /// Year, with the century
+    pub year: u16,
+    /// Month, 1 to 12
+    pub month: u8,
+    /// Day of the month, 1 to 31
+    pub day: u8,
+    /// Day of the week, 1 to 7, 1 on Sunday
+    pub weekday: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl RtcTime {
+    /// Returns the UTC time of a Unix timestamp
+    pub fn from_unix(timestamp: u64) -> RtcTime {
+        let days = timestamp / 86400;
+        let seconds = timestamp % 86400;
+
+        // Days to civil date, from the algorithms of Howard Hinnant
+        let z = days + 719_468;
+        let era = z / 146_097;
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        RtcTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            // The 1st of January of 1970 was Thursday
+            weekday: ((days + 4) % 7 + 1) as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+
+    fn days_in_month(&self) -> u8 {
+        match self.month {
+            2 => {
+                let leap = self.year % 4 == 0
+                    && (self.year % 100 != 0 || self.year % 400 == 0);
+                if leap { 29 } else { 28 }
+            }
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        }
+    }
+
+    /// Advances a second
+    pub fn advance(&mut self) {
+        // Out of range values, as written by a program, roll over
+        if self.second < 59 {
+            self.second += 1;
+            return;
+        }
+        self.second = 0;
+        if self.minute < 59 {
+            self.minute += 1;
+            return;
+        }
+        self.minute = 0;
+        if self.hour < 23 {
+            self.hour += 1;
+            return;
+        }
+        self.hour = 0;
+        self.weekday = self.weekday % 7 + 1;
+        if self.day < self.days_in_month() {
+            self.day += 1;
+            return;
+        }
+        self.day = 1;
+        if self.month < 12 {
+            self.month += 1;
+            return;
+        }
+        self.month = 1;
+        self.year = (self.year + 1) % 10000;
+    }
+}
+
+impl Default for RtcTime {
+    /// Saturday, 1st of January of 2000
+    fn default() -> Self {
+        RtcTime {
+            year: 2000,
+            month: 1,
+            day: 1,
+            weekday: 7,
+            hour: 0,
+            minute: 0,
+            second: 0,
+        }
+    }
+}
+
+/// The real time clock of the eZ80F92
+#[derive(Clone, Debug)]
+pub struct Rtc {
+    /// Date and time of the counters
+    pub time: RtcTime,
+    /// RTC_CTRL
+    pub ctrl: u8,
+    /// RTC_ACTRL
+    pub actrl: u8,
+    // RTC_ASEC, RTC_AMIN, RTC_AHRS and RTC_ADOW, binary
+    alarm: [u8; 4],
+    // Cycles of the system clock in a second
+    clock: u32,
+    cycles: u32,
+}
+
+impl Rtc {
+    /// Returns the clock after a reset, with the frequency of the system
+    /// clock in Hz. The counters have the default RtcTime.
+    pub fn new(clock: u32) -> Rtc {
+        Rtc {
+            time: RtcTime::default(),
+            ctrl: 0,
+            actrl: 0,
+            alarm: [0; 4],
+            clock,
+            cycles: 0,
+        }
+    }
+
+    /// Sets the counters to the current time of the host, in UTC
+    #[cfg(feature = "std")]
+    pub fn set_host_time(&mut self) {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        self.time = RtcTime::from_unix(now);
+        self.cycles = 0;
+    }
+
+    fn encode(&self, value: u8) -> u8 {
+        if self.ctrl & CTRL_BCD_EN != 0 {
+            ((value / 10) << 4) | (value % 10)
+        } else {
+            value
+        }
+    }
+
+    fn decode(&self, value: u8) -> u8 {
+        if self.ctrl & CTRL_BCD_EN != 0 {
+            (value >> 4) * 10 + (value & 0x0f)
+        } else {
+            value
+        }
+    }
+
+    fn check_alarm(&mut self) {
+        let counters = [self.time.second, self.time.minute, self.time.hour, self.time.weekday];
+        let enabled = self.actrl & 0x0f;
+        let matches = (0..4).all(|i| enabled & (1 << i) == 0 || counters[i] == self.alarm[i]);
+        if enabled != 0 && matches {
+            self.ctrl |= CTRL_ALARM;
+        }
+    }
+}
+
+impl Peripheral for Rtc {
+    fn port_in(&mut self, port: u16) -> Option<u8> {
+        let value = match port.checked_sub(BASE_PORT)? {
+            0 => self.encode(self.time.second),
+            1 => self.encode(self.time.minute),
+            2 => self.encode(self.time.hour),
+            3 => self.encode(self.time.weekday),
+            4 => self.encode(self.time.day),
+            5 => self.encode(self.time.month),
+            6 => self.encode((self.time.year % 100) as u8),
+            7 => self.encode((self.time.year / 100) as u8),
+            register @ 8..=11 => self.encode(self.alarm[register as usize - 8]),
+            12 => self.actrl,
+            13 => {
+                let value = self.ctrl;
+                self.ctrl &= !CTRL_ALARM;
+                value
+            }
+            _ => return None
+        };
+        Some(value)
+    }
+
+    fn port_out(&mut self, port: u16, value: u8) -> bool {
+        let register = match port.checked_sub(BASE_PORT).filter(|r| *r < 14) {
+            Some(register) => register,
+            None => return false
+        };
+        let decoded = self.decode(value);
+        let unlocked = self.ctrl & CTRL_UNLOCK != 0;
+        match register {
+            0..=7 if !unlocked => {}
+            0 => self.time.second = decoded,
+            1 => self.time.minute = decoded,
+            2 => self.time.hour = decoded,
+            3 => self.time.weekday = decoded,
+            4 => self.time.day = decoded,
+            5 => self.time.month = decoded,
+            6 => self.time.year = self.time.year / 100 * 100 + decoded as u16,
+            7 => self.time.year = decoded as u16 * 100 + self.time.year % 100,
+            8..=11 => self.alarm[register as usize - 8] = decoded,
+            12 => self.actrl = value & 0x0f,
+            _ => {
+                if value & CTRL_UNLOCK == 0 && unlocked {
+                    // Counting restarts when locked
+                    self.cycles = 0;
+                }
+                self.ctrl = (self.ctrl & CTRL_ALARM) | (value & !CTRL_ALARM);
+            }
+        }
+        true
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if self.ctrl & CTRL_UNLOCK != 0 || self.clock == 0 {
+            return;
+        }
+        self.cycles += cycles;
+        while self.cycles >= self.clock {
+            self.cycles -= self.clock;
+            self.time.advance();
+            self.check_alarm();
+        }
+    }
+
+    fn interrupt(&self) -> Option<u8> {
+        if self.ctrl & (CTRL_ALARM | CTRL_INT_EN) == CTRL_ALARM | CTRL_INT_EN {
+            Some(vectors::RTC)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,311 @@
+//! SD card in SPI mode
+//!
+//! A high capacity card, addressed by blocks of 512 bytes, on a disk image.
+//! It answers the commands used by the FAT drivers to initialize the card
+//! and to read and write blocks:
+//!
+//! ```text
+//! CMD0   GO_IDLE_STATE         CMD17  READ_SINGLE_BLOCK
+//! CMD8   SEND_IF_COND          CMD18  READ_MULTIPLE_BLOCK
+//! CMD9   SEND_CSD              CMD24  WRITE_BLOCK
+//! CMD10  SEND_CID              CMD25  WRITE_MULTIPLE_BLOCK
+//! CMD12  STOP_TRANSMISSION     CMD55  APP_CMD
+//! CMD13  SEND_STATUS           CMD58  READ_OCR
+//! CMD16  SET_BLOCKLEN          CMD59  CRC_ON_OFF
+//! ACMD41 SD_SEND_OP_COND
+//! ```
+//!
+//! The CRCs are not checked and are sent as $FF.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use super::SpiDevice;
+
+const BLOCK_SIZE: usize = 512;
+
+const R1_READY: u8 = 0x00;
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL: u8 = 0x04;
+const R1_ADDRESS_ERROR: u8 = 0x20;
+
+const TOKEN_BLOCK: u8 = 0xfe;
+const TOKEN_MULTIPLE: u8 = 0xfc;
+const TOKEN_STOP: u8 = 0xfd;
+const TOKEN_ERROR: u8 = 0x01;
+
+const DATA_ACCEPTED: u8 = 0x05;
+const DATA_WRITE_ERROR: u8 = 0x0d;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    // Waiting for a command
+    Command,
+    // Sending the blocks of READ_MULTIPLE_BLOCK from the next one
+    ReadMultiple(u32),
+    // Waiting for the data token of a write to the block
+    WriteToken { block: u32, multiple: bool },
+    // Receiving the data of a write to the block
+    WriteData { block: u32, multiple: bool },
+}
+
+/// An SD card on a disk image, a SPI device
+pub struct SdCard<S: Read + Write + Seek> {
+    storage: S,
+    blocks: u32,
+    selected: bool,
+    idle: bool,
+    app_command: bool,
+    state: State,
+    // Bytes of the command being received
+    command: Vec<u8>,
+    // Data block being written
+    data: Vec<u8>,
+    // Bytes to send
+    output: VecDeque<u8>,
+}
+
+impl SdCard<File> {
+    /// Opens a disk image file, writable
+    pub fn open(path: &str) -> io::Result<SdCard<File>> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        SdCard::new(file)
+    }
+}
+
+impl<S: Read + Write + Seek> SdCard<S> {
+    /// Uses a storage with the contents of the card. The size is rounded
+    /// down to a multiple of the block size.
+    pub fn new(mut storage: S) -> io::Result<SdCard<S>> {
+        let size = storage.seek(SeekFrom::End(0))?;
+        Ok(SdCard {
+            storage,
+            blocks: (size / BLOCK_SIZE as u64) as u32,
+            selected: false,
+            idle: true,
+            app_command: false,
+            state: State::Command,
+            command: Vec::with_capacity(6),
+            data: Vec::with_capacity(BLOCK_SIZE + 2),
+            output: VecDeque::new(),
+        })
+    }
+
+    /// Returns the number of blocks of 512 bytes
+    pub fn blocks(&self) -> u32 {
+        self.blocks
+    }
+
+    /// Returns the storage of the card
+    pub fn into_inner(self) -> S {
+        self.storage
+    }
+
+    fn r1(&self, flags: u8) -> u8 {
+        flags | if self.idle { R1_IDLE } else { R1_READY }
+    }
+
+    fn respond(&mut self, response: &[u8]) {
+        // One byte of delay before the response
+        self.output.push_back(0xff);
+        self.output.extend(response);
+    }
+
+    fn read_block(&mut self, block: u32) -> io::Result<[u8; BLOCK_SIZE]> {
+        let mut data = [0u8; BLOCK_SIZE];
+        self.storage.seek(SeekFrom::Start(block as u64 * BLOCK_SIZE as u64))?;
+        self.storage.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn write_block(&mut self, block: u32) -> io::Result<()> {
+        self.storage.seek(SeekFrom::Start(block as u64 * BLOCK_SIZE as u64))?;
+        self.storage.write_all(&self.data[..BLOCK_SIZE])?;
+        self.storage.flush()
+    }
+
+    /// Queues the data token, the block and the CRC, or the error token.
+    /// Returns false if the block can't be read.
+    fn send_block(&mut self, block: u32) -> bool {
+        let data = if block < self.blocks { self.read_block(block).ok() } else { None };
+        match data {
+            Some(data) => {
+                self.output.push_back(TOKEN_BLOCK);
+                self.output.extend(data.iter());
+                self.output.extend(&[0xff, 0xff]);
+                true
+            }
+            None => {
+                self.output.push_back(TOKEN_ERROR);
+                false
+            }
+        }
+    }
+
+    fn csd(&self) -> [u8; 16] {
+        // CSD version 2, the size is (C_SIZE + 1) * 512 KiB
+        let c_size = (self.blocks / 1024).max(1) - 1;
+        [
+            0x40, 0x0e, 0x00, 0x32, 0x5b, 0x59, 0x00,
+            (c_size >> 16) as u8 & 0x3f, (c_size >> 8) as u8, c_size as u8,
+            0x7f, 0x80, 0x0a, 0x40, 0x00, 0x01,
+        ]
+    }
+
+    fn execute(&mut self) {
+        let index = self.command[0] & 0x3f;
+        let argument = u32::from_be_bytes([self.command[1], self.command[2], self.command[3], self.command[4]]);
+        let app_command = self.app_command;
+        self.app_command = false;
+        self.output.clear();
+        self.state = State::Command;
+
+        match (app_command, index) {
+            (_, 0) => {
+                self.idle = true;
+                self.respond(&[R1_IDLE]);
+            }
+            (_, 8) => {
+                let r1 = self.r1(0);
+                self.respond(&[r1, 0x00, 0x00, (argument >> 8) as u8 & 0x0f, argument as u8]);
+            }
+            (_, 9) | (_, 10) => {
+                let r1 = self.r1(0);
+                let register = if index == 9 {
+                    self.csd()
+                } else {
+                    // Manufacturer, OEM, name, revision, serial, date and CRC
+                    *b"\x00EZEZ80S\x10\x00\x00\x00\x01\x01\x4a\x01"
+                };
+                self.respond(&[r1, TOKEN_BLOCK]);
+                self.output.extend(register.iter());
+                self.output.extend(&[0xff, 0xff]);
+            }
+            (_, 12) => {
+                let r1 = self.r1(0);
+                // The stuff byte, the response and the busy signal
+                self.output.push_back(0xff);
+                self.respond(&[r1, 0x00]);
+            }
+            (_, 13) => {
+                let r1 = self.r1(0);
+                self.respond(&[r1, 0x00]);
+            }
+            (_, 16) => {
+                let flags = if argument as usize == BLOCK_SIZE { 0 } else { R1_ILLEGAL };
+                let r1 = self.r1(flags);
+                self.respond(&[r1]);
+            }
+            (_, 17) | (_, 18) => {
+                let valid = argument < self.blocks;
+                let r1 = self.r1(if valid { 0 } else { R1_ADDRESS_ERROR });
+                self.respond(&[r1]);
+                if valid && self.send_block(argument) && index == 18 {
+                    self.state = State::ReadMultiple(argument + 1);
+                }
+            }
+            (_, 24) | (_, 25) => {
+                if argument < self.blocks {
+                    let r1 = self.r1(0);
+                    self.respond(&[r1]);
+                    self.state = State::WriteToken { block: argument, multiple: index == 25 };
+                } else {
+                    let r1 = self.r1(R1_ADDRESS_ERROR);
+                    self.respond(&[r1]);
+                }
+            }
+            (true, 41) => {
+                self.idle = false;
+                self.respond(&[R1_READY]);
+            }
+            (_, 55) => {
+                self.app_command = true;
+                let r1 = self.r1(0);
+                self.respond(&[r1]);
+            }
+            (_, 58) => {
+                // Powered up, high capacity, 2.7 to 3.6 V
+                let r1 = self.r1(0);
+                self.respond(&[r1, 0xc0, 0xff, 0x80, 0x00]);
+            }
+            (_, 59) => {
+                let r1 = self.r1(0);
+                self.respond(&[r1]);
+            }
+            _ => {
+                let r1 = self.r1(R1_ILLEGAL);
+                self.respond(&[r1]);
+            }
+        }
+    }
+
+    fn receive(&mut self, value: u8) {
+        match self.state {
+            State::WriteToken { block, multiple } => {
+                if value == TOKEN_STOP && multiple {
+                    // Busy for a byte
+                    self.output.extend(&[0xff, 0x00]);
+                    self.state = State::Command;
+                } else if value == TOKEN_BLOCK || (value == TOKEN_MULTIPLE && multiple) {
+                    self.data.clear();
+                    self.state = State::WriteData { block, multiple };
+                }
+            }
+            State::WriteData { block, multiple } => {
+                self.data.push(value);
+                if self.data.len() < BLOCK_SIZE + 2 {
+                    return;
+                }
+                let response = if self.write_block(block).is_ok() {
+                    DATA_ACCEPTED
+                } else {
+                    DATA_WRITE_ERROR
+                };
+                self.output.extend(&[response, 0x00]);
+                self.state = if multiple && response == DATA_ACCEPTED && block + 1 < self.blocks {
+                    State::WriteToken { block: block + 1, multiple }
+                } else {
+                    State::Command
+                };
+            }
+            State::Command | State::ReadMultiple(_) => {
+                if self.command.is_empty() && value & 0xc0 != 0x40 {
+                    return;
+                }
+                self.command.push(value);
+                if self.command.len() == 6 {
+                    self.execute();
+                    self.command.clear();
+                }
+            }
+        }
+    }
+}
+
+impl<S: Read + Write + Seek> SpiDevice for SdCard<S> {
+    fn select(&mut self, selected: bool) {
+        if !selected {
+            self.command.clear();
+        }
+        self.selected = selected;
+    }
+
+    fn transfer(&mut self, value: u8) -> u8 {
+        if !self.selected {
+            return 0xff;
+        }
+        let result = self.output.pop_front().unwrap_or(0xff);
+        if let State::ReadMultiple(block) = self.state {
+            if self.output.is_empty() {
+                if self.send_block(block) {
+                    self.state = State::ReadMultiple(block + 1);
+                } else {
+                    self.state = State::Command;
+                }
+            }
+        }
+        self.receive(value);
+        result
+    }
+}
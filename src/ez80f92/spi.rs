@@ -0,0 +1,156 @@
+//! Serial peripheral interface
+//!
+//! In master mode, a write to SPI_TSR shifts the byte out to the device and
+//! shifts in the byte from the device, 8 bits at the rate of the baud rate
+//! generator: 2 clock cycles per bit for each unit of the divisor. At the
+//! end of the transfer SPIF is set and the byte received is on SPI_RBR.
+//!
+//! ```text
+//! SPI_BRG_L  $B8  divisor, low byte
+//! SPI_BRG_H  $B9  divisor, high byte
+//! SPI_CTL    $BA  bit 7 IRQ_EN, bit 5 SPI_EN, bit 4 MASTER_EN,
+//!                 bit 3 CPOL, bit 2 CPHA
+//! SPI_SR     $BB  bit 7 SPIF, bit 6 WCOL, bit 4 MODF, cleared when read
+//! SPI_TSR    $BC  write: transmit shift register
+//! SPI_RBR    $BC  read: receive buffer
+//! ```
+//!
+//! The device is a `SpiDevice`. Its chip select is usually a GPIO pin,
+//! the host passes its level with `select()`. The slave mode and the mode
+//! fault detection are not emulated.
+
+use super::{vectors, Peripheral};
+
+const BASE_PORT: u16 = 0xb8;
+
+const CTL_IRQ_EN: u8 = 0x80;
+const CTL_SPI_EN: u8 = 0x20;
+const CTL_MASTER_EN: u8 = 0x10;
+
+const SR_SPIF: u8 = 0x80;
+const SR_WCOL: u8 = 0x40;
+
+/// A slave device on the SPI bus
+pub trait SpiDevice {
+    /// Called when the chip select of the device changes
+    fn select(&mut self, selected: bool);
+    /// Receives the byte sent by the master and returns the byte sent by
+    /// the device
+    fn transfer(&mut self, value: u8) -> u8;
+}
+
+/// A slot that may have a device, the bytes read when empty are $FF
+impl<D: SpiDevice> SpiDevice for Option<D> {
+    fn select(&mut self, selected: bool) {
+        if let Some(device) = self.as_mut() {
+            device.select(selected);
+        }
+    }
+
+    fn transfer(&mut self, value: u8) -> u8 {
+        self.as_mut().map_or(0xff, |device| device.transfer(value))
+    }
+}
+
+/// The SPI controller of the eZ80F92
+pub struct Spi<D: SpiDevice> {
+    /// Slave device on the bus
+    pub device: D,
+    /// SPI_CTL
+    pub ctl: u8,
+    /// SPI_SR
+    pub sr: u8,
+    /// Divisor of the baud rate generator
+    pub divisor: u16,
+    // SPI_RBR
+    rbr: u8,
+    // Byte being transferred
+    shifting: Option<u8>,
+    cycles: u32,
+}
+
+impl<D: SpiDevice> Spi<D> {
+    /// Returns the SPI controller after a reset
+    pub fn new(device: D) -> Spi<D> {
+        Spi {
+            device,
+            ctl: 0x04,
+            sr: 0,
+            divisor: 2,
+            rbr: 0,
+            shifting: None,
+            cycles: 0,
+        }
+    }
+
+    /// Returns the cycles of the system clock to transfer a byte
+    pub fn byte_cycles(&self) -> u32 {
+        16 * self.divisor.max(2) as u32
+    }
+
+    /// Returns true if a byte is being transferred
+    pub fn is_busy(&self) -> bool {
+        self.shifting.is_some()
+    }
+
+    /// Sets the chip select of the device, true when asserted
+    pub fn select(&mut self, selected: bool) {
+        self.device.select(selected);
+    }
+}
+
+impl<D: SpiDevice> Peripheral for Spi<D> {
+    fn port_in(&mut self, port: u16) -> Option<u8> {
+        match port.checked_sub(BASE_PORT)? {
+            0 => Some(self.divisor as u8),
+            1 => Some((self.divisor >> 8) as u8),
+            2 => Some(self.ctl),
+            3 => {
+                let value = self.sr;
+                self.sr = 0;
+                Some(value)
+            }
+            4 => Some(self.rbr),
+            _ => None
+        }
+    }
+
+    fn port_out(&mut self, port: u16, value: u8) -> bool {
+        match port.checked_sub(BASE_PORT) {
+            Some(0) => self.divisor = (self.divisor & 0xff00) | value as u16,
+            Some(1) => self.divisor = (self.divisor & 0x00ff) | ((value as u16) << 8),
+            Some(2) => self.ctl = value & 0xbc,
+            Some(3) => {}
+            Some(4) => {
+                let master = CTL_SPI_EN | CTL_MASTER_EN;
+                if self.shifting.is_some() {
+                    self.sr |= SR_WCOL;
+                } else if self.ctl & master == master {
+                    self.shifting = Some(value);
+                    self.cycles = 0;
+                }
+            }
+            _ => return false
+        }
+        true
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if let Some(value) = self.shifting {
+            self.cycles += cycles;
+            if self.cycles >= self.byte_cycles() {
+                self.rbr = self.device.transfer(value);
+                self.shifting = None;
+                self.sr |= SR_SPIF;
+            }
+        }
+    }
+
+    fn interrupt(&self) -> Option<u8> {
+        if self.ctl & CTL_IRQ_EN != 0 && self.sr & SR_SPIF != 0 {
+            Some(vectors::SPI)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,434 @@
+//! UART0 and UART1
+//!
+//! The UARTs are compatible with the 16550, with 16 byte FIFOs. The bytes
+//! are transmitted and received at the rate of the baud rate generator,
+//! counted on the cycles of the system clock: 16 clock cycles per bit for
+//! each unit of the divisor.
+//!
+//! ```text
+//! UARTx_RBR / THR     $C0  $D0  receive buffer / transmit holding
+//! UARTx_BRG_L         $C0  $D0  divisor, with LCR bit 7 set
+//! UARTx_IER           $C1  $D1  interrupt enable
+//! UARTx_BRG_H         $C1  $D1  divisor, with LCR bit 7 set
+//! UARTx_IIR / FCR     $C2  $D2  interrupt identification / FIFO control
+//! UARTx_LCR           $C3  $D3  line control
+//! UARTx_MCR           $C4  $D4  modem control, bit 4 loopback
+//! UARTx_LSR           $C5  $D5  line status
+//! UARTx_MSR           $C6  $D6  modem status
+//! UARTx_SPR           $C7  $D7  scratch pad
+//! ```
+//!
+//! The bytes go to and come from a `SerialBackend`. The parity, framing
+//! and break errors, the modem status interrupts and the transmission
+//! complete interrupt are not emulated.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use super::{vectors, Peripheral};
+
+#[cfg(feature = "std")]
+use std::io::{Read, Write};
+#[cfg(feature = "std")]
+use std::sync::mpsc::{self, Receiver};
+#[cfg(feature = "std")]
+use std::thread;
+
+const FIFO_SIZE: usize = 16;
+
+const IER_RIE: u8 = 0x01;
+const IER_TIE: u8 = 0x02;
+const IER_LSE: u8 = 0x04;
+
+const IIR_NONE: u8 = 0x01;
+const IIR_LINE_STATUS: u8 = 0x06;
+const IIR_RECEIVE: u8 = 0x04;
+const IIR_TIMEOUT: u8 = 0x0c;
+const IIR_TRANSMIT: u8 = 0x02;
+const IIR_FIFO: u8 = 0xc0;
+
+const FCR_FIFO_EN: u8 = 0x01;
+const FCR_CLEAR_RX: u8 = 0x02;
+const FCR_CLEAR_TX: u8 = 0x04;
+
+const LCR_DLAB: u8 = 0x80;
+
+const MCR_LOOP: u8 = 0x10;
+
+const LSR_DR: u8 = 0x01;
+const LSR_OE: u8 = 0x02;
+const LSR_THRE: u8 = 0x20;
+const LSR_TEMT: u8 = 0x40;
+
+const MSR_CTS: u8 = 0x10;
+const MSR_DSR: u8 = 0x20;
+const MSR_DCD: u8 = 0x80;
+
+/// Host side of a serial line
+pub trait SerialBackend {
+    /// Returns a byte sent to the UART, if there is one waiting
+    fn receive(&mut self) -> Option<u8>;
+    /// Receives a byte transmitted by the UART
+    fn send(&mut self, value: u8);
+}
+
+/// Serial line on memory buffers, for tests and for hosts that move the
+/// bytes themselves
+#[derive(Clone, Debug, Default)]
+pub struct BufferBackend {
+    /// Bytes to be received by the UART
+    pub input: VecDeque<u8>,
+    /// Bytes transmitted by the UART
+    pub output: Vec<u8>,
+}
+
+impl BufferBackend {
+    pub fn new() -> BufferBackend {
+        BufferBackend::default()
+    }
+
+    /// Queues bytes to be received by the UART
+    pub fn push_input(&mut self, data: &[u8]) {
+        self.input.extend(data.iter());
+    }
+
+    /// Returns and clears the bytes transmitted by the UART
+    pub fn take_output(&mut self) -> Vec<u8> {
+        core::mem::take(&mut self.output)
+    }
+}
+
+impl SerialBackend for BufferBackend {
+    fn receive(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+
+    fn send(&mut self, value: u8) {
+        self.output.push(value);
+    }
+}
+
+#[cfg(feature = "std")]
+/// Serial line on a host byte stream: stdin and stdout, a TCP socket or a
+/// pseudo terminal
+///
+/// The input is read on a separate thread, so the UART can poll it without
+/// blocking.
+pub struct StreamBackend {
+    rx: Receiver<u8>,
+    writer: Box<dyn Write + Send>,
+}
+
+#[cfg(feature = "std")]
+impl StreamBackend {
+    /// Uses a reader for the bytes received and a writer for the bytes
+    /// transmitted
+    pub fn new<R: Read + Send + 'static>(mut reader: R, writer: Box<dyn Write + Send>) -> StreamBackend {
+        let (tx, rx) = mpsc::channel::<u8>();
+        thread::spawn(move || {
+            let mut buffer = [0u8; 256];
+            while let Ok(count) = reader.read(&mut buffer) {
+                if count == 0 || buffer[..count].iter().any(|b| tx.send(*b).is_err()) {
+                    break;
+                }
+            }
+        });
+        StreamBackend {
+            rx,
+            writer,
+        }
+    }
+
+    /// Uses the host stdin and stdout
+    pub fn stdio() -> StreamBackend {
+        StreamBackend::new(std::io::stdin(), Box::new(std::io::stdout()))
+    }
+
+    /// Uses a connected TCP socket, like one accepted on a loopback port
+    pub fn tcp(stream: std::net::TcpStream) -> std::io::Result<StreamBackend> {
+        let reader = stream.try_clone()?;
+        stream.set_nodelay(true)?;
+        Ok(StreamBackend::new(reader, Box::new(stream)))
+    }
+
+    /// Uses a terminal device, like the slave side of a pseudo terminal
+    /// created with `socat -d -d pty,raw,echo=0 pty,raw,echo=0`
+    pub fn terminal(path: &str) -> std::io::Result<StreamBackend> {
+        let file = std::fs::OpenOptions::new().read(true).write(true).open(path)?;
+        let reader = file.try_clone()?;
+        Ok(StreamBackend::new(reader, Box::new(file)))
+    }
+}
+
+#[cfg(feature = "std")]
+impl SerialBackend for StreamBackend {
+    fn receive(&mut self) -> Option<u8> {
+        self.rx.try_recv().ok()
+    }
+
+    fn send(&mut self, value: u8) {
+        let _ = self.writer.write_all(&[value]);
+        let _ = self.writer.flush();
+    }
+}
+
+/// A UART of the eZ80F92
+pub struct Uart<B: SerialBackend> {
+    /// Host side of the serial line
+    pub backend: B,
+    /// UARTx_IER
+    pub ier: u8,
+    /// UARTx_FCR, without the clear bits
+    pub fcr: u8,
+    /// UARTx_LCR
+    pub lcr: u8,
+    /// UARTx_MCR
+    pub mcr: u8,
+    /// UARTx_SPR
+    pub spr: u8,
+    /// Divisor of the baud rate generator
+    pub divisor: u16,
+    base: u16,
+    vector: u8,
+    rx_fifo: VecDeque<u8>,
+    tx_fifo: VecDeque<u8>,
+    // Byte being transmitted
+    shifting: Option<u8>,
+    overrun: bool,
+    // The transmit interrupt is cleared when IIR is read
+    thre_pending: bool,
+    tx_cycles: u32,
+    rx_cycles: u32,
+    // Cycles since the last byte was received or read, for the timeout
+    idle_cycles: u32,
+}
+
+impl<B: SerialBackend> Uart<B> {
+    /// Returns the UART [index], 0 or 1, after a reset
+    pub fn new(index: usize, backend: B) -> Uart<B> {
+        Uart {
+            backend,
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            spr: 0,
+            divisor: 2,
+            base: 0xc0 + 0x10 * index as u16,
+            vector: vectors::UART0 + 2 * index as u8,
+            rx_fifo: VecDeque::with_capacity(FIFO_SIZE),
+            tx_fifo: VecDeque::with_capacity(FIFO_SIZE),
+            shifting: None,
+            overrun: false,
+            thre_pending: false,
+            tx_cycles: 0,
+            rx_cycles: 0,
+            idle_cycles: 0,
+        }
+    }
+
+    /// Returns the cycles of the system clock to transmit a byte, with
+    /// the start, data, parity and stop bits set on LCR
+    pub fn byte_cycles(&self) -> u32 {
+        let data = 5 + (self.lcr & 0x03) as u32;
+        let parity = ((self.lcr >> 3) & 1) as u32;
+        let stop = 1 + ((self.lcr >> 2) & 1) as u32;
+        16 * self.divisor.max(1) as u32 * (1 + data + parity + stop)
+    }
+
+    fn fifo_size(&self) -> usize {
+        if self.fcr & FCR_FIFO_EN != 0 { FIFO_SIZE } else { 1 }
+    }
+
+    fn trigger_level(&self) -> usize {
+        if self.fcr & FCR_FIFO_EN == 0 {
+            return 1;
+        }
+        match self.fcr >> 6 {
+            0 => 1,
+            1 => 4,
+            2 => 8,
+            _ => 14,
+        }
+    }
+
+    /// Returns the interrupt identification, without the FIFO bits
+    fn identification(&self) -> u8 {
+        if self.ier & IER_LSE != 0 && self.overrun {
+            IIR_LINE_STATUS
+        } else if self.ier & IER_RIE != 0 && self.rx_fifo.len() >= self.trigger_level() {
+            IIR_RECEIVE
+        } else if self.ier & IER_RIE != 0 && !self.rx_fifo.is_empty()
+                && self.idle_cycles >= 4 * self.byte_cycles() {
+            IIR_TIMEOUT
+        } else if self.ier & IER_TIE != 0 && self.thre_pending {
+            IIR_TRANSMIT
+        } else {
+            IIR_NONE
+        }
+    }
+
+    fn line_status(&mut self) -> u8 {
+        let mut lsr = 0;
+        if !self.rx_fifo.is_empty() {
+            lsr |= LSR_DR;
+        }
+        if self.overrun {
+            lsr |= LSR_OE;
+            self.overrun = false;
+        }
+        if self.tx_fifo.is_empty() {
+            lsr |= LSR_THRE;
+            if self.shifting.is_none() {
+                lsr |= LSR_TEMT;
+            }
+        }
+        lsr
+    }
+
+    fn modem_status(&self) -> u8 {
+        if self.mcr & MCR_LOOP != 0 {
+            // DTR to DSR, RTS to CTS
+            ((self.mcr & 0x01) << 5) | ((self.mcr & 0x02) << 3)
+        } else {
+            MSR_CTS | MSR_DSR | MSR_DCD
+        }
+    }
+
+    /// Moves the next byte to transmit to the shift register
+    fn next_byte(&mut self) -> Option<u8> {
+        let value = self.tx_fifo.pop_front();
+        if value.is_some() && self.tx_fifo.is_empty() {
+            self.thre_pending = true;
+        }
+        value
+    }
+
+    fn received(&mut self, value: u8) {
+        if self.rx_fifo.len() < self.fifo_size() {
+            self.rx_fifo.push_back(value);
+        } else {
+            self.overrun = true;
+        }
+        self.idle_cycles = 0;
+    }
+}
+
+impl<B: SerialBackend> Peripheral for Uart<B> {
+    fn port_in(&mut self, port: u16) -> Option<u8> {
+        let register = port.checked_sub(self.base).filter(|r| *r < 8)?;
+        let dlab = self.lcr & LCR_DLAB != 0;
+        Some(match register {
+            0 if dlab => self.divisor as u8,
+            0 => {
+                self.idle_cycles = 0;
+                self.rx_fifo.pop_front().unwrap_or(0)
+            }
+            1 if dlab => (self.divisor >> 8) as u8,
+            1 => self.ier,
+            2 => {
+                let iir = self.identification();
+                if iir == IIR_TRANSMIT {
+                    self.thre_pending = false;
+                }
+                let fifo = if self.fcr & FCR_FIFO_EN != 0 { IIR_FIFO } else { 0 };
+                iir | fifo
+            }
+            3 => self.lcr,
+            4 => self.mcr,
+            5 => self.line_status(),
+            6 => self.modem_status(),
+            _ => self.spr,
+        })
+    }
+
+    fn port_out(&mut self, port: u16, value: u8) -> bool {
+        let register = match port.checked_sub(self.base).filter(|r| *r < 8) {
+            Some(register) => register,
+            None => return false
+        };
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match register {
+            0 if dlab => self.divisor = (self.divisor & 0xff00) | value as u16,
+            0 => {
+                if self.tx_fifo.len() < self.fifo_size() {
+                    self.tx_fifo.push_back(value);
+                }
+                self.thre_pending = false;
+            }
+            1 if dlab => self.divisor = (self.divisor & 0x00ff) | ((value as u16) << 8),
+            1 => {
+                // Enabling the interrupt with the FIFO empty requests it
+                if value & IER_TIE != 0 && self.ier & IER_TIE == 0 && self.tx_fifo.is_empty() {
+                    self.thre_pending = true;
+                }
+                self.ier = value & 0x1f;
+            }
+            2 => {
+                if value & FCR_CLEAR_RX != 0 || (value ^ self.fcr) & FCR_FIFO_EN != 0 {
+                    self.rx_fifo.clear();
+                }
+                if value & FCR_CLEAR_TX != 0 || (value ^ self.fcr) & FCR_FIFO_EN != 0 {
+                    self.tx_fifo.clear();
+                }
+                self.fcr = value & !(FCR_CLEAR_RX | FCR_CLEAR_TX);
+            }
+            3 => self.lcr = value,
+            4 => self.mcr = value & 0x1f,
+            5 | 6 => {}
+            _ => self.spr = value,
+        }
+        true
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        let byte_cycles = self.byte_cycles();
+        let loopback = self.mcr & MCR_LOOP != 0;
+
+        // Transmit
+        if self.shifting.is_none() {
+            self.shifting = self.next_byte();
+            self.tx_cycles = 0;
+        }
+        if self.shifting.is_some() {
+            self.tx_cycles += cycles;
+            while let Some(value) = self.shifting {
+                if self.tx_cycles < byte_cycles {
+                    break;
+                }
+                self.tx_cycles -= byte_cycles;
+                if loopback {
+                    self.received(value);
+                } else {
+                    self.backend.send(value);
+                }
+                self.shifting = self.next_byte();
+            }
+        }
+
+        // Receive, a byte each byte time at most
+        self.idle_cycles = self.idle_cycles.saturating_add(cycles);
+        self.rx_cycles += cycles;
+        while self.rx_cycles >= byte_cycles {
+            self.rx_cycles -= byte_cycles;
+            if loopback {
+                continue;
+            }
+            match self.backend.receive() {
+                Some(value) => self.received(value),
+                None => {
+                    self.rx_cycles = 0;
+                    break;
+                }
+            }
+        }
+    }
+
+    fn interrupt(&self) -> Option<u8> {
+        if self.identification() != IIR_NONE {
+            Some(self.vector)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Watchdog timer
+//!
+//! Once enabled, the watchdog can only be stopped by a reset. The program
+//! restarts the count writing $A5 and then $5A to WDT_RR. If the count
+//! ends, the watchdog resets the CPU or requests a NMI.
+//!
+//! ```text
+//! WDT_CTL  $93  bit 7 WDT_EN, bit 6 NMI_OUT, bit 5 RST_FLAG, set after a
+//!               reset by the watchdog, cleared when read,
+//!               bits 3-2 WDT_CLK, 0 system clock, 1 RTC oscillator,
+//!               2 internal oscillator, bits 1-0 WDT_PERIOD, 2^27, 2^25,
+//!               2^22 or 2^18 clock cycles
+//! WDT_RR   $94  write: $A5, $5A restarts the count
+//! ```
+//!
+//! WDT_CTL can't be written while the watchdog is enabled. The host gets
+//! the timeouts with `timeout()` or passes them to the CPU with
+//! `signal()`.
+
+use super::Peripheral;
+use crate::{GenericCpu, Machine};
+
+const CTL_PORT: u16 = 0x93;
+const RR_PORT: u16 = 0x94;
+
+const CTL_EN: u8 = 0x80;
+const CTL_NMI_OUT: u8 = 0x40;
+const CTL_RST_FLAG: u8 = 0x20;
+
+const RTC_OSCILLATOR: u64 = 32768;
+const INTERNAL_OSCILLATOR: u64 = 10000;
+
+/// Action of the watchdog at the end of the count
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchdogTimeout {
+    Reset,
+    Nmi,
+}
+
+/// The watchdog timer of the eZ80F92
+#[derive(Clone, Debug)]
+pub struct Watchdog {
+    /// WDT_CTL
+    pub ctl: u8,
+    // Frequency of the system clock in Hz
+    clock: u32,
+    // Cycles of the system clock since the count started
+    cycles: u64,
+    // The $A5 of the restart sequence was written
+    unlocked: bool,
+    timeout: Option<WatchdogTimeout>,
+}
+
+impl Watchdog {
+    /// Returns the watchdog after a reset, with the frequency of the
+    /// system clock in Hz
+    pub fn new(clock: u32) -> Watchdog {
+        Watchdog {
+            ctl: 0,
+            clock,
+            cycles: 0,
+            unlocked: false,
+            timeout: None,
+        }
+    }
+
+    /// Returns true if the watchdog is counting
+    pub fn is_enabled(&self) -> bool {
+        self.ctl & CTL_EN != 0
+    }
+
+    /// Returns the cycles of the system clock of a count
+    pub fn period_cycles(&self) -> u64 {
+        let period = 1u64 << [27, 25, 22, 18][(self.ctl & 0x03) as usize];
+        match (self.ctl >> 2) & 0x03 {
+            0 => period,
+            1 => period * self.clock as u64 / RTC_OSCILLATOR,
+            _ => period * self.clock as u64 / INTERNAL_OSCILLATOR,
+        }
+    }
+
+    /// Returns and clears the timeout, if the count ended
+    pub fn timeout(&mut self) -> Option<WatchdogTimeout> {
+        self.timeout.take()
+    }
+
+    /// Passes the timeout, if the count ended, to the CPU as a reset or as
+    /// a NMI. Returns the timeout.
+    pub fn signal<M: Machine + ?Sized>(&mut self, cpu: &mut GenericCpu<M>) -> Option<WatchdogTimeout> {
+        let timeout = self.timeout();
+        match timeout {
+            Some(WatchdogTimeout::Reset) => cpu.signal_reset(),
+            Some(WatchdogTimeout::Nmi) => cpu.signal_nmi(),
+            None => {}
+        }
+        timeout
+    }
+}
+
+impl Peripheral for Watchdog {
+    fn port_in(&mut self, port: u16) -> Option<u8> {
+        match port {
+            CTL_PORT => {
+                let value = self.ctl;
+                self.ctl &= !CTL_RST_FLAG;
+                Some(value)
+            }
+            RR_PORT => Some(0xff),
+            _ => None
+        }
+    }
+
+    fn port_out(&mut self, port: u16, value: u8) -> bool {
+        match port {
+            CTL_PORT => {
+                if !self.is_enabled() {
+                    self.ctl = (self.ctl & CTL_RST_FLAG) | (value & !CTL_RST_FLAG);
+                    self.cycles = 0;
+                }
+            }
+            RR_PORT => {
+                if self.unlocked && value == 0x5a {
+                    self.cycles = 0;
+                }
+                self.unlocked = value == 0xa5;
+            }
+            _ => return false
+        }
+        true
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.cycles += cycles as u64;
+        if self.cycles < self.period_cycles() {
+            return;
+        }
+        self.cycles = 0;
+        if self.ctl & CTL_NMI_OUT != 0 {
+            self.timeout = Some(WatchdogTimeout::Nmi);
+        } else {
+            // The reset stops the watchdog
+            self.timeout = Some(WatchdogTimeout::Reset);
+            self.ctl = CTL_RST_FLAG;
+        }
+    }
+
+    fn interrupt(&self) -> Option<u8> {
+        // The NMI is not vectored
+        None
+    }
+}
@@ -0,0 +1,184 @@
+use core::fmt;
+use core::ops::{BitAnd, BitOr, Not};
+
+use super::registers::Flag;
+
+/// Value of the F register
+///
+/// A set of flags, like the ones of the bitflags crate, with an accessor
+/// for each flag. Get it with `Registers::flags()`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Flags(u8);
+
+impl Flags {
+    /// Sign flag
+    pub const S: Flags = Flags(Flag::S as u8);
+    /// Zero flag
+    pub const Z: Flags = Flags(Flag::Z as u8);
+    /// Undocumented bit 5, copy of the bit 5 of the result
+    pub const Y: Flags = Flags(Flag::_5 as u8);
+    /// Half carry flag
+    pub const H: Flags = Flags(Flag::H as u8);
+    /// Undocumented bit 3, copy of the bit 3 of the result
+    pub const X: Flags = Flags(Flag::_3 as u8);
+    /// Parity or overflow flag
+    pub const PV: Flags = Flags(Flag::P as u8);
+    /// Negative flag
+    pub const N: Flags = Flags(Flag::N as u8);
+    /// Carry flag
+    pub const C: Flags = Flags(Flag::C as u8);
+
+    /// Returns the flags with no flag set
+    pub const fn empty() -> Flags {
+        Flags(0)
+    }
+
+    /// Returns the flags with all the flags set
+    pub const fn all() -> Flags {
+        Flags(0xff)
+    }
+
+    /// Returns the flags of a value of the F register
+    pub const fn from_bits(bits: u8) -> Flags {
+        Flags(bits)
+    }
+
+    /// Returns the value of the F register
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Returns true if all the flags of [other] are set
+    pub const fn contains(self, other: Flags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Sets the flags of [other]
+    pub fn insert(&mut self, other: Flags) {
+        self.0 |= other.0;
+    }
+
+    /// Clears the flags of [other]
+    pub fn remove(&mut self, other: Flags) {
+        self.0 &= !other.0;
+    }
+
+    /// Sets or clears the flags of [other]
+    pub fn set(&mut self, other: Flags, value: bool) {
+        if value {
+            self.insert(other);
+        } else {
+            self.remove(other);
+        }
+    }
+
+    pub const fn s(self) -> bool { self.contains(Flags::S) }
+    pub const fn z(self) -> bool { self.contains(Flags::Z) }
+    pub const fn y(self) -> bool { self.contains(Flags::Y) }
+    pub const fn h(self) -> bool { self.contains(Flags::H) }
+    pub const fn x(self) -> bool { self.contains(Flags::X) }
+    pub const fn pv(self) -> bool { self.contains(Flags::PV) }
+    pub const fn n(self) -> bool { self.contains(Flags::N) }
+    pub const fn c(self) -> bool { self.contains(Flags::C) }
+
+    /// Returns a Display of the flags with the names of [layout]
+    pub fn display(self, layout: FlagsLayout) -> FlagsDisplay {
+        FlagsDisplay {
+            flags: self,
+            layout,
+        }
+    }
+}
+
+impl From<u8> for Flags {
+    fn from(bits: u8) -> Flags {
+        Flags(bits)
+    }
+}
+
+impl From<Flags> for u8 {
+    fn from(flags: Flags) -> u8 {
+        flags.0
+    }
+}
+
+impl From<Flag> for Flags {
+    fn from(flag: Flag) -> Flags {
+        Flags(flag as u8)
+    }
+}
+
+impl BitOr for Flags {
+    type Output = Flags;
+    fn bitor(self, other: Flags) -> Flags {
+        Flags(self.0 | other.0)
+    }
+}
+
+impl BitAnd for Flags {
+    type Output = Flags;
+    fn bitand(self, other: Flags) -> Flags {
+        Flags(self.0 & other.0)
+    }
+}
+
+impl Not for Flags {
+    type Output = Flags;
+    fn not(self) -> Flags {
+        Flags(!self.0)
+    }
+}
+
+/// Shows the flags with the Z80 names, `SZ5H3PNC`, and `-` for the flags
+/// not set
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.display(FlagsLayout::Z80).fmt(f)
+    }
+}
+
+/// Names of the bits of the F register
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FlagsLayout {
+    /// `SZ5H3PNC`
+    Z80,
+    /// `SZ0A0P1C`, the bits 5, 3 and 1 are fixed and shown as they are
+    /// on the 8080
+    I8080,
+    /// `SZKA0PVC`, with the undocumented K and V flags
+    I8085,
+}
+
+impl FlagsLayout {
+    /// Returns the names of the bits and the mask of the fixed ones
+    fn names(self) -> (&'static [u8; 8], u8) {
+        match self {
+            FlagsLayout::Z80 => (b"SZ5H3PNC", 0x00),
+            FlagsLayout::I8080 => (b"SZ0A0P1C", 0x2a),
+            FlagsLayout::I8085 => (b"SZKA0PVC", 0x08),
+        }
+    }
+}
+
+/// Display of Flags with a FlagsLayout
+pub struct FlagsDisplay {
+    flags: Flags,
+    layout: FlagsLayout,
+}
+
+impl fmt::Display for FlagsDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (names, fixed) = self.layout.names();
+        for (i, name) in names.iter().enumerate() {
+            // The fixed bits are shown always
+            let bit = 0x80 >> i;
+            let c = if (self.flags.0 | fixed) & bit != 0 {
+                *name
+            } else {
+                b'-'
+            };
+            write!(f, "{}", c as char)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+//! Execution history
+//!
+//! With `Cpu::set_history()` the Cpu keeps, for the last instructions
+//! executed, the state of the CPU before the instruction and the previous
+//! values of the memory it wrote. `Cpu::step_back()` uses them to undo
+//! instructions. Port writes are not undone.
+
+use alloc::vec::Vec;
+use alloc::collections::VecDeque;
+
+use crate::registers::Registers;
+use crate::state::State;
+
+/// An instruction executed, with what is needed to undo it
+#[derive(Clone)]
+pub struct HistoryEntry {
+    /// Address of the instruction, including MBASE
+    pub pc: u32,
+    /// ADL mode when the instruction was executed
+    pub adl: bool,
+    /// Addresses written by the instruction with their previous values,
+    /// in the order of the writes
+    pub memory_writes: Vec<(u32, u8)>,
+    state: State,
+}
+
+impl HistoryEntry {
+    /// The registers before the instruction
+    pub fn registers(&self) -> &Registers {
+        &self.state.reg
+    }
+
+    /// Count of instructions executed before this one
+    pub fn instructions_executed(&self) -> u64 {
+        self.state.instructions_executed
+    }
+
+    /// Returns true if the instruction wrote to `address`
+    pub fn wrote(&self, address: u32) -> bool {
+        self.memory_writes.iter().any(|(a, _)| *a == address)
+    }
+}
+
+/// Ring buffer with the last instructions executed
+pub struct History {
+    capacity: usize,
+    entries: VecDeque<HistoryEntry>,
+}
+
+impl History {
+    pub(crate) fn new(capacity: usize) -> History {
+        History {
+            capacity,
+            entries: VecDeque::with_capacity(capacity.min(0x10000)),
+        }
+    }
+
+    /// The instructions recorded, oldest first
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item = &HistoryEntry> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Returns the last instruction recorded that wrote to `address`
+    pub fn last_writer(&self, address: u32) -> Option<&HistoryEntry> {
+        self.entries.iter().rev().find(|entry| entry.wrote(address))
+    }
+
+    pub(crate) fn push(&mut self, pc: u32, state: State, memory_writes: Vec<(u32, u8)>) {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(HistoryEntry {
+            pc,
+            adl: state.reg.adl,
+            memory_writes,
+            state,
+        });
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<(State, Vec<(u32, u8)>)> {
+        self.entries.pop_back().map(|entry| (entry.state, entry.memory_writes))
+    }
+}
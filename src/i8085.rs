@@ -0,0 +1,189 @@
+//! Intel 8085 interrupt inputs, serial lines and instruction timings
+//!
+//! The 8085 adds to the 8080 four interrupt inputs with fixed vectors:
+//!
+//! ```text
+//! TRAP     $0024  non maskable
+//! RST 7.5  $003C  rising edge, latched until acknowledged or reset by SIM
+//! RST 6.5  $0034  level
+//! RST 5.5  $002C  level
+//! ```
+//!
+//! The RST inputs are accepted with the interrupts enabled and not masked
+//! by SIM. Accepting an interrupt disables the interrupts. The INTR input
+//! of the 8080 is not emulated.
+//!
+//! RIM reads the masks, the pending interrupts and the SID serial input.
+//! SIM writes the masks and the SOD serial output.
+
+use super::opcode::Action;
+use super::registers::Reg8;
+
+const TRAP_ADDRESS: u32 = 0x0024;
+const RST55_ADDRESS: u32 = 0x002c;
+const RST65_ADDRESS: u32 = 0x0034;
+const RST75_ADDRESS: u32 = 0x003c;
+
+const MASK_55: u8 = 0x01;
+const MASK_65: u8 = 0x02;
+const MASK_75: u8 = 0x04;
+
+const SIM_MSE: u8 = 0x08;
+const SIM_R75: u8 = 0x10;
+const SIM_SDE: u8 = 0x40;
+
+/// Interrupt inputs, interrupt masks and serial lines of the 8085
+#[derive(Clone, Copy, Debug)]
+pub struct I8085Io {
+    /// Masks of RST 5.5, 6.5 and 7.5 on the bits 0 to 2, as set by SIM
+    pub mask: u8,
+    /// TRAP requested, cleared when the interrupt is accepted
+    pub trap: bool,
+    /// RST 7.5 latch, cleared when the interrupt is accepted or by SIM
+    pub rst75: bool,
+    /// Level of the RST 6.5 input, set and cleared by the host
+    pub rst65: bool,
+    /// Level of the RST 5.5 input, set and cleared by the host
+    pub rst55: bool,
+    /// Serial input data, read by RIM
+    pub sid: bool,
+    /// Serial output data, written by SIM
+    pub sod: bool,
+}
+
+impl I8085Io {
+    /// Returns the state after a reset, with the RST interrupts masked
+    pub fn new() -> I8085Io {
+        I8085Io {
+            mask: MASK_55 | MASK_65 | MASK_75,
+            trap: false,
+            rst75: false,
+            rst65: false,
+            rst55: false,
+            sid: false,
+            sod: false,
+        }
+    }
+
+    /// Restores the values after a reset. The input levels are kept.
+    pub fn reset(&mut self) {
+        self.mask = MASK_55 | MASK_65 | MASK_75;
+        self.trap = false;
+        self.rst75 = false;
+        self.sod = false;
+    }
+
+    /// Returns the address of the interrupt to accept, by priority
+    pub(crate) fn pending(&self, enabled: bool) -> Option<u32> {
+        if self.trap {
+            Some(TRAP_ADDRESS)
+        } else if !enabled {
+            None
+        } else if self.rst75 && self.mask & MASK_75 == 0 {
+            Some(RST75_ADDRESS)
+        } else if self.rst65 && self.mask & MASK_65 == 0 {
+            Some(RST65_ADDRESS)
+        } else if self.rst55 && self.mask & MASK_55 == 0 {
+            Some(RST55_ADDRESS)
+        } else {
+            None
+        }
+    }
+
+    /// Accepts the interrupt with the higher priority. Returns its address.
+    pub(crate) fn acknowledge(&mut self, enabled: bool) -> Option<u32> {
+        let address = self.pending(enabled)?;
+        match address {
+            TRAP_ADDRESS => self.trap = false,
+            RST75_ADDRESS => self.rst75 = false,
+            _ => {}
+        }
+        Some(address)
+    }
+
+    /// Returns the value of A after RIM
+    pub(crate) fn rim(&self, enabled: bool) -> u8 {
+        (self.sid as u8) << 7
+            | (self.rst75 as u8) << 6
+            | (self.rst65 as u8) << 5
+            | (self.rst55 as u8) << 4
+            | (enabled as u8) << 3
+            | self.mask
+    }
+
+    /// Executes SIM with the value of A
+    pub(crate) fn sim(&mut self, value: u8) {
+        if value & SIM_MSE != 0 {
+            self.mask = value & (MASK_55 | MASK_65 | MASK_75);
+        }
+        if value & SIM_R75 != 0 {
+            self.rst75 = false;
+        }
+        if value & SIM_SDE != 0 {
+            self.sod = value & 0x80 != 0;
+        }
+    }
+}
+
+impl Default for I8085Io {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the T-states of an 8085 instruction, as listed on the 8085AH
+/// datasheet. `start` is the address of the instruction and `next` the PC
+/// after its execution, to know if the conditional jumps are taken.
+pub(crate) fn cycles(action: &Action, start: u32, next: u32) -> i32 {
+    let memory = |r: &Reg8| *r == Reg8::_HL;
+    let taken = |len: u32| next & 0xffff != start.wrapping_add(len) & 0xffff;
+
+    match action {
+        Action::Nop => 4,
+        Action::Halt => 5,
+        Action::PopRr(_) => 10,
+        Action::PushRr(_) => 12,
+        Action::ConfInterrupts(_) => 4,
+        Action::Rim | Action::Sim => 4,
+
+        Action::OperatorAR(r, _) | Action::OperatorARExt(r, _) => if memory(r) { 7 } else { 4 },
+        Action::OperatorAN(_) => 7,
+        Action::AddHlRr(_) => 10,
+        Action::Dsub => 10,
+        Action::IncR(r) | Action::DecR(r) => if memory(r) { 10 } else { 4 },
+        Action::IncDecRr(_, _) => 6,
+        Action::Daa8080 => 4,
+        Action::RotR(_, _, _, _, _) => 4,
+        Action::Arhl => 7,
+        Action::Rdel => 10,
+        Action::Cpl | Action::Scf | Action::Ccf => 4,
+
+        Action::OutNA | Action::InAN => 10,
+
+        Action::JpUnconditional => 10,
+        Action::JpEq(_, _) => if taken(3) { 10 } else { 7 },
+        Action::JpHl => 6,
+        Action::Call => 18,
+        Action::CallEq(_, _) => if taken(3) { 18 } else { 9 },
+        Action::Rst(_) => 12,
+        Action::Rstv => if taken(1) { 12 } else { 6 },
+        Action::Ret => 10,
+        Action::RetEq(_, _) => if taken(1) { 12 } else { 6 },
+
+        Action::LdRR(_, _) => 4,
+        Action::LdRRExt(dst, src) => if memory(dst) || memory(src) { 7 } else { 4 },
+        Action::LdRN(r) => if memory(r) { 10 } else { 7 },
+        Action::LdAPrr(_) | Action::LdPrrA(_) => 7,
+        Action::LdAPnn | Action::LdPnnA => 13,
+        Action::LdRrNn(_) => 10,
+        Action::LdSpHl => 6,
+        Action::LdPnnRr(_) | Action::LdRrPnn(_) => 16,
+        Action::LdDeRrN(_) => 10,
+        Action::Shlx | Action::Lhlx => 10,
+        Action::ExDeHl => 4,
+        Action::ExPspHl => 16,
+
+        // Z80, Z180 and eZ80 instructions, not decoded for the 8085
+        _ => 0
+    }
+}
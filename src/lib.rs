@@ -13,6 +13,7 @@
 //!    // Prepare the device
 //!    let mut machine = PlainMachine::new();
 //!    let mut cpu = Cpu::new(); // Or Cpu::new_8080()
+//!#    #[cfg(feature = "std")]
 //!    cpu.set_trace(true);
 //!
 //!    // Load program inline or from a file with:
@@ -36,7 +37,16 @@
 //!    }
 //!}
 //! ```
+//!
+//!# no_std
+//! The `std` feature is on by default. Without it the crate is `no_std` and
+//! only needs `alloc`. The `cpm` and `agon` modules and the trace sinks that
+//! write to `std::io` are not available, and the diagnostic messages are
+//! discarded unless the Machine implements `Machine::diagnostic()`.
+
+#![cfg_attr(not(feature = "std"), no_std)]
 
+extern crate alloc;
 
 mod cpu;
 mod machine;
@@ -44,10 +54,17 @@ mod registers;
 mod state;
 
 
+mod blocks;
+mod decode_cache;
 mod decoder_ez80;
 mod decoder_z80;
+mod decoder_z80n;
+mod decoder_z180;
 mod decoder_8080;
+mod decoder_8085;
 mod environment;
+mod flags;
+mod i8085;
 mod opcode;
 mod opcode_alu;
 mod opcode_arith;
@@ -56,12 +73,26 @@ mod opcode_io;
 mod opcode_jumps;
 mod opcode_ld;
 mod operators;
+mod z180;
 
+#[cfg(feature = "std")]
+pub mod agon;
+#[cfg(feature = "std")]
+pub mod cpm;
 pub mod disassembler;
+pub mod ez80f92;
+pub mod history;
+pub mod trace;
+pub mod trap;
 pub mod z80_mem_tools;
 
-pub use cpu::Cpu;
+pub use cpu::{Cpu, GenericCpu};
+pub use trap::{Trap, TrapAction, TrapHandler};
+pub use machine::AddressMode;
 pub use machine::Machine;
 pub use machine::PlainMachine;
 pub use registers::*;
+pub use flags::{Flags, FlagsDisplay, FlagsLayout};
 pub use environment::Environment;
+pub use z180::Z180Io;
+pub use i8085::I8085Io;
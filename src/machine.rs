@@ -1,3 +1,38 @@
+use core::cell::Cell;
+use core::fmt;
+
+/// Addressing context of the multi byte accessors of Machine
+///
+/// It defines how the addresses are formed and wrapped, like the CPU does
+/// with `Environment::wrap_address16()` and `Environment::wrap_address24()`.
+/// Use `Registers::address_mode()` to get the current mode of the CPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressMode {
+    /// 24 bit addresses wrapping at $FFFFFF, as the eZ80 in ADL mode
+    Adl,
+    /// 16 bit addresses wrapping at $FFFF on the 64KB page selected by
+    /// MBASE, as the eZ80 in Z80 mode. The top byte of the address is
+    /// replaced by MBASE. Use `Z80(0)` for the Z80 and the 8080.
+    Z80(u8),
+}
+
+impl AddressMode {
+    /// Returns the Z80 mode on the 64KB page of [address]
+    pub fn page_of(address: u32) -> AddressMode {
+        AddressMode::Z80((address >> 16) as u8)
+    }
+
+    /// Returns the address [increment] bytes after [address]
+    pub fn wrap(self, address: u32, increment: i32) -> u32 {
+        match self {
+            AddressMode::Adl =>
+                address.wrapping_add(increment as u32) & 0xff_ffff,
+            AddressMode::Z80(mbase) =>
+                ((mbase as u32) << 16) + (address as u16).wrapping_add(increment as u16) as u32,
+        }
+    }
+}
+
 /// Abstraction of the device hosting the Z80 CPU
 /// 
 /// The device hosting the CPU has to provide implementations
@@ -12,32 +47,92 @@ pub trait Machine {
 
     fn use_cycles(&self, cycles: i32);
 
-    /// Returns the memory contents in [address] as word
-    /// XXX wrapping is wrong in non-ADL ez80
+    /// Returns the memory contents in [address] for the debugging tools.
+    /// Implementations with side effects on reads, like cycle counting or
+    /// watchpoints, should override it to avoid them.
+    fn peek_debug(&self, address: u32) -> u8 {
+        self.peek(address)
+    }
+
+    /// Returns a counter of changes of the memory seen by the CPU not done
+    /// with poke(), like bank switches. Implementations that change the
+    /// memory that way should increment it, to clear the decode cache of
+    /// the Cpu.
+    fn memory_generation(&self) -> u64 {
+        0
+    }
+
+    /// Receives the diagnostic messages of the emulator, like unimplemented
+    /// opcodes or invalid size prefixes. They are written to stderr, or
+    /// discarded without the `std` feature.
+    fn diagnostic(&self, message: fmt::Arguments) {
+        #[cfg(feature = "std")]
+        eprintln!("{}", message);
+        #[cfg(not(feature = "std"))]
+        let _ = message;
+    }
+
+    /// Writes a register of the ZX Spectrum Next with the Z80N NEXTREG
+    /// instructions. By default it is written like the programs do with
+    /// OUT, selecting the register on port $243B and writing the value on
+    /// port $253B.
+    fn nextreg(&mut self, register: u8, value: u8) {
+        self.port_out(0x243b, register);
+        self.port_out(0x253b, value);
+    }
+
+    /// Returns the memory contents in [address] as word, wrapping inside
+    /// the 64KB page of the address like the Z80 does
     fn _peek16(&self, address: u32) -> u16 {
-        self.peek(address) as u16
-        + ((self.peek(address.wrapping_add(1)) as u16) << 8)
+        self.peek16_mode(address, AddressMode::page_of(address))
     }
 
-    /// Sets the memory content to the word [value] in [address]
-    /// XXX wrapping is wrong in non-ADL ez80
+    /// Sets the memory content to the word [value] in [address], wrapping
+    /// inside the 64KB page of the address like the Z80 does
     fn _poke16(&mut self, address: u32, value: u16) {
-        self.poke(address, value as u8 );
-        self.poke(address.wrapping_add(1), (value >> 8) as u8);
+        self.poke16_mode(address, value, AddressMode::page_of(address))
     }
 
-    /// XXX wrapping is wrong in non-ADL ez80
+    /// Returns the memory contents in [address] as a 24 bit value,
+    /// wrapping at $FFFFFF like the eZ80 in ADL mode
     fn _peek24(&self, address: u32) -> u32 {
-        self.peek(address) as u32
-        + ((self.peek(address.wrapping_add(1)) as u32) << 8)
-        + ((self.peek(address.wrapping_add(2)) as u32) << 16)
+        self.peek24_mode(address, AddressMode::Adl)
     }
 
-    /// XXX wrapping is wrong in non-ADL ez80
+    /// Sets the memory content to the 24 bit [value] in [address],
+    /// wrapping at $FFFFFF like the eZ80 in ADL mode
     fn _poke24(&mut self, address: u32, value: u32) {
-        self.poke(address, value as u8 );
-        self.poke(address.wrapping_add(1), (value >> 8) as u8);
-        self.poke(address.wrapping_add(2), (value >> 16) as u8);
+        self.poke24_mode(address, value, AddressMode::Adl)
+    }
+
+    /// Returns the memory contents in [address] as word, with the address
+    /// and the wrapping of [mode]
+    fn peek16_mode(&self, address: u32, mode: AddressMode) -> u16 {
+        self.peek(mode.wrap(address, 0)) as u16
+        + ((self.peek(mode.wrap(address, 1)) as u16) << 8)
+    }
+
+    /// Sets the memory content to the word [value] in [address], with the
+    /// address and the wrapping of [mode]
+    fn poke16_mode(&mut self, address: u32, value: u16, mode: AddressMode) {
+        self.poke(mode.wrap(address, 0), value as u8);
+        self.poke(mode.wrap(address, 1), (value >> 8) as u8);
+    }
+
+    /// Returns the memory contents in [address] as a 24 bit value, with the
+    /// address and the wrapping of [mode]
+    fn peek24_mode(&self, address: u32, mode: AddressMode) -> u32 {
+        self.peek(mode.wrap(address, 0)) as u32
+        + ((self.peek(mode.wrap(address, 1)) as u32) << 8)
+        + ((self.peek(mode.wrap(address, 2)) as u32) << 16)
+    }
+
+    /// Sets the memory content to the 24 bit [value] in [address], with the
+    /// address and the wrapping of [mode]
+    fn poke24_mode(&mut self, address: u32, value: u32, mode: AddressMode) {
+        self.poke(mode.wrap(address, 0), value as u8);
+        self.poke(mode.wrap(address, 1), (value >> 8) as u8);
+        self.poke(mode.wrap(address, 2), (value >> 16) as u8);
     }
 
     /// Port in, from the device to the CPU. Returns the port value
@@ -48,6 +143,52 @@ pub trait Machine {
     fn port_out(&mut self, address: u16, value: u8);
 }
 
+/// Conversion to `&mut dyn Machine` of the Machines of a Cpu, sized or not
+pub(crate) trait AsDynMachine: Machine {
+    fn as_dyn_machine(&mut self) -> &mut dyn Machine;
+}
+
+impl<T: Machine> AsDynMachine for T {
+    fn as_dyn_machine(&mut self) -> &mut dyn Machine {
+        self
+    }
+}
+
+impl AsDynMachine for dyn Machine + '_ {
+    fn as_dyn_machine(&mut self) -> &mut dyn Machine {
+        self
+    }
+}
+
+/// Read only view of a Machine for the decoding without side effects. The
+/// reads are done with peek_debug(), the writes, the ports and the cycles
+/// are ignored.
+pub(crate) struct DebugMachine<'a, M: Machine + ?Sized>(pub &'a M);
+
+impl<M: Machine + ?Sized> Machine for DebugMachine<'_, M> {
+    fn peek(&self, address: u32) -> u8 {
+        self.0.peek_debug(address)
+    }
+
+    fn poke(&mut self, _address: u32, _value: u8) {}
+
+    fn use_cycles(&self, _cycles: i32) {}
+
+    fn memory_generation(&self) -> u64 {
+        self.0.memory_generation()
+    }
+
+    fn diagnostic(&self, _message: fmt::Arguments) {}
+
+    fn nextreg(&mut self, _register: u8, _value: u8) {}
+
+    fn port_in(&mut self, _address: u16) -> u8 {
+        0xff
+    }
+
+    fn port_out(&mut self, _address: u16, _value: u8) {}
+}
+
 /// A simple Machine implementation
 /// 
 /// A minimum implementation of Machine. It uses two arrays of 65536 bytes to back the peeks and
@@ -55,7 +196,7 @@ pub trait Machine {
 pub struct PlainMachine {
     mem: [u8; 4*65536],
     io: [u8; 4*65536],
-    pub elapsed_cycles: std::cell::Cell<i64>
+    pub elapsed_cycles: Cell<i64>
 }
 
 impl PlainMachine {
@@ -64,7 +205,7 @@ impl PlainMachine {
         PlainMachine {
             mem: [0; 4*65536],
             io: [0; 4*65536],
-            elapsed_cycles: std::cell::Cell::new(0)
+            elapsed_cycles: Cell::new(0)
         }
     }
 
@@ -88,6 +229,9 @@ impl Machine for PlainMachine {
         self.use_cycles(1);
         self.mem[address as usize]
     }
+    fn peek_debug(&self, address: u32) -> u8 {
+        self.mem[address as usize]
+    }
     fn poke(&mut self, address: u32, value: u8) {
         self.use_cycles(1);
         self.mem[address as usize] = value;
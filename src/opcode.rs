@@ -1,24 +1,308 @@
-use super::state::SizePrefix;
+use alloc::format;
+use alloc::string::{String, ToString};
+use super::state::{SizePrefix, State};
 use super::environment::*;
+use super::machine::Machine;
+use super::opcode_alu::*;
+use super::opcode_arith::*;
+use super::opcode_bits::*;
+use super::opcode_io::*;
+use super::opcode_jumps::*;
+use super::opcode_ld::*;
+use super::operators::Operator;
 use super::registers::*;
 
-type OpcodeFn = dyn Fn(&mut Environment);
+/// The operation of an opcode with its decoded operands. Executed with
+/// a match on the variant, to avoid an indirect call per instruction.
+#[derive(Copy, Clone)]
+pub enum Action {
+    Nop,
+    Halt,
+    Slp,
+    Rim,
+    Sim,
+    PushNn,
+    PopRr(Reg16),
+    PushRr(Reg16),
+    ConfInterrupts(bool),
+    Im(u8),
+    Stmix,
+    Rsmix,
+    LogUnimplemented(&'static str),
+
+    // ALU
+    LeaRrIndOffset(Reg16, Reg16),
+    Pea(Reg16),
+    TstAR(Reg8),
+    TstAN,
+    OperatorAIdxOffset(Reg16, Operator),
+    OperatorAR(Reg8, Operator),
+    OperatorARExt(Reg8, Operator),
+    OperatorAN(Operator),
+    CpBlock(bool, bool),
+    MltRr(Reg16),
+    AddRrA(Reg16),
+    AddRrNn(Reg16),
+    Pixeldn,
+    Pixelad,
+
+    // Arithmetic
+    AddHlRr(Reg16),
+    AdcHlRr(Reg16),
+    SbcHlRr(Reg16),
+    IncR(Reg8),
+    DecR(Reg8),
+    IncDecRr(Reg16, bool),
+    Neg,
+    Daa,
+    Daa8080,
+    Dsub,
+    LdDeRrN(Reg16),
+
+    // Bits
+    RotR(Reg8, ShiftDir, ShiftMode, bool, bool),
+    BitR(u8, Reg8),
+    SetResR(u8, Reg8, bool),
+    IndexedSetResR(u8, Reg8, bool),
+    Cpl,
+    Scf,
+    Ccf,
+    Rxd(ShiftDir),
+    Arhl,
+    Rdel,
+    Swapnib,
+    Mirror,
+    Setae,
+    BarrelShift(u8),
+
+    // IO
+    OutCR(Reg8),
+    OutC0,
+    OutNA,
+    Out0NR(Reg8),
+    In0RN(Reg8),
+    InRC(Reg8),
+    In0C,
+    InAN,
+    InBlock(bool, bool),
+    OutBlock(bool, bool),
+    OtirxOrOtdrx(bool),
+    TstIoN,
+    Otim(bool, bool),
+    Outinb,
+    Nextreg(bool),
+
+    // Jumps
+    Djnz,
+    JrUnconditional,
+    JrEq(Flag, bool),
+    JpUnconditional,
+    JpEq(Flag, bool),
+    JpHl,
+    Call,
+    CallEq(Flag, bool),
+    Rst(u8),
+    Trap(bool),
+    Rstv,
+    JpC,
+    Ret,
+    Reti,
+    Retn,
+    RetEq(Flag, bool),
+
+    // Loads
+    LdRR(Reg8, Reg8),
+    LdRRExt(Reg8, Reg8),
+    LdRN(Reg8),
+    LdAPrr(Reg16),
+    LdAPnn,
+    LdPrrA(Reg16),
+    LdPnnA,
+    LdRrNn(Reg16),
+    LdSpHl,
+    LdPnnRr(Reg16),
+    LdRrPnn(Reg16),
+    ExAf,
+    Exx,
+    ExDeHl,
+    ExPspHl,
+    Shlx,
+    Lhlx,
+    LdBlock(bool, bool),
+    LdBlockX(bool, bool),
+    Ldpirx,
+    Ldws,
+    LdAMb,
+    LdMbA,
+    LdIdxDispRr(Reg16, Reg16),
+    LdRrIdxDisp(Reg16, Reg16),
+    LdRrIndHl(Reg16),
+    LdIndHlRr(Reg16),
+}
 
 pub struct Opcode {
     pub name: String,
-    pub action: Box<OpcodeFn>,
+    pub action: Action,
+}
+
+impl Action {
+    pub fn execute<M: Machine + ?Sized>(self, env: &mut Environment<M>) {
+        match self {
+            Action::Nop => {},
+            Action::Halt => env.state.halted = true,
+            Action::Slp => env.state.halted = true,
+            Action::Rim => rim(env),
+            Action::Sim => sim(env),
+            Action::PushNn => push_nn(env),
+            Action::PopRr(rr) => pop_rr(env, rr),
+            Action::PushRr(rr) => push_rr(env, rr),
+            Action::ConfInterrupts(enable) => env.state.reg.set_interrupts(enable),
+            Action::Im(im) => env.state.reg.set_interrupt_mode(im),
+            Action::Stmix => env.state.reg.madl = true,
+            Action::Rsmix => env.state.reg.madl = false,
+            Action::LogUnimplemented(name) => env.sys.diagnostic(format_args!("Unimplemented opcode: {}", name)),
+
+            Action::LeaRrIndOffset(dest, src) => lea_rr_ind_offset(env, dest, src),
+            Action::Pea(src) => pea(env, src),
+            Action::TstAR(r) => tst_a_r(env, r),
+            Action::TstAN => tst_a_n(env),
+            Action::OperatorAIdxOffset(idx, op) => operator_a_idx_offset(env, idx, op),
+            Action::OperatorAR(r, op) => operator_a_r(env, r, op),
+            Action::OperatorARExt(r, op) => operator_a_r_ext(env, r, op),
+            Action::OperatorAN(op) => operator_a_n(env, op),
+            Action::CpBlock(inc, repeat) => cp_block(env, inc, repeat),
+            Action::MltRr(rr) => mlt_rr(env, rr),
+            Action::AddRrA(rr) => add_rr_a(env, rr),
+            Action::AddRrNn(rr) => add_rr_nn(env, rr),
+            Action::Pixeldn => pixeldn(env),
+            Action::Pixelad => pixelad(env),
+
+            Action::AddHlRr(rr) => add_hl_rr(env, rr),
+            Action::AdcHlRr(rr) => adc_hl_rr(env, rr),
+            Action::SbcHlRr(rr) => sbc_hl_rr(env, rr),
+            Action::IncR(r) => inc_r(env, r),
+            Action::DecR(r) => dec_r(env, r),
+            Action::IncDecRr(rr, inc) => inc_dec_rr(env, rr, inc),
+            Action::Neg => neg(env),
+            Action::Daa => daa(env),
+            Action::Daa8080 => daa8080(env),
+            Action::Dsub => dsub(env),
+            Action::LdDeRrN(rr) => ld_de_rr_n(env, rr),
+
+            Action::RotR(r, dir, mode, fast, indexed) => rot_r(env, r, dir, mode, fast, indexed),
+            Action::BitR(n, r) => bit_r(env, n, r),
+            Action::SetResR(bit, r, value) => set_res_r(env, bit, r, value),
+            Action::IndexedSetResR(bit, r, value) => indexed_set_res_r(env, bit, r, value),
+            Action::Cpl => cpl(env),
+            Action::Scf => scf(env),
+            Action::Ccf => ccf(env),
+            Action::Rxd(dir) => rxd(env, dir),
+            Action::Arhl => arhl(env),
+            Action::Rdel => rdel(env),
+            Action::Swapnib => swapnib(env),
+            Action::Mirror => mirror(env),
+            Action::Setae => setae(env),
+            Action::BarrelShift(kind) => barrel_shift(env, kind),
+
+            Action::OutCR(r) => out_c_r(env, r),
+            Action::OutC0 => out_c_0(env),
+            Action::OutNA => out_n_a(env),
+            Action::Out0NR(r) => out0_n_r(env, r),
+            Action::In0RN(r) => in0_r_n(env, r),
+            Action::InRC(r) => in_r_c(env, r),
+            Action::In0C => in_0_c(env),
+            Action::InAN => in_a_n(env),
+            Action::InBlock(inc, repeat) => in_block(env, inc, repeat),
+            Action::OutBlock(inc, repeat) => out_block(env, inc, repeat),
+            Action::OtirxOrOtdrx(inc) => otirx_or_otdrx(env, inc),
+            Action::TstIoN => tstio_n(env),
+            Action::Otim(inc, repeat) => otim(env, inc, repeat),
+            Action::Outinb => outinb(env),
+            Action::Nextreg(immediate) => nextreg(env, immediate),
+
+            Action::Djnz => djnz(env),
+            Action::JrUnconditional => jr_unconditional(env),
+            Action::JrEq(flag, value) => jr_eq(env, flag, value),
+            Action::JpUnconditional => jp_unconditional(env),
+            Action::JpEq(flag, value) => jp_eq(env, flag, value),
+            Action::JpHl => jp_hl(env),
+            Action::Call => call(env),
+            Action::CallEq(flag, value) => call_eq(env, flag, value),
+            Action::Rst(d) => rst(env, d),
+            Action::Trap(third_byte) => trap(env, third_byte),
+            Action::Rstv => rstv(env),
+            Action::JpC => jp_c(env),
+            Action::Ret => ret(env),
+            Action::Reti => reti(env),
+            Action::Retn => retn(env),
+            Action::RetEq(flag, value) => ret_eq(env, flag, value),
+
+            Action::LdRR(dst, src) => ld_r_r(env, dst, src),
+            Action::LdRRExt(dst, src) => ld_r_r_ext(env, dst, src),
+            Action::LdRN(r) => ld_r_n(env, r),
+            Action::LdAPrr(rr) => ld_a_prr(env, rr),
+            Action::LdAPnn => ld_a_pnn(env),
+            Action::LdPrrA(rr) => ld_prr_a(env, rr),
+            Action::LdPnnA => ld_pnn_a(env),
+            Action::LdRrNn(rr) => ld_rr_nn(env, rr),
+            Action::LdSpHl => ld_sp_hl(env),
+            Action::LdPnnRr(rr) => ld_pnn_rr(env, rr),
+            Action::LdRrPnn(rr) => ld_rr_pnn(env, rr),
+            Action::ExAf => env.state.reg.swap16(Reg16::AF),
+            Action::Exx => exx(env),
+            Action::ExDeHl => ex_de_hl(env),
+            Action::ExPspHl => ex_psp_hl(env),
+            Action::Shlx => shlx(env),
+            Action::Lhlx => lhlx(env),
+            Action::LdBlock(inc, repeat) => ld_block(env, inc, repeat),
+            Action::LdBlockX(inc, repeat) => ld_block_x(env, inc, repeat),
+            Action::Ldpirx => ldpirx(env),
+            Action::Ldws => ldws(env),
+            Action::LdAMb => env.state.reg.set8(Reg8::A, env.state.reg.mbase),
+            Action::LdMbA => env.state.reg.mbase = env.state.reg.get8(Reg8::A),
+            Action::LdIdxDispRr(index_reg, src) => ld_idx_disp_rr(env, index_reg, src),
+            Action::LdRrIdxDisp(dest, index_reg) => ld_rr_idx_disp(env, dest, index_reg),
+            Action::LdRrIndHl(dest) => ld_rr_ind_hl(env, dest),
+            Action::LdIndHlRr(src) => ld_ind_hl_rr(env, src),
+        }
+    }
 }
 
 impl Opcode {
-    pub fn execute(&self, env: &mut Environment) {
-        (self.action)(env);
+    pub fn execute<M: Machine + ?Sized>(&self, env: &mut Environment<M>) {
+        self.action.execute(env);
+    }
+
+    /// Returns the number of bytes of immediate operands after the opcode
+    /// and the displacement, as counted by disasm()
+    pub fn operand_len(&self, state: &State) -> u8 {
+        let name = self.name.as_bytes();
+        let mut len = 0;
+        let mut i = 0;
+        while i < name.len() {
+            if name[i..].starts_with(b"__index") {
+                i += 7;
+                continue;
+            }
+            match name[i] {
+                b'n' if name.get(i + 1) == Some(&b'n') => {
+                    return if state.is_imm_long() { 3 } else { 2 };
+                }
+                b'm' if name.get(i + 1) == Some(&b'm') => return 2,
+                b'n' if name[i + 1..].starts_with(b", n") => return 2,
+                b'n' | b'd' | b'l' => len = 1,
+                _ => {}
+            }
+            i += 1;
+        }
+        len
     }
 
     /// returns String, and u32 PC increment due to immediates
     /// (the PC increment due to the opcode itself, (and due 
     /// to the state.index hack), have already been applied by
     /// the decoder.
-    pub fn disasm(&self, env: &Environment) -> (String, u32) {
+    pub fn disasm<M: Machine + ?Sized>(&self, env: &Environment<M>) -> (String, u32) {
         let mut name = if self.name.contains("__index") {
             self.name.replace("__index", &env.index_description())
         } else {
@@ -59,6 +343,16 @@ impl Opcode {
                 let nn_str = format!("${:x}", nn);
                 (name.replace("nn", &nn_str), 2)
             }
+        } else if name.contains("mm") {
+            // Immediate argument 16 bits big endian, on the Z80N PUSH
+            let mm = ((env.peek_pc() as u16) << 8) + env.peek(env.state.pc() + 1) as u16;
+            let mm_str = format!("${:x}", mm);
+            (name.replace("mm", &mm_str), 2)
+        } else if name.contains("n, n") {
+            // Two immediate arguments 8 bits, on the Z80N NEXTREG
+            let n1_str = format!("${:x}", env.peek_pc());
+            let n2_str = format!("${:x}", env.peek(env.state.pc() + 1));
+            (name.replacen('n', &n1_str, 1).replacen('n', &n2_str, 1), 2)
         } else if name.contains('n') {
             // Immediate argument 8 bits
             let n = env.peek_pc();
@@ -85,87 +379,99 @@ impl Opcode {
 pub fn build_nop() -> Opcode {
     Opcode {
         name: "NOP".to_string(),
-        action: Box::new(|_: &mut Environment| {
-            // Nothing done
-        })
+        action: Action::Nop
     }
 }
 
 pub fn build_noni_nop() -> Opcode {
     Opcode {
         name: "NONINOP".to_string(),
-        action: Box::new(|_: &mut Environment| {
-            // Nothing done
-        })
+        action: Action::Nop
     }
 }
 
 pub fn build_halt() -> Opcode {
     Opcode {
         name: "HALT".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            env.state.halted = true;
-        })
+        action: Action::Halt
+    }
+}
+
+pub fn build_slp() -> Opcode {
+    // The clock is stopped until an interrupt, as with HALT
+    Opcode {
+        name: "SLP".to_string(),
+        action: Action::Slp
     }
 }
 
 pub fn build_pop_rr(rr: Reg16) -> Opcode {
     Opcode {
         name: format!("POP {:?}", rr),
-        action: Box::new(move |env: &mut Environment| {
-            let value = env.pop();
-            if env.state.is_op_long() && rr != Reg16::AF {
-                env.set_reg24(rr, value);
-            } else {
-                env.set_reg16(rr, value as u16);
-            }
-        })
+        action: Action::PopRr(rr)
+    }
+}
+
+fn pop_rr<M: Machine + ?Sized>(env: &mut Environment<M>, rr: Reg16) {
+    let value = env.pop();
+    if env.state.is_op_long() && rr != Reg16::AF {
+        env.set_reg24(rr, value);
+    } else {
+        env.set_reg16(rr, value as u16);
     }
 }
 
 pub fn build_push_rr(rr: Reg16) -> Opcode {
     Opcode {
         name: format!("PUSH {:?}", rr),
-        action: Box::new(move |env: &mut Environment| {
-            let value = env.reg16or24_ext(rr);
-            env.push(value);
-        })
+        action: Action::PushRr(rr)
+    }
+}
+
+fn push_rr<M: Machine + ?Sized>(env: &mut Environment<M>, rr: Reg16) {
+    let value = env.reg16or24_ext(rr);
+    env.push(value);
+}
+
+pub fn build_push_nn() -> Opcode {
+    // Z80N, the operand is big endian
+    Opcode {
+        name: "PUSH mm".to_string(),
+        action: Action::PushNn
     }
 }
 
+fn push_nn<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let h = env.advance_pc();
+    let l = env.advance_pc();
+    env.push(((h as u32) << 8) | l as u32);
+}
+
 pub fn build_conf_interrupts(enable: bool) -> Opcode {
     let name = if enable {"EI"} else  {"DI"};
     Opcode {
         name: name.to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            env.state.reg.set_interrupts(enable);
-        })
+        action: Action::ConfInterrupts(enable)
     }
 }
 
 pub fn build_im(im: u8) -> Opcode {
     Opcode {
         name: format!("IM {}", im),
-        action: Box::new(move |env: &mut Environment| {
-            env.state.reg.set_interrupt_mode(im);
-        })
+        action: Action::Im(im)
     }
 }
 
 pub fn build_stmix() -> Opcode {
     Opcode {
         name: "STMIX".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            env.state.reg.madl = true;
-        })
+        action: Action::Stmix
     }
 }
 
 pub fn build_rsmix() -> Opcode {
     Opcode {
         name: "RSMIX".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            env.state.reg.madl = false;
-        })
+        action: Action::Rsmix
     }
 }
@@ -1,164 +1,203 @@
+use alloc::format;
+use alloc::string::ToString;
 use super::opcode::*;
 use super::environment::*;
+use super::machine::Machine;
 use super::registers::*;
 use super::operators::*;
 
 pub fn build_lea_rr_ind_offset(dest: Reg16, src: Reg16) -> Opcode {
     Opcode {
         name: format!("LEA {:?}, {:?}d", dest, src),
-        action: Box::new(move |env: &mut Environment| {
-            let imm = env.advance_pc() as i8 as i32 as u32;
-            if env.state.is_op_long() {
-                let value = env.state.reg.get24(src).wrapping_add(imm);
-                env.state.reg.set24(dest, value);
-            } else {
-                let value = env.state.reg.get16(src).wrapping_add(imm as u16);
-                env.state.reg.set16(dest, value);
-            }
-        })
+        action: Action::LeaRrIndOffset(dest, src)
+    }
+}
+
+pub fn lea_rr_ind_offset<M: Machine + ?Sized>(env: &mut Environment<M>, dest: Reg16, src: Reg16) {
+    let imm = env.advance_pc() as i8 as i32 as u32;
+    if env.state.is_op_long() {
+        let value = env.state.reg.get24(src.to_reg24()).wrapping_add(imm);
+        env.state.reg.set24(dest.to_reg24(), value);
+    } else {
+        let value = env.state.reg.get16(src).wrapping_add(imm as u16);
+        env.state.reg.set16(dest, value);
     }
 }
 
 pub fn build_pea(src: Reg16) -> Opcode {
     Opcode {
         name: format!("PEA {:?}d", src),
-        action: Box::new(move |env: &mut Environment| {
-            let imm = env.advance_pc() as i8 as i32 as u32;
-            if env.state.is_op_long() {
-                let value = env.state.reg.get24(src).wrapping_add(imm);
-                env.push(value);
-            } else {
-                let value = env.state.reg.get16(src).wrapping_add(imm as u16);
-                env.push(value as u32);
-            }
-        })
+        action: Action::Pea(src)
+    }
+}
+
+pub fn pea<M: Machine + ?Sized>(env: &mut Environment<M>, src: Reg16) {
+    let imm = env.advance_pc() as i8 as i32 as u32;
+    if env.state.is_op_long() {
+        let value = env.state.reg.get24(src.to_reg24()).wrapping_add(imm);
+        env.push(value);
+    } else {
+        let value = env.state.reg.get16(src).wrapping_add(imm as u16);
+        env.push(value as u32);
     }
 }
 
 pub fn build_tst_a_r(reg: Reg8) -> Opcode {
     Opcode {
         name: format!("TST A, {}", reg),
-        action: Box::new(move |env: &mut Environment| {
-            let a = env.state.reg.a();
-            let b = env.reg8_ext(reg);
-            operator_tst(env, a, b);
-        })
+        action: Action::TstAR(reg)
     }
 }
 
+pub fn tst_a_r<M: Machine + ?Sized>(env: &mut Environment<M>, reg: Reg8) {
+    let a = env.state.reg.a();
+    let b = env.reg8_ext(reg);
+    operator_tst(&mut env.state.reg, a, b);
+}
+
 pub fn build_tst_a_n() -> Opcode {
     Opcode {
-        name: format!("TST A, n"),
-        action: Box::new(move |env: &mut Environment| {
-            let a = env.state.reg.a();
-            let b = env.advance_pc();
-            operator_tst(env, a, b);
-        })
+        name: "TST A, n".to_string(),
+        action: Action::TstAN
     }
 }
 
+pub fn tst_a_n<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let a = env.state.reg.a();
+    let b = env.advance_pc();
+    operator_tst(&mut env.state.reg, a, b);
+}
+
 pub fn build_operator_a_idx_offset(idx: Reg16, (op, name): (Operator, &str)) -> Opcode {
     Opcode {
         name: format!("{} A, ({:?}d)", name, idx),
-        action: Box::new(move |env: &mut Environment| {
-            let offset = env.advance_pc() as i8 as i32 as u32;
-            let a = env.state.reg.a();
-            let address = if env.state.is_op_long() {
-                env.state.reg.get24(idx).wrapping_add(offset)
-            } else {
-                env.state.reg.get16_mbase_offset(idx, offset as u16)
-            };
-            let b = env.peek(address);
-            let v = op(env, a, b);
-            env.state.reg.set_a(v);
-        })
+        action: Action::OperatorAIdxOffset(idx, op)
     }
 }
 
+pub fn operator_a_idx_offset<M: Machine + ?Sized>(env: &mut Environment<M>, idx: Reg16, op: Operator) {
+    let offset = env.advance_pc() as i8 as i32 as u32;
+    let a = env.state.reg.a();
+    let address = if env.state.is_op_long() {
+        env.state.reg.get24(idx.to_reg24()).wrapping_add(offset)
+    } else {
+        env.state.reg.get16_mbase_offset(idx, offset as u16)
+    };
+    let b = env.peek(address);
+    let v = op(&mut env.state.reg, a, b);
+    env.state.reg.set_a(v);
+}
+
 pub fn build_operator_a_r(r: Reg8, (op, name): (Operator, &str)) -> Opcode {
     if r != Reg8::_HL && r != Reg8::H && r != Reg8::L {
         // Fast version
         Opcode {
             name: format!("{} A, {}", name, r),
-            action: Box::new(move |env: &mut Environment| {
-                let a = env.state.reg.a();
-                let b = env.state.reg.get8(r);
-                let v = op(env, a, b);
-                env.state.reg.set_a(v);
-            })
+            action: Action::OperatorAR(r, op)
         }
     } else {
         Opcode {
             name: format!("{} A, {}", name, r),
-            action: Box::new(move |env: &mut Environment| {
-                let a = env.state.reg.a();
-                let b = env.reg8_ext(r);
-                let v = op(env, a, b);
-
-                env.state.reg.set_a(v);
-            })
+            action: Action::OperatorARExt(r, op)
         }
     }
 }
 
+pub fn operator_a_r<M: Machine + ?Sized>(env: &mut Environment<M>, r: Reg8, op: Operator) {
+    let a = env.state.reg.a();
+    let b = env.state.reg.get8(r);
+    let v = op(&mut env.state.reg, a, b);
+    env.state.reg.set_a(v);
+}
+
+pub fn operator_a_r_ext<M: Machine + ?Sized>(env: &mut Environment<M>, r: Reg8, op: Operator) {
+    let a = env.state.reg.a();
+    let b = env.reg8_ext(r);
+    let v = op(&mut env.state.reg, a, b);
+
+    env.state.reg.set_a(v);
+}
+
 pub fn build_operator_a_n((op, name): (Operator, &str)) -> Opcode {
     Opcode {
         name: format!("{} A, n", name),
-        action: Box::new(move |env: &mut Environment| {
-            let a = env.state.reg.a();
-            let b = env.advance_pc();
-            let v = op(env, a, b);
-
-            env.state.reg.set_a(v);
-        })
+        action: Action::OperatorAN(op)
     }
 }
 
+pub fn operator_a_n<M: Machine + ?Sized>(env: &mut Environment<M>, op: Operator) {
+    let a = env.state.reg.a();
+    let b = env.advance_pc();
+    let v = op(&mut env.state.reg, a, b);
+
+    env.state.reg.set_a(v);
+}
+
 pub fn build_cp_block((inc, repeat, postfix) : (bool, bool, &'static str)) -> Opcode {
     Opcode {
         name: format!("CP{}", postfix),
-        action: Box::new(move |env: &mut Environment| {
-            let a = env.state.reg.a();
-            let b = env.reg8_ext(Reg8::_HL);
-            let c_bak = env.state.reg.get_flag(Flag::C);
-            operator_cp(env, a, b);
-            let bc = if env.state.is_op_long() {
-                env.state.reg.inc_dec24(Reg16::HL, inc);
-                env.state.reg.inc_dec24(Reg16::BC, false /*decrement*/)
-            } else {
-                env.state.reg.inc_dec16(Reg16::HL, inc);
-                env.state.reg.inc_dec16(Reg16::BC, false /*decrement*/)
-            };
-
-            // TUZD-4.2
-            let mut n = a.wrapping_sub(b);
-            if env.state.reg.get_flag(Flag::H) {
-                n = n.wrapping_sub(1);
-            }
-            env.state.reg.update_undocumented_flags_block(n);
-            env.state.reg.set_flag(Flag::N);
-            env.state.reg.put_flag(Flag::P, bc != 0);
-            env.state.reg.put_flag(Flag::C, c_bak); // C unchanged
-            // S, Z and H set by operator_cp()
-
-            if repeat && bc != 0 &&  a != b {
-                // Back to redo the instruction
-                let pc = env.wrap_address(env.state.pc(), -2);
-                env.state.set_pc(pc);
-            }
-        })
+        action: Action::CpBlock(inc, repeat)
+    }
+}
+
+pub fn cp_block<M: Machine + ?Sized>(env: &mut Environment<M>, inc: bool, repeat: bool) {
+    let a = env.state.reg.a();
+    let b = env.reg8_ext(Reg8::_HL);
+    let c_bak = env.state.reg.get_flag(Flag::C);
+    operator_cp(&mut env.state.reg, a, b);
+    let bc = if env.state.is_op_long() {
+        env.state.reg.inc_dec24(Reg24::HL, inc);
+        env.state.reg.inc_dec24(Reg24::BC, false /*decrement*/)
+    } else {
+        env.state.reg.inc_dec16(Reg16::HL, inc);
+        env.state.reg.inc_dec16(Reg16::BC, false /*decrement*/)
+    };
+
+    // TUZD-4.2
+    let mut n = a.wrapping_sub(b);
+    if env.state.reg.get_flag(Flag::H) {
+        n = n.wrapping_sub(1);
+    }
+    env.state.reg.update_undocumented_flags_block(n);
+    env.state.reg.set_flag(Flag::N);
+    env.state.reg.put_flag(Flag::P, bc != 0);
+    env.state.reg.put_flag(Flag::C, c_bak); // C unchanged
+    // S, Z and H set by operator_cp()
+
+    if repeat && bc != 0 &&  a != b {
+        // Back to redo the instruction
+        let pc = env.wrap_address(env.state.pc(), -2);
+        env.state.set_pc(pc);
     }
 }
 
 pub fn build_mlt_rr(reg: Reg16) -> Opcode {
     Opcode {
         name: format!("MLT {:?}", reg),
-        action: Box::new(move |env: &mut Environment| {
-            let r = env.state.reg.get16(reg);
-            let a = r & 0xff;
-            let b = (r >> 8) & 0xff;
-            env.state.reg.set16(reg, a * b);
-            env.sys.use_cycles(4);
-        })
+        action: Action::MltRr(reg)
+    }
+}
+
+pub fn mlt_rr<M: Machine + ?Sized>(env: &mut Environment<M>, reg: Reg16) {
+    let r = env.state.reg.get16(reg);
+    let a = r & 0xff;
+    let b = (r >> 8) & 0xff;
+    env.state.reg.set16(reg, a * b);
+    env.use_cycles(4);
+}
+
+// Z80N
+pub fn build_test_n() -> Opcode {
+    Opcode {
+        name: "TEST n".to_string(),
+        action: Action::TstAN
+    }
+}
+
+pub fn build_mul_d_e() -> Opcode {
+    // Same as MLT DE
+    Opcode {
+        name: "MUL D, E".to_string(),
+        action: Action::MltRr(Reg16::DE)
     }
 }
@@ -1,5 +1,8 @@
+use alloc::format;
+use alloc::string::ToString;
 use super::opcode::*;
 use super::environment::*;
+use super::machine::Machine;
 use super::operators::*;
 use super::registers::*;
 
@@ -7,53 +10,59 @@ use super::registers::*;
 pub fn build_add_hl_rr(rr: Reg16) -> Opcode {
     Opcode {
         name: format!("ADD HL, {:?}", rr),
-        action: Box::new(move |env: &mut Environment| {
-            let aa = env.index_value();
-            let bb = env.reg16or24_ext(rr);
+        action: Action::AddHlRr(rr)
+    }
+}
+
+pub fn add_hl_rr<M: Machine + ?Sized>(env: &mut Environment<M>, rr: Reg16) {
+    let aa = env.index_value();
+    let bb = env.reg16or24_ext(rr);
 
-            if env.state.is_op_long() {
-                let vv = operator_add24(env, aa, bb);
-                env.set_reg24(Reg16::HL, vv);
-            } else {
-                let vv = operator_add16(env, aa as u16, bb as u16);
-                env.set_reg16(Reg16::HL, vv);
-            }
-        })
+    if env.state.is_op_long() {
+        let vv = operator_add24(&mut env.state.reg, aa, bb);
+        env.set_reg24(Reg16::HL, vv);
+    } else {
+        let vv = operator_add16(&mut env.state.reg, aa as u16, bb as u16);
+        env.set_reg16(Reg16::HL, vv);
     }
 }
 
 pub fn build_adc_hl_rr(rr: Reg16) -> Opcode {
     Opcode {
         name: format!("ADC HL, {:?}", rr),
-        action: Box::new(move |env: &mut Environment| {
-            let aa = env.index_value(); // This will always be HL.
-            let bb = env.reg16or24_ext(rr);
-            
-            if env.state.is_op_long() {
-                let vv = operator_adc24(env, aa, bb);
-                env.state.reg.set24(Reg16::HL, vv);
-            } else {
-                let vv = operator_adc16(env, aa as u16, bb as u16);
-                env.state.reg.set16(Reg16::HL, vv);
-            }
-        })
+        action: Action::AdcHlRr(rr)
+    }
+}
+
+pub fn adc_hl_rr<M: Machine + ?Sized>(env: &mut Environment<M>, rr: Reg16) {
+    let aa = env.index_value(); // This will always be HL.
+    let bb = env.reg16or24_ext(rr);
+
+    if env.state.is_op_long() {
+        let vv = operator_adc24(&mut env.state.reg, aa, bb);
+        env.state.reg.set24(Reg24::HL, vv);
+    } else {
+        let vv = operator_adc16(&mut env.state.reg, aa as u16, bb as u16);
+        env.state.reg.set16(Reg16::HL, vv);
     }
 }
 
 pub fn build_sbc_hl_rr(rr: Reg16) -> Opcode {
     Opcode {
         name: format!("SBC HL, {:?}", rr),
-        action: Box::new(move |env: &mut Environment| {
-            let aa = env.index_value(); // This will always be HL.
-            let bb = env.reg16or24_ext(rr);
-            if env.state.is_op_long() {
-                let vv = operator_sbc24(env, aa, bb);
-                env.state.reg.set24(Reg16::HL, vv);
-            } else {
-                let vv = operator_sbc16(env, aa as u16, bb as u16);
-                env.state.reg.set16(Reg16::HL, vv);
-            }
-        })
+        action: Action::SbcHlRr(rr)
+    }
+}
+
+pub fn sbc_hl_rr<M: Machine + ?Sized>(env: &mut Environment<M>, rr: Reg16) {
+    let aa = env.index_value(); // This will always be HL.
+    let bb = env.reg16or24_ext(rr);
+    if env.state.is_op_long() {
+        let vv = operator_sbc24(&mut env.state.reg, aa, bb);
+        env.state.reg.set24(Reg24::HL, vv);
+    } else {
+        let vv = operator_sbc16(&mut env.state.reg, aa as u16, bb as u16);
+        env.state.reg.set16(Reg16::HL, vv);
     }
 }
 
@@ -62,112 +71,225 @@ pub fn build_sbc_hl_rr(rr: Reg16) -> Opcode {
 pub fn build_inc_r(r: Reg8) -> Opcode {
     Opcode {
         name: format!("INC {}", r),
-        action: Box::new(move |env: &mut Environment| {
-            let a = env.reg8_ext(r);
-            let v = operator_inc(env, a);
-            env.set_reg(r, v);
-        })
+        action: Action::IncR(r)
     }
 }
 
+pub fn inc_r<M: Machine + ?Sized>(env: &mut Environment<M>, r: Reg8) {
+    let a = env.reg8_ext(r);
+    let v = operator_inc(&mut env.state.reg, a);
+    env.set_reg(r, v);
+}
+
 pub fn build_dec_r(r: Reg8) -> Opcode {
     Opcode {
         name: format!("DEC {}", r),
-        action: Box::new(move |env: &mut Environment| {
-            let a = env.reg8_ext(r);
-            let v = operator_dec(env, a);
-            env.set_reg(r, v);
-        })
+        action: Action::DecR(r)
     }
 }
 
+pub fn dec_r<M: Machine + ?Sized>(env: &mut Environment<M>, r: Reg8) {
+    let a = env.reg8_ext(r);
+    let v = operator_dec(&mut env.state.reg, a);
+    env.set_reg(r, v);
+}
+
 pub fn build_inc_dec_rr(rr: Reg16, inc: bool) -> Opcode {
-    let delta = if inc {1} else {-1_i32 as u32};
     let mnemonic = if inc {"INC"} else {"DEC"};
     Opcode {
         name: format!("{} {:?}", mnemonic, rr),
-        action: Box::new(move |env: &mut Environment| {
-            let mut v = env.reg16or24_ext(rr);
-            v = v.wrapping_add(delta);
-            if env.state.is_op_long() {
-                env.set_reg24(rr, v);
-            } else {
-                env.set_reg16(rr, v as u16);
-            }
-            // Note: flags not affected on the 16 bit INC and DEC
-        })
-    }    
-}    
+        action: Action::IncDecRr(rr, inc)
+    }
+}
+
+pub fn inc_dec_rr<M: Machine + ?Sized>(env: &mut Environment<M>, rr: Reg16, inc: bool) {
+    let delta = if inc {1} else {-1_i32 as u32};
+    let mut v = env.reg16or24_ext(rr);
+    v = v.wrapping_add(delta);
+    if env.state.is_op_long() {
+        env.set_reg24(rr, v);
+    } else {
+        env.set_reg16(rr, v as u16);
+    }
+    // Note: flags not affected on the 16 bit INC and DEC, but K on the 8085
+    env.state.reg.update_inc_dec16_k_flag(v as u16, inc);
+}
+
+// 8085 undocumented 16 bit opcodes
+pub fn build_dsub() -> Opcode {
+    Opcode {
+        name: "SUB HL, BC".to_string(),
+        action: Action::Dsub
+    }
+}
+
+pub fn dsub<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    // SBC without the carry
+    let aa = env.state.reg.get16(Reg16::HL);
+    let bb = env.state.reg.get16(Reg16::BC);
+    env.state.reg.clear_flag(Flag::C);
+    let vv = operator_sbc16(&mut env.state.reg, aa, bb);
+    env.state.reg.set16(Reg16::HL, vv);
+}
+
+pub fn build_ld_de_rr_n(rr: Reg16) -> Opcode {
+    Opcode {
+        name: format!("LD DE, {:?}+n", rr),
+        action: Action::LdDeRrN(rr)
+    }
+}
+
+pub fn ld_de_rr_n<M: Machine + ?Sized>(env: &mut Environment<M>, rr: Reg16) {
+    // LDHI and LDSI, the offset is unsigned
+    let offset = env.advance_pc();
+    let value = env.state.reg.get16(rr).wrapping_add(offset as u16);
+    env.state.reg.set16(Reg16::DE, value);
+}
 
 // Misc. opcodes
 pub fn build_neg() -> Opcode {
     Opcode {
         name: "NEG".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            let b = env.state.reg.a();
-            let v = operator_sub(env, 0, b);
-            env.state.reg.set_a(v);
-        })
+        action: Action::Neg
     }
 }
 
+pub fn neg<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let b = env.state.reg.a();
+    let v = operator_sub(&mut env.state.reg, 0, b);
+    env.state.reg.set_a(v);
+}
+
 pub fn build_daa() -> Opcode {
     Opcode {
         name: "DAA".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            // See TUZD-4.7
-            let a = env.state.reg.a();
-            let hi = a >> 4;
-            let lo = a & 0xf;
-
-            let nf = env.state.reg.get_flag(Flag::N);
-            let cf = env.state.reg.get_flag(Flag::C);
-            let hf = env.state.reg.get_flag(Flag::H);
-
-            let lo6 = hf || (lo > 9);
-            let hi6 = cf || (hi > 9) || (hi == 9 && lo > 9);
-            let diff = if lo6 {6} else {0}
-                + if hi6 {6<<4} else {0};
-            let new_a = if nf {
-                a.wrapping_sub(diff)
-            } else {
-                a.wrapping_add(diff)
-            };
-
-            env.state.reg.set_a(new_a);
-            env.state.reg.update_sz53_flags(new_a);
-            env.state.reg.update_p_flag(new_a);
-            let new_hf = (!nf && lo > 9) || (nf && hf && lo < 6);
-            env.state.reg.put_flag(Flag::H, new_hf);
-            env.state.reg.put_flag(Flag::C, hi6);
-    
-
-            // N unchanged
-        })
+        action: Action::Daa
     }
 }
 
+pub fn daa<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    // See TUZD-4.7
+    let a = env.state.reg.a();
+    let hi = a >> 4;
+    let lo = a & 0xf;
+
+    let nf = env.state.reg.get_flag(Flag::N);
+    let cf = env.state.reg.get_flag(Flag::C);
+    let hf = env.state.reg.get_flag(Flag::H);
+
+    let lo6 = hf || (lo > 9);
+    let hi6 = cf || (hi > 9) || (hi == 9 && lo > 9);
+    let diff = if lo6 {6} else {0}
+        + if hi6 {6<<4} else {0};
+    let new_a = if nf {
+        a.wrapping_sub(diff)
+    } else {
+        a.wrapping_add(diff)
+    };
+
+    env.state.reg.set_a(new_a);
+    env.state.reg.update_sz53_flags(new_a);
+    env.state.reg.update_p_flag(new_a);
+    let new_hf = (!nf && lo > 9) || (nf && hf && lo < 6);
+    env.state.reg.put_flag(Flag::H, new_hf);
+    env.state.reg.put_flag(Flag::C, hi6);
+
+
+    // N unchanged
+}
+
 pub fn build_daa8080() -> Opcode {
     Opcode {
         name: "DAA".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            // See TUZD-4.7
-            let a = env.state.reg.a();
-            let hi = a >> 4;
-            let lo = a & 0xf;
+        action: Action::Daa8080
+    }
+}
+
+pub fn daa8080<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    // See TUZD-4.7
+    let a = env.state.reg.a();
+    let hi = a >> 4;
+    let lo = a & 0xf;
+
+    let cf = env.state.reg.get_flag(Flag::C);
+    let hf = env.state.reg.get_flag(Flag::H);
+
+    let lo6 = hf || (lo > 9);
+    let hi6 = cf || (hi > 9) || (hi == 9 && lo > 9);
+    let diff = if lo6 {6} else {0}
+        + if hi6 {6<<4} else {0};
+
+    // The 8085 V and K flags are not changed
+    let vf = env.state.reg.get_flag(Flag::V);
+    let kf = env.state.reg.get_flag(Flag::K);
+    let new_a = operator_add(&mut env.state.reg, a, diff);
+    env.state.reg.set_a(new_a);
+    env.state.reg.put_flag(Flag::C, cf || hi6);
+    env.state.reg.put_flag(Flag::V, vf);
+    env.state.reg.put_flag(Flag::K, kf);
+
+}
+
+// Z80N
+pub fn build_add_rr_a(rr: Reg16) -> Opcode {
+    Opcode {
+        name: format!("ADD {:?}, A", rr),
+        action: Action::AddRrA(rr)
+    }
+}
 
-            let cf = env.state.reg.get_flag(Flag::C);
-            let hf = env.state.reg.get_flag(Flag::H);
+pub fn add_rr_a<M: Machine + ?Sized>(env: &mut Environment<M>, rr: Reg16) {
+    // Flags not affected
+    let a = env.state.reg.a() as u16;
+    let v = env.state.reg.get16(rr).wrapping_add(a);
+    env.state.reg.set16(rr, v);
+}
 
-            let lo6 = hf || (lo > 9);
-            let hi6 = cf || (hi > 9) || (hi == 9 && lo > 9);
-            let diff = if lo6 {6} else {0}
-                + if hi6 {6<<4} else {0};
+pub fn build_add_rr_nn(rr: Reg16) -> Opcode {
+    Opcode {
+        name: format!("ADD {:?}, nn", rr),
+        action: Action::AddRrNn(rr)
+    }
+}
 
-            let new_a = operator_add(env, a, diff);
-            env.state.reg.set_a(new_a);
-            env.state.reg.put_flag(Flag::C, cf || hi6);
+pub fn add_rr_nn<M: Machine + ?Sized>(env: &mut Environment<M>, rr: Reg16) {
+    // Flags not affected
+    let nn = env.advance_immediate16();
+    let v = env.state.reg.get16(rr).wrapping_add(nn);
+    env.state.reg.set16(rr, v);
+}
 
-        })
+pub fn build_pixeldn() -> Opcode {
+    Opcode {
+        name: "PIXELDN".to_string(),
+        action: Action::Pixeldn
     }
 }
+
+pub fn pixeldn<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    // HL to the address of the line below on the ULA screen
+    let hl = env.state.reg.get16(Reg16::HL);
+    let v = if hl & 0x0700 != 0x0700 {
+        hl.wrapping_add(0x0100)
+    } else if hl & 0x00e0 != 0x00e0 {
+        (hl & 0xf8ff).wrapping_add(0x0020)
+    } else {
+        (hl & 0xf81f).wrapping_add(0x0800)
+    };
+    env.state.reg.set16(Reg16::HL, v);
+}
+
+pub fn build_pixelad() -> Opcode {
+    Opcode {
+        name: "PIXELAD".to_string(),
+        action: Action::Pixelad
+    }
+}
+
+pub fn pixelad<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    // HL to the address on the ULA screen of the pixel at E, D
+    let d = env.state.reg.get8(Reg8::D) as u16;
+    let e = env.state.reg.get8(Reg8::E) as u16;
+    let v = 0x4000 + ((d & 0xc0) << 5) + ((d & 0x07) << 8) + ((d & 0x38) << 2) + (e >> 3);
+    env.state.reg.set16(Reg16::HL, v);
+}
@@ -1,5 +1,8 @@
+use alloc::format;
+use alloc::string::ToString;
 use super::opcode::*;
 use super::environment::*;
+use super::machine::Machine;
 use super::registers::*;
 
 #[derive(Copy, Clone)]
@@ -26,95 +29,128 @@ pub fn build_rot_r(r: Reg8, (dir, mode, name): (ShiftDir, ShiftMode, &str), fast
 
     Opcode {
         name: full_name,
-        action: Box::new(move |env: &mut Environment| {
-            let mut v = if indexed {
-                env.reg8_ext(Reg8::_HL)
-            } else {
-                env.reg8_ext(r)
-            };
+        action: Action::RotR(r, dir, mode, fast, indexed)
+    }
+}
+
+pub fn rot_r<M: Machine + ?Sized>(env: &mut Environment<M>, r: Reg8, dir: ShiftDir, mode: ShiftMode, fast: bool, indexed: bool) {
+    let mut v = if indexed {
+        env.reg8_ext(Reg8::_HL)
+    } else {
+        env.reg8_ext(r)
+    };
 
-            let carry: bool;
-            match dir {
-                ShiftDir::Left => {
-                    let upper_bit = v >= 0x80;
-                    v <<= 1;
-                    let set_lower_bit = match mode {
-                        ShiftMode::Arithmetic => false, // always 0 in bit 0
-                        ShiftMode::Logical => true, // always 1 in bit 0
-                        ShiftMode::Rotate => env.state.reg.get_flag(Flag::C), // carry in bit 0
-                        ShiftMode::RotateCarry => upper_bit, // bit 7 moves to bit 0
-                    };
-                    if set_lower_bit { // bit 0 is 0 already
-                        v |= 1;
-                    }
-                    carry = upper_bit;
-                },
-                ShiftDir::Right => {
-                    let upper_bit = v >= 0x80;
-                    let lower_bit = (v & 1) == 1;
-                    v >>= 1;
-                    let set_upper_bit = match mode {
-                        ShiftMode::Arithmetic => upper_bit, // extend bit 7
-                        ShiftMode::Logical => false, // always 0 in bit 7
-                        ShiftMode::Rotate => env.state.reg.get_flag(Flag::C), // carry in bit 0
-                        ShiftMode::RotateCarry => lower_bit, // bit 0 goes to bit 7
-                    };
-                    if set_upper_bit { // bit 7 is 0 already
-                        v |= 0x80;
-                    }
-                    carry = lower_bit;
-                }
+    let carry = match dir {
+        ShiftDir::Left => {
+            let upper_bit = v >= 0x80;
+            v <<= 1;
+            let set_lower_bit = match mode {
+                ShiftMode::Arithmetic => false, // always 0 in bit 0
+                ShiftMode::Logical => true, // always 1 in bit 0
+                ShiftMode::Rotate => env.state.reg.get_flag(Flag::C), // carry in bit 0
+                ShiftMode::RotateCarry => upper_bit, // bit 7 moves to bit 0
             };
-            if indexed && r != Reg8::_HL {
-                env.set_reg(Reg8::_HL, v);
+            if set_lower_bit { // bit 0 is 0 already
+                v |= 1;
             }
-            env.set_reg(r, v);
-
-            env.state.reg.put_flag(Flag::C, carry);
-            env.state.reg.update_hn_flags(false, false);
-            if fast {
-                env.state.reg.update_undocumented_flags(v);
-            } else {
-                env.state.reg.update_bits_in_flags(v);
+            upper_bit
+        },
+        ShiftDir::Right => {
+            let upper_bit = v >= 0x80;
+            let lower_bit = (v & 1) == 1;
+            v >>= 1;
+            let set_upper_bit = match mode {
+                ShiftMode::Arithmetic => upper_bit, // extend bit 7
+                ShiftMode::Logical => false, // always 0 in bit 7
+                ShiftMode::Rotate => env.state.reg.get_flag(Flag::C), // carry in bit 0
+                ShiftMode::RotateCarry => lower_bit, // bit 0 goes to bit 7
+            };
+            if set_upper_bit { // bit 7 is 0 already
+                v |= 0x80;
             }
-        })
+            lower_bit
+        }
+    };
+    if indexed && r != Reg8::_HL {
+        env.set_reg(Reg8::_HL, v);
+    }
+    env.set_reg(r, v);
+
+    env.state.reg.put_flag(Flag::C, carry);
+    env.state.reg.update_hn_flags(false, false);
+    if fast {
+        env.state.reg.update_undocumented_flags(v);
+    } else {
+        env.state.reg.update_bits_in_flags(v);
     }
 }
 
+// 8085 undocumented 16 bit shifts
+pub fn build_arhl() -> Opcode {
+    Opcode {
+        name: "SRA HL".to_string(),
+        action: Action::Arhl
+    }
+}
+
+pub fn arhl<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let v = env.state.reg.get16(Reg16::HL);
+    env.state.reg.put_flag(Flag::C, v & 1 != 0);
+    env.state.reg.set16(Reg16::HL, ((v as i16) >> 1) as u16);
+}
+
+pub fn build_rdel() -> Opcode {
+    Opcode {
+        name: "RL DE".to_string(),
+        action: Action::Rdel
+    }
+}
+
+pub fn rdel<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let v = env.state.reg.get16(Reg16::DE);
+    let carry = env.state.reg.get_flag(Flag::C) as u16;
+    let result = (v << 1) | carry;
+    env.state.reg.put_flag(Flag::C, v & 0x8000 != 0);
+    env.state.reg.put_flag(Flag::V, (v ^ result) & 0x8000 != 0);
+    env.state.reg.set16(Reg16::DE, result);
+}
+
 pub fn build_bit_r(n: u8, r: Reg8) -> Opcode {
     Opcode {
         name: format!("BIT {}, {}", n, r),
-        action: Box::new(move |env: &mut Environment| {
-            let v = env.reg8_ext(r);
-            let z = v & (1<<n);
-            env.state.reg.put_flag(Flag::S, (z & 0x80) != 0);
-            env.state.reg.put_flag(Flag::Z, z == 0);
-            env.state.reg.put_flag(Flag::P, z == 0);
-            env.state.reg.set_flag(Flag::H);
-            env.state.reg.clear_flag(Flag::N); // BIT is Z80 only
-
-
-            if r == Reg8::_HL {
-                // Exceptions for (IX+d) TUZD-4-1
-                /* With the BIT n,(IX+d) instructions, the flags behave just
-                like the BIT n,r instruction, except for YF and XF. These are
-                not copied from the result but from something completely
-                different, namely bit 5 and 3 of the high byte of IX+d (so IX
-                plus the displacement).
-                */
-                let address = env.index_address();
-                env.state.reg.update_undocumented_flags((address >> 8) as u8);
-
-                // Exceptions for (HL) TUZD-4-1
-                /* Things get more bizarre with the BIT n,(HL)
-                instruction. Again, except for YF and XF the flags
-                are the same. YF and XF are copied from some sort
-                of internal register */
-                // Not implemented. Just done the same than for (IX+d)
-            } else {
-                env.state.reg.update_undocumented_flags(v); // TUZD-4.1, copy bits from reg
-            }
-        })
+        action: Action::BitR(n, r)
+    }
+}
+
+pub fn bit_r<M: Machine + ?Sized>(env: &mut Environment<M>, n: u8, r: Reg8) {
+    let v = env.reg8_ext(r);
+    let z = v & (1<<n);
+    env.state.reg.put_flag(Flag::S, (z & 0x80) != 0);
+    env.state.reg.put_flag(Flag::Z, z == 0);
+    env.state.reg.put_flag(Flag::P, z == 0);
+    env.state.reg.set_flag(Flag::H);
+    env.state.reg.clear_flag(Flag::N); // BIT is Z80 only
+
+
+    if r == Reg8::_HL {
+        // Exceptions for (IX+d) TUZD-4-1
+        /* With the BIT n,(IX+d) instructions, the flags behave just
+        like the BIT n,r instruction, except for YF and XF. These are
+        not copied from the result but from something completely
+        different, namely bit 5 and 3 of the high byte of IX+d (so IX
+        plus the displacement).
+        */
+        let address = env.index_address();
+        env.state.reg.update_undocumented_flags((address >> 8) as u8);
+
+        // Exceptions for (HL) TUZD-4-1
+        /* Things get more bizarre with the BIT n,(HL)
+        instruction. Again, except for YF and XF the flags
+        are the same. YF and XF are copied from some sort
+        of internal register */
+        // Not implemented. Just done the same than for (IX+d)
+    } else {
+        env.state.reg.update_undocumented_flags(v); // TUZD-4.1, copy bits from reg
     }
 }
 
@@ -122,41 +158,45 @@ pub fn build_set_res_r(bit: u8, r: Reg8, value: bool) -> Opcode {
     let name = if value {"SET"} else {"RES"};
     Opcode {
         name: format!("{} {}, {}", name, bit, r),
-        action: Box::new(move |env: &mut Environment| {
-            let mut v = env.reg8_ext(r);
-            if value {
-                v |= 1<<bit;
-            } else {
-                v &= !(1<<bit);
-            }
+        action: Action::SetResR(bit, r, value)
+    }
+}
 
-            env.set_reg(r, v);
-        })
+pub fn set_res_r<M: Machine + ?Sized>(env: &mut Environment<M>, bit: u8, r: Reg8, value: bool) {
+    let mut v = env.reg8_ext(r);
+    if value {
+        v |= 1<<bit;
+    } else {
+        v &= !(1<<bit);
     }
+
+    env.set_reg(r, v);
 }
 
 pub fn build_indexed_set_res_r(bit: u8, r: Reg8, value: bool) -> Opcode {
     let name = if value {"SET"} else {"RES"};
     Opcode {
         name: format!("LD {}, {} {}, {}", r, name, bit, Reg8::_HL),
-        action: Box::new(move |env: &mut Environment| {
-            /*
-            An instruction such as LD r, RES b, (IX+d) should be interpreted as
-            "attempt to reset bit b of the byte at (IX+d), and copy the result
-            to register r, even the new byte cannot be written at the said
-            address (e.g. when it points to a ROM location).
-            */
-            let mut v = env.reg8_ext(Reg8::_HL);
-            if value {
-                v |= 1<<bit;
-            } else {
-                v &= !(1<<bit);
-            }
-            env.set_reg(Reg8::_HL, v);
-            if r != Reg8::_HL {
-                env.set_reg(r, v);
-            }
-        })
+        action: Action::IndexedSetResR(bit, r, value)
+    }
+}
+
+pub fn indexed_set_res_r<M: Machine + ?Sized>(env: &mut Environment<M>, bit: u8, r: Reg8, value: bool) {
+    /*
+    An instruction such as LD r, RES b, (IX+d) should be interpreted as
+    "attempt to reset bit b of the byte at (IX+d), and copy the result
+    to register r, even the new byte cannot be written at the said
+    address (e.g. when it points to a ROM location).
+    */
+    let mut v = env.reg8_ext(Reg8::_HL);
+    if value {
+        v |= 1<<bit;
+    } else {
+        v &= !(1<<bit);
+    }
+    env.set_reg(Reg8::_HL, v);
+    if r != Reg8::_HL {
+        env.set_reg(r, v);
     }
 }
 
@@ -165,69 +205,138 @@ pub fn build_indexed_set_res_r(bit: u8, r: Reg8, value: bool) -> Opcode {
 pub fn build_cpl() -> Opcode {
     Opcode {
         name: "CPL".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            let mut v = env.state.reg.a();
-            v = !v;
-            env.state.reg.set_a(v);
-
-            env.state.reg.update_hn_flags(true, true);
-            env.state.reg.update_undocumented_flags(v);
-        })
+        action: Action::Cpl
     }
 }
 
+pub fn cpl<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let mut v = env.state.reg.a();
+    v = !v;
+    env.state.reg.set_a(v);
+
+    env.state.reg.update_hn_flags(true, true);
+    env.state.reg.update_undocumented_flags(v);
+}
+
 pub fn build_scf() -> Opcode {
     Opcode {
         name: "SCF".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            let a = env.state.reg.a();
-
-            env.state.reg.set_flag(Flag::C);
-            env.state.reg.update_hn_flags(false, false);
-            env.state.reg.update_undocumented_flags(a);
-        })
+        action: Action::Scf
     }
 }
 
+pub fn scf<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let a = env.state.reg.a();
+
+    env.state.reg.set_flag(Flag::C);
+    env.state.reg.update_hn_flags(false, false);
+    env.state.reg.update_undocumented_flags(a);
+}
+
 pub fn build_ccf() -> Opcode {
     Opcode {
         name: "CCF".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            let a = env.state.reg.a();
-            let c = env.state.reg.get_flag(Flag::C);
-
-            env.state.reg.put_flag(Flag::C, !c);
-            env.state.reg.update_hn_flags(c, false);
-            env.state.reg.update_undocumented_flags(a);
-        })
+        action: Action::Ccf
     }
 }
 
+pub fn ccf<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let a = env.state.reg.a();
+    let c = env.state.reg.get_flag(Flag::C);
+
+    env.state.reg.put_flag(Flag::C, !c);
+    env.state.reg.update_hn_flags(c, false);
+    env.state.reg.update_undocumented_flags(a);
+}
+
 pub fn build_rxd(dir: ShiftDir, name: &str) -> Opcode {
     Opcode {
         name: name.to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            let mut a = env.state.reg.a();
-            let mut phl = env.reg8_ext(Reg8::_HL);
-            // a = 0xWX, phl = 0xYZ
-            match dir {
-                ShiftDir::Left => {
-                    // a= 0xWY, phl = 0xZX
-                    let temp = (a & 0xf0) | (phl >> 4);
-                    phl = (phl << 4) | (a & 0x0f);
-                    a = temp;
-                },
-                ShiftDir::Right => {
-                    // a= 0xWZ, phl = 0xXY
-                    let temp = (a & 0xf0) | (phl & 0x0f);
-                    phl = (a << 4) | (phl >> 4);
-                    a = temp;
-                }
-            }
-            env.state.reg.set_a(a);
-            env.set_reg(Reg8::_HL, phl);
+        action: Action::Rxd(dir)
+    }
+}
+
+pub fn rxd<M: Machine + ?Sized>(env: &mut Environment<M>, dir: ShiftDir) {
+    let mut a = env.state.reg.a();
+    let mut phl = env.reg8_ext(Reg8::_HL);
+    // a = 0xWX, phl = 0xYZ
+    match dir {
+        ShiftDir::Left => {
+            // a= 0xWY, phl = 0xZX
+            let temp = (a & 0xf0) | (phl >> 4);
+            phl = (phl << 4) | (a & 0x0f);
+            a = temp;
+        },
+        ShiftDir::Right => {
+            // a= 0xWZ, phl = 0xXY
+            let temp = (a & 0xf0) | (phl & 0x0f);
+            phl = (a << 4) | (phl >> 4);
+            a = temp;
+        }
+    }
+    env.state.reg.set_a(a);
+    env.set_reg(Reg8::_HL, phl);
+
+    env.state.reg.update_bits_in_flags(a);
+}
+
+// Z80N
+pub fn build_swapnib() -> Opcode {
+    Opcode {
+        name: "SWAPNIB".to_string(),
+        action: Action::Swapnib
+    }
+}
+
+pub fn swapnib<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let a = env.state.reg.a();
+    env.state.reg.set_a(a.rotate_left(4));
+}
 
-            env.state.reg.update_bits_in_flags(a);
-        })
+pub fn build_mirror() -> Opcode {
+    Opcode {
+        name: "MIRROR A".to_string(),
+        action: Action::Mirror
+    }
+}
+
+pub fn mirror<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let a = env.state.reg.a();
+    env.state.reg.set_a(a.reverse_bits());
+}
+
+pub fn build_setae() -> Opcode {
+    Opcode {
+        name: "SETAE".to_string(),
+        action: Action::Setae
     }
 }
+
+pub fn setae<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let e = env.state.reg.get8(Reg8::E);
+    env.state.reg.set_a(0x80 >> (e & 0x07));
+}
+
+const BARREL_SHIFTS: [&str; 5] = ["BSLA", "BSRA", "BSRL", "BSRF", "BRLC"];
+
+pub fn build_barrel_shift(kind: u8) -> Opcode {
+    Opcode {
+        name: format!("{} DE, B", BARREL_SHIFTS[kind as usize]),
+        action: Action::BarrelShift(kind)
+    }
+}
+
+pub fn barrel_shift<M: Machine + ?Sized>(env: &mut Environment<M>, kind: u8) {
+    // Flags not affected
+    let de = env.state.reg.get16(Reg16::DE) as u32;
+    let b = env.state.reg.get8(Reg8::B) as u32;
+    let shift = b & 0x1f;
+    let v = match kind {
+        0 => de << shift, // BSLA
+        1 => ((de as u16 as i16 as i32) >> shift) as u32, // BSRA
+        2 => de >> shift, // BSRL
+        3 => !((!de & 0xffff) >> shift), // BSRF, filled with ones
+        _ => (de << (b & 0x0f)) | (de >> (16 - (b & 0x0f))), // BRLC
+    };
+    env.state.reg.set16(Reg16::DE, v as u16);
+}
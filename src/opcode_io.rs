@@ -1,5 +1,9 @@
+use alloc::format;
+use alloc::string::ToString;
 use super::opcode::*;
 use super::environment::*;
+use super::machine::Machine;
+use super::operators::operator_tst;
 use super::registers::*;
 
 /*
@@ -18,95 +22,111 @@ instructions before.
 pub fn build_out_c_r(r: Reg8) -> Opcode {
     Opcode {
         name: format!("OUT (C), {}", r),
-        action: Box::new(move |env: &mut Environment| {
-            let address = env.state.reg.get16(Reg16::BC);
-            let value = env.state.reg.get8(r);
-            env.port_out(address, value);
-        })
+        action: Action::OutCR(r)
     }
 }
 
+pub fn out_c_r<M: Machine + ?Sized>(env: &mut Environment<M>, r: Reg8) {
+    let address = env.state.reg.get16(Reg16::BC);
+    let value = env.state.reg.get8(r);
+    env.port_out(address, value);
+}
+
 pub fn build_out_c_0() -> Opcode {
     Opcode {
         name: "OUT (C), 0".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            let address = env.state.reg.get16(Reg16::BC);
-            env.port_out(address, 0);
-        })
+        action: Action::OutC0
     }
 }
 
+pub fn out_c_0<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let address = env.state.reg.get16(Reg16::BC);
+    env.port_out(address, 0);
+}
+
 pub fn build_out_n_a() -> Opcode {
     Opcode {
         name: "OUT (n), A".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            let a = env.state.reg.a();
-            let address = ((a as u16) << 8) + env.advance_pc() as u16;
-            env.port_out(address, a);
-        })
+        action: Action::OutNA
     }
 }
 
+pub fn out_n_a<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let a = env.state.reg.a();
+    let address = ((a as u16) << 8) + env.advance_pc() as u16;
+    env.port_out(address, a);
+}
+
 pub fn build_out0_n_r(r: Reg8) -> Opcode {
     Opcode {
         name: format!("OUT0 (n), {}", r),
-        action: Box::new(move |env: &mut Environment| {
-            let address = env.advance_pc() as u16;
-            let data = env.state.reg.get8(r);
-            env.port_out(address, data);
-        })
+        action: Action::Out0NR(r)
     }
 }
 
+pub fn out0_n_r<M: Machine + ?Sized>(env: &mut Environment<M>, r: Reg8) {
+    let address = env.advance_pc() as u16;
+    let data = env.state.reg.get8(r);
+    env.port_out(address, data);
+}
+
 pub fn build_in0_r_n(r: Reg8) -> Opcode {
     Opcode {
         name: format!("IN0 {}, (n)", r),
-        action: Box::new(move |env: &mut Environment| {
-            let address = env.advance_pc() as u16;
-            let data = env.port_in(address);
-            env.state.reg.update_arithmetic_flags(data as u16, data as u16, data as u16, true, false); 
-            env.state.reg.set8(r, data);
-        })
+        action: Action::In0RN(r)
     }
 }
 
+pub fn in0_r_n<M: Machine + ?Sized>(env: &mut Environment<M>, r: Reg8) {
+    let address = env.advance_pc() as u16;
+    let data = env.port_in(address);
+    env.state.reg.update_arithmetic_flags(data as u16, data as u16, data as u16, true, false); 
+    env.state.reg.set8(r, data);
+}
+
 pub fn build_in_r_c(r: Reg8) -> Opcode {
     Opcode {
         name: format!("IN {}, (C)", r),
-        action: Box::new(move |env: &mut Environment| {
-            let address = env.state.reg.get16(Reg16::BC);
-            let value = env.port_in(address);
-            env.state.reg.set8(r, value);
-
-            env.state.reg.update_bits_in_flags(value);
-        })
+        action: Action::InRC(r)
     }
 }
 
+pub fn in_r_c<M: Machine + ?Sized>(env: &mut Environment<M>, r: Reg8) {
+    let address = env.state.reg.get16(Reg16::BC);
+    let value = env.port_in(address);
+    env.state.reg.set8(r, value);
+
+    env.state.reg.update_bits_in_flags(value);
+}
+
 pub fn build_in_0_c() -> Opcode {
     Opcode {
         name: "IN (C)".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            let address = env.state.reg.get16(Reg16::BC);
-            let value = env.port_in(address);
-
-            env.state.reg.update_bits_in_flags(value);
-        })
+        action: Action::In0C
     }
 }
 
+pub fn in_0_c<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let address = env.state.reg.get16(Reg16::BC);
+    let value = env.port_in(address);
+
+    env.state.reg.update_bits_in_flags(value);
+}
+
 pub fn build_in_a_n() -> Opcode {
     Opcode {
         name: "IN A, (n)".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            let a = env.state.reg.a();
-            let address = ((a as u16) << 8) + env.advance_pc() as u16;
-            let value = env.port_in(address);
-            env.state.reg.set_a(value);
-        })
+        action: Action::InAN
     }
 }
 
+pub fn in_a_n<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let a = env.state.reg.a();
+    let address = ((a as u16) << 8) + env.advance_pc() as u16;
+    let value = env.port_in(address);
+    env.state.reg.set_a(value);
+}
+
 /*
 , and the OUTI/OTIR/OUTD/OTDR
 instructions before.
@@ -115,32 +135,34 @@ instructions before.
 pub fn build_in_block((inc, repeat, postfix) : (bool, bool, &'static str)) -> Opcode {
     Opcode {
         name: format!("IN{}", postfix),
-        action: Box::new(move |env: &mut Environment| {
-            // The INI/INIR/IND/INDR instructions use BC after decrementing B
-            let b = env.state.reg.inc_dec8(Reg8::B, false /* decrement */);
-            let address = env.state.reg.get16(Reg16::BC);
-
-            let value = env.port_in(address);
-            // We won't have IX and IY cases to consider
-            env.set_reg(Reg8::_HL, value);
-            if env.state.is_op_long() {
-                env.state.reg.inc_dec24(Reg16::HL, inc);
-            } else {
-                env.state.reg.inc_dec16(Reg16::HL, inc);
-            }
-
-            // TUZD-4.3
-            let mut j = env.state.reg.get8(Reg8::C) as u16;
-            j = if inc {j+1} else {j-1};
-            let k = value as u16 + (j & 0xff);
-            env.state.reg.update_block_flags(value, k, b);
-
-            if repeat && b != 0 {
-                // Back to redo the instruction
-                let pc = env.wrap_address(env.state.pc(), -2);
-                env.state.set_pc(pc);
-            }
-                })
+        action: Action::InBlock(inc, repeat)
+    }
+}
+
+pub fn in_block<M: Machine + ?Sized>(env: &mut Environment<M>, inc: bool, repeat: bool) {
+    // The INI/INIR/IND/INDR instructions use BC after decrementing B
+    let b = env.state.reg.inc_dec8(Reg8::B, false /* decrement */);
+    let address = env.state.reg.get16(Reg16::BC);
+
+    let value = env.port_in(address);
+    // We won't have IX and IY cases to consider
+    env.set_reg(Reg8::_HL, value);
+    if env.state.is_op_long() {
+        env.state.reg.inc_dec24(Reg24::HL, inc);
+    } else {
+        env.state.reg.inc_dec16(Reg16::HL, inc);
+    }
+
+    // TUZD-4.3
+    let mut j = env.state.reg.get8(Reg8::C) as u16;
+    j = if inc {j+1} else {j-1};
+    let k = value as u16 + (j & 0xff);
+    env.state.reg.update_block_flags(value, k, b);
+
+    if repeat && b != 0 {
+        // Back to redo the instruction
+        let pc = env.wrap_address(env.state.pc(), -2);
+        env.state.set_pc(pc);
     }
 }
 
@@ -148,73 +170,183 @@ pub fn build_out_block((inc, repeat, postfix) : (bool, bool, &'static str)) -> O
     let n0 = if repeat {"OT"} else {"OUT"};
     Opcode {
         name: format!("{}{}", n0, postfix),
-        action: Box::new(move |env: &mut Environment| {
-            // the OUTI/OTIR/OUTD/OTDR instructions use BC before decrementing B
-            let address = env.state.reg.get16(Reg16::BC);
-            let b = env.state.reg.inc_dec8(Reg8::B, false /* decrement */);
+        action: Action::OutBlock(inc, repeat)
+    }
+}
 
-            // We won't have IX and IY cases to consider
-            let value = env.reg8_ext(Reg8::_HL);
-            env.port_out(address, value);
-            if env.state.is_op_long() {
-                env.state.reg.inc_dec24(Reg16::HL, inc);
-            } else {
-                env.state.reg.inc_dec16(Reg16::HL, inc);
-            }
+pub fn out_block<M: Machine + ?Sized>(env: &mut Environment<M>, inc: bool, repeat: bool) {
+    // the OUTI/OTIR/OUTD/OTDR instructions use BC before decrementing B
+    let address = env.state.reg.get16(Reg16::BC);
+    let b = env.state.reg.inc_dec8(Reg8::B, false /* decrement */);
+
+    // We won't have IX and IY cases to consider
+    let value = env.reg8_ext(Reg8::_HL);
+    env.port_out(address, value);
+    if env.state.is_op_long() {
+        env.state.reg.inc_dec24(Reg24::HL, inc);
+    } else {
+        env.state.reg.inc_dec16(Reg16::HL, inc);
+    }
 
-            // TUZD-4.3
-            let k = value as u16 + env.state.reg.get8(Reg8::L) as u16;
-            env.state.reg.update_block_flags(value, k, b);
+    // TUZD-4.3
+    let k = value as u16 + env.state.reg.get8(Reg8::L) as u16;
+    env.state.reg.update_block_flags(value, k, b);
 
-            if repeat && b != 0 {
-                // Back to redo the instruction
-                let pc = env.wrap_address(env.state.pc(), -2);
-                env.state.set_pc(pc);
-            }
-        })
+    if repeat && b != 0 {
+        // Back to redo the instruction
+        let pc = env.wrap_address(env.state.pc(), -2);
+        env.state.set_pc(pc);
     }
 }
 
 pub fn build_otirx_or_otdrx(inc: bool) -> Opcode {
     Opcode {
         name: format!("OT{}RX", if inc { 'I' } else { 'D' }),
-        action: Box::new(move |env: &mut Environment| {
-            let value = env.reg8_ext(Reg8::_HL);
-            let address = env.state.reg.get16(Reg16::DE);
-            env.sys.use_cycles(1);
-
-            let bc = if env.state.is_op_long() {
-                env.state.reg.inc_dec24(Reg16::HL, inc);
-                env.state.reg.inc_dec24(Reg16::BC, false /*decrement*/)
-            } else {
-                env.state.reg.inc_dec16(Reg16::HL, inc);
-                env.state.reg.inc_dec16(Reg16::BC, false /*decrement*/)
-            };
-
-            if env.state.cached_instruction {
-                env.sys.use_cycles(-2);
-            }
-
-            env.port_out(address, value);
-
-            // TUZD-4.3
-            env.state.reg.put_flag(Flag::Z, bc == 0);
-            env.state.reg.put_flag(Flag::N, if value & 0x80 == 0x80 { true } else { false });
-
-            if bc != 0 {
-                // Back to redo the instruction
-                let instruction_len = match env.state.sz_prefix {
-                        crate::state::SizePrefix::None => 2,
-                        _ => 3
-                };
-                let pc = env.wrap_address(env.state.pc(), -instruction_len);
-                env.state.set_pc(pc);
-                // and the size prefix is cached if present
-                if let crate::state::SizePrefix::None = env.state.sz_prefix {
-                } else {
-                    env.sys.use_cycles(-1);
-                }
-            }
-        })
+        action: Action::OtirxOrOtdrx(inc)
     }
 }
+
+pub fn otirx_or_otdrx<M: Machine + ?Sized>(env: &mut Environment<M>, inc: bool) {
+    let value = env.reg8_ext(Reg8::_HL);
+    let address = env.state.reg.get16(Reg16::DE);
+    env.use_cycles(1);
+
+    let bc = if env.state.is_op_long() {
+        env.state.reg.inc_dec24(Reg24::HL, inc);
+        env.state.reg.inc_dec24(Reg24::BC, false /*decrement*/)
+    } else {
+        env.state.reg.inc_dec16(Reg16::HL, inc);
+        env.state.reg.inc_dec16(Reg16::BC, false /*decrement*/)
+    };
+
+    if env.state.cached_instruction {
+        env.use_cycles(-2);
+    }
+
+    env.port_out(address, value);
+
+    // TUZD-4.3
+    env.state.reg.put_flag(Flag::Z, bc == 0);
+    env.state.reg.put_flag(Flag::N, value & 0x80 == 0x80);
+
+    if bc != 0 {
+        // Back to redo the instruction
+        let instruction_len = match env.state.sz_prefix {
+                crate::state::SizePrefix::None => 2,
+                _ => 3
+        };
+        let pc = env.wrap_address(env.state.pc(), -instruction_len);
+        env.state.set_pc(pc);
+        // and the size prefix is cached if present
+        if let crate::state::SizePrefix::None = env.state.sz_prefix {
+        } else {
+            env.use_cycles(-1);
+        }
+    }
+}
+
+pub fn build_tstio_n() -> Opcode {
+    Opcode {
+        name: "TSTIO n".to_string(),
+        action: Action::TstIoN
+    }
+}
+
+pub fn tstio_n<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    // The port address is C, with 0 on the high byte
+    let mask = env.advance_pc();
+    let address = env.state.reg.get8(Reg8::C) as u16;
+    let value = env.port_in(address);
+    operator_tst(&mut env.state.reg, value, mask);
+}
+
+pub fn build_otim((inc, repeat, postfix) : (bool, bool, &'static str)) -> Opcode {
+    Opcode {
+        name: format!("OT{}M{}", &postfix[..1], &postfix[1..]),
+        action: Action::Otim(inc, repeat)
+    }
+}
+
+pub fn otim<M: Machine + ?Sized>(env: &mut Environment<M>, inc: bool, repeat: bool) {
+    // Like OUTI/OUTD, with C incremented or decremented as HL and the
+    // port address with 0 on the high byte
+    let c = env.state.reg.get8(Reg8::C);
+    let address = c as u16;
+    let value = env.reg8_ext(Reg8::_HL);
+    env.port_out(address, value);
+    env.state.reg.inc_dec16(Reg16::HL, inc);
+    env.state.reg.set8(Reg8::C, if inc { c.wrapping_add(1) } else { c.wrapping_sub(1) });
+    let b = env.state.reg.inc_dec8(Reg8::B, false /* decrement */);
+
+    env.state.reg.put_flag(Flag::S, b & 0x80 != 0);
+    env.state.reg.put_flag(Flag::Z, b == 0);
+    env.state.reg.put_flag(Flag::N, value & 0x80 != 0);
+
+    if repeat && b != 0 {
+        // Back to redo the instruction
+        let pc = env.wrap_address(env.state.pc(), -2);
+        env.state.set_pc(pc);
+    }
+}
+
+pub fn build_rim() -> Opcode {
+    Opcode {
+        name: "RIM".to_string(),
+        action: Action::Rim
+    }
+}
+
+pub fn rim<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let enabled = env.state.reg.get_iff1();
+    if let Some(i8085) = env.state.i8085.as_ref() {
+        let value = i8085.rim(enabled);
+        env.state.reg.set_a(value);
+    }
+}
+
+pub fn build_sim() -> Opcode {
+    Opcode {
+        name: "SIM".to_string(),
+        action: Action::Sim
+    }
+}
+
+pub fn sim<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let value = env.state.reg.a();
+    if let Some(i8085) = env.state.i8085.as_mut() {
+        i8085.sim(value);
+    }
+}
+
+// Z80N
+pub fn build_outinb() -> Opcode {
+    Opcode {
+        name: "OUTINB".to_string(),
+        action: Action::Outinb
+    }
+}
+
+pub fn outinb<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    // OUTI without changing B nor the flags
+    let address = env.state.reg.get16(Reg16::BC);
+    let value = env.reg8_ext(Reg8::_HL);
+    env.port_out(address, value);
+    env.state.reg.inc_dec16(Reg16::HL, true);
+}
+
+pub fn build_nextreg(immediate: bool) -> Opcode {
+    Opcode {
+        name: if immediate { "NEXTREG n, n" } else { "NEXTREG n, A" }.to_string(),
+        action: Action::Nextreg(immediate)
+    }
+}
+
+pub fn nextreg<M: Machine + ?Sized>(env: &mut Environment<M>, immediate: bool) {
+    let register = env.advance_pc();
+    let value = if immediate {
+        env.advance_pc()
+    } else {
+        env.state.reg.a()
+    };
+    env.sys.nextreg(register, value);
+}
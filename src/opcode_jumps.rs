@@ -1,5 +1,8 @@
+use alloc::format;
+use alloc::string::ToString;
 use super::opcode::*;
 use super::environment::*;
+use super::machine::Machine;
 use super::registers::*;
 use super::state::SizePrefix;
 
@@ -7,56 +10,62 @@ use super::state::SizePrefix;
 pub fn build_djnz() -> Opcode {
     Opcode {
         name: "DJNZ l".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            let offset = env.advance_pc();
-            let b = env.state.reg.get8(Reg8::B).wrapping_add(0xff /* -1 */);
-            env.state.reg.set8(Reg8::B, b);
-            if b != 0 {
-                // Condition not met
-                env.sys.use_cycles(2);
-                relative_jump(env, offset);
-            }
-        })
+        action: Action::Djnz
+    }
+}
+
+pub fn djnz<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let offset = env.advance_pc();
+    let b = env.state.reg.get8(Reg8::B).wrapping_add(0xff /* -1 */);
+    env.state.reg.set8(Reg8::B, b);
+    if b != 0 {
+        // Condition not met
+        env.use_cycles(2);
+        relative_jump(env, offset);
     }
 }
 
 pub fn build_jr_unconditional() -> Opcode {
     Opcode {
         name: "JR l".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            let offset = env.advance_pc();
-            env.sys.use_cycles(1);
-            relative_jump(env, offset);
-        })
+        action: Action::JrUnconditional
     }
 }
 
+pub fn jr_unconditional<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let offset = env.advance_pc();
+    env.use_cycles(1);
+    relative_jump(env, offset);
+}
+
 pub fn build_jr_eq((flag, value, name): (Flag, bool, &str)) -> Opcode {
     Opcode {
         name: format!("JR {}, l", name),
-        action: Box::new(move |env: &mut Environment| {
-            let offset = env.advance_pc();
-            if env.state.reg.get_flag(flag) == value {
-                env.sys.use_cycles(2);
-                relative_jump(env, offset);
-            }
-        })
+        action: Action::JrEq(flag, value)
+    }
+}
+
+pub fn jr_eq<M: Machine + ?Sized>(env: &mut Environment<M>, flag: Flag, value: bool) {
+    let offset = env.advance_pc();
+    if env.state.reg.get_flag(flag) == value {
+        env.use_cycles(2);
+        relative_jump(env, offset);
     }
 }
 
 
-fn relative_jump(env: &mut Environment, offset: u8) {
+fn relative_jump<M: Machine + ?Sized>(env: &mut Environment<M>, offset: u8) {
     let mut pc = env.state.pc();
     pc = env.wrap_address(pc, offset as i8 as i32);
     env.state.set_pc(pc);
 }
 
-fn handle_jump_adl_state(env: &mut Environment) {
+fn handle_jump_adl_state<M: Machine + ?Sized>(env: &mut Environment<M>) {
     if env.state.reg.adl {
         match env.state.sz_prefix {
             SizePrefix::SIS => { env.state.reg.adl = false },
             SizePrefix::LIS | SizePrefix::SIL => {
-                eprintln!("Invalid size prefix for ADL=1 with jump at PC=${:x}", env.state.pc());
+                env.sys.diagnostic(format_args!("Invalid size prefix for ADL=1 with jump at PC=${:x}", env.state.pc()));
             }
             SizePrefix::LIL |
             SizePrefix::None => {}
@@ -65,7 +74,7 @@ fn handle_jump_adl_state(env: &mut Environment) {
         match env.state.sz_prefix {
             SizePrefix::LIL => { env.state.reg.adl = true },
             SizePrefix::LIS | SizePrefix::SIL => {
-                eprintln!("Invalid size prefix for ADL=0 with jump at PC=${:x}", env.state.pc());
+                env.sys.diagnostic(format_args!("Invalid size prefix for ADL=0 with jump at PC=${:x}", env.state.pc()));
             },
             SizePrefix::SIS | SizePrefix::None => {}
         }
@@ -76,41 +85,47 @@ fn handle_jump_adl_state(env: &mut Environment) {
 pub fn build_jp_unconditional() -> Opcode {
     Opcode {
         name: "JP nn".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            let address = env.advance_immediate_16mbase_or_24();
-            handle_jump_adl_state(env);
-            env.sys.use_cycles(1);
-            env.state.set_pc(address);
-        })
+        action: Action::JpUnconditional
     }
 }
 
+pub fn jp_unconditional<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let address = env.advance_immediate_16mbase_or_24();
+    handle_jump_adl_state(env);
+    env.use_cycles(1);
+    env.state.set_pc(address);
+}
+
 pub fn build_jp_eq((flag, value, name): (Flag, bool, &str)) -> Opcode {
     Opcode {
         name: format!("JP {}, nn", name),
-        action: Box::new(move |env: &mut Environment| {
-            let address = env.advance_immediate_16mbase_or_24();
-            if env.state.reg.get_flag(flag) == value {
-                env.sys.use_cycles(1);
-                env.state.set_pc(address);
-            }
-        })
+        action: Action::JpEq(flag, value)
+    }
+}
+
+pub fn jp_eq<M: Machine + ?Sized>(env: &mut Environment<M>, flag: Flag, value: bool) {
+    let address = env.advance_immediate_16mbase_or_24();
+    if env.state.reg.get_flag(flag) == value {
+        env.use_cycles(1);
+        env.state.set_pc(address);
     }
 }
 
 pub fn build_jp_hl() -> Opcode {
     Opcode {
         name: "JP (HL)".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            // Note: no displacement added to the index
-            let address = env.index_value();
-            env.sys.use_cycles(1);
-            env.state.set_pc(address);
-        })
+        action: Action::JpHl
     }
 }
 
-fn handle_call_size_prefix(env: &mut Environment) {
+pub fn jp_hl<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    // Note: no displacement added to the index
+    let address = env.index_value();
+    env.use_cycles(1);
+    env.state.set_pc(address);
+}
+
+fn handle_call_size_prefix<M: Machine + ?Sized>(env: &mut Environment<M>) {
     let pc = env.state.pc();
 
     if env.state.reg.adl {
@@ -132,7 +147,7 @@ fn handle_call_size_prefix(env: &mut Environment) {
             }
             prefix => {
                 env.push(pc); // 3 bytes onto SPL
-                eprintln!("invalid call size prefix for ADL=1: {}", prefix);
+                env.sys.diagnostic(format_args!("invalid call size prefix for ADL=1: {}", prefix));
             }
         }
     } else {
@@ -156,7 +171,7 @@ fn handle_call_size_prefix(env: &mut Environment) {
             SizePrefix::LIS => {
                 env.push_byte_spl((pc >> 8) as u8);
                 env.push_byte_spl(pc as u8);
-                eprintln!("invalid call size prefix for ADL=0: LIS");
+                env.sys.diagnostic(format_args!("invalid call size prefix for ADL=0: LIS"));
             }
         }
     }
@@ -166,28 +181,32 @@ fn handle_call_size_prefix(env: &mut Environment) {
 pub fn build_call() -> Opcode {
     Opcode {
         name: "CALL nn".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            let address = env.advance_immediate16or24();
-            handle_call_size_prefix(env);
-            env.state.set_pc(address);
-        })
+        action: Action::Call
     }
 }
 
+pub fn call<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let address = env.advance_immediate16or24();
+    handle_call_size_prefix(env);
+    env.state.set_pc(address);
+}
+
 pub fn build_call_eq((flag, value, name): (Flag, bool, &str)) -> Opcode {
     Opcode {
         name: format!("CALL {}, nn", name),
-        action: Box::new(move |env: &mut Environment| {
-            let address = env.advance_immediate_16mbase_or_24();
-            if env.state.reg.get_flag(flag) == value {
-                handle_call_size_prefix(env);
-                env.state.set_pc(address);
-            }
-        })
+        action: Action::CallEq(flag, value)
     }
 }
 
-fn handle_rst_size_prefix(env: &mut Environment, vec: u32) {
+pub fn call_eq<M: Machine + ?Sized>(env: &mut Environment<M>, flag: Flag, value: bool) {
+    let address = env.advance_immediate_16mbase_or_24();
+    if env.state.reg.get_flag(flag) == value {
+        handle_call_size_prefix(env);
+        env.state.set_pc(address);
+    }
+}
+
+fn handle_rst_size_prefix<M: Machine + ?Sized>(env: &mut Environment<M>, vec: u32) {
     let pc = env.state.pc();
 
     if env.state.reg.adl {
@@ -211,7 +230,7 @@ fn handle_rst_size_prefix(env: &mut Environment, vec: u32) {
                 env.state.reg.pc = vec;
             }
             SizePrefix::SIS => {
-                eprintln!("invalid rst size prefix");
+                env.sys.diagnostic(format_args!("invalid rst size prefix"));
             }
         }
     } else {
@@ -240,10 +259,44 @@ fn handle_rst_size_prefix(env: &mut Environment, vec: u32) {
 pub fn build_rst(d: u8) -> Opcode {
     Opcode {
         name: format!("RST {:02x}h", d),
-        action: Box::new(move |env: &mut Environment| {
-            let address = d as u32;
-            handle_rst_size_prefix(env, address);
-        })
+        action: Action::Rst(d)
+    }
+}
+
+pub fn rst<M: Machine + ?Sized>(env: &mut Environment<M>, d: u8) {
+    let address = d as u32;
+    handle_rst_size_prefix(env, address);
+}
+
+pub fn build_trap(third_byte: bool) -> Opcode {
+    Opcode {
+        name: "TRAP".to_string(),
+        action: Action::Trap(third_byte)
+    }
+}
+
+pub fn trap<M: Machine + ?Sized>(env: &mut Environment<M>, third_byte: bool) {
+    // Z180 undefined opcode. The PC pushed is the address of the
+    // instruction plus 1, or plus 2 if the undefined byte is the third.
+    let pc = env.wrap_address(env.state.pc(), if third_byte { -2 } else { -1 });
+    env.state.set_pc(pc);
+    if let Some(z180) = env.state.z180.as_mut() {
+        z180.trap(third_byte);
+    }
+    env.subroutine_call(0x0000);
+}
+
+pub fn build_rstv() -> Opcode {
+    Opcode {
+        name: "RST V, 40h".to_string(),
+        action: Action::Rstv
+    }
+}
+
+pub fn rstv<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    // 8085 undocumented, RST 40h if the overflow flag is set
+    if env.state.reg.get_flag(Flag::V) {
+        env.subroutine_call(0x0040);
     }
 }
 
@@ -252,44 +305,68 @@ pub fn build_rst(d: u8) -> Opcode {
 pub fn build_ret() -> Opcode {
     Opcode {
         name: "RET".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            env.sys.use_cycles(2);
-            env.subroutine_return();
-        })
+        action: Action::Ret
     }
 }
 
+pub fn ret<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    env.use_cycles(2);
+    env.subroutine_return();
+}
+
 pub fn build_reti() -> Opcode {
     Opcode {
         name: "RETI".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            env.sys.use_cycles(2);
-            env.subroutine_return();
-        })
+        action: Action::Reti
     }
 }
 
+pub fn reti<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    env.state.reti_executed += 1;
+    ret(env);
+}
+
 pub fn build_retn() -> Opcode {
     Opcode {
         name: "RETN".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            env.sys.use_cycles(2);
-            env.subroutine_return();
-            env.state.reg.end_nmi();
-        })
+        action: Action::Retn
     }
 }
 
+pub fn retn<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    env.use_cycles(2);
+    env.subroutine_return();
+    env.state.reg.end_nmi();
+}
+
 pub fn build_ret_eq((flag, value, name): (Flag, bool, &str)) -> Opcode {
     Opcode {
         name: format!("RET {}", name),
-        action: Box::new(move |env: &mut Environment| {
-            if env.state.reg.get_flag(flag) == value {
-                env.sys.use_cycles(2);
-                env.subroutine_return();
-            } else {
-                env.sys.use_cycles(1);
-            }
-        })
+        action: Action::RetEq(flag, value)
+    }
+}
+
+pub fn ret_eq<M: Machine + ?Sized>(env: &mut Environment<M>, flag: Flag, value: bool) {
+    if env.state.reg.get_flag(flag) == value {
+        env.use_cycles(2);
+        env.subroutine_return();
+    } else {
+        env.use_cycles(1);
     }
 }
+
+pub fn build_jp_c() -> Opcode {
+    Opcode {
+        name: "JP (C)".to_string(),
+        action: Action::JpC
+    }
+}
+
+pub fn jp_c<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    // Z80N, jumps to a 64 byte slot of the 16K of PC, with the value of
+    // the port BC
+    let address = env.state.reg.get16(Reg16::BC);
+    let value = env.port_in(address) as u32;
+    let pc = env.state.pc();
+    env.state.set_pc((pc & !0x3fff) | (value << 6));
+}
@@ -1,5 +1,9 @@
+use alloc::format;
+use alloc::string::ToString;
 use super::opcode::*;
 use super::environment::*;
+use super::machine::Machine;
+use super::operators::operator_inc;
 use super::registers::*;
 
 /*
@@ -57,101 +61,113 @@ pub fn build_ld_r_r(dst: Reg8, src: Reg8, _special: bool) -> Opcode {
         // Faster version
         Opcode {
             name: format!("LD {}, {}", dst, src),
-            action: Box::new(move |env: &mut Environment| {
-                let value = env.state.reg.get8(src);
-                env.state.reg.set8(dst, value);
-
-                if dst == Reg8::A && (src == Reg8::R || src == Reg8::I) {
-                    // special case. does set some flags
-                    env.state.reg.put_flag(Flag::N, false);
-                    env.state.reg.put_flag(Flag::H, false);
-                    env.state.reg.put_flag(Flag::Z, value == 0);
-                    env.state.reg.put_flag(Flag::S, (value as i8) < 0);
-                    env.state.reg.put_flag(Flag::P, env.state.reg.iff2);
-                }
-            })
+            action: Action::LdRR(dst, src)
         }
     } else {
         // Full version
         Opcode {
             name: format!("LD {}, {}", dst, src),
-            action: Box::new(move |env: &mut Environment| {
-                /*
-                If the next opcode makes use of (HL), it will be replaced by (IX+d), and any other
-                instances of H and L will be unaffected. Therefore, an instruction like LD IXH, (IX+d)
-                does not exist, but LD H, (IX+d) does. It's impossible for both src and dst to be (HL)
-                */
-                let value = if dst == Reg8::_HL {
-                    env.state.reg.get8(src)
-                } else {
-                    env.reg8_ext(src)
-                };
-                if src == Reg8::_HL {
-                    env.state.reg.set8(dst, value);
-                } else {
-                    env.set_reg(dst, value);
-                }
-            })
+            action: Action::LdRRExt(dst, src)
         }
     }
 }
 
+pub fn ld_r_r<M: Machine + ?Sized>(env: &mut Environment<M>, dst: Reg8, src: Reg8) {
+    let value = env.state.reg.get8(src);
+    env.state.reg.set8(dst, value);
+
+    if dst == Reg8::A && (src == Reg8::R || src == Reg8::I) {
+        // special case. does set some flags
+        env.state.reg.put_flag(Flag::N, false);
+        env.state.reg.put_flag(Flag::H, false);
+        env.state.reg.put_flag(Flag::Z, value == 0);
+        env.state.reg.put_flag(Flag::S, (value as i8) < 0);
+        env.state.reg.put_flag(Flag::P, env.state.reg.iff2);
+    }
+}
+
+pub fn ld_r_r_ext<M: Machine + ?Sized>(env: &mut Environment<M>, dst: Reg8, src: Reg8) {
+    /*
+    If the next opcode makes use of (HL), it will be replaced by (IX+d), and any other
+    instances of H and L will be unaffected. Therefore, an instruction like LD IXH, (IX+d)
+    does not exist, but LD H, (IX+d) does. It's impossible for both src and dst to be (HL)
+    */
+    let value = if dst == Reg8::_HL {
+        env.state.reg.get8(src)
+    } else {
+        env.reg8_ext(src)
+    };
+    if src == Reg8::_HL {
+        env.state.reg.set8(dst, value);
+    } else {
+        env.set_reg(dst, value);
+    }
+}
+
 pub fn build_ld_r_n(r: Reg8) -> Opcode {
     Opcode {
         name: format!("LD {}, n", r),
-        action: Box::new(move |env: &mut Environment| {
-            let value = env.advance_pc();
-            env.set_reg(r, value);
-        })
+        action: Action::LdRN(r)
     }
 }
 
+pub fn ld_r_n<M: Machine + ?Sized>(env: &mut Environment<M>, r: Reg8) {
+    let value = env.advance_pc();
+    env.set_reg(r, value);
+}
+
 pub fn build_ld_a_prr(rr: Reg16) -> Opcode {
     // rr can be only BC or DE
     Opcode {
         name: format!("LD A, ({:?})", rr),
-        action: Box::new(move |env: &mut Environment| {
-            let address = env.reg16mbase_or_24(rr);
-            let value = env.peek(address);
-            env.state.reg.set_a(value);
-        })
+        action: Action::LdAPrr(rr)
     }
 }
 
+pub fn ld_a_prr<M: Machine + ?Sized>(env: &mut Environment<M>, rr: Reg16) {
+    let address = env.reg16mbase_or_24(rr);
+    let value = env.peek(address);
+    env.state.reg.set_a(value);
+}
+
 pub fn build_ld_a_pnn() -> Opcode {
     Opcode {
         name: "LD A, (nn)".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            let address = env.advance_immediate_16mbase_or_24();
-            let value = env.peek(address);
-            env.state.reg.set_a(value);
-        })
+        action: Action::LdAPnn
     }
 }
 
+pub fn ld_a_pnn<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let address = env.advance_immediate_16mbase_or_24();
+    let value = env.peek(address);
+    env.state.reg.set_a(value);
+}
+
 pub fn build_ld_prr_a(rr: Reg16) -> Opcode {
     // rr can be only BC or DE
     Opcode {
         name: format!("LD ({:?}), A", rr),
-        action: Box::new(move |env: &mut Environment| {
-            let value = env.state.reg.a();
-            let address = env.reg16mbase_or_24(rr);
-            env.poke(address, value);
-        })
+        action: Action::LdPrrA(rr)
     }
-    
+}
+
+pub fn ld_prr_a<M: Machine + ?Sized>(env: &mut Environment<M>, rr: Reg16) {
+    let value = env.state.reg.a();
+    let address = env.reg16mbase_or_24(rr);
+    env.poke(address, value);
 }
 
 pub fn build_ld_pnn_a() -> Opcode {
     Opcode {
         name: "LD (nn), A".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            let value = env.state.reg.a();
-            let address = env.advance_immediate_16mbase_or_24();
-            env.poke(address, value);
-        })
+        action: Action::LdPnnA
     }
-    
+}
+
+pub fn ld_pnn_a<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let value = env.state.reg.a();
+    let address = env.advance_immediate_16mbase_or_24();
+    env.poke(address, value);
 }
 
 
@@ -159,244 +175,360 @@ pub fn build_ld_pnn_a() -> Opcode {
 pub fn build_ld_rr_nn(rr: Reg16) -> Opcode {
     Opcode {
         name: format!("LD {:?}, nn", rr),
-        action: Box::new(move |env: &mut Environment| {
-            let value: u32 = env.advance_immediate16or24();
-            env.set_reg16or24(rr, value);
-        })
+        action: Action::LdRrNn(rr)
     }
 }
 
+pub fn ld_rr_nn<M: Machine + ?Sized>(env: &mut Environment<M>, rr: Reg16) {
+    let value: u32 = env.advance_immediate16or24();
+    env.set_reg16or24(rr, value);
+}
+
 pub fn build_ld_sp_hl() -> Opcode {
     Opcode {
         name: "LD SP, HL".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            let value = env.reg16or24_ext(Reg16::HL);
-            if env.state.is_op_long() {
-                env.set_reg24(Reg16::SP, value);
-            } else {
-                env.set_reg16(Reg16::SP, value as u16);
-            }
-        })
+        action: Action::LdSpHl
+    }
+}
+
+pub fn ld_sp_hl<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let value = env.reg16or24_ext(Reg16::HL);
+    if env.state.is_op_long() {
+        env.set_reg24(Reg16::SP, value);
+    } else {
+        env.set_reg16(Reg16::SP, value as u16);
     }
 }
 
 pub fn build_ld_pnn_rr(rr: Reg16, _fast: bool) -> Opcode {
     Opcode {
         name: format!("LD (nn), {:?}", rr),
-        action: Box::new(move |env: &mut Environment| {
-            let address = env.advance_immediate_16mbase_or_24();
-            let value = env.reg16or24_ext(rr);
-            if env.state.is_op_long() {
-                env.poke24(address, value);
-            } else {
-                env.poke16(address, value as u16);
-            }
-        })
+        action: Action::LdPnnRr(rr)
+    }
+}
+
+pub fn ld_pnn_rr<M: Machine + ?Sized>(env: &mut Environment<M>, rr: Reg16) {
+    let address = env.advance_immediate_16mbase_or_24();
+    let value = env.reg16or24_ext(rr);
+    if env.state.is_op_long() {
+        env.poke24(address, value);
+    } else {
+        env.poke16(address, value as u16);
     }
 }
 
 pub fn build_ld_rr_pnn(rr: Reg16, _fast: bool) -> Opcode {
     Opcode {
         name: format!("LD {:?}, (nn)", rr),
-        action: Box::new(move |env: &mut Environment| {
-            let address = env.advance_immediate_16mbase_or_24();
-            if env.state.is_op_long() {
-                let value = env.peek24(address);
-                env.set_reg24(rr, value);
-            } else {
-                let value = env.peek16(address);
-                env.set_reg16(rr, value);
-            }
-        })
+        action: Action::LdRrPnn(rr)
+    }
+}
+
+pub fn ld_rr_pnn<M: Machine + ?Sized>(env: &mut Environment<M>, rr: Reg16) {
+    let address = env.advance_immediate_16mbase_or_24();
+    if env.state.is_op_long() {
+        let value = env.peek24(address);
+        env.set_reg24(rr, value);
+    } else {
+        let value = env.peek16(address);
+        env.set_reg16(rr, value);
     }
 }
 
+pub fn build_shlx() -> Opcode {
+    Opcode {
+        name: "LD (DE), HL".to_string(),
+        action: Action::Shlx
+    }
+}
+
+pub fn shlx<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let address = env.state.reg.get16_mbase(Reg16::DE);
+    let value = env.state.reg.get16(Reg16::HL);
+    env.poke16(address, value);
+}
+
+pub fn build_lhlx() -> Opcode {
+    Opcode {
+        name: "LD HL, (DE)".to_string(),
+        action: Action::Lhlx
+    }
+}
+
+pub fn lhlx<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let address = env.state.reg.get16_mbase(Reg16::DE);
+    let value = env.peek16(address);
+    env.state.reg.set16(Reg16::HL, value);
+}
+
 pub fn build_ex_af() -> Opcode {
     Opcode {
         name: "EX AF, AF'".to_string(),
-        action: Box::new(|env: &mut Environment| {
-            env.state.reg.swap16(Reg16::AF);
-        })
+        action: Action::ExAf
     }
 }
 
 pub fn build_exx() -> Opcode {
     Opcode {
         name: "EXX".to_string(),
-        action: Box::new(|env: &mut Environment| {
-            env.state.reg.swap24(Reg16::BC);
-            env.state.reg.swap24(Reg16::DE);
-            env.state.reg.swap24(Reg16::HL); // NO IX, IY variant
-        })
+        action: Action::Exx
     }
 }
 
+pub fn exx<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    env.state.reg.swap24(Reg24::BC);
+    env.state.reg.swap24(Reg24::DE);
+    env.state.reg.swap24(Reg24::HL); // NO IX, IY variant
+}
+
 pub fn build_ex_de_hl() -> Opcode {
     Opcode {
         name: "EX DE, HL".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            if env.state.is_op_long() {
-                let temp = env.state.reg.get24(Reg16::HL); // No IX/IY variant
-                env.state.reg.set24(Reg16::HL, env.state.reg.get24(Reg16::DE));
-                env.state.reg.set24(Reg16::DE, temp);
-            } else {
-                let temp = env.state.reg.get16(Reg16::HL); // No IX/IY variant
-                env.state.reg.set16(Reg16::HL, env.state.reg.get16(Reg16::DE));
-                env.state.reg.set16(Reg16::DE, temp);
-            }
-        })         
+        action: Action::ExDeHl
+    }
+}
+
+pub fn ex_de_hl<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    if env.state.is_op_long() {
+        let temp = env.state.reg.get24(Reg24::HL); // No IX/IY variant
+        env.state.reg.set24(Reg24::HL, env.state.reg.get24(Reg24::DE));
+        env.state.reg.set24(Reg24::DE, temp);
+    } else {
+        let temp = env.state.reg.get16(Reg16::HL); // No IX/IY variant
+        env.state.reg.set16(Reg16::HL, env.state.reg.get16(Reg16::DE));
+        env.state.reg.set16(Reg16::DE, temp);
     }
 }
 
 pub fn build_ex_psp_hl() -> Opcode {
     Opcode {
         name: "EX (SP), HL".to_string(),
-        action: Box::new(move |env: &mut Environment| {
-            let address = env.state.sp();
-            let temp = env.reg16or24_ext(Reg16::HL);
-            if env.state.is_op_long() {
-                env.set_reg24(Reg16::HL, env.peek24(address));
-                env.poke24(address, temp);
-            } else {
-                env.set_reg16_preserve_17_to_24(Reg16::HL, env.peek16(address));
-                env.poke16(address, temp as u16);
-            }
-        })         
+        action: Action::ExPspHl
+    }
+}
+
+pub fn ex_psp_hl<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    let address = env.state.sp();
+    let temp = env.reg16or24_ext(Reg16::HL);
+    if env.state.is_op_long() {
+        env.set_reg24(Reg16::HL, env.peek24(address));
+        env.poke24(address, temp);
+    } else {
+        env.set_reg16_preserve_17_to_24(Reg16::HL, env.peek16(address));
+        env.poke16(address, temp as u16);
     }
 }
 
 pub fn build_ld_block((inc, repeat, postfix) : (bool, bool, &'static str)) -> Opcode {
     Opcode {
         name: format!("LD{}", postfix),
-        action: Box::new(move |env: &mut Environment| {
-            let value = env.reg8_ext(Reg8::_HL);
-            let address = env.reg16mbase_or_24(Reg16::DE);
-            env.poke(address, value);
-            env.sys.use_cycles(1);
-
-            let bc = if env.state.is_op_long() {
-                env.state.reg.inc_dec24(Reg16::DE, inc);
-                env.state.reg.inc_dec24(Reg16::HL, inc);
-                env.state.reg.inc_dec24(Reg16::BC, false /*decrement*/)
-            } else {
-                env.state.reg.inc_dec16(Reg16::DE, inc);
-                env.state.reg.inc_dec16(Reg16::HL, inc);
-                env.state.reg.inc_dec16(Reg16::BC, false /*decrement*/)
-            };
-
-            // TUZD-4.2
-            let n = value.wrapping_add(env.state.reg.a());
-            env.state.reg.update_undocumented_flags_block(n);
-            env.state.reg.clear_flag(Flag::N);
-            env.state.reg.clear_flag(Flag::H);
-            env.state.reg.put_flag(Flag::P, bc != 0);
-            // S, Z and C unchanged. What about N?
-
-            if repeat && bc != 0 {
-                // Back to redo the instruction
-                let instruction_len = match env.state.sz_prefix {
-                        crate::state::SizePrefix::None => 2,
-                        _ => 3
-                };
-                let pc = env.wrap_address(env.state.pc(), -instruction_len);
-                env.state.set_pc(pc);
-                // all but one repeat gets the 2-byte opcode cached
-                env.sys.use_cycles(-2);
-                // and the size prefix is cached if present
-                if let crate::state::SizePrefix::None = env.state.sz_prefix {
-                } else {
-                    env.sys.use_cycles(-1);
-                }
-            }
-        })         
+        action: Action::LdBlock(inc, repeat)
+    }
+}
+
+pub fn ld_block<M: Machine + ?Sized>(env: &mut Environment<M>, inc: bool, repeat: bool) {
+    let value = env.reg8_ext(Reg8::_HL);
+    let address = env.reg16mbase_or_24(Reg16::DE);
+    env.poke(address, value);
+    env.use_cycles(1);
+
+    let bc = if env.state.is_op_long() {
+        env.state.reg.inc_dec24(Reg24::DE, inc);
+        env.state.reg.inc_dec24(Reg24::HL, inc);
+        env.state.reg.inc_dec24(Reg24::BC, false /*decrement*/)
+    } else {
+        env.state.reg.inc_dec16(Reg16::DE, inc);
+        env.state.reg.inc_dec16(Reg16::HL, inc);
+        env.state.reg.inc_dec16(Reg16::BC, false /*decrement*/)
+    };
+
+    // TUZD-4.2
+    let n = value.wrapping_add(env.state.reg.a());
+    env.state.reg.update_undocumented_flags_block(n);
+    env.state.reg.clear_flag(Flag::N);
+    env.state.reg.clear_flag(Flag::H);
+    env.state.reg.put_flag(Flag::P, bc != 0);
+    // S, Z and C unchanged. What about N?
+
+    if repeat && bc != 0 {
+        // Back to redo the instruction
+        let instruction_len = match env.state.sz_prefix {
+                crate::state::SizePrefix::None => 2,
+                _ => 3
+        };
+        let pc = env.wrap_address(env.state.pc(), -instruction_len);
+        env.state.set_pc(pc);
+        // all but one repeat gets the 2-byte opcode cached
+        env.use_cycles(-2);
+        // and the size prefix is cached if present
+        if let crate::state::SizePrefix::None = env.state.sz_prefix {
+        } else {
+            env.use_cycles(-1);
+        }
     }
 }
 
 pub fn build_ld_a_mb() -> Opcode {
     Opcode {
         name: "LD A, MB".to_string(),
-        action: Box::new(|env: &mut Environment| {
-            env.state.reg.set8(Reg8::A, env.state.reg.mbase);
-        })
+        action: Action::LdAMb
     }
 }
 
 pub fn build_ld_mb_a() -> Opcode {
     Opcode {
         name: "LD MB, A".to_string(),
-        action: Box::new(|env: &mut Environment| {
-            env.state.reg.mbase = env.state.reg.get8(Reg8::A);
-        })
+        action: Action::LdMbA
     }
 }
 
 pub fn build_ld_idx_disp_rr(index_reg: Reg16, src: Reg16) -> Opcode {
     Opcode {
         name: format!("LD ({:?}d), {:?}", index_reg, src),
-        action: Box::new(move |env: &mut Environment| {
-            let imm = env.advance_pc() as i8 as i32 as u32;
-            if env.state.is_op_long() {
-                let value = env.state.reg.get24(src);
-                let address = env.state.reg.get24(index_reg).wrapping_add(imm);
-                env.poke24(address, value);
-            } else {
-                let value = env.state.reg.get16(src);
-                // this is wrong XXX only wrap the 16-bit part
-                let address = env.state.reg.get16_mbase(index_reg).wrapping_add(imm);
-                env.poke16(address, value);
-            }
-        })
+        action: Action::LdIdxDispRr(index_reg, src)
+    }
+}
+
+pub fn ld_idx_disp_rr<M: Machine + ?Sized>(env: &mut Environment<M>, index_reg: Reg16, src: Reg16) {
+    let imm = env.advance_pc() as i8 as i32 as u32;
+    if env.state.is_op_long() {
+        let value = env.state.reg.get24(src.to_reg24());
+        let address = env.state.reg.get24(index_reg.to_reg24()).wrapping_add(imm);
+        env.poke24(address, value);
+    } else {
+        let value = env.state.reg.get16(src);
+        // this is wrong XXX only wrap the 16-bit part
+        let address = env.state.reg.get16_mbase(index_reg).wrapping_add(imm);
+        env.poke16(address, value);
     }
 }
 
 pub fn build_ld_rr_idx_disp(dest: Reg16, index_reg: Reg16) -> Opcode {
     Opcode {
         name: format!("LD {:?}, ({:?}d)", dest, index_reg),
-        action: Box::new(move |env: &mut Environment| {
-            let imm = env.advance_pc() as i8 as i32 as u32;
-            if env.state.is_op_long() {
-                let address = env.state.reg.get24(index_reg).wrapping_add(imm);
-                let value = env.peek24(address);
-                env.state.reg.set24(dest, value);
-            } else {
-                let address = env.state.reg.get16_mbase(index_reg).wrapping_add(imm);
-                let value = env.peek16(address);
-                env.state.reg.set16(dest, value);
-            }
-        })
+        action: Action::LdRrIdxDisp(dest, index_reg)
+    }
+}
+
+pub fn ld_rr_idx_disp<M: Machine + ?Sized>(env: &mut Environment<M>, dest: Reg16, index_reg: Reg16) {
+    let imm = env.advance_pc() as i8 as i32 as u32;
+    if env.state.is_op_long() {
+        let address = env.state.reg.get24(index_reg.to_reg24()).wrapping_add(imm);
+        let value = env.peek24(address);
+        env.state.reg.set24(dest.to_reg24(), value);
+    } else {
+        let address = env.state.reg.get16_mbase(index_reg).wrapping_add(imm);
+        let value = env.peek16(address);
+        env.state.reg.set16(dest, value);
     }
 }
 
 pub fn build_ld_rr_ind_hl(dest: Reg16) -> Opcode {
     Opcode {
         name: format!("LD {:?}, (HL)", dest),
-        action: Box::new(move |env: &mut Environment| {
-            if env.state.is_op_long() {
-                let address = env.state.reg.get24(Reg16::HL);
-                let value = env.peek24(address);
-                env.state.reg.set24(dest, value);
-            } else {
-                let address = env.state.reg.get16_mbase(Reg16::HL);
-                let value = env.peek16(address);
-                env.state.reg.set16(dest, value);
-            }
-        })
+        action: Action::LdRrIndHl(dest)
+    }
+}
+
+pub fn ld_rr_ind_hl<M: Machine + ?Sized>(env: &mut Environment<M>, dest: Reg16) {
+    if env.state.is_op_long() {
+        let address = env.state.reg.get24(Reg24::HL);
+        let value = env.peek24(address);
+        env.state.reg.set24(dest.to_reg24(), value);
+    } else {
+        let address = env.state.reg.get16_mbase(Reg16::HL);
+        let value = env.peek16(address);
+        env.state.reg.set16(dest, value);
     }
 }
 
 pub fn build_ld_ind_hl_rr(src: Reg16) -> Opcode {
     Opcode {
         name: format!("LD (HL), {:?}", src),
-        action: Box::new(move |env: &mut Environment| {
-            if env.state.is_op_long() {
-                let address = env.state.reg.get24(Reg16::HL);
-                let value = env.state.reg.get24(src);
-                env.poke24(address, value);
-            } else {
-                let address = env.state.reg.get16_mbase(Reg16::HL);
-                let value = env.state.reg.get16(src);
-                env.poke16(address, value);
-            }
-        })
+        action: Action::LdIndHlRr(src)
+    }
+}
+
+pub fn ld_ind_hl_rr<M: Machine + ?Sized>(env: &mut Environment<M>, src: Reg16) {
+    if env.state.is_op_long() {
+        let address = env.state.reg.get24(Reg24::HL);
+        let value = env.state.reg.get24(src.to_reg24());
+        env.poke24(address, value);
+    } else {
+        let address = env.state.reg.get16_mbase(Reg16::HL);
+        let value = env.state.reg.get16(src);
+        env.poke16(address, value);
     }
 }
+
+// Z80N block loads
+pub fn build_ld_block_x((inc, repeat, postfix) : (bool, bool, &'static str)) -> Opcode {
+    Opcode {
+        name: format!("LD{}", postfix),
+        action: Action::LdBlockX(inc, repeat)
+    }
+}
+
+pub fn ld_block_x<M: Machine + ?Sized>(env: &mut Environment<M>, inc: bool, repeat: bool) {
+    // Like LDI and LDD without copying the bytes equal to A. DE is always
+    // incremented and the flags are not affected.
+    let value = env.reg8_ext(Reg8::_HL);
+    if value != env.state.reg.a() {
+        let address = env.reg16mbase_or_24(Reg16::DE);
+        env.poke(address, value);
+    }
+    env.state.reg.inc_dec16(Reg16::DE, true);
+    env.state.reg.inc_dec16(Reg16::HL, inc);
+    let bc = env.state.reg.inc_dec16(Reg16::BC, false /*decrement*/);
+
+    if repeat && bc != 0 {
+        // Back to redo the instruction
+        let pc = env.wrap_address(env.state.pc(), -2);
+        env.state.set_pc(pc);
+    }
+}
+
+pub fn build_ldpirx() -> Opcode {
+    Opcode {
+        name: "LDPIRX".to_string(),
+        action: Action::Ldpirx
+    }
+}
+
+pub fn ldpirx<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    // Copies from the 8 byte pattern at HL, aligned, indexed by E
+    let hl = env.state.reg.get16(Reg16::HL);
+    let e = env.state.reg.get8(Reg8::E) as u16;
+    let source = ((env.state.reg.mbase as u32) << 16) + ((hl & 0xfff8) | (e & 0x07)) as u32;
+    let value = env.peek(source);
+    if value != env.state.reg.a() {
+        let address = env.reg16mbase_or_24(Reg16::DE);
+        env.poke(address, value);
+    }
+    env.state.reg.inc_dec16(Reg16::DE, true);
+    let bc = env.state.reg.inc_dec16(Reg16::BC, false /*decrement*/);
+
+    if bc != 0 {
+        let pc = env.wrap_address(env.state.pc(), -2);
+        env.state.set_pc(pc);
+    }
+}
+
+pub fn build_ldws() -> Opcode {
+    Opcode {
+        name: "LDWS".to_string(),
+        action: Action::Ldws
+    }
+}
+
+pub fn ldws<M: Machine + ?Sized>(env: &mut Environment<M>) {
+    // Copies (HL) to (DE), then increments L and D. The flags as INC D.
+    let value = env.reg8_ext(Reg8::_HL);
+    let address = env.reg16mbase_or_24(Reg16::DE);
+    env.poke(address, value);
+    let l = env.state.reg.get8(Reg8::L).wrapping_add(1);
+    env.state.reg.set8(Reg8::L, l);
+    let d = env.state.reg.get8(Reg8::D);
+    let v = operator_inc(&mut env.state.reg, d);
+    env.state.reg.set8(Reg8::D, v);
+}
@@ -1,4 +1,6 @@
-use std::{fmt, mem};
+use core::{fmt, mem};
+use super::machine::AddressMode;
+use super::flags::*;
 
 /// 8 bit registers
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -55,39 +57,61 @@ pub enum Reg8 {
 const REG_COUNT8: usize = 24;
 
 
-/// Long registers -- either 16 or 24 bits, depending on CPU mode.
-/// SP is actually 2 separate registers, SPS (16-bit) and SPL (24-bit),
-/// Which you access depends whether you use reg16, reg24, set16 or set24
-/// XXX Reg16 is a poor name
+/// 16 bit registers, composed from 8 bit registers. On the eZ80 the
+/// instructions use the 16 or 24 bit registers depending on the mode.
+/// SP is the 16 bit stack pointer SPS, use Reg24::SPL for the 24 bit one.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Reg16 {
+    /// 16 bit register AF
     AF,
+    /// 16 bit register BC
     BC,
+    /// 16 bit register DE
     DE,
+    /// 16 bit register HL
     HL,
+    /// 16 bit register IX
     IX,
+    /// 16 bit register IY
     IY,
+    /// 16 bit stack pointer, SPS on the eZ80
     SP,
 }
 
-/*
-/// 24 bit registers, composed from 8 bit registers
+impl Reg16 {
+    /// eZ80 16 bit stack pointer, the SP of the Z80
+    pub const SPS: Reg16 = Reg16::SP;
+
+    /// Returns the 24 bit register extending this one, SPL for SP
+    pub(crate) fn to_reg24(self) -> Reg24 {
+        match self {
+            Reg16::AF => panic!("AF has no 24 bit register"),
+            Reg16::BC => Reg24::BC,
+            Reg16::DE => Reg24::DE,
+            Reg16::HL => Reg24::HL,
+            Reg16::IX => Reg24::IX,
+            Reg16::IY => Reg24::IY,
+            Reg16::SP => Reg24::SPL,
+        }
+    }
+}
+
+/// 24 bit registers of the eZ80, composed from 8 bit registers
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Reg24 {
     /// 24 bit register BC
-    BC = Reg8::BCU as isize,
+    BC,
     /// 24 bit register DE
-    DE = Reg8::DEU as isize,
-    /// 16 bit register HL
-    HL = Reg8::HLU as isize,
+    DE,
+    /// 24 bit register HL
+    HL,
     /// 24 bit register IX
-    IX = Reg8::IXU as isize,
+    IX,
     /// 24 bit register IY
-    IY = Reg8::IYU as isize,
-    /// 24 bit register SPL
-    SPL = Reg8::SPU as isize
+    IY,
+    /// 24 bit stack pointer SPL, separate from SPS
+    SPL,
 }
-*/
 
 /// Z80 flags
 #[derive(Copy, Clone, Debug)]
@@ -111,6 +135,13 @@ pub enum Flag {
     S  = 128
 }
 
+impl Flag {
+    /// 8085 undocumented K flag, on the bit of the fifth flag
+    pub const K: Flag = Flag::_5;
+    /// 8085 undocumented overflow flag, on the bit of the negative flag
+    pub const V: Flag = Flag::N;
+}
+
 impl fmt::Display for Reg8 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -120,6 +151,43 @@ impl fmt::Display for Reg8 {
     }
 }
 
+/// Snapshot of all the registers of the CPU
+///
+/// The 16 bit registers of the Z80 are the low 16 bits of the 24 bit
+/// registers of the eZ80. Use `Registers::get_all()` and
+/// `Registers::set_all()` to read or write all the CPU state at once.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RegisterFile {
+    pub af: u16,
+    pub bc: u32,
+    pub de: u32,
+    pub hl: u32,
+    /// Alternate AF', swapped with EX AF, AF'
+    pub af_shadow: u16,
+    /// Alternate BC', swapped with EXX
+    pub bc_shadow: u32,
+    /// Alternate DE', swapped with EXX
+    pub de_shadow: u32,
+    /// Alternate HL', swapped with EXX
+    pub hl_shadow: u32,
+    pub ix: u32,
+    pub iy: u32,
+    /// 16 bit stack pointer, SP on the Z80
+    pub sps: u16,
+    /// 24 bit stack pointer of the eZ80
+    pub spl: u32,
+    pub pc: u32,
+    pub i: u8,
+    pub r: u8,
+    pub iff1: bool,
+    pub iff2: bool,
+    /// Interrupt mode, 0 to 2
+    pub im: u8,
+    pub adl: bool,
+    pub madl: bool,
+    pub mbase: u8,
+}
+
 /// Z80 internal register values
 #[derive(Debug, Clone)]
 pub struct Registers {
@@ -130,6 +198,7 @@ pub struct Registers {
     pub iff2: bool,
     im: u8,
     mode8080: bool,
+    mode8085: bool,
     pub adl: bool,  // ez80 24-bit flat addressing mode
     pub madl: bool,  // ez80
     pub mbase: u8,  // provides the top 8-bits of a 24-bit address when ez80 is in z80 mode
@@ -146,6 +215,7 @@ impl Registers {
             iff2: false,
             im: 0,
             mode8080: false,
+            mode8085: false,
             adl: false,
             madl: false,
             mbase: 0,
@@ -163,6 +233,82 @@ impl Registers {
         self.set_flag(Flag::N);
     }
 
+    pub(crate) fn set_8085(&mut self) {
+        // The K and V flags are stored on the bits 5 and 1
+        self.mode8085 = true;
+        self.set_8080();
+    }
+
+    /// Returns the values of all the registers
+    pub fn get_all(&self) -> RegisterFile {
+        let shadow24 = |r: Reg8| {
+            let i = r as usize;
+            ((self.shadow[i] as u32) << 16) + ((self.shadow[i + 1] as u32) << 8) + self.shadow[i + 2] as u32
+        };
+        RegisterFile {
+            af: self.get16(Reg16::AF),
+            bc: self.get24(Reg24::BC),
+            de: self.get24(Reg24::DE),
+            hl: self.get24(Reg24::HL),
+            af_shadow: ((self.shadow[Reg8::A as usize] as u16) << 8) + self.shadow[Reg8::F as usize] as u16,
+            bc_shadow: shadow24(Reg8::BCU),
+            de_shadow: shadow24(Reg8::DEU),
+            hl_shadow: shadow24(Reg8::HLU),
+            ix: self.get24(Reg24::IX),
+            iy: self.get24(Reg24::IY),
+            sps: self.get16(Reg16::SPS),
+            spl: self.get24(Reg24::SPL),
+            pc: self.pc,
+            i: self.get8(Reg8::I),
+            r: self.get8(Reg8::R),
+            iff1: self.iff1,
+            iff2: self.iff2,
+            im: self.im,
+            adl: self.adl,
+            madl: self.madl,
+            mbase: self.mbase,
+        }
+    }
+
+    /// Sets the values of all the registers. The flags that don't exist
+    /// on the 8080 are fixed like with set16().
+    pub fn set_all(&mut self, file: &RegisterFile) {
+        let mut set_shadow24 = |r: Reg8, value: u32| {
+            let i = r as usize;
+            self.shadow[i] = (value >> 16) as u8;
+            self.shadow[i + 1] = (value >> 8) as u8;
+            self.shadow[i + 2] = value as u8;
+        };
+        set_shadow24(Reg8::BCU, file.bc_shadow);
+        set_shadow24(Reg8::DEU, file.de_shadow);
+        set_shadow24(Reg8::HLU, file.hl_shadow);
+        self.shadow[Reg8::A as usize] = (file.af_shadow >> 8) as u8;
+        self.shadow[Reg8::F as usize] = file.af_shadow as u8;
+
+        self.set16(Reg16::AF, file.af);
+        self.set24(Reg24::BC, file.bc);
+        self.set24(Reg24::DE, file.de);
+        self.set24(Reg24::HL, file.hl);
+        self.set24(Reg24::IX, file.ix);
+        self.set24(Reg24::IY, file.iy);
+        self.set16(Reg16::SPS, file.sps);
+        self.set24(Reg24::SPL, file.spl);
+        self.pc = file.pc;
+        self.set8(Reg8::I, file.i);
+        self.set8(Reg8::R, file.r);
+        self.iff1 = file.iff1;
+        self.iff2 = file.iff2;
+        self.im = file.im;
+        self.adl = file.adl;
+        self.madl = file.madl;
+        self.mbase = file.mbase;
+    }
+
+    /// Returns the interrupt mode, 0 to 2
+    pub fn get_interrupt_mode(&self) -> u8 {
+        self.im
+    }
+
     /// Returns the value of the A register
     #[inline]
     pub fn a(&self) -> u8 {
@@ -204,6 +350,16 @@ impl Registers {
         v
     }
 
+    /// Returns the addressing mode of the CPU, to use with the multi byte
+    /// accessors of Machine
+    pub fn address_mode(&self) -> AddressMode {
+        if self.adl {
+            AddressMode::Adl
+        } else {
+            AddressMode::Z80(self.mbase)
+        }
+    }
+
     #[inline]
     pub fn get16_mbase(&self, rr: Reg16) -> u32 {
         ((self.mbase as u32) << 16) + self.get16(rr) as u32
@@ -224,6 +380,7 @@ impl Registers {
         + ((self.data[r8 as usize] as u16) << 8)
     }
 
+    #[inline]
     fn map_reg16_to_reg8(&self, rr: Reg16) -> Reg8 {
         match rr {
             Reg16::AF => Reg8::A,
@@ -236,15 +393,15 @@ impl Registers {
         }
     }
 
-    fn map_reg24_to_reg8(&self, rr: Reg16) -> Reg8 {
+    #[inline]
+    fn map_reg24_to_reg8(&self, rr: Reg24) -> Reg8 {
         match rr {
-            Reg16::AF => panic!(),
-            Reg16::BC => Reg8::BCU,
-            Reg16::DE => Reg8::DEU,
-            Reg16::HL => Reg8::HLU,
-            Reg16::IX => Reg8::IXU,
-            Reg16::IY => Reg8::IYU,
-            Reg16::SP => Reg8::SPLU,
+            Reg24::BC => Reg8::BCU,
+            Reg24::DE => Reg8::DEU,
+            Reg24::HL => Reg8::HLU,
+            Reg24::IX => Reg8::IXU,
+            Reg24::IY => Reg8::IYU,
+            Reg24::SPL => Reg8::SPLU,
         }
     }
 
@@ -261,9 +418,11 @@ impl Registers {
         //if self.mode8080 && rr == Reg16::AF {
         if self.mode8080 && rr == Reg16::AF {
             // Ensure non existent flags have proper values
-            self.set_flag(Flag::N);
             self.clear_flag(Flag::_3);
-            self.clear_flag(Flag::_5);
+            if !self.mode8085 {
+                self.set_flag(Flag::N);
+                self.clear_flag(Flag::_5);
+            }
         }
     }
 
@@ -276,9 +435,11 @@ impl Registers {
         //if self.mode8080 && rr == Reg16::AF {
         if self.mode8080 && rr == Reg16::AF {
             // Ensure non existent flags have proper values
-            self.set_flag(Flag::N);
             self.clear_flag(Flag::_3);
-            self.clear_flag(Flag::_5);
+            if !self.mode8085 {
+                self.set_flag(Flag::N);
+                self.clear_flag(Flag::_5);
+            }
         }
     }
 
@@ -293,7 +454,7 @@ impl Registers {
         v as u32
     }
 
-    pub(crate) fn inc_dec24(&mut self, rr: Reg16, inc: bool) -> u32 {
+    pub(crate) fn inc_dec24(&mut self, rr: Reg24, inc: bool) -> u32 {
         let mut v = self.get24(rr);
         if inc {
             v = v.wrapping_add(1);
@@ -304,9 +465,9 @@ impl Registers {
         v
     }
 
-    /// Returns the value of a 16 bit register
+    /// Returns the value of a 24 bit register
     #[inline]
-    pub fn get24(&self, rr: Reg16) -> u32 {
+    pub fn get24(&self, rr: Reg24) -> u32 {
         let r8 = self.map_reg24_to_reg8(rr);
         self.data[r8 as usize +2] as u32
         + ((self.data[r8 as usize +1] as u32) << 8)
@@ -316,7 +477,7 @@ impl Registers {
     /// Sets the value of a 24 bit register. Changes the
     /// value of the three underlying 8 bit registers.
     #[inline]
-    pub fn set24(&mut self, rr: Reg16, value: u32) {
+    pub fn set24(&mut self, rr: Reg24, value: u32) {
         let r8 = self.map_reg24_to_reg8(rr);
         self.data[r8 as usize +2] = value as u8;
         self.data[r8 as usize +1] = (value >> 8) as u8;
@@ -329,13 +490,41 @@ impl Registers {
         mem::swap(&mut self.data[ih + 1], &mut self.shadow[ih + 1]);
     }
 
-    pub(crate) fn swap24(&mut self, rr: Reg16) {
+    pub(crate) fn swap24(&mut self, rr: Reg24) {
         let iu = self.map_reg24_to_reg8(rr) as usize;
         mem::swap(&mut self.data[iu], &mut self.shadow[iu]);
         mem::swap(&mut self.data[iu + 1], &mut self.shadow[iu + 1]);
         mem::swap(&mut self.data[iu + 2], &mut self.shadow[iu + 2]);
     }
 
+    /// Returns the value of the F register as Flags
+    #[inline]
+    pub fn flags(&self) -> Flags {
+        Flags::from_bits(self.data[Reg8::F as usize])
+    }
+
+    /// Sets the F register
+    #[inline]
+    pub fn set_flags(&mut self, flags: Flags) {
+        self.data[Reg8::F as usize] = flags.bits();
+    }
+
+    /// Returns the names of the bits of the F register for the CPU model
+    pub fn flags_layout(&self) -> FlagsLayout {
+        if self.mode8085 {
+            FlagsLayout::I8085
+        } else if self.mode8080 {
+            FlagsLayout::I8080
+        } else {
+            FlagsLayout::Z80
+        }
+    }
+
+    /// Returns a Display of the flags with the names of the CPU model
+    pub fn flags_display(&self) -> FlagsDisplay {
+        self.flags().display(self.flags_layout())
+    }
+
     /// Returns the value of a flag
     #[inline]
     pub fn get_flag(&self, flag: Flag) -> bool {
@@ -452,9 +641,21 @@ impl Registers {
                 let a_b3 = (a & 0x08) != 0;
                 let b_b3 = (b & 0x08) != 0;
                 let r_b3 = (reference & 0x08) != 0;
-                let neg_half_bit = (!a_b3 && !b_b3 && !r_b3) || (a_b3 && !(b_b3 && r_b3)); 
+                let neg_half_bit = if a_b3 { !(b_b3 && r_b3) } else { !b_b3 && !r_b3 };
                 self.put_flag(Flag::H, neg_half_bit);    
             }
+            if self.mode8085 && update_carry {
+                // Not on INR and DCR. K from the signs of the operands and
+                // the result, with the second operand complemented on
+                // subtractions.
+                let a_b7 = (a & 0x80) != 0;
+                let b_b7 = ((b & 0x80) != 0) != neg;
+                let r_b7 = (reference & 0x80) != 0;
+                let top_xor = (xor & 0x80) != 0;
+                self.put_flag(Flag::V, carry_bit != top_xor);
+                let k = (a_b7 && (b_b7 || r_b7)) || (b_b7 && r_b7);
+                self.put_flag(Flag::K, k);
+            }
         } else {
             let top_xor = (xor & 0x80) != 0;
             self.put_flag(Flag::P, carry_bit != top_xor); // As overflow flag
@@ -462,6 +663,13 @@ impl Registers {
         }
     }
 
+    pub(crate) fn update_inc_dec16_k_flag(&mut self, reference: u16, inc: bool) {
+        if self.mode8085 {
+            // Set when INX overflows or DCX underflows
+            self.put_flag(Flag::K, reference == if inc { 0x0000 } else { 0xffff });
+        }
+    }
+
     pub(crate) fn update_logic_flags(&mut self, a: u8, b: u8, reference: u8, is_and: bool) {
         self.update_sz53_flags(reference);
         self.update_p_flag(reference);
@@ -521,6 +729,7 @@ impl Registers {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
@@ -1,7 +1,10 @@
 use super::registers::*;
+use super::i8085::I8085Io;
+use super::z180::Z180Io;
 
 /// ez80 opcode "suffixes". we call them prefixes here
 /// because they appear before the opcode in machine code
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone,Copy,Debug)]
 pub enum SizePrefix {
     None,
@@ -30,7 +33,13 @@ pub struct State {
     pub displacement: i8, // Used for (IX+d) and (iY+d)
     pub sz_prefix: SizePrefix,
     pub instructions_executed: u64,
+    /// Count of RETI executed, for the interrupt controllers
+    pub reti_executed: u64,
     pub cached_instruction: bool,
+    /// MMU and internal registers of a Z180
+    pub z180: Option<Z180Io>,
+    /// Interrupt inputs and serial lines of an 8085
+    pub i8085: Option<I8085Io>,
 }
 
 impl State {
@@ -45,14 +54,19 @@ impl State {
             displacement: 0,
             sz_prefix: SizePrefix::None,
             instructions_executed: 0,
+            reti_executed: 0,
             cached_instruction: false,
+            z180: None,
+            i8085: None,
         }
     }
 
+    #[inline]
     pub fn clear_sz_prefix(&mut self) {
         self.sz_prefix = SizePrefix::None;
     }
 
+    #[inline]
     pub fn is_op_long(&self) -> bool {
         match self.sz_prefix {
             SizePrefix::None => self.reg.adl,
@@ -63,6 +77,7 @@ impl State {
         }
     }
 
+    #[inline]
     pub fn is_imm_long(&self) -> bool {
         match self.sz_prefix {
             SizePrefix::None => self.reg.adl,
@@ -75,7 +90,7 @@ impl State {
 
     pub fn sp(&self) -> u32 {
         if self.is_op_long() {
-            self.reg.get24(Reg16::SP)
+            self.reg.get24(Reg24::SPL)
         } else {
             self.reg.get16_mbase(Reg16::SP)
         }
@@ -87,23 +102,24 @@ impl State {
         if self.reg.adl {
             self.reg.pc
         } else {
-            ((self.reg.mbase as u32) << 16) + (self.reg.pc & 0xffff) as u32
+            ((self.reg.mbase as u32) << 16) + (self.reg.pc & 0xffff)
         }
     }
 
+    #[inline]
     pub fn set_pc(&mut self, value: u32) {
         self.reg.pc = value & 0xffffff;
     }
 }
 
-impl std::fmt::Display for SizePrefix {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", match self {
-            &SizePrefix::LIL => ".LIL",
-            &SizePrefix::LIS => ".LIS",
-            &SizePrefix::SIL => ".SIL",
-            &SizePrefix::SIS => ".SIS",
-            &SizePrefix::None => "",
+impl core::fmt::Display for SizePrefix {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{}", match *self {
+            SizePrefix::LIL => ".LIL",
+            SizePrefix::LIS => ".LIS",
+            SizePrefix::SIL => ".SIL",
+            SizePrefix::SIS => ".SIS",
+            SizePrefix::None => "",
 
         })
     }
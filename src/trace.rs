@@ -0,0 +1,331 @@
+//! Instruction trace hooks
+//!
+//! A `TraceSink` set with `Cpu::set_trace_sink()` receives a `TraceRecord`
+//! for each instruction executed, with the registers before and after the
+//! instruction and the memory and port accesses it made. The sinks provided
+//! write text or binary traces, with the `std` feature, or keep the last
+//! records in memory.
+
+use alloc::collections::VecDeque;
+#[cfg(feature = "std")]
+use alloc::format;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use core::fmt;
+#[cfg(feature = "std")]
+use std::io::{self, Write};
+
+use crate::machine::Machine;
+use crate::registers::*;
+
+/// Kind of bus access
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+    MemoryRead = 0,
+    MemoryWrite = 1,
+    PortIn = 2,
+    PortOut = 3,
+}
+
+/// A memory or port access made by an instruction
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BusAccess {
+    pub kind: AccessKind,
+    pub address: u32,
+    pub value: u8,
+}
+
+/// Everything an instruction did
+#[derive(Clone, Debug)]
+pub struct TraceRecord {
+    /// Address of the instruction, including MBASE
+    pub pc: u32,
+    /// Address of the next instruction, including MBASE
+    pub next_pc: u32,
+    /// Bytes of the instruction, prefixes included
+    pub bytes: Vec<u8>,
+    /// The instruction disassembled
+    pub instruction: String,
+    pub before: Registers,
+    pub after: Registers,
+    /// Memory and port accesses, in order. Instruction fetches included.
+    pub accesses: Vec<BusAccess>,
+    /// One cycle per bus access plus the cycles reported to
+    /// `Machine::use_cycles()` by the instruction
+    pub cycles: i32,
+    /// Instructions executed, this one included
+    pub instructions_executed: u64,
+}
+
+/// Receiver of the trace records
+pub trait TraceSink {
+    fn record(&mut self, record: &TraceRecord);
+}
+
+/// Shared sinks, to inspect them while the Cpu owns them
+impl<T: TraceSink> TraceSink for Rc<RefCell<T>> {
+    fn record(&mut self, record: &TraceRecord) {
+        self.borrow_mut().record(record);
+    }
+}
+
+#[cfg(feature = "std")]
+/// Writes a line of text per instruction, in the format of `Cpu::set_trace()`
+pub struct TextTrace<W: Write> {
+    writer: W,
+}
+
+#[cfg(feature = "std")]
+impl TextTrace<io::Stdout> {
+    /// Trace to stdout
+    pub fn stdout() -> TextTrace<io::Stdout> {
+        TextTrace::new(io::stdout())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: Write> TextTrace<W> {
+    pub fn new(writer: W) -> TextTrace<W> {
+        TextTrace { writer }
+    }
+
+    /// Returns the writer, to get the trace from a Vec<u8> or flush a file
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: Write> TraceSink for TextTrace<W> {
+    fn record(&mut self, record: &TraceRecord) {
+        let reg = &record.after;
+        let bytes: Vec<String> = record.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let _ = writeln!(self.writer, "==> {:06x}: {:20} PC:{:06x} AF:{:04x} F:{} BC:{:06x} DE:{:06x} HL:{:06x} SPS:{:04x} SPL:{:06x} IX:{:06x} IY:{:06x} MB {:02x} ADL {:01x} MADL {:01x} tick {} [{}]",
+            record.pc,
+            record.instruction,
+            record.next_pc,
+            reg.get16(Reg16::AF),
+            reg.flags_display(),
+            reg.get24(Reg24::BC),
+            reg.get24(Reg24::DE),
+            reg.get24(Reg24::HL),
+            reg.get16(Reg16::SP),
+            reg.get24(Reg24::SPL),
+            reg.get24(Reg24::IX),
+            reg.get24(Reg24::IY),
+            reg.mbase,
+            reg.adl as i32,
+            reg.madl as i32,
+            record.instructions_executed,
+            bytes.join(" "),
+        );
+    }
+}
+
+#[cfg(feature = "std")]
+/// Writes a compact binary trace
+///
+/// The stream starts with the 8 bytes "EZ80TRC1". Each record is, with
+/// little endian values:
+///
+/// * pc: 3 bytes
+/// * length of the instruction: 1 byte, followed by the bytes
+/// * registers before and after: 29 bytes each. AF (2), BC, DE, HL, IX,
+///   IY (3 each), SPS (2), SPL (3), PC (3), I, R, MBASE and a mode byte
+///   with ADL in bit 0, MADL in bit 1, IFF1 in bit 2 and IFF2 in bit 3.
+/// * number of accesses: 2 bytes, followed by 5 bytes per access: the
+///   AccessKind, the address (3 bytes) and the value.
+/// * cycles: 4 bytes
+pub struct BinaryTrace<W: Write> {
+    writer: W,
+    header_written: bool,
+}
+
+#[cfg(feature = "std")]
+impl<W: Write> BinaryTrace<W> {
+    pub fn new(writer: W) -> BinaryTrace<W> {
+        BinaryTrace {
+            writer,
+            header_written: false,
+        }
+    }
+
+    /// Returns the writer, to get the trace from a Vec<u8> or flush a file
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write_record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        if !self.header_written {
+            self.writer.write_all(b"EZ80TRC1")?;
+            self.header_written = true;
+        }
+        let mut data = Vec::with_capacity(64 + record.accesses.len() * 5);
+        push24(&mut data, record.pc);
+        data.push(record.bytes.len() as u8);
+        data.extend_from_slice(&record.bytes);
+        push_registers(&mut data, &record.before);
+        push_registers(&mut data, &record.after);
+        data.extend_from_slice(&(record.accesses.len() as u16).to_le_bytes());
+        for access in &record.accesses {
+            data.push(access.kind as u8);
+            push24(&mut data, access.address);
+            data.push(access.value);
+        }
+        data.extend_from_slice(&record.cycles.to_le_bytes());
+        self.writer.write_all(&data)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: Write> TraceSink for BinaryTrace<W> {
+    fn record(&mut self, record: &TraceRecord) {
+        if let Err(e) = self.write_record(record) {
+            eprintln!("Error writing the trace: {}", e);
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+fn push24(data: &mut Vec<u8>, value: u32) {
+    data.extend_from_slice(&value.to_le_bytes()[0..3]);
+}
+
+#[cfg(feature = "std")]
+fn push_registers(data: &mut Vec<u8>, reg: &Registers) {
+    data.extend_from_slice(&reg.get16(Reg16::AF).to_le_bytes());
+    for rr in [Reg24::BC, Reg24::DE, Reg24::HL, Reg24::IX, Reg24::IY].iter() {
+        push24(data, reg.get24(*rr));
+    }
+    data.extend_from_slice(&reg.get16(Reg16::SP).to_le_bytes());
+    push24(data, reg.get24(Reg24::SPL));
+    push24(data, reg.pc);
+    data.push(reg.get8(Reg8::I));
+    data.push(reg.get8(Reg8::R));
+    data.push(reg.mbase);
+    data.push(reg.adl as u8
+        | (reg.madl as u8) << 1
+        | (reg.iff1 as u8) << 2
+        | (reg.iff2 as u8) << 3);
+}
+
+/// Keeps the last records in memory
+pub struct RingTrace {
+    capacity: usize,
+    records: VecDeque<TraceRecord>,
+}
+
+impl RingTrace {
+    /// Returns a RingTrace keeping up to `capacity` records
+    pub fn new(capacity: usize) -> RingTrace {
+        RingTrace {
+            capacity,
+            records: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// The records kept, oldest first
+    pub fn records(&self) -> impl Iterator<Item = &TraceRecord> {
+        self.records.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+}
+
+impl TraceSink for RingTrace {
+    fn record(&mut self, record: &TraceRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record.clone());
+    }
+}
+
+/// Machine wrapper recording the accesses for the trace and the previous
+/// values of the memory written for the history
+pub(crate) struct TracingMachine<'a, M: Machine + ?Sized> {
+    sys: &'a mut M,
+    pub accesses: RefCell<Vec<BusAccess>>,
+    pub extra_cycles: Cell<i32>,
+    record_undo: bool,
+    pub undo: Vec<(u32, u8)>,
+}
+
+impl<'a, M: Machine + ?Sized> TracingMachine<'a, M> {
+    pub fn new(sys: &'a mut M, record_undo: bool) -> TracingMachine<'a, M> {
+        TracingMachine {
+            sys,
+            accesses: RefCell::new(Vec::new()),
+            extra_cycles: Cell::new(0),
+            record_undo,
+            undo: Vec::new(),
+        }
+    }
+
+    fn push(&self, kind: AccessKind, address: u32, value: u8) {
+        self.accesses.borrow_mut().push(BusAccess { kind, address, value });
+    }
+}
+
+impl<'a, M: Machine + ?Sized> Machine for TracingMachine<'a, M> {
+    fn peek(&self, address: u32) -> u8 {
+        let value = self.sys.peek(address);
+        self.push(AccessKind::MemoryRead, address, value);
+        value
+    }
+
+    fn poke(&mut self, address: u32, value: u8) {
+        if self.record_undo {
+            self.undo.push((address, self.sys.peek_debug(address)));
+        }
+        self.push(AccessKind::MemoryWrite, address, value);
+        self.sys.poke(address, value);
+    }
+
+    fn peek_debug(&self, address: u32) -> u8 {
+        self.sys.peek_debug(address)
+    }
+
+    fn use_cycles(&self, cycles: i32) {
+        self.extra_cycles.set(self.extra_cycles.get() + cycles);
+        self.sys.use_cycles(cycles);
+    }
+
+    fn memory_generation(&self) -> u64 {
+        self.sys.memory_generation()
+    }
+
+    fn diagnostic(&self, message: fmt::Arguments) {
+        self.sys.diagnostic(message);
+    }
+
+    fn nextreg(&mut self, register: u8, value: u8) {
+        self.sys.nextreg(register, value);
+    }
+
+    fn port_in(&mut self, address: u16) -> u8 {
+        let value = self.sys.port_in(address);
+        self.push(AccessKind::PortIn, address as u32, value);
+        value
+    }
+
+    fn port_out(&mut self, address: u16, value: u8) {
+        self.push(AccessKind::PortOut, address as u32, value);
+        self.sys.port_out(address, value);
+    }
+}
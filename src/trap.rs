@@ -0,0 +1,239 @@
+//! Traps for high level emulation
+//!
+//! A trap intercepts an instruction before it is executed and passes the
+//! registers and the Machine to a handler on the host, that can service in
+//! Rust the call the instruction makes, like the API of an operating
+//! system. Traps are set with `Cpu::set_trap()` and are checked by
+//! execute_instruction() and execute_instructions(). With the block cache,
+//! the blocks end before the instructions trapped.
+//!
+//! The instructions are trapped by their address, like the entry point of
+//! the CP/M BDOS, in any mode or only in the ADL or Z80 mode of the eZ80,
+//! or by their bytes in memory at PC, read with
+//! `Machine::peek_debug()`: the RST instructions, like the calls to the
+//! Agon MOS, or an instruction reserved for the emulator, usually ED FE,
+//! undefined on the Z80 and the eZ80. Trapped instructions are not traced,
+//! and pending interrupts are accepted before the check. The writes of the
+//! handlers invalidate the cached code and are undone by
+//! `Cpu::step_back()` with the trap.
+//!
+//!# Example
+//! ```
+//!use ez80::*;
+//!
+//!let mut sys = PlainMachine::new();
+//!let mut cpu = Cpu::new_z80();
+//!sys.poke(0x0000, 0xcd); // CALL $0005
+//!sys.poke(0x0001, 0x05);
+//!sys.poke(0x0002, 0x00);
+//!sys.poke(0x0003, 0x76); // HALT
+//!cpu.registers().set16(Reg16::SP, 0x8000);
+//!cpu.set_trap(Trap::Address { address: 0x0005, adl: None }, Some(Box::new(|reg, _| {
+//!    reg.set_a(0x42);
+//!    TrapAction::Return
+//!})));
+//!cpu.execute_instructions(&mut sys, 10);
+//!assert_eq!(0x42, cpu.registers().a());
+//! ```
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use core::fmt;
+
+use crate::blocks::CodeMap;
+use crate::decode_cache::DecodeCache;
+use crate::machine::Machine;
+use crate::registers::Registers;
+use crate::state::State;
+
+/// An instruction intercepted by a trap
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trap {
+    /// The instruction at the address, 24 bits including MBASE in the Z80
+    /// mode of the eZ80. With `adl`, only in the ADL mode, `Some(true)`, or
+    /// in the Z80 mode, `Some(false)`, as the same address can be reached
+    /// with different code in both modes.
+    Address { address: u32, adl: Option<bool> },
+    /// The RST instructions to the vector, $00 to $38, with any suffix of
+    /// the eZ80, like RST.LIL 08h
+    Rst(u8),
+    /// The instruction ED with the second byte, like the emulator trap
+    /// ED FE
+    Ed(u8),
+}
+
+/// What the CPU does when the handler of a trap returns
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrapAction {
+    /// Executes the instruction, as if there was no trap
+    Execute,
+    /// Continues after the instruction without executing it
+    Skip,
+    /// Continues on the PC set by the handler, that has emulated the
+    /// instruction on the registers and the Machine
+    Emulated,
+    /// Executes a RET instead of the instruction, to return from a call to
+    /// the address trapped. It is counted as an instruction executed, with
+    /// the cycles and the R increment of the RET.
+    Return,
+}
+
+/// The host side of a trap. It gets the registers, with PC on the
+/// instruction trapped, and the Machine.
+pub type TrapHandler = Box<dyn FnMut(&mut Registers, &mut dyn Machine) -> TrapAction>;
+
+/// The traps of a Cpu with their handlers
+pub(crate) struct Traps {
+    handlers: Vec<(Trap, TrapHandler)>,
+}
+
+impl Traps {
+    pub fn new() -> Traps {
+        Traps {
+            handlers: Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    /// Sets the handler of a trap, or removes it with None
+    pub fn set(&mut self, trap: Trap, handler: Option<TrapHandler>) {
+        self.handlers.retain(|(t, _)| *t != trap);
+        if let Some(handler) = handler {
+            self.handlers.push((trap, handler));
+        }
+    }
+
+    /// Returns the index of the trap of the instruction at PC and the
+    /// length of the instruction, 0 if trapped by the address
+    pub fn find<N: Machine + ?Sized>(&self, state: &State, ez80: bool, sys: &N) -> Option<(usize, u32)> {
+        if self.handlers.is_empty() {
+            return None;
+        }
+        let pc = state.pc();
+        let mode = state.reg.adl;
+        if let Some(index) = self.handlers.iter().position(|(trap, _)| matches!(*trap,
+                Trap::Address { address, adl } if address == pc && adl.map_or(true, |adl| adl == mode))) {
+            return Some((index, 0));
+        }
+
+        let peek = |offset: u32| {
+            // The instructions don't wrap, like in the blocks
+            let address = pc.wrapping_add(offset);
+            match state.z180.as_ref() {
+                Some(z180) => sys.peek_debug(z180.translate(address)),
+                None => sys.peek_debug(address),
+            }
+        };
+
+        let mut len = 1;
+        let mut opcode = peek(0);
+        if ez80 && matches!(opcode, 0x40 | 0x49 | 0x52 | 0x5b) {
+            // Suffix
+            opcode = peek(1);
+            len = 2;
+        }
+
+        let instruction = if opcode & 0xc7 == 0xc7 {
+            Trap::Rst(opcode & 0x38)
+        } else if opcode == 0xed {
+            len += 1;
+            Trap::Ed(peek(len - 1))
+        } else {
+            return None;
+        };
+        self.handlers.iter()
+            .position(|(trap, _)| *trap == instruction)
+            .map(|index| (index, len))
+    }
+
+    /// Calls the handler on the position [index]
+    pub fn call(&mut self, index: usize, reg: &mut Registers, sys: &mut dyn Machine) -> TrapAction {
+        (self.handlers[index].1)(reg, sys)
+    }
+}
+
+/// The Machine passed to the handlers. The writes invalidate the cached
+/// code, like the writes of the CPU, and are recorded for the history.
+pub(crate) struct TrapMachine<'a> {
+    sys: &'a mut dyn Machine,
+    decode_cache: Option<&'a mut DecodeCache>,
+    code_map: Option<&'a mut CodeMap>,
+    // The writes on the Z180 are on physical addresses, the caches use the
+    // logical ones
+    physical: bool,
+    record_undo: bool,
+    /// Addresses written with their previous values
+    pub undo: Vec<(u32, u8)>,
+    /// Set on the writes that the caches can't invalidate
+    pub remapped: bool,
+}
+
+impl<'a> TrapMachine<'a> {
+    pub fn new(sys: &'a mut dyn Machine, decode_cache: Option<&'a mut DecodeCache>,
+            code_map: Option<&'a mut CodeMap>, physical: bool, record_undo: bool) -> TrapMachine<'a> {
+        TrapMachine {
+            sys,
+            decode_cache,
+            code_map,
+            physical,
+            record_undo,
+            undo: Vec::new(),
+            remapped: false,
+        }
+    }
+}
+
+impl Machine for TrapMachine<'_> {
+    fn peek(&self, address: u32) -> u8 {
+        self.sys.peek(address)
+    }
+
+    fn poke(&mut self, address: u32, value: u8) {
+        if self.physical {
+            self.remapped = true;
+        } else {
+            if let Some(cache) = self.decode_cache.as_deref_mut() {
+                cache.written(address);
+            }
+            if let Some(code) = self.code_map.as_deref_mut() {
+                code.written(address);
+            }
+        }
+        if self.record_undo {
+            self.undo.push((address, self.sys.peek_debug(address)));
+        }
+        self.sys.poke(address, value);
+    }
+
+    fn peek_debug(&self, address: u32) -> u8 {
+        self.sys.peek_debug(address)
+    }
+
+    fn use_cycles(&self, cycles: i32) {
+        self.sys.use_cycles(cycles);
+    }
+
+    fn memory_generation(&self) -> u64 {
+        self.sys.memory_generation()
+    }
+
+    fn diagnostic(&self, message: fmt::Arguments) {
+        self.sys.diagnostic(message);
+    }
+
+    fn nextreg(&mut self, register: u8, value: u8) {
+        self.sys.nextreg(register, value);
+    }
+
+    fn port_in(&mut self, address: u16) -> u8 {
+        self.sys.port_in(address)
+    }
+
+    fn port_out(&mut self, address: u16, value: u8) {
+        self.sys.port_out(address, value);
+    }
+}
@@ -0,0 +1,258 @@
+//! Z180 internal registers and instruction timings
+//!
+//! The MMU maps the 64K logical addresses on 1 MiB of physical memory in
+//! three areas. Common area 0 starts at logical 0 and is not translated.
+//! The bank area starts at the 4K page in the low nibble of CBAR and is
+//! translated with BBR, the common area 1 starts at the page in the high
+//! nibble of CBAR and is translated with CBR:
+//!
+//! ```text
+//! physical = logical + (BBR or CBR) * 4096
+//! ```
+//!
+//! The 64 internal I/O registers are at the ports with the high byte 0 and
+//! the bits 7-6 of the low byte equal to the ones of ICR. The CPU handles
+//! the MMU registers, ITC and ICR. The accesses to the rest of them, like
+//! the ASCI or PRT registers, are passed to the Machine with the port
+//! address used by the program.
+
+use super::opcode::Action;
+use super::registers::{Reg8, Reg16};
+
+const ITC: u8 = 0x34;
+const CBR: u8 = 0x38;
+const BBR: u8 = 0x39;
+const CBAR: u8 = 0x3a;
+const ICR: u8 = 0x3f;
+
+const ITC_TRAP: u8 = 0x80;
+const ITC_UFO: u8 = 0x40;
+
+/// Internal I/O registers of the Z180 emulated by the CPU
+#[derive(Clone, Copy, Debug)]
+pub struct Z180Io {
+    /// Common/Bank Area Register, the start pages of the areas
+    pub cbar: u8,
+    /// Common Base Register, the base of the common area 1
+    pub cbr: u8,
+    /// Bank Base Register, the base of the bank area
+    pub bbr: u8,
+    /// I/O Control Register, the bits 7-6 locate the internal registers
+    pub icr: u8,
+    /// INT/TRAP Control Register
+    pub itc: u8,
+    // Incremented when the mapping changes, to clear the decoded code
+    generation: u64,
+}
+
+impl Z180Io {
+    /// Returns the registers after a reset, with the logical addresses
+    /// mapped on the first 64K of physical memory
+    pub fn new() -> Z180Io {
+        Z180Io {
+            cbar: 0xf0,
+            cbr: 0x00,
+            bbr: 0x00,
+            icr: 0x1f,
+            itc: 0x01,
+            generation: 0,
+        }
+    }
+
+    /// Restores the values after a reset
+    pub fn reset(&mut self) {
+        let generation = self.generation + 1;
+        *self = Z180Io::new();
+        self.generation = generation;
+    }
+
+    /// Returns the physical address of a logical address
+    #[inline]
+    pub fn translate(&self, address: u32) -> u32 {
+        let logical = address & 0xffff;
+        let page = (logical >> 12) as u8;
+        let base = if page >= self.cbar >> 4 {
+            self.cbr
+        } else if page >= self.cbar & 0x0f {
+            self.bbr
+        } else {
+            0
+        };
+        (logical + ((base as u32) << 12)) & 0xfffff
+    }
+
+    /// Returns a counter of the changes of the mapping
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Returns the number of the internal register on a port address
+    fn internal_register(&self, address: u16) -> Option<u8> {
+        if address & 0xff00 == 0 && address as u8 & 0xc0 == self.icr & 0xc0 {
+            Some(address as u8 & 0x3f)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the value of an internal register handled by the CPU
+    pub(crate) fn port_in(&self, address: u16) -> Option<u8> {
+        match self.internal_register(address)? {
+            ITC => Some(self.itc),
+            CBR => Some(self.cbr),
+            BBR => Some(self.bbr),
+            CBAR => Some(self.cbar),
+            ICR => Some(self.icr),
+            _ => None
+        }
+    }
+
+    /// Writes an internal register handled by the CPU. Returns false if
+    /// the port is for the Machine.
+    pub(crate) fn port_out(&mut self, address: u16, value: u8) -> bool {
+        match self.internal_register(address) {
+            Some(ITC) => {
+                // TRAP can only be cleared and UFO is read only
+                let trap = self.itc & value & ITC_TRAP;
+                self.itc = trap | (self.itc & ITC_UFO) | (value & 0x07);
+            }
+            Some(CBR) => {
+                self.cbr = value;
+                self.generation += 1;
+            }
+            Some(BBR) => {
+                self.bbr = value;
+                self.generation += 1;
+            }
+            Some(CBAR) => {
+                self.cbar = value;
+                self.generation += 1;
+            }
+            Some(ICR) => self.icr = (value & 0xe0) | 0x1f,
+            _ => return false
+        }
+        true
+    }
+
+    /// Records an undefined opcode on ITC. `third_byte` is true if the
+    /// undefined byte is the third of the opcode.
+    pub(crate) fn trap(&mut self, third_byte: bool) {
+        self.itc |= ITC_TRAP;
+        if third_byte {
+            self.itc |= ITC_UFO;
+        } else {
+            self.itc &= !ITC_UFO;
+        }
+    }
+}
+
+impl Default for Z180Io {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the cycles of a Z180 instruction, as listed on the Z8018x
+/// product specification, without wait states. `start` is the address of
+/// the instruction and `next` the PC after its execution, to know if the
+/// conditional jumps are taken and the block instructions repeated.
+pub(crate) fn cycles(action: &Action, indexed: bool, start: u32, next: u32) -> i32 {
+    let memory = |r: &Reg8| *r == Reg8::_HL;
+    let taken = |len: u32| next & 0xffff != start.wrapping_add(len) & 0xffff;
+    let repeated = next == start;
+    // Cycles with (HL), with (IX+d) and with the registers
+    let by_operand = |r: &Reg8, hl: i32, ix: i32, other: i32| {
+        if !memory(r) {
+            other
+        } else if indexed {
+            ix
+        } else {
+            hl
+        }
+    };
+    let by_index = |hl: i32, ix: i32| if indexed { ix } else { hl };
+
+    match action {
+        Action::Nop => 3,
+        Action::Halt => 3,
+        Action::Slp => 8,
+        Action::Trap(_) => 11,
+        Action::PopRr(_) => by_index(9, 12),
+        Action::PushRr(_) => by_index(11, 14),
+        Action::ConfInterrupts(_) => 3,
+        Action::Im(_) => 6,
+
+        Action::TstAR(r) => by_operand(r, 10, 10, 7),
+        Action::TstAN => 9,
+        Action::TstIoN => 12,
+        Action::OperatorAR(r, _) | Action::OperatorARExt(r, _) => by_operand(r, 6, 14, 4),
+        Action::OperatorAN(_) => 6,
+        Action::CpBlock(_, repeat) | Action::LdBlock(_, repeat)
+        | Action::InBlock(_, repeat) | Action::OutBlock(_, repeat) => {
+            if *repeat && repeated { 14 } else { 12 }
+        }
+        Action::Otim(_, repeat) => if *repeat && repeated { 16 } else { 14 },
+        Action::MltRr(_) => 17,
+
+        Action::AddHlRr(_) => by_index(7, 10),
+        Action::AdcHlRr(_) | Action::SbcHlRr(_) => 10,
+        Action::IncR(r) | Action::DecR(r) => by_operand(r, 10, 18, 4),
+        Action::IncDecRr(_, _) => by_index(4, 7),
+        Action::Neg => 6,
+        Action::Daa => 4,
+
+        Action::RotR(r, _, _, fast, _) => if *fast { 3 } else { by_operand(r, 13, 19, 7) },
+        Action::BitR(_, r) => by_operand(r, 9, 15, 6),
+        Action::SetResR(_, r, _) => by_operand(r, 13, 19, 7),
+        Action::IndexedSetResR(_, _, _) => 19,
+        Action::Cpl | Action::Scf | Action::Ccf => 3,
+        Action::Rxd(_) => 16,
+
+        Action::OutCR(_) | Action::OutC0 => 10,
+        Action::OutNA => 10,
+        Action::Out0NR(_) => 13,
+        Action::In0RN(_) => 12,
+        Action::InRC(_) | Action::In0C => 9,
+        Action::InAN => 9,
+
+        Action::Djnz => if taken(2) { 9 } else { 7 },
+        Action::JrUnconditional => 8,
+        Action::JrEq(_, _) => if taken(2) { 8 } else { 6 },
+        Action::JpUnconditional => 9,
+        Action::JpEq(_, _) => if taken(3) { 9 } else { 6 },
+        Action::JpHl => by_index(3, 6),
+        Action::Call => 16,
+        Action::CallEq(_, _) => if taken(3) { 16 } else { 6 },
+        Action::Rst(_) => 11,
+        Action::Ret => 9,
+        Action::Reti => 22,
+        Action::Retn => 12,
+        Action::RetEq(_, _) => if taken(1) { 10 } else { 5 },
+
+        Action::LdRR(dst, src) => {
+            if matches!(dst, Reg8::I | Reg8::R) || matches!(src, Reg8::I | Reg8::R) { 6 } else { 4 }
+        }
+        Action::LdRRExt(dst, src) => {
+            if memory(dst) {
+                by_index(7, 15)
+            } else {
+                by_operand(src, 6, 14, 4)
+            }
+        }
+        Action::LdRN(r) => by_operand(r, 9, 15, 6),
+        Action::LdAPrr(_) => 6,
+        Action::LdAPnn => 12,
+        Action::LdPrrA(_) => 7,
+        Action::LdPnnA => 13,
+        Action::LdRrNn(_) => by_index(9, 12),
+        Action::LdSpHl => by_index(4, 7),
+        Action::LdPnnRr(rr) => if *rr == Reg16::HL { by_index(16, 19) } else { 19 },
+        Action::LdRrPnn(rr) => if *rr == Reg16::HL { by_index(15, 18) } else { 18 },
+        Action::ExAf => 4,
+        Action::Exx | Action::ExDeHl => 3,
+        Action::ExPspHl => by_index(16, 19),
+
+        // eZ80 and 8080 instructions, not decoded for the Z180
+        _ => 0
+    }
+}
@@ -1,4 +1,8 @@
 // misc Machine tools
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
 use crate::Machine;
 
 pub fn memset<M: Machine>(machine: &mut M, address: u32, fill: u8, count: u32) {
@@ -8,10 +12,8 @@ pub fn memset<M: Machine>(machine: &mut M, address: u32, fill: u8, count: u32) {
 }
 
 pub fn memcpy_to_z80<M: Machine>(machine: &mut M, start: u32, data: &[u8]) {
-    let mut loc = start;
-    for byte in data {
+    for (loc, byte) in (start..).zip(data.iter()) {
         machine.poke(loc, *byte);
-        loc += 1;
     }
 }
 
@@ -32,7 +34,62 @@ pub fn get_cstring<M: Machine>(machine: &M, address: u32) -> Vec<u8> {
 pub fn checksum<M: Machine>(machine: &M, start: u32, len: u32) -> u32 {
     let mut checksum = 0u32;
     for i in (start..(start+len)).step_by(3) {
-        checksum ^= machine._peek24(i as u32);
+        checksum ^= machine._peek24(i);
     }
     checksum
 }
+
+/// Loads an Intel HEX file into memory. Supports the data, end of file,
+/// extended segment and extended linear address records. Returns the start
+/// address, if the file has a start address record.
+pub fn load_intel_hex<M: Machine>(machine: &mut M, text: &str) -> Result<Option<u32>, String> {
+    let mut base = 0u32;
+    let mut start = None;
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |msg: &str| format!("Intel HEX line {}: {}", number + 1, msg);
+        let digits = line.strip_prefix(':').ok_or_else(|| error("missing ':'"))?;
+        if digits.len() < 10 || digits.len() % 2 != 0 {
+            return Err(error("bad record length"));
+        }
+        if !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(error("bad hex digit"));
+        }
+        let bytes = (0..digits.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| error("bad hex digit"))?;
+        let count = bytes[0] as usize;
+        if bytes.len() != count + 5 {
+            return Err(error("byte count mismatch"));
+        }
+        if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(error("bad checksum"));
+        }
+        let offset = ((bytes[1] as u32) << 8) + bytes[2] as u32;
+        let data = &bytes[4..4 + count];
+        let expected_count = match bytes[3] {
+            0x02 | 0x04 => Some(2),
+            0x03 | 0x05 => Some(4),
+            _ => None
+        };
+        if expected_count.is_some_and(|c| c != count) {
+            return Err(error("bad address record"));
+        }
+        match bytes[3] {
+            0x00 => memcpy_to_z80(machine, base + offset, data),
+            0x01 => break,
+            0x02 => base = (((data[0] as u32) << 8) + data[1] as u32) << 4,
+            0x03 => start = Some((((data[0] as u32) << 8) + data[1] as u32) * 16
+                + ((data[2] as u32) << 8) + data[3] as u32),
+            0x04 => base = (((data[0] as u32) << 8) + data[1] as u32) << 16,
+            0x05 => start = Some(data.iter().fold(0u32, |a, b| (a << 8) + *b as u32)),
+            _ => return Err(error("unknown record type"))
+        }
+    }
+    Ok(start)
+}
@@ -0,0 +1,470 @@
+use std::fs;
+use std::path::PathBuf;
+
+use ez80::*;
+use ez80::agon::*;
+use ez80::cpm::BufferConsole;
+use ez80::ez80f92::{ChipSelect, SdCard, SerialBackend};
+
+mod common;
+use common::temp_dir;
+
+fn send(vdp: &mut Vdp, data: &[u8]) {
+    for b in data.iter() {
+        vdp.send(*b);
+    }
+}
+
+fn receive_all(vdp: &mut Vdp) -> Vec<u8> {
+    let mut data = Vec::new();
+    while let Some(b) = vdp.receive() {
+        data.push(b);
+    }
+    data
+}
+
+#[test]
+fn test_vdp_text() {
+    let mut vdp = Vdp::new(false);
+    send(&mut vdp, b"Agon\r\nLight");
+    assert_eq!(vdp.text(), "Agon\nLight");
+    assert_eq!(vdp.cursor(), (5, 1));
+
+    // VDU 31, x, y moves the cursor
+    send(&mut vdp, &[31, 10, 3]);
+    send(&mut vdp, b"MOS");
+    assert_eq!(vdp.line(3), "          MOS");
+
+    // VDU 12 clears the screen
+    send(&mut vdp, &[12]);
+    assert_eq!(vdp.text(), "");
+    assert_eq!(vdp.cursor(), (0, 0));
+}
+
+#[test]
+fn test_vdp_scroll() {
+    let mut vdp = Vdp::new(false);
+    for row in 0..ROWS + 1 {
+        send(&mut vdp, format!("{}\r\n", row).as_bytes());
+    }
+    assert_eq!(vdp.line(0), "2");
+    assert_eq!(vdp.line(ROWS - 2), format!("{}", ROWS));
+    assert_eq!(vdp.cursor(), (0, ROWS - 1));
+}
+
+#[test]
+fn test_vdp_system_commands() {
+    let mut vdp = Vdp::new(false);
+
+    // General poll
+    send(&mut vdp, &[23, 0, 0x80, 0x5a]);
+    assert_eq!(receive_all(&mut vdp), vec![0x80, 1, 0x5a]);
+
+    // Cursor position
+    send(&mut vdp, &[31, 7, 2, 23, 0, 0x82]);
+    assert_eq!(receive_all(&mut vdp), vec![0x82, 2, 7, 2]);
+
+    // Mode information
+    send(&mut vdp, &[23, 0, 0x86]);
+    assert_eq!(receive_all(&mut vdp), vec![0x86, 8, 0x80, 0x02, 0xe0, 0x01, 80, 60, 16, 0]);
+
+    // Keys
+    vdp.press_key(b'A');
+    assert_eq!(receive_all(&mut vdp), vec![0x81, 4, b'A', 0, 0, 1, 0x81, 4, b'A', 0, 0, 0]);
+}
+
+#[test]
+fn test_agon_uart0_and_memory() {
+    let code = [
+        0x3e, 0x80,             // LD A, $80
+        0xed, 0x39, 0xc3,       // OUT0 (UART0_LCR), A
+        0x3e, 0x0a,             // LD A, 10
+        0xed, 0x39, 0xc0,       // OUT0 (UART0_BRG_L), A
+        0xaf,                   // XOR A
+        0xed, 0x39, 0xc1,       // OUT0 (UART0_BRG_H), A
+        0x3e, 0x03,             // LD A, 3
+        0xed, 0x39, 0xc3,       // OUT0 (UART0_LCR), A
+        0x21, 0x00, 0x00, 0x04, // LD HL, $040000
+        0x3e, b'*',             // LD A, '*'
+        0x77,                   // LD (HL), A
+        0x21, 0x00, 0xe0, 0x0b, // LD HL, $0BE000
+        0x3e, b'+',             // LD A, '+'
+        0x77,                   // LD (HL), A
+        0x3a, 0x00, 0x00, 0x04, // LD A, ($040000)
+        0x47,                   // LD B, A
+        0xed, 0x38, 0xc5,       // IN0 A, (UART0_LSR)
+        0xcb, 0x6f,             // BIT 5, A
+        0x28, 0xf9,             // JR Z, -7
+        0x78,                   // LD A, B
+        0xed, 0x39, 0xc0,       // OUT0 (UART0_THR), A
+        0xed, 0x38, 0xc5,       // IN0 A, (UART0_LSR)
+        0xcb, 0x6f,             // BIT 5, A
+        0x28, 0xf9,             // JR Z, -7
+        0x3a, 0x00, 0xe0, 0x0b, // LD A, ($0BE000)
+        0xed, 0x39, 0xc0,       // OUT0 (UART0_THR), A
+        0x76,                   // HALT
+    ];
+    let mut agon = Agon::new(false);
+    agon.load_mos(&code);
+
+    assert!(agon.run_until(100_000, |agon| agon.vdp().text() == "*+"));
+    assert!(agon.cpu.is_halted());
+
+    // The on-chip RAM is over the external RAM
+    assert_eq!(agon.sys.bus.ram[0], b'*');
+    assert_eq!(agon.sys.bus.ram[(ONCHIP_RAM - EXTERNAL_RAM) as usize], 0);
+    assert_eq!(agon.sys.peek_debug(ONCHIP_RAM), b'+');
+}
+
+/// Copies [code] on the image at [address]
+fn place(image: &mut Vec<u8>, address: usize, code: &[u8]) {
+    if image.len() < address + code.len() {
+        image.resize(address + code.len(), 0xff);
+    }
+    image[address..address + code.len()].copy_from_slice(code);
+}
+
+/// A boot ROM that does what MOS does on boot, with the same peripherals:
+/// it maps the RAM, reads the first block of the SD card and prints the
+/// text on it and a prompt, then waits for the keys of the VDP on the
+/// UART0 interrupt
+fn boot_rom() -> Vec<u8> {
+    let mut rom = Vec::new();
+    place(&mut rom, 0x0000, &[
+        // Memory controller
+        0x3e, 0x04,             // LD A, $04
+        0xed, 0x39, 0xa8,       // OUT0 (CS0_LBR), A
+        0x3e, 0x0b,             // LD A, $0B
+        0xed, 0x39, 0xa9,       // OUT0 (CS0_UBR), A
+        0x3e, 0x08,             // LD A, $08
+        0xed, 0x39, 0xaa,       // OUT0 (CS0_CTL), A
+        0x3e, 0x0b,             // LD A, $0B
+        0xed, 0x39, 0xb5,       // OUT0 (RAM_ADDR_U), A
+        0x3e, 0x80,             // LD A, $80
+        0xed, 0x39, 0xb4,       // OUT0 (RAM_CTL), A
+        0x31, 0x00, 0x00, 0x0c, // LD SP, $0C0000
+        0x21, 0x00, 0x02, 0x04, // LD HL, $040200
+        0x22, 0x00, 0xe0, 0x0b, // LD ($0BE000), HL
+        // UART0, with the receive interrupt
+        0x3e, 0x80,             // LD A, $80
+        0xed, 0x39, 0xc3,       // OUT0 (UART0_LCR), A
+        0x3e, 0x0a,             // LD A, 10
+        0xed, 0x39, 0xc0,       // OUT0 (UART0_BRG_L), A
+        0xaf,                   // XOR A
+        0xed, 0x39, 0xc1,       // OUT0 (UART0_BRG_H), A
+        0x3e, 0x03,             // LD A, 3
+        0xed, 0x39, 0xc3,       // OUT0 (UART0_LCR), A
+        0x3e, 0x01,             // LD A, 1
+        0xed, 0x39, 0xc1,       // OUT0 (UART0_IER), A
+        // PB4, the chip select of the SD card, as output
+        0x3e, 0x10,             // LD A, $10
+        0xed, 0x39, 0x9a,       // OUT0 (PB_DR), A
+        0xaf,                   // XOR A
+        0xed, 0x39, 0x9c,       // OUT0 (PB_ALT1), A
+        0xed, 0x39, 0x9d,       // OUT0 (PB_ALT2), A
+        0x3e, 0xef,             // LD A, $EF
+        0xed, 0x39, 0x9b,       // OUT0 (PB_DDR), A
+        // SPI master
+        0x3e, 0x03,             // LD A, 3
+        0xed, 0x39, 0xb8,       // OUT0 (SPI_BRG_L), A
+        0xaf,                   // XOR A
+        0xed, 0x39, 0xb9,       // OUT0 (SPI_BRG_H), A
+        0x3e, 0x30,             // LD A, $30
+        0xed, 0x39, 0xba,       // OUT0 (SPI_CTL), A
+        // The card is initialized and the block 0 is read at $040000
+        0xaf,                   // XOR A
+        0xed, 0x39, 0x9a,       // OUT0 (PB_DR), A
+        0x3e, 0x00,             // LD A, 0
+        0xcd, 0x40, 0x03, 0x00, // CALL sd_command
+        0x3e, 0x37,             // LD A, 55
+        0xcd, 0x40, 0x03, 0x00, // CALL sd_command
+        0x3e, 0x29,             // LD A, 41
+        0xcd, 0x40, 0x03, 0x00, // CALL sd_command
+        0x3e, 0x11,             // LD A, 17
+        0xcd, 0x40, 0x03, 0x00, // CALL sd_command
+        0x3e, 0xff,             // LD A, $FF
+        0xcd, 0x00, 0x03, 0x00, // CALL spi_transfer
+        0xfe, 0xfe,             // CP $FE
+        0x20, 0xf6,             // JR NZ, -10
+        0x21, 0x00, 0x00, 0x04, // LD HL, $040000
+        0x01, 0x00, 0x02, 0x00, // LD BC, 512
+        0x3e, 0xff,             // LD A, $FF
+        0xcd, 0x00, 0x03, 0x00, // CALL spi_transfer
+        0x77,                   // LD (HL), A
+        0x23,                   // INC HL
+        0x0b,                   // DEC BC
+        0x78,                   // LD A, B
+        0xb1,                   // OR C
+        0x20, 0xf3,             // JR NZ, -13
+        0x3e, 0x10,             // LD A, $10
+        0xed, 0x39, 0x9a,       // OUT0 (PB_DR), A
+        // Text of the card and prompt
+        0x21, 0x00, 0x00, 0x04, // LD HL, $040000
+        0xcd, 0xc0, 0x03, 0x00, // CALL print
+        0x21, 0x00, 0x04, 0x00, // LD HL, prompt
+        0xcd, 0xc0, 0x03, 0x00, // CALL print
+        // Vectors on $0100
+        0x3e, 0x01,             // LD A, 1
+        0xed, 0x47,             // LD I, A
+        0xed, 0x5e,             // IM 2
+        0xfb,                   // EI
+        0x76,                   // HALT
+        0x18, 0xfd,             // JR -3
+    ]);
+    // UART0 vector
+    place(&mut rom, 0x0118, &[0x00, 0x02]);
+    // UART0 handler, stores the bytes received from $040200
+    place(&mut rom, 0x0200, &[
+        0xf5,                   // PUSH AF
+        0xe5,                   // PUSH HL
+        0xed, 0x38, 0xc5,       // IN0 A, (UART0_LSR)
+        0xe6, 0x01,             // AND DR
+        0x28, 0x0f,             // JR Z, +15
+        0xed, 0x38, 0xc0,       // IN0 A, (UART0_RBR)
+        0x2a, 0x00, 0xe0, 0x0b, // LD HL, ($0BE000)
+        0x77,                   // LD (HL), A
+        0x23,                   // INC HL
+        0x22, 0x00, 0xe0, 0x0b, // LD ($0BE000), HL
+        0x18, 0xea,             // JR -22
+        0xe1,                   // POP HL
+        0xf1,                   // POP AF
+        0xfb,                   // EI
+        0xed, 0x4d,             // RETI
+    ]);
+    // spi_transfer: sends A and returns the byte received in A
+    place(&mut rom, 0x0300, &[
+        0xed, 0x39, 0xbc,       // OUT0 (SPI_TSR), A
+        0xed, 0x38, 0xbb,       // IN0 A, (SPI_SR)
+        0xe6, 0x80,             // AND SPIF
+        0x28, 0xf9,             // JR Z, -7
+        0xed, 0x38, 0xbc,       // IN0 A, (SPI_RBR)
+        0xc9,                   // RET
+    ]);
+    // sd_command: sends the command A with the argument 0, returns R1
+    let mut sd_command = vec![
+        0xf6, 0x40,             // OR $40
+        0xcd, 0x00, 0x03, 0x00, // CALL spi_transfer
+    ];
+    for _ in 0..4 {
+        sd_command.extend([
+            0xaf,                   // XOR A
+            0xcd, 0x00, 0x03, 0x00, // CALL spi_transfer
+        ]);
+    }
+    sd_command.extend([
+        0x3e, 0x95,             // LD A, $95
+        0xcd, 0x00, 0x03, 0x00, // CALL spi_transfer
+        0x3e, 0xff,             // LD A, $FF
+        0xcd, 0x00, 0x03, 0x00, // CALL spi_transfer
+        0xfe, 0xff,             // CP $FF
+        0x28, 0xf6,             // JR Z, -10
+        0xc9,                   // RET
+    ]);
+    place(&mut rom, 0x0340, &sd_command);
+    // putc: writes A to UART0
+    place(&mut rom, 0x0380, &[
+        0xf5,                   // PUSH AF
+        0xed, 0x38, 0xc5,       // IN0 A, (UART0_LSR)
+        0xe6, 0x20,             // AND THRE
+        0x28, 0xf9,             // JR Z, -7
+        0xf1,                   // POP AF
+        0xed, 0x39, 0xc0,       // OUT0 (UART0_THR), A
+        0xc9,                   // RET
+    ]);
+    // print: writes the text at HL up to a 0
+    place(&mut rom, 0x03c0, &[
+        0x7e,                   // LD A, (HL)
+        0xb7,                   // OR A
+        0xc8,                   // RET Z
+        0xcd, 0x80, 0x03, 0x00, // CALL putc
+        0x23,                   // INC HL
+        0x18, 0xf6,             // JR -10
+    ]);
+    place(&mut rom, 0x0400, b"\r\n*\0");
+    rom
+}
+
+#[test]
+fn test_agon_boot_with_sdcard_and_interrupts() {
+    let dir = temp_dir("mos_boot");
+    let path = dir.join("sdcard.img");
+    let mut image = vec![0u8; 1024 * 512];
+    place(&mut image, 0, b"Agon test card\0");
+    fs::write(&path, &image).unwrap();
+
+    let mut agon = Agon::new(false);
+    // The memory controller as after a reset, the ROM maps the memory
+    agon.sys.cs[0] = ChipSelect::new(0);
+    agon.sys.ram_addr_u = 0xff;
+    agon.load_mos(&boot_rom());
+    agon.insert_sdcard(Some(SdCard::open(path.to_str().unwrap()).unwrap()));
+
+    let booted = agon.run_until(CLOCK as u64, |agon| agon.vdp().text().ends_with('*'));
+    assert!(booted, "No prompt:\n{}", agon.vdp().text());
+    assert_eq!(agon.vdp().text(), "Agon test card\n*");
+    assert_eq!(agon.sys.cs[0].ubr, 0x0b);
+    assert_eq!(&agon.sys.bus.ram[..4], b"Agon");
+
+    // The key down and up packets arrive with the UART0 interrupt
+    agon.vdp().press_key(b'A');
+    // The pointer of the handler is on the on-chip RAM
+    let received = agon.run_until(CLOCK as u64 / 10, |agon| agon.sys.ram[..3] == [0x0c, 0x02, 0x04]);
+    assert!(received);
+    assert_eq!(&agon.sys.bus.ram[0x200..0x20c], &[0x81, 4, b'A', 0, 0, 1, 0x81, 4, b'A', 0, 0, 0]);
+    assert!(agon.cpu.is_halted());
+    assert!(agon.sys.bus.intc.active().is_empty());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+/// Boots the MOS image on the path of the environment variable AGON_MOS,
+/// up to the prompt
+#[test]
+#[ignore]
+fn test_agon_mos_boot() {
+    let path = std::env::var("AGON_MOS").expect("AGON_MOS must have the path of a MOS image");
+    let mut agon = Agon::new(false);
+    agon.load_mos_file(&path).unwrap();
+    let booted = agon.run_until(20 * CLOCK as u64, |agon| {
+        let vdp = agon.vdp();
+        (0..ROWS).any(|row| vdp.line(row).starts_with('*'))
+    });
+    assert!(booted, "No prompt:\n{}", agon.vdp().text());
+}
+
+fn run_program(code: &[u8], dir: Option<&PathBuf>) -> (Agon, Mos<BufferConsole>, u32) {
+    let mut agon = Agon::new(false);
+    let mos = Mos::new(BufferConsole::new(b""));
+    if let Some(dir) = dir {
+        mos.map_directory(dir);
+    }
+    mos.install(&mut agon.cpu);
+    mos.load(&mut agon.cpu, &mut agon.sys, code, "");
+    let exit_code = mos.run(&mut agon.cpu, &mut agon.sys);
+    (agon, mos, exit_code)
+}
+
+#[test]
+fn test_mos_output() {
+    let code = [
+        0x21, 0x15, 0x00, 0x04, // LD HL, message
+        0x01, 0x00, 0x00, 0x00, // LD BC, 0
+        0x3e, 0x00,             // LD A, 0
+        0x5b, 0xdf,             // RST.LIL 18h
+        0x3e, b'!',             // LD A, '!'
+        0x5b, 0xd7,             // RST.LIL 10h
+        0x21, 0x2a, 0x00, 0x00, // LD HL, 42
+        0xc9,                   // RET
+        b'H', b'e', b'l', b'l', b'o', 0,
+    ];
+    let (agon, mos, exit_code) = run_program(&code, None);
+    assert_eq!(mos.console().output_string(), "Hello!");
+    assert_eq!(exit_code, 42);
+    assert!(mos.is_terminated());
+    assert_eq!(agon.cpu.state.pc(), EXIT_ADDRESS + 1);
+}
+
+#[test]
+fn test_mos_files() {
+    let dir = temp_dir("mos_files");
+    let code = [
+        0x21, 0x3c, 0x00, 0x04, // LD HL, name
+        0x0e, 0x0a,             // LD C, FA_WRITE | FA_CREATE_ALWAYS
+        0x3e, 0x0a,             // LD A, mos_fopen
+        0x5b, 0xcf,             // RST.LIL 08h
+        0x4f,                   // LD C, A
+        0x06, b'X',             // LD B, 'X'
+        0x3e, 0x0d,             // LD A, mos_fputc
+        0x5b, 0xcf,             // RST.LIL 08h
+        0x3e, 0x0b,             // LD A, mos_fclose
+        0x5b, 0xcf,             // RST.LIL 08h
+        0x21, 0x3c, 0x00, 0x04, // LD HL, name
+        0x0e, 0x01,             // LD C, FA_READ
+        0x3e, 0x0a,             // LD A, mos_fopen
+        0x5b, 0xcf,             // RST.LIL 08h
+        0x4f,                   // LD C, A
+        0x3e, 0x0c,             // LD A, mos_fgetc
+        0x5b, 0xcf,             // RST.LIL 08h
+        0x32, 0x00, 0x00, 0x05, // LD ($050000), A
+        0x3e, 0x0c,             // LD A, mos_fgetc
+        0x5b, 0xcf,             // RST.LIL 08h
+        0x3e, 0x00,             // LD A, 0
+        0x17,                   // RLA
+        0x32, 0x01, 0x00, 0x05, // LD ($050001), A
+        0x3e, 0x0b,             // LD A, mos_fclose
+        0x5b, 0xcf,             // RST.LIL 08h
+        0x21, 0x00, 0x00, 0x00, // LD HL, 0
+        0xc9,                   // RET
+        b't', b'e', b's', b't', b'.', b't', b'x', b't', 0,
+    ];
+    let (agon, _, exit_code) = run_program(&code, Some(&dir));
+    assert_eq!(exit_code, 0);
+    assert_eq!(fs::read(dir.join("test.txt")).unwrap(), b"X");
+    // The byte read and the carry of the end of file
+    assert_eq!(agon.sys.peek_debug(0x050000), b'X');
+    assert_eq!(agon.sys.peek_debug(0x050001), 1);
+}
+
+#[test]
+fn test_mos_fgetc_last_byte() {
+    let dir = temp_dir("mos_fgetc");
+    fs::write(dir.join("two.bin"), [1, 2]).unwrap();
+    let code = [
+        0x21, 0x32, 0x00, 0x04, // LD HL, name
+        0x0e, 0x01,             // LD C, FA_READ
+        0x3e, 0x0a,             // LD A, mos_fopen
+        0x5b, 0xcf,             // RST.LIL 08h
+        0x4f,                   // LD C, A
+        0x3e, 0x0c,             // LD A, mos_fgetc
+        0x5b, 0xcf,             // RST.LIL 08h
+        0x32, 0x00, 0x00, 0x05, // LD ($050000), A
+        0x3e, 0x00,             // LD A, 0
+        0x17,                   // RLA
+        0x32, 0x01, 0x00, 0x05, // LD ($050001), A
+        0x3e, 0x0c,             // LD A, mos_fgetc
+        0x5b, 0xcf,             // RST.LIL 08h
+        0x32, 0x02, 0x00, 0x05, // LD ($050002), A
+        0x3e, 0x00,             // LD A, 0
+        0x17,                   // RLA
+        0x32, 0x03, 0x00, 0x05, // LD ($050003), A
+        0x3e, 0x0b,             // LD A, mos_fclose
+        0x5b, 0xcf,             // RST.LIL 08h
+        0x21, 0x00, 0x00, 0x00, // LD HL, 0
+        0xc9,                   // RET
+        b't', b'w', b'o', b'.', b'b', b'i', b'n', 0,
+    ];
+    let (agon, _, exit_code) = run_program(&code, Some(&dir));
+    assert_eq!(exit_code, 0);
+    // The carry is set with the last byte of the file
+    assert_eq!(agon.sys.peek_debug(0x050000), 1);
+    assert_eq!(agon.sys.peek_debug(0x050001), 0);
+    assert_eq!(agon.sys.peek_debug(0x050002), 2);
+    assert_eq!(agon.sys.peek_debug(0x050003), 1);
+}
+
+#[test]
+fn test_mos_load_and_sysvars() {
+    let dir = temp_dir("mos_load");
+    fs::write(dir.join("DATA.BIN"), [1, 2, 3]).unwrap();
+    let code = [
+        0x21, 0x1c, 0x00, 0x04, // LD HL, name
+        0x11, 0x00, 0x00, 0x05, // LD DE, $050000
+        0x01, 0x00, 0x01, 0x00, // LD BC, $100
+        0x3e, 0x01,             // LD A, mos_load
+        0x5b, 0xcf,             // RST.LIL 08h
+        0x32, 0x00, 0x01, 0x05, // LD ($050100), A
+        0x3e, 0x08,             // LD A, mos_sysvars
+        0x5b, 0xcf,             // RST.LIL 08h
+        0xdd, 0xe5,             // PUSH IX
+        0xe1,                   // POP HL
+        0xc9,                   // RET
+        b'd', b'a', b't', b'a', b'.', b'b', b'i', b'n', 0,
+    ];
+    let (agon, _, exit_code) = run_program(&code, Some(&dir));
+    assert_eq!(exit_code, SYSVARS);
+    assert_eq!(agon.sys.peek_debug(0x050100), 0);
+    assert_eq!(agon.sys.peek_debug(0x050000), 1);
+    assert_eq!(agon.sys.peek_debug(0x050002), 3);
+    // Screen columns and rows
+    assert_eq!(agon.sys.peek_debug(SYSVARS + 0x13), 80);
+    assert_eq!(agon.sys.peek_debug(SYSVARS + 0x14), 60);
+}
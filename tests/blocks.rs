@@ -0,0 +1,179 @@
+use std::cell::RefCell;
+
+use ez80::*;
+
+mod common;
+use common::BankedMachine;
+
+/// Records every call to the Machine
+struct LoggingMachine {
+    sys: Box<PlainMachine>,
+    log: RefCell<Vec<(char, u32, i32)>>,
+}
+
+impl LoggingMachine {
+    fn new(code: &[u8]) -> LoggingMachine {
+        let mut sys = Box::new(PlainMachine::new());
+        for (i, byte) in code.iter().enumerate() {
+            sys.poke(i as u32, *byte);
+        }
+        LoggingMachine {
+            sys,
+            log: RefCell::new(Vec::new()),
+        }
+    }
+}
+
+impl Machine for LoggingMachine {
+    fn peek(&self, address: u32) -> u8 {
+        self.log.borrow_mut().push(('r', address, 0));
+        self.sys.peek(address)
+    }
+
+    fn poke(&mut self, address: u32, value: u8) {
+        self.log.borrow_mut().push(('w', address, value as i32));
+        self.sys.poke(address, value);
+    }
+
+    fn use_cycles(&self, cycles: i32) {
+        self.log.borrow_mut().push(('c', 0, cycles));
+    }
+
+    fn port_in(&mut self, address: u16) -> u8 {
+        self.log.borrow_mut().push(('i', address as u32, 0));
+        0x55
+    }
+
+    fn port_out(&mut self, address: u16, value: u8) {
+        self.log.borrow_mut().push(('o', address as u32, value as i32));
+    }
+}
+
+#[test]
+fn test_blocks_same_machine_calls() {
+    let code = [
+        0x5b, 0x21, 0x00, 0x10, 0x00,  // LD.LIL HL, $001000
+        0xdd, 0x36, 0x05, 0x42,        // LD (IX+5), $42
+        0x01, 0x04, 0x00, 0x00,        // LD BC, $000004
+        0xed, 0xb0,                    // LDIR
+        0xcd, 0x20, 0x00, 0x00,        // CALL $000020
+        0x18, 0xeb,                    // JR $0000
+    ];
+    let mut program = vec![0; 0x20];
+    program.extend_from_slice(&[
+        0xdb, 0x10,                    // IN A, ($10)
+        0x3c,                          // INC A
+        0xd3, 0x11,                    // OUT ($11), A
+        0xc9,                          // RET
+    ]);
+    program[..code.len()].copy_from_slice(&code);
+
+    let mut results = Vec::new();
+    for blocks in [false, true] {
+        let mut sys = LoggingMachine::new(&program);
+        let mut cpu = Cpu::new_ez80();
+        cpu.set_adl(true);
+        cpu.set_block_cache(blocks);
+        cpu.registers().set24(Reg24::SPL, 0x8000);
+        cpu.registers().set24(Reg24::IX, 0x2000);
+        cpu.registers().set24(Reg24::DE, 0x3000);
+        let executed = cpu.execute_instructions(&mut sys, 1000);
+        results.push((executed, sys.log.into_inner(), format!("{:?}", cpu.registers())));
+    }
+    assert_eq!(results[0], results[1]);
+}
+
+#[test]
+fn test_blocks_self_modifying_code() {
+    let code = [
+        0x3e, 0x05,        // LD A, $05
+        0x3c,              // INC A
+        0x32, 0x01, 0x00,  // LD ($0001), A
+        0xc3, 0x00, 0x00,  // JP $0000
+    ];
+    let mut sys = LoggingMachine::new(&code);
+    let mut cpu = Cpu::new();
+    cpu.set_block_cache(true);
+
+    assert_eq!(8, cpu.execute_instructions(&mut sys, 8));
+    assert_eq!(0x07, sys.sys.peek(0x0001));
+    assert_eq!(0x07, cpu.registers().a());
+
+    // Modified without the CPU
+    sys.sys.poke(0x0002, 0x3d);  // DEC A
+    cpu.invalidate_decode_cache(0x0002, 0x0003);
+    cpu.execute_instructions(&mut sys, 2);
+    assert_eq!(0x06, cpu.registers().a());
+}
+
+#[test]
+fn test_blocks_breakpoints() {
+    let code = [
+        0x3c,              // INC A
+        0x04,              // INC B
+        0x0c,              // INC C
+        0xc3, 0x00, 0x00,  // JP $0000
+    ];
+    let mut sys = LoggingMachine::new(&code);
+    let mut cpu = Cpu::new();
+    cpu.set_block_cache(true);
+    cpu.registers().set_a(0x00);
+
+    cpu.execute_instructions(&mut sys, 100);
+    assert_eq!(100, cpu.state.instructions_executed);
+
+    cpu.set_breakpoints(&[0x0002]);
+    cpu.execute_instructions(&mut sys, 100);
+    assert_eq!(0x0002, cpu.state.pc());
+
+    // Continues from the breakpoint
+    assert_eq!(4, cpu.execute_instructions(&mut sys, 100));
+    assert_eq!(0x0002, cpu.state.pc());
+
+    cpu.set_breakpoints(&[]);
+    assert_eq!(100, cpu.execute_instructions(&mut sys, 100));
+}
+
+#[test]
+fn test_blocks_with_history() {
+    let code = [
+        0x3c,              // INC A
+        0xc3, 0x00, 0x00,  // JP $0000
+    ];
+    let mut sys = LoggingMachine::new(&code);
+    let mut cpu = Cpu::new();
+    cpu.set_block_cache(true);
+    cpu.set_history(10);
+    cpu.registers().set_a(0x00);
+
+    cpu.execute_instructions(&mut sys, 4);
+    assert_eq!(0x02, cpu.registers().a());
+    assert!(cpu.step_back(&mut sys));
+    assert!(cpu.step_back(&mut sys));
+    assert_eq!(0x01, cpu.registers().a());
+}
+
+#[test]
+fn test_blocks_bank_switch() {
+    let mut sys = BankedMachine::new();
+    let mut cpu = Cpu::new();
+    cpu.set_block_cache(true);
+
+    for bank in 0..2 {
+        sys.bank = bank;
+        sys.poke(0x0000, 0x3c);  // INC A
+        sys.poke(0x0001, 0xd3);  // OUT ($00), A
+        sys.poke(0x0002, 0x00);
+        sys.poke(0x0003, 0xc3);  // JP $0000
+        sys.poke(0x0004, 0x00);
+        sys.poke(0x0005, 0x00);
+    }
+    sys.banks[1].poke(0x0000, 0x3d);  // DEC A
+    sys.bank = 0;
+    cpu.registers().set_a(0x00);
+
+    // INC A and OUT on bank 0, then DEC A on bank 1
+    cpu.execute_instructions(&mut sys, 4);
+    assert_eq!(1, sys.bank);
+    assert_eq!(0x00, cpu.registers().a());
+}
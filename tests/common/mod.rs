@@ -0,0 +1,87 @@
+//! Fixtures shared by the integration tests. Each test crate uses only some
+//! of them.
+#![allow(dead_code)]
+
+use std::cell::RefCell;
+use std::fs;
+use std::path::PathBuf;
+
+use ez80::*;
+
+/// Two banks of memory, switched by writing to any port. Each switch
+/// changes the memory generation.
+pub struct BankedMachine {
+    pub banks: [Box<PlainMachine>; 2],
+    pub bank: usize,
+    pub generation: u64,
+}
+
+impl BankedMachine {
+    pub fn new() -> BankedMachine {
+        BankedMachine {
+            banks: [Box::new(PlainMachine::new()), Box::new(PlainMachine::new())],
+            bank: 0,
+            generation: 0,
+        }
+    }
+}
+
+impl Machine for BankedMachine {
+    fn peek(&self, address: u32) -> u8 {
+        self.banks[self.bank].peek(address)
+    }
+
+    fn poke(&mut self, address: u32, value: u8) {
+        self.banks[self.bank].poke(address, value);
+    }
+
+    fn use_cycles(&self, _cycles: i32) {}
+
+    fn memory_generation(&self) -> u64 {
+        self.generation
+    }
+
+    fn port_in(&mut self, _address: u16) -> u8 {
+        0
+    }
+
+    fn port_out(&mut self, _address: u16, value: u8) {
+        self.bank = value as usize & 1;
+        self.generation += 1;
+    }
+}
+
+/// Records the diagnostic messages of the emulator
+pub struct DiagnosticMachine {
+    pub sys: Box<PlainMachine>,
+    pub messages: RefCell<Vec<String>>,
+}
+
+impl DiagnosticMachine {
+    pub fn new() -> DiagnosticMachine {
+        DiagnosticMachine {
+            sys: Box::new(PlainMachine::new()),
+            messages: RefCell::new(Vec::new()),
+        }
+    }
+}
+
+impl Machine for DiagnosticMachine {
+    fn peek(&self, address: u32) -> u8 { self.sys.peek(address) }
+    fn poke(&mut self, address: u32, value: u8) { self.sys.poke(address, value) }
+    fn use_cycles(&self, cycles: i32) { self.sys.use_cycles(cycles) }
+    fn port_in(&mut self, address: u16) -> u8 { self.sys.port_in(address) }
+    fn port_out(&mut self, address: u16, value: u8) { self.sys.port_out(address, value) }
+
+    fn diagnostic(&self, message: std::fmt::Arguments) {
+        self.messages.borrow_mut().push(message.to_string());
+    }
+}
+
+/// Returns an empty directory for the files of a test
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ez80_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
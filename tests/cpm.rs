@@ -0,0 +1,323 @@
+use std::fs;
+use std::path::PathBuf;
+
+use ez80::*;
+use ez80::cpm::*;
+
+mod common;
+use common::{temp_dir, DiagnosticMachine};
+
+const CTRL_Z: u8 = 0x1a;
+
+fn run(cpu: &mut Cpu, machine: &mut PlainMachine, code: &[u8], args: &str, dir: Option<&PathBuf>) -> Cpm<BufferConsole> {
+    let mut cpm = Cpm::new(BufferConsole::new(&[]));
+    if let Some(dir) = dir {
+        cpm.map_drive(0, dir);
+    }
+    cpm.load(cpu, machine, code, args);
+    for _ in 0..10000 {
+        if !cpm.step(cpu, machine) {
+            break;
+        }
+    }
+    assert!(cpm.is_terminated());
+    cpm
+}
+
+#[test]
+fn test_cpm_write_string() {
+    let code = [
+        0x0e, 0x09,       // LD C, 9
+        0x11, 0x09, 0x01, // LD DE, $0109
+        0xcd, 0x05, 0x00, // CALL 5
+        0xc9,             // RET
+        b'H', b'E', b'L', b'L', b'O', b'$',
+    ];
+    for mut cpu in [Cpu::new_8080(), Cpu::new_z80(), Cpu::new_ez80()] {
+        let mut machine = PlainMachine::new();
+        let cpm = run(&mut cpu, &mut machine, &code, "", None);
+        assert_eq!("HELLO", cpm.console.output_string());
+    }
+}
+
+#[test]
+fn test_cpm_read_console() {
+    let code = [
+        0x0e, 0x01,       // LD C, 1
+        0xcd, 0x05, 0x00, // CALL 5
+        0x5f,             // LD E, A
+        0x1c,             // INC E
+        0x0e, 0x02,       // LD C, 2
+        0xcd, 0x05, 0x00, // CALL 5
+        0xc9,             // RET
+    ];
+    let mut cpu = Cpu::new_z80();
+    let mut machine = PlainMachine::new();
+    let mut cpm = Cpm::new(BufferConsole::new(b"A"));
+    cpm.load(&mut cpu, &mut machine, &code, "");
+    cpm.run(&mut cpu, &mut machine);
+    assert_eq!("AB", cpm.console.output_string());
+}
+
+#[test]
+fn test_cpm_run_instructions() {
+    let code = [
+        0x0e, 0x02,       // LD C, 2
+        0x1e, b'A',       // LD E, 'A'
+        0xcd, 0x05, 0x00, // CALL 5
+        0x1c,             // INC E
+        0x7b,             // LD A, E
+        0xfe, b'E',       // CP 'E'
+        0xc2, 0x04, 0x01, // JP NZ, $0104
+        0xc9,             // RET
+    ];
+    for block_cache in [false, true] {
+        let mut cpu = Cpu::new_z80();
+        cpu.set_block_cache(block_cache);
+        let mut machine = PlainMachine::new();
+        let mut cpm = Cpm::new(BufferConsole::new(&[]));
+        cpm.load(&mut cpu, &mut machine, &code, "");
+        let mut executed = 0;
+        while !cpm.is_terminated() {
+            let count = cpm.run_instructions(&mut cpu, &mut machine, 3);
+            assert!(count <= 3);
+            executed += count;
+        }
+        assert_eq!("ABCD", cpm.console.output_string());
+        // 2 LD, then CALL, JP to the BDOS, RET, INC, LD, CP and JP four
+        // times and the RET
+        assert_eq!(2 + 4 * 7 + 1, executed);
+    }
+}
+
+#[test]
+fn test_cpm_command_tail() {
+    let mut machine = PlainMachine::new();
+    run(&mut Cpu::new_z80(), &mut machine, &[0xc9], "b:foo.txt *.c", None);
+    assert_eq!(14, machine.peek(0x80));
+    assert_eq!(b" B:FOO.TXT *.C", &machine_bytes(&machine, 0x81, 14)[..]);
+    assert_eq!(2, machine.peek(0x5c));
+    assert_eq!(b"FOO     TXT", &machine_bytes(&machine, 0x5d, 11)[..]);
+    assert_eq!(0, machine.peek(0x6c));
+    assert_eq!(b"????????C  ", &machine_bytes(&machine, 0x6d, 11)[..]);
+}
+
+#[test]
+fn test_cpm_make_write_file() {
+    let dir = temp_dir("cpm_write");
+    let mut code = vec![
+        0x11, 0x5c, 0x00, // LD DE, $005c
+        0x0e, 0x16,       // LD C, 22 ; F_MAKE
+        0xcd, 0x05, 0x00, // CALL 5
+        0x11, 0x40, 0x01, // LD DE, $0140
+        0x0e, 0x1a,       // LD C, 26 ; F_DMAOFF
+        0xcd, 0x05, 0x00, // CALL 5
+        0x11, 0x5c, 0x00, // LD DE, $005c
+        0x0e, 0x15,       // LD C, 21 ; F_WRITE
+        0xcd, 0x05, 0x00, // CALL 5
+        0x11, 0x5c, 0x00, // LD DE, $005c
+        0x0e, 0x10,       // LD C, 16 ; F_CLOSE
+        0xcd, 0x05, 0x00, // CALL 5
+        0xc9,             // RET
+    ];
+    code.resize(0x40, 0);
+    let data: Vec<u8> = (0..128).collect();
+    code.extend_from_slice(&data);
+
+    let mut machine = PlainMachine::new();
+    run(&mut Cpu::new_ez80(), &mut machine, &code, "out.txt", Some(&dir));
+    assert_eq!(data, fs::read(dir.join("OUT.TXT")).unwrap());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_cpm_open_read_file() {
+    let dir = temp_dir("cpm_read");
+    fs::write(dir.join("in.txt"), b"ABC").unwrap();
+    let code = [
+        0x11, 0x5c, 0x00, // LD DE, $005c
+        0x0e, 0x0f,       // LD C, 15 ; F_OPEN
+        0xcd, 0x05, 0x00, // CALL 5
+        0x32, 0x00, 0x02, // LD ($0200), A
+        0x11, 0x5c, 0x00, // LD DE, $005c
+        0x0e, 0x14,       // LD C, 20 ; F_READ
+        0xcd, 0x05, 0x00, // CALL 5
+        0x32, 0x01, 0x02, // LD ($0201), A
+        0x11, 0x5c, 0x00, // LD DE, $005c
+        0x0e, 0x14,       // LD C, 20 ; F_READ
+        0xcd, 0x05, 0x00, // CALL 5
+        0x32, 0x02, 0x02, // LD ($0202), A
+        0xc9,             // RET
+    ];
+
+    let mut machine = PlainMachine::new();
+    run(&mut Cpu::new_8080(), &mut machine, &code, "IN.TXT", Some(&dir));
+    assert_eq!([0, 0, 1], &machine_bytes(&machine, 0x200, 3)[..]);
+    assert_eq!(b"ABC\x1a\x1a", &machine_bytes(&machine, 0x80, 5)[..]);
+    assert_eq!(0x1a, machine.peek(0xff));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_cpm_rename_and_make_with_open_fcb() {
+    let dir = temp_dir("cpm_rename");
+    fs::write(dir.join("in.txt"), b"OLD").unwrap();
+    let mut code = vec![
+        0x11, 0x5c, 0x00, // LD DE, $005c
+        0x0e, 0x0f,       // LD C, 15 ; F_OPEN
+        0xcd, 0x05, 0x00, // CALL 5
+        0x11, 0x5c, 0x00, // LD DE, $005c
+        0x0e, 0x14,       // LD C, 20 ; F_READ
+        0xcd, 0x05, 0x00, // CALL 5
+        0x11, 0x5c, 0x00, // LD DE, $005c
+        0x0e, 0x17,       // LD C, 23 ; F_RENAME
+        0xcd, 0x05, 0x00, // CALL 5
+        0x11, 0x5c, 0x00, // LD DE, $005c
+        0x0e, 0x16,       // LD C, 22 ; F_MAKE
+        0xcd, 0x05, 0x00, // CALL 5
+        0xaf,             // XOR A
+        0x32, 0x7c, 0x00, // LD ($007c), A ; CR
+        0x11, 0x40, 0x01, // LD DE, $0140
+        0x0e, 0x1a,       // LD C, 26 ; F_DMAOFF
+        0xcd, 0x05, 0x00, // CALL 5
+        0x11, 0x5c, 0x00, // LD DE, $005c
+        0x0e, 0x15,       // LD C, 21 ; F_WRITE
+        0xcd, 0x05, 0x00, // CALL 5
+        0x11, 0x5c, 0x00, // LD DE, $005c
+        0x0e, 0x10,       // LD C, 16 ; F_CLOSE
+        0xcd, 0x05, 0x00, // CALL 5
+        0xc9,             // RET
+    ];
+    code.resize(0x40, 0);
+    let mut data = b"NEW".to_vec();
+    data.resize(128, CTRL_Z);
+    code.extend_from_slice(&data);
+
+    let mut machine = PlainMachine::new();
+    run(&mut Cpu::new_z80(), &mut machine, &code, "IN.TXT OUT.TXT", Some(&dir));
+    // The file open before the rename is not written to
+    assert_eq!(b"OLD", &fs::read(dir.join("OUT.TXT")).unwrap()[..]);
+    assert_eq!(data, fs::read(dir.join("IN.TXT")).unwrap());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_cpm_open_missing_file() {
+    let dir = temp_dir("cpm_missing");
+    let code = [
+        0x11, 0x5c, 0x00, // LD DE, $005c
+        0x0e, 0x0f,       // LD C, 15 ; F_OPEN
+        0xcd, 0x05, 0x00, // CALL 5
+        0x32, 0x00, 0x02, // LD ($0200), A
+        0xc9,             // RET
+    ];
+
+    let mut machine = PlainMachine::new();
+    run(&mut Cpu::new_z80(), &mut machine, &code, "NONE.TXT", Some(&dir));
+    assert_eq!(0xff, machine.peek(0x200));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_cpm_search_files() {
+    let dir = temp_dir("cpm_search");
+    fs::write(dir.join("b.txt"), b"").unwrap();
+    fs::write(dir.join("a.txt"), b"").unwrap();
+    fs::write(dir.join("c.com"), b"").unwrap();
+    let code = [
+        0x11, 0x00, 0x03, // LD DE, $0300
+        0x0e, 0x1a,       // LD C, 26 ; F_DMAOFF
+        0xcd, 0x05, 0x00, // CALL 5
+        0x11, 0x5c, 0x00, // LD DE, $005c
+        0x0e, 0x11,       // LD C, 17 ; F_SFIRST
+        0xcd, 0x05, 0x00, // CALL 5
+        0x32, 0x00, 0x02, // LD ($0200), A
+        0x11, 0x20, 0x03, // LD DE, $0320
+        0x0e, 0x1a,       // LD C, 26 ; F_DMAOFF
+        0xcd, 0x05, 0x00, // CALL 5
+        0x0e, 0x12,       // LD C, 18 ; F_SNEXT
+        0xcd, 0x05, 0x00, // CALL 5
+        0x32, 0x01, 0x02, // LD ($0201), A
+        0x0e, 0x12,       // LD C, 18 ; F_SNEXT
+        0xcd, 0x05, 0x00, // CALL 5
+        0x32, 0x02, 0x02, // LD ($0202), A
+        0xc9,             // RET
+    ];
+
+    let mut machine = PlainMachine::new();
+    run(&mut Cpu::new_z80(), &mut machine, &code, "*.TXT", Some(&dir));
+    assert_eq!([0, 0, 0xff], &machine_bytes(&machine, 0x200, 3)[..]);
+    assert_eq!(b"A       TXT", &machine_bytes(&machine, 0x301, 11)[..]);
+    assert_eq!(b"B       TXT", &machine_bytes(&machine, 0x321, 11)[..]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_cpm_bios_conout() {
+    let code = [
+        0x2a, 0x01, 0x00, // LD HL, ($0001) ; WBOOT entry
+        0x11, 0x09, 0x00, // LD DE, 9
+        0x19,             // ADD HL, DE ; CONOUT entry
+        0x0e, b'X',       // LD C, 'X'
+        0x11, 0x0e, 0x01, // LD DE, $010e
+        0xd5,             // PUSH DE
+        0xe9,             // JP (HL)
+        0xc9,             // RET
+    ];
+    let mut machine = PlainMachine::new();
+    let cpm = run(&mut Cpu::new_8080(), &mut machine, &code, "", None);
+    assert_eq!("X", cpm.console.output_string());
+}
+
+#[test]
+fn test_cpm_halt_terminates() {
+    let code = [
+        0xf3, // DI
+        0x76, // HALT
+    ];
+    let mut cpu = Cpu::new_z80();
+    let mut machine = PlainMachine::new();
+    let mut cpm = Cpm::new(BufferConsole::new(&[]));
+    cpm.load(&mut cpu, &mut machine, &code, "");
+    cpu.set_breakpoints(&[0x1234]);
+    cpm.run(&mut cpu, &mut machine);
+    assert!(cpm.is_terminated());
+    assert!(!cpm.step(&mut cpu, &mut machine));
+    // The breakpoints of the caller are kept
+    assert_eq!(&[0x1234], cpu.breakpoints());
+}
+
+#[test]
+fn test_cpm_write_string_without_terminator() {
+    let code = [
+        0x0e, 0x09,       // LD C, 9
+        0x11, 0x00, 0x00, // LD DE, 0
+        0xcd, 0x05, 0x00, // CALL 5
+        0xc9,             // RET
+    ];
+    let mut machine = PlainMachine::new();
+    let cpm = run(&mut Cpu::new_z80(), &mut machine, &code, "", None);
+    // The whole memory once
+    assert_eq!(0x10000, cpm.console.output.len());
+}
+
+#[test]
+fn test_cpm_unimplemented_bdos_diagnostic() {
+    let code = [
+        0x0e, 0x64,       // LD C, 100
+        0xcd, 0x05, 0x00, // CALL 5
+        0x32, 0x00, 0x02, // LD ($0200), A
+        0xc9,             // RET
+    ];
+    let mut cpu = Cpu::new_z80();
+    let mut machine = DiagnosticMachine::new();
+    let mut cpm = Cpm::new(BufferConsole::new(&[]));
+    cpm.load(&mut cpu, &mut machine, &code, "");
+    cpm.run(&mut cpu, &mut machine);
+    assert_eq!(0xff, machine.peek(0x200));
+    assert_eq!(vec!["Unimplemented BDOS function 100".to_string()], machine.messages.into_inner());
+}
+
+fn machine_bytes(machine: &PlainMachine, address: u32, len: u32) -> Vec<u8> {
+    (address..address + len).map(|a| machine.peek(a)).collect()
+}
@@ -1,8 +1,9 @@
 use ez80::*;
+use ez80::cpm::*;
 
 // Diagnostics II, version 1.2, CPU test by Supersoft Associates
 
-static CODE: &'static [u8] = include_bytes!("res/CPUTEST.COM");
+static CODE: &[u8] = include_bytes!("res/CPUTEST.COM");
 
 #[test]
 fn test_cpu_test_8080() {
@@ -16,57 +17,22 @@ fn test_cpu_test_z80() {
 
 fn cpu_test(mut cpu: Cpu) {
     let mut machine = PlainMachine::new();
+    let mut cpm = Cpm::new(BufferConsole::new(&[]));
+    cpm.console.echo = true;
 
-    // Load program
-    let code = CODE;
-    let size = code.len();
-    for i in 0..size {
-        machine.poke(0x100 + i as u32, code[i]);
-    }
-
-    /*
-    System call 5
+    cpm.load(&mut cpu, &mut machine, CODE, "");
 
-    .org $5
-        ret
-    */
-    //let code = [0xD3, 0x00, 0xC9];
-    let code = [0xC9];
-    for i in 0..code.len() {
-        machine.poke(5 + i as u32, code[i]);
-    }
-
-    cpu.state.set_pc(0x100);
     let trace = false;
     cpu.set_trace(trace);
-    let mut msg = String::new();
-    loop {
-        cpu.execute_instruction(&mut machine);
-
+    while cpm.step(&mut cpu, &mut machine) {
         // Avoid tracing the long loop
         if cpu.state.pc() == 0x31b3 {
             cpu.set_trace(false);
         } else if cpu.state.pc() == 0x31b5 {
             cpu.set_trace(trace);
         }
-
-        if cpu.state.pc() == 0x0000 {
-            println!("");
-            break;
-        }
-
-        if cpu.state.pc() == 0x0005 {
-            match cpu.registers().get8(Reg8::C) {
-                2 => {
-                    // C_WRITE
-                    let ch = cpu.registers().get8(Reg8::E) as char;
-                    print!("{}", ch);
-                    msg.push(ch);
-                },
-                _ => panic!("BDOS command not implemented")
-            }
-        }
     }
+    println!();
 
-    assert_eq!(true, msg.contains("CPU TESTS OK"));
+    assert!(cpm.console.output_string().contains("CPU TESTS OK"));
 }
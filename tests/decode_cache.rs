@@ -0,0 +1,99 @@
+use ez80::*;
+
+mod common;
+use common::BankedMachine;
+
+#[test]
+fn test_decode_cache_self_modifying_code() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new();
+    cpu.set_decode_cache(true);
+
+    sys.poke(0x0000, 0x3e);  // LD A, $05
+    sys.poke(0x0001, 0x05);
+    sys.poke(0x0002, 0x3c);  // INC A
+    sys.poke(0x0003, 0x32);  // LD ($0001), A
+    sys.poke(0x0004, 0x01);
+    sys.poke(0x0005, 0x00);
+    sys.poke(0x0006, 0xc3);  // JP $0000
+    sys.poke(0x0007, 0x00);
+    sys.poke(0x0008, 0x00);
+
+    for _ in 0..4 {
+        cpu.execute_instruction(&mut sys);
+    }
+    assert_eq!(0x06, sys.peek(0x0001));
+
+    cpu.execute_instruction(&mut sys);
+    assert_eq!(0x06, cpu.registers().a());
+}
+
+#[test]
+fn test_decode_cache_counts_the_same_cycles() {
+    let code = [
+        0x5b, 0x21, 0x00, 0x10, 0x00,  // LD.LIL HL, $001000
+        0xdd, 0x36, 0x05, 0x42,        // LD (IX+5), $42
+        0xed, 0xb0,                    // LDIR
+        0x10, 0xf3,                    // DJNZ $0000
+    ];
+    let mut elapsed = Vec::new();
+    for decode_cache in [false, true] {
+        let mut sys = PlainMachine::new();
+        let mut cpu = Cpu::new_ez80();
+        cpu.set_decode_cache(decode_cache);
+        for (i, byte) in code.iter().enumerate() {
+            sys.poke(i as u32, *byte);
+        }
+        cpu.registers().set16(Reg16::IX, 0x2000);
+        cpu.registers().set16(Reg16::DE, 0x3000);
+        cpu.registers().set16(Reg16::BC, 0x0004);
+        for _ in 0..100 {
+            cpu.execute_instruction(&mut sys);
+        }
+        elapsed.push((sys.get_elapsed_cycles(), cpu.state.pc(), sys.peek(0x2005)));
+    }
+    assert_eq!(elapsed[0], elapsed[1]);
+}
+
+#[test]
+fn test_decode_cache_explicit_invalidation() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new();
+    cpu.set_decode_cache(true);
+
+    // NOPs, the first one executed again with JR
+    sys.poke(0x0001, 0x18);  // JR $0000
+    sys.poke(0x0002, 0xfd);
+    cpu.execute_instruction(&mut sys);
+    cpu.execute_instruction(&mut sys);
+
+    sys.poke(0x0000, 0x3c);  // INC A
+    cpu.invalidate_decode_cache(0x0000, 0x0001);
+    cpu.registers().set_a(0x00);
+    cpu.execute_instruction(&mut sys);
+    assert_eq!(0x01, cpu.registers().a());
+}
+
+#[test]
+fn test_decode_cache_bank_switch() {
+    let mut sys = BankedMachine::new();
+    let mut cpu = Cpu::new();
+    cpu.set_decode_cache(true);
+
+    for bank in 0..2 {
+        sys.bank = bank;
+        sys.poke(0x0002, 0xc3);  // JP $0000
+        sys.poke(0x0003, 0x00);
+        sys.poke(0x0004, 0x00);
+    }
+    sys.banks[0].poke(0x0000, 0xd3);  // OUT ($00), A
+    sys.banks[1].poke(0x0000, 0x3c);  // INC A
+    sys.bank = 0;
+    cpu.registers().set_a(0x01);
+
+    cpu.execute_instruction(&mut sys);
+    assert_eq!(1, sys.bank);
+    cpu.execute_instruction(&mut sys);
+    cpu.execute_instruction(&mut sys);
+    assert_eq!(0x02, cpu.registers().a());
+}
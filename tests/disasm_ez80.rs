@@ -1,3 +1,6 @@
+// The lint exceptions keep the test as written upstream
+#![allow(clippy::needless_range_loop)]
+
 use ez80::*;
 
 fn test_disasm_z80(code: &[u8], expected: &str) {
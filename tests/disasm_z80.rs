@@ -1,3 +1,6 @@
+// The lint exceptions keep the test as written upstream
+#![allow(clippy::needless_range_loop)]
+
 use ez80::*;
 
 fn test_disasm_z80(code: &[u8], expected: &str) {
@@ -26,3 +29,41 @@ fn test_disasm_ld_hl_n() {
 fn test_disasm_ld_ix_d_n() {
     test_disasm_z80(&[0xdd, 0x36, 22, 0x33], "LD (IX+22), $33");
 }
+
+fn disasm_comment(cpu: &mut Cpu, code: &[u8]) -> String {
+    let mut sys = PlainMachine::new();
+    for (i, b) in code.iter().enumerate() {
+        sys.poke(i as u32, *b);
+    }
+    let dis = ez80::disassembler::disassemble(&mut sys, cpu, None, 0, 1);
+    dis[0].comment.clone()
+}
+
+#[test]
+fn test_disasm_flags_comment() {
+    let mut cpu = Cpu::new_z80();
+    assert_eq!("SZ5H3PNC", disasm_comment(&mut cpu, &[0x80])); // ADD A, B
+    assert_eq!("--5H3-NC", disasm_comment(&mut cpu, &[0x09])); // ADD HL, BC
+    assert_eq!("SZ5H3PN-", disasm_comment(&mut cpu, &[0x04])); // INC B
+    assert_eq!("-Z------", disasm_comment(&mut cpu, &[0xca, 0x00, 0x10])); // JP Z, $1000
+    assert_eq!("-----P--", disasm_comment(&mut cpu, &[0xe0])); // RET PO
+    assert_eq!("", disasm_comment(&mut cpu, &[0x03])); // INC BC
+    assert_eq!("", disasm_comment(&mut cpu, &[0x41])); // LD B, C
+}
+
+#[test]
+fn test_disasm_flags_comment_8080() {
+    // The fixed bits are shown, there is no N flag
+    let mut cpu = Cpu::new_8080();
+    assert_eq!("SZ0A0P1C", disasm_comment(&mut cpu, &[0x80])); // ADD B
+    assert_eq!("--0-0-1C", disasm_comment(&mut cpu, &[0x09])); // DAD B
+    assert_eq!("-Z0-0-1-", disasm_comment(&mut cpu, &[0xca, 0x00, 0x10])); // JZ $1000
+    assert_eq!("", disasm_comment(&mut cpu, &[0x41])); // MOV B, C
+
+    // K and V only on the instructions that use them
+    let mut cpu = Cpu::new_8085();
+    assert_eq!("SZKA0PVC", disasm_comment(&mut cpu, &[0x80])); // ADD B
+    assert_eq!("SZ-A0P-C", disasm_comment(&mut cpu, &[0xa0])); // ANA B
+    assert_eq!("--K-0---", disasm_comment(&mut cpu, &[0x03])); // INX B
+    assert_eq!("--K-0---", disasm_comment(&mut cpu, &[0xfd, 0x00, 0x10])); // JK $1000
+}
@@ -0,0 +1,48 @@
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+fn ez80_debug(code: &[u8], commands: &str) -> String {
+    let path = std::env::temp_dir().join(format!("ez80_debug_{}.bin", std::process::id()));
+    fs::write(&path, code).unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ez80-debug"))
+        .args(["--cpu", "z80"])
+        .arg(&path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(commands.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    fs::remove_file(path).unwrap();
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+#[test]
+fn test_ez80_debug_session() {
+    let code = [
+        0x31, 0x00, 0x80, // LD SP, $8000
+        0xcd, 0x0a, 0x00, // CALL $000A
+        0x32, 0x00, 0x20, // LD ($2000), A
+        0x76,             // HALT
+        0x3e, 0x42,       // LD A, $42
+        0xc9,             // RET
+    ];
+    let output = ez80_debug(&code, "\
+s
+n
+w 2000
+g
+who 2000
+bs
+mem ffffff ffffffff
+q
+");
+    // Stepped over the call
+    assert!(output.contains("AF:42"), "{}", output);
+    assert!(output.contains("Watchpoint: write to 002000 42"), "{}", output);
+    assert!(output.contains("Written by the instruction at 000006"), "{}", output);
+    // The dump stops at the end of the memory
+    assert!(output.contains("fffff0:"), "{}", output);
+    assert!(!output.contains("\n000000:"), "{}", output);
+}
@@ -0,0 +1,65 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn write_program(name: &str, code: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("ez80_run_{}_{}", std::process::id(), name));
+    fs::write(&path, code).unwrap();
+    path
+}
+
+fn ez80_run(args: &[&str], program: &PathBuf) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ez80-run"))
+        .args(args)
+        .arg(program)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_ez80_run_ports() {
+    let program = write_program("ports.bin", &[
+        0x3e, b'H',       // LD A, 'H'
+        0xd3, 0x01,       // OUT (1), A
+        0x3e, b'i',       // LD A, 'i'
+        0xd3, 0x01,       // OUT (1), A
+        0x3e, 0x03,       // LD A, 3
+        0xd3, 0xff,       // OUT ($FF), A
+        0x18, 0xfe,       // JR $
+    ]);
+    let output = ez80_run(&["--out-port", "1", "--exit-port", "0xff"], &program);
+    assert_eq!(b"Hi", &output.stdout[..]);
+    assert_eq!(Some(3), output.status.code());
+    fs::remove_file(program).unwrap();
+}
+
+#[test]
+fn test_ez80_run_halt_and_max_cycles() {
+    // LD A, 7; HALT
+    let program = write_program("halt.hex", b":030000003E077642\n:00000001FF\n");
+    let output = ez80_run(&[], &program);
+    assert_eq!(Some(7), output.status.code());
+    fs::remove_file(program).unwrap();
+
+    let program = write_program("loop.bin", &[
+        0x18, 0xfe, // JR $
+    ]);
+    let output = ez80_run(&["--max-cycles", "1000"], &program);
+    assert_eq!(Some(124), output.status.code());
+    fs::remove_file(program).unwrap();
+}
+
+#[test]
+fn test_ez80_run_trace_on_stderr() {
+    let program = write_program("trace.bin", &[
+        0x3e, b'H',       // LD A, 'H'
+        0xd3, 0x01,       // OUT (1), A
+        0x76,             // HALT
+    ]);
+    // A budget beyond 32 bits
+    let output = ez80_run(&["--out-port", "1", "--trace", "--max-cycles", "5000000000"], &program);
+    assert_eq!(b"H", &output.stdout[..]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("OUT ($1), A"));
+    assert_eq!(Some(b'H' as i32), output.status.code());
+    fs::remove_file(program).unwrap();
+}
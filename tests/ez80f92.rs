@@ -0,0 +1,914 @@
+use ez80::*;
+use ez80::ez80f92::*;
+
+/// A machine with the eZ80F92 peripherals on the internal ports
+struct F92Machine {
+    plain: PlainMachine,
+    timers: Timers,
+    uart0: Uart<BufferBackend>,
+    gpio: [GpioPort; 3],
+}
+
+impl F92Machine {
+    fn new() -> F92Machine {
+        F92Machine {
+            plain: PlainMachine::new(),
+            timers: Timers::new(),
+            uart0: Uart::new(0, BufferBackend::new()),
+            gpio: [GpioPort::new(0), GpioPort::new(1), GpioPort::new(2)],
+        }
+    }
+
+    fn peripherals(&mut self) -> [&mut dyn Peripheral; 5] {
+        let [pb, pc, pd] = &mut self.gpio;
+        [&mut self.timers, &mut self.uart0, pb, pc, pd]
+    }
+
+    fn load(&mut self, address: u32, code: &[u8]) {
+        for (i, b) in code.iter().enumerate() {
+            self.plain.poke(address + i as u32, *b);
+        }
+    }
+}
+
+impl Machine for F92Machine {
+    fn peek(&self, address: u32) -> u8 { self.plain.peek(address) }
+    fn poke(&mut self, address: u32, value: u8) { self.plain.poke(address, value) }
+    fn use_cycles(&self, cycles: i32) { self.plain.use_cycles(cycles) }
+
+    fn port_in(&mut self, address: u16) -> u8 {
+        self.plain.use_cycles(1);
+        if address & 0xff00 == 0 {
+            for peripheral in self.peripherals().iter_mut() {
+                if let Some(value) = peripheral.port_in(address) {
+                    return value;
+                }
+            }
+        }
+        0xff
+    }
+
+    fn port_out(&mut self, address: u16, value: u8) {
+        self.plain.use_cycles(1);
+        if address & 0xff00 == 0 {
+            for peripheral in self.peripherals().iter_mut() {
+                if peripheral.port_out(address, value) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Executes instructions advancing the peripherals. Returns the number of
+/// interrupts accepted.
+fn run(cpu: &mut Cpu, sys: &mut F92Machine, instructions: usize) -> usize {
+    let mut accepted = 0;
+    for _ in 0..instructions {
+        let cycles = sys.plain.get_elapsed_cycles();
+        if cpu.is_halted() {
+            // The clock keeps running
+            sys.plain.use_cycles(4);
+        }
+        cpu.execute_instruction(sys);
+        let elapsed = (sys.plain.get_elapsed_cycles() - cycles) as u32;
+        let mut vector = None;
+        for peripheral in sys.peripherals().iter_mut() {
+            peripheral.tick(elapsed);
+            vector = vector.or(peripheral.interrupt());
+        }
+        if let Some(vector) = vector {
+            if cpu.interrupt(sys, vector) {
+                accepted += 1;
+            }
+        }
+    }
+    accepted
+}
+
+#[test]
+fn test_prt_single_pass() {
+    let mut timers = Timers::new();
+    timers.port_out(0x84, 0x10);  // TMR1_RR_L
+    timers.port_out(0x85, 0x01);  // TMR1_RR_H
+    timers.port_out(0x83, 0x45);  // TMR1_CTL: IRQ_EN, divide by 16, enabled
+    assert_eq!(16, timers.prt[1].divider());
+
+    timers.tick(16 * 0x100 + 15);
+    assert_eq!(0x10, timers.port_in(0x84).unwrap());
+    timers.tick(16 * 0x10);
+    assert_eq!(0x00, timers.port_in(0x85).unwrap());  // Latched with TMR1_DR_L
+    assert_eq!(Some(0x0c), timers.interrupt());
+
+    // Stopped at the end of the count, the IRQ is cleared when read
+    assert_eq!(0xc4, timers.port_in(0x83).unwrap());
+    assert_eq!(None, timers.interrupt());
+    assert!(!timers.prt[1].is_enabled());
+    assert_eq!(None, timers.port_in(0x92));
+}
+
+#[test]
+fn test_prt_continuous() {
+    let mut timers = Timers::new();
+    timers.port_out(0x8d, 100);  // TMR4_RR_L
+    timers.port_out(0x8c, 0x1d);  // TMR4_CTL: continuous, divide by 256, enabled
+    timers.tick(256 * 250);
+    assert_eq!(50, timers.prt[4].counter);
+    assert!(timers.prt[4].is_enabled());
+    // IRQ_EN is not set
+    assert_eq!(None, timers.interrupt());
+    assert_eq!(0x9d, timers.port_in(0x8c).unwrap());
+    assert_eq!(0x1d, timers.port_in(0x8c).unwrap());
+}
+
+#[test]
+fn test_prt_interrupts() {
+    let mut sys = F92Machine::new();
+    let mut cpu = Cpu::new_ez80();
+    sys.load(0x0000, &[
+        0xed, 0x5e,        // IM 2
+        0x3e, 0x01,        // LD A, $01
+        0xed, 0x47,        // LD I, A
+        0x3e, 0x20,        // LD A, $20
+        0xed, 0x39, 0x81,  // OUT0 ($81), A ; TMR0_RR_L
+        0xaf,              // XOR A
+        0xed, 0x39, 0x82,  // OUT0 ($82), A ; TMR0_RR_H
+        0x3e, 0x53,        // LD A, $53
+        0xed, 0x39, 0x80,  // OUT0 ($80), A ; continuous with interrupts
+        0xfb,              // EI
+        0x76,              // HALT
+        0x18, 0xfd,        // JR $0015
+    ]);
+    sys.load(0x010a, &[0x00, 0x02]);  // PRT0 vector
+    sys.load(0x0200, &[
+        0xed, 0x38, 0x80,  // IN0 A, ($80) ; clears the IRQ
+        0x04,              // INC B
+        0xfb,              // EI
+        0xed, 0x4d,        // RETI
+    ]);
+    cpu.registers().set16(Reg16::SP, 0x8000);
+
+    let accepted = run(&mut cpu, &mut sys, 1000);
+    assert!(accepted > 5);
+    assert_eq!(accepted as u8, cpu.registers().get8(Reg8::B));
+}
+
+fn uart_8n1() -> Uart<BufferBackend> {
+    let mut uart = Uart::new(1, BufferBackend::new());
+    uart.port_out(0xd3, 0x83);  // LCR: 8N1 with DLAB
+    uart.port_out(0xd0, 0x04);  // BRG_L
+    uart.port_out(0xd1, 0x00);  // BRG_H
+    uart.port_out(0xd3, 0x03);  // LCR: 8N1
+    uart.port_out(0xd2, 0x87);  // FCR: FIFO, trigger at 8
+    uart
+}
+
+#[test]
+fn test_uart_transmit() {
+    let mut uart = uart_8n1();
+    assert_eq!(16 * 4 * 10, uart.byte_cycles());
+    uart.port_out(0xd0, b'H');
+    uart.port_out(0xd0, b'i');
+    assert_eq!(0x00, uart.port_in(0xd5).unwrap() & 0x60);
+
+    uart.tick(639);
+    assert!(uart.backend.output.is_empty());
+    uart.tick(1);
+    assert_eq!(b"H".to_vec(), uart.backend.output);
+    // The second byte is on the shift register
+    assert_eq!(0x20, uart.port_in(0xd5).unwrap() & 0x60);
+    uart.tick(640);
+    assert_eq!(b"Hi".to_vec(), uart.backend.take_output());
+    assert_eq!(0x60, uart.port_in(0xd5).unwrap() & 0x60);
+
+    // Transmit interrupt, cleared by reading IIR
+    assert_eq!(None, uart.interrupt());
+    uart.port_out(0xd1, 0x02);  // IER: TIE
+    assert_eq!(Some(0x1a), uart.interrupt());
+    assert_eq!(0xc2, uart.port_in(0xd2).unwrap());
+    assert_eq!(None, uart.interrupt());
+    assert_eq!(0xc1, uart.port_in(0xd2).unwrap());
+}
+
+#[test]
+fn test_uart_receive() {
+    let mut uart = uart_8n1();
+    uart.port_out(0xd1, 0x01);  // IER: RIE
+    uart.backend.push_input(b"0123456789");
+    uart.tick(640 * 7);
+    assert_eq!(None, uart.interrupt());
+    uart.tick(640);
+    assert_eq!(Some(0x1a), uart.interrupt());
+    assert_eq!(0xc4, uart.port_in(0xd2).unwrap());
+    for c in b"01234567" {
+        assert_eq!(0x01, uart.port_in(0xd5).unwrap() & 0x01);
+        assert_eq!(*c, uart.port_in(0xd0).unwrap());
+    }
+    assert_eq!(None, uart.interrupt());
+
+    // Below the trigger level, after 4 bytes times without data
+    uart.tick(640 * 2);
+    uart.tick(640 * 4);
+    assert_eq!(0xcc, uart.port_in(0xd2).unwrap());
+
+    // Overrun without the FIFO
+    uart.port_out(0xd2, 0x00);
+    uart.backend.push_input(b"ab");
+    uart.tick(640 * 2);
+    assert_eq!(0x03, uart.port_in(0xd5).unwrap() & 0x03);
+    assert_eq!(b'a', uart.port_in(0xd0).unwrap());
+    assert_eq!(0x00, uart.port_in(0xd5).unwrap() & 0x03);
+}
+
+#[test]
+fn test_uart_loopback() {
+    let mut uart = uart_8n1();
+    uart.port_out(0xd4, 0x13);  // MCR: loopback, RTS, DTR
+    assert_eq!(0x30, uart.port_in(0xd6).unwrap());
+    uart.port_out(0xd0, 0x55);
+    uart.tick(640);
+    assert!(uart.backend.output.is_empty());
+    assert_eq!(0x55, uart.port_in(0xd0).unwrap());
+}
+
+#[test]
+fn test_uart_echo_program() {
+    let mut sys = F92Machine::new();
+    let mut cpu = Cpu::new_ez80();
+    sys.load(0x0000, &[
+        0xed, 0x38, 0xc5,  // IN0 A, ($C5) ; LSR
+        0xe6, 0x01,        // AND $01
+        0x28, 0xf9,        // JR Z, $0000
+        0xed, 0x38, 0xc0,  // IN0 A, ($C0) ; RBR
+        0xee, 0x20,        // XOR $20
+        0xed, 0x39, 0xc0,  // OUT0 ($C0), A ; THR
+        0x18, 0xef,        // JR $0000
+    ]);
+    sys.uart0.backend.push_input(b"echo");
+    run(&mut cpu, &mut sys, 2000);
+    assert_eq!(b"ECHO".to_vec(), sys.uart0.backend.output);
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_uart_tcp_backend() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    let mut uart = Uart::new(0, StreamBackend::tcp(stream).unwrap());
+
+    client.write_all(b"z").unwrap();
+    let mut received = None;
+    for _ in 0..1000 {
+        uart.tick(uart.byte_cycles());
+        if uart.port_in(0xc5).unwrap() & 0x01 != 0 {
+            received = uart.port_in(0xc0);
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    assert_eq!(Some(b'z'), received);
+
+    uart.port_out(0xc0, b'y');
+    uart.tick(2 * uart.byte_cycles());
+    let mut buffer = [0u8; 1];
+    client.read_exact(&mut buffer).unwrap();
+    assert_eq!(b'y', buffer[0]);
+}
+
+#[test]
+fn test_gpio_modes() {
+    let mut port = GpioPort::new(0);
+    assert_eq!(2, port.mode(0));
+    assert_eq!(0xff, port.port_in(0x9a).unwrap());
+
+    port.port_out(0x9a, 0x0c);  // PB_DR
+    port.port_out(0x9b, 0xf0);  // PB_DDR: outputs on 0 to 3
+    port.port_out(0x9c, 0x0c);  // PB_ALT1: open drain on 2 and 3
+    assert_eq!(1, port.mode(0));
+    assert_eq!(3, port.mode(2));
+    // The pins 2 and 3 are not driven high
+    assert_eq!((0x03, 0x00), port.driven());
+    port.set_inputs(0x00);
+    assert_eq!(0x00, port.port_in(0x9a).unwrap());
+    port.set_input(3, true);
+    assert_eq!(0x08, port.port_in(0x9a).unwrap());
+    assert_eq!(None, port.interrupt());
+    assert_eq!(None, port.port_in(0x9e));
+}
+
+#[test]
+fn test_gpio_output_callback() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let mut sys = F92Machine::new();
+    let mut cpu = Cpu::new_ez80();
+    let changes = Rc::new(RefCell::new(Vec::new()));
+    let recorder = changes.clone();
+    sys.gpio[1].set_callback(Some(Box::new(move |mask, levels| {
+        recorder.borrow_mut().push((mask, levels));
+    })));
+    sys.load(0x0000, &[
+        0x3e, 0xa5,        // LD A, $A5
+        0xed, 0x39, 0x9e,  // OUT0 ($9E), A ; PC_DR
+        0xaf,              // XOR A
+        0xed, 0x39, 0x9f,  // OUT0 ($9F), A ; PC_DDR: all outputs
+        0x3e, 0x5a,        // LD A, $5A
+        0xed, 0x39, 0x9e,  // OUT0 ($9E), A ; PC_DR
+        0x76,              // HALT
+    ]);
+    run(&mut cpu, &mut sys, 10);
+    assert_eq!(vec![(0xff, 0xa5), (0xff, 0x5a)], *changes.borrow());
+}
+
+#[test]
+fn test_gpio_inputs() {
+    let mut sys = F92Machine::new();
+    let mut cpu = Cpu::new_ez80();
+    sys.load(0x0000, &[
+        0xed, 0x38, 0xa2,  // IN0 A, ($A2) ; PD_DR
+        0x32, 0x00, 0x10, 0x00,  // LD ($001000), A
+        0x76,              // HALT
+    ]);
+    sys.gpio[2].set_inputs(0x3c);
+    run(&mut cpu, &mut sys, 3);
+    assert_eq!(0x3c, sys.plain.peek(0x1000));
+}
+
+#[test]
+fn test_gpio_edge_interrupt() {
+    let mut sys = F92Machine::new();
+    let mut cpu = Cpu::new_ez80();
+    sys.load(0x0000, &[
+        0xed, 0x5e,        // IM 2
+        0x3e, 0x01,        // LD A, $01
+        0xed, 0x47,        // LD I, A
+        0x3e, 0x04,        // LD A, $04
+        0xed, 0x39, 0x9d,  // OUT0 ($9D), A ; PB_ALT2: dual edge on pin 2
+        0xfb,              // EI
+        0x76,              // HALT
+        0x18, 0xfd,        // JR $000C
+    ]);
+    sys.load(0x0134, &[0x00, 0x02]);  // PB2 vector
+    sys.load(0x0200, &[
+        0x3e, 0x04,        // LD A, $04
+        0xed, 0x39, 0x9a,  // OUT0 ($9A), A ; clears the interrupt
+        0x04,              // INC B
+        0xfb,              // EI
+        0xed, 0x4d,        // RETI
+    ]);
+    cpu.registers().set16(Reg16::SP, 0x8000);
+
+    assert_eq!(0, run(&mut cpu, &mut sys, 20));
+    sys.gpio[0].set_input(2, false);
+    assert_eq!(1, run(&mut cpu, &mut sys, 20));
+    sys.gpio[0].set_input(2, true);
+    assert_eq!(1, run(&mut cpu, &mut sys, 20));
+    assert_eq!(2, cpu.registers().get8(Reg8::B));
+}
+
+#[test]
+fn test_gpio_level_interrupt() {
+    let mut port = GpioPort::new(2);
+    port.port_out(0xa2, 0x00);  // PD_DR: low level
+    port.port_out(0xa4, 0x80);  // PD_ALT1
+    port.port_out(0xa5, 0x80);  // PD_ALT2: level interrupt on pin 7
+    assert_eq!(8, port.mode(7));
+    assert_eq!(None, port.interrupt());
+    port.set_input(7, false);
+    assert_eq!(Some(0x5e), port.interrupt());
+    // Requested while the level stays
+    assert_eq!(Some(0x5e), port.interrupt());
+    port.set_input(7, true);
+    assert_eq!(None, port.interrupt());
+}
+
+/// Answers the complement of the byte received on the previous transfer
+struct ComplementDevice {
+    selected: bool,
+    last: u8,
+}
+
+impl SpiDevice for ComplementDevice {
+    fn select(&mut self, selected: bool) {
+        self.selected = selected;
+    }
+
+    fn transfer(&mut self, value: u8) -> u8 {
+        let result = if self.selected { !self.last } else { 0xff };
+        self.last = value;
+        result
+    }
+}
+
+#[test]
+fn test_spi_transfer() {
+    let mut spi = Spi::new(ComplementDevice { selected: false, last: 0 });
+    spi.port_out(0xb8, 0x04);  // SPI_BRG_L
+    spi.port_out(0xba, 0xb0);  // SPI_CTL: IRQ_EN, SPI_EN, MASTER_EN
+    spi.select(true);
+    assert_eq!(16 * 4, spi.byte_cycles());
+
+    spi.port_out(0xbc, 0x12);
+    assert!(spi.is_busy());
+    spi.port_out(0xbc, 0x34);  // Collision, ignored
+    spi.tick(63);
+    assert_eq!(None, spi.interrupt());
+    spi.tick(1);
+    assert_eq!(Some(0x1e), spi.interrupt());
+    assert_eq!(0xc0, spi.port_in(0xbb).unwrap());
+    assert_eq!(None, spi.interrupt());
+    assert_eq!(0xff, spi.port_in(0xbc).unwrap());
+
+    spi.port_out(0xbc, 0x56);
+    spi.tick(64);
+    assert_eq!(0xed, spi.port_in(0xbc).unwrap());
+
+    // Not sent without MASTER_EN
+    spi.port_out(0xba, 0x20);
+    spi.port_out(0xbc, 0x78);
+    assert!(!spi.is_busy());
+}
+
+/// A memory with a register pointer, like an EEPROM
+struct MemoryDevice {
+    address: u8,
+    data: std::rc::Rc<std::cell::RefCell<[u8; 256]>>,
+    pointer: u8,
+    pointer_set: bool,
+}
+
+impl I2cDevice for MemoryDevice {
+    fn start(&mut self, address: u8, _read: bool) -> bool {
+        self.pointer_set = false;
+        address == self.address
+    }
+
+    fn write(&mut self, value: u8) -> bool {
+        if self.pointer_set {
+            self.data.borrow_mut()[self.pointer as usize] = value;
+            self.pointer = self.pointer.wrapping_add(1);
+        } else {
+            self.pointer = value;
+            self.pointer_set = true;
+        }
+        true
+    }
+
+    fn read(&mut self, _ack: bool) -> u8 {
+        let value = self.data.borrow()[self.pointer as usize];
+        self.pointer = self.pointer.wrapping_add(1);
+        value
+    }
+
+    fn stop(&mut self) {}
+}
+
+/// Waits for IFLG and returns the status
+fn i2c_wait(i2c: &mut I2c) -> u8 {
+    for _ in 0..100 {
+        if i2c.port_in(0xcb).unwrap() & 0x08 != 0 {
+            return i2c.port_in(0xcc).unwrap();
+        }
+        i2c.tick(i2c.bit_cycles());
+    }
+    panic!("IFLG not set");
+}
+
+#[test]
+fn test_i2c_write_and_read() {
+    let data = std::rc::Rc::new(std::cell::RefCell::new([0u8; 256]));
+    let mut i2c = I2c::new();
+    i2c.attach(Box::new(MemoryDevice { address: 0x50, data: data.clone(), pointer: 0, pointer_set: false }));
+    i2c.port_out(0xcc, 0x0a);  // I2C_CCR: M = 1, N = 2
+    assert_eq!(80, i2c.bit_cycles());
+    assert_eq!(0xf8, i2c.port_in(0xcc).unwrap());
+
+    // Write $AB and $CD on $10
+    i2c.port_out(0xcb, 0x60);  // ENAB, STA
+    assert_eq!(0x08, i2c_wait(&mut i2c));
+    for (value, status) in [(0xa0, 0x18), (0x10, 0x28), (0xab, 0x28), (0xcd, 0x28)].iter() {
+        i2c.port_out(0xca, *value);
+        i2c.port_out(0xcb, 0x40);  // Clears IFLG
+        assert_eq!(*status, i2c_wait(&mut i2c));
+    }
+    i2c.port_out(0xcb, 0x50);  // STP
+    assert_eq!(0xf8, i2c.port_in(0xcc).unwrap());
+    assert_eq!([0xab, 0xcd], data.borrow()[0x10..0x12]);
+
+    // Set the pointer, repeated start and read two bytes
+    i2c.port_out(0xcb, 0x60);
+    assert_eq!(0x08, i2c_wait(&mut i2c));
+    i2c.port_out(0xca, 0xa0);
+    i2c.port_out(0xcb, 0x40);
+    assert_eq!(0x18, i2c_wait(&mut i2c));
+    i2c.port_out(0xca, 0x10);
+    i2c.port_out(0xcb, 0x40);
+    assert_eq!(0x28, i2c_wait(&mut i2c));
+    i2c.port_out(0xcb, 0x60);
+    assert_eq!(0x10, i2c_wait(&mut i2c));
+    i2c.port_out(0xca, 0xa1);
+    i2c.port_out(0xcb, 0x40);
+    assert_eq!(0x40, i2c_wait(&mut i2c));
+    i2c.port_out(0xcb, 0x44);  // AAK
+    assert_eq!(0x50, i2c_wait(&mut i2c));
+    assert_eq!(0xab, i2c.port_in(0xca).unwrap());
+    i2c.port_out(0xcb, 0x40);  // Last byte, not acknowledged
+    assert_eq!(0x58, i2c_wait(&mut i2c));
+    assert_eq!(0xcd, i2c.port_in(0xca).unwrap());
+    i2c.port_out(0xcb, 0x50);
+
+    // No device on the address, with interrupts
+    i2c.port_out(0xcb, 0xe0);  // IEN, ENAB, STA
+    assert_eq!(0x08, i2c_wait(&mut i2c));
+    assert_eq!(Some(0x1c), i2c.interrupt());
+    i2c.port_out(0xca, 0x42);
+    i2c.port_out(0xcb, 0xc0);
+    assert_eq!(None, i2c.interrupt());
+    assert_eq!(0x20, i2c_wait(&mut i2c));
+}
+
+/// Sends a command to the card and returns the first byte of the response
+#[cfg(feature = "std")]
+fn sd_command<D: SpiDevice>(card: &mut D, index: u8, argument: u32) -> u8 {
+    card.transfer(0x40 | index);
+    for b in argument.to_be_bytes().iter() {
+        card.transfer(*b);
+    }
+    card.transfer(0x95);
+    for _ in 0..8 {
+        let response = card.transfer(0xff);
+        if response != 0xff {
+            return response;
+        }
+    }
+    0xff
+}
+
+#[cfg(feature = "std")]
+fn sd_data<D: SpiDevice>(card: &mut D, count: usize) -> Vec<u8> {
+    while card.transfer(0xff) == 0xff {}
+    (0..count).map(|_| card.transfer(0xff)).collect()
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_sdcard_initialization() {
+    let mut card = SdCard::new(std::io::Cursor::new(vec![0u8; 2048 * 512])).unwrap();
+    card.select(true);
+    assert_eq!(0x01, sd_command(&mut card, 0, 0));
+    assert_eq!(0x01, sd_command(&mut card, 8, 0x1aa));
+    assert_eq!([0x00, 0x00, 0x01, 0xaa], [card.transfer(0xff), card.transfer(0xff), card.transfer(0xff), card.transfer(0xff)]);
+    assert_eq!(0x01, sd_command(&mut card, 55, 0));
+    assert_eq!(0x00, sd_command(&mut card, 41, 0x4000_0000));
+    assert_eq!(0x00, sd_command(&mut card, 58, 0));
+    assert_eq!(0xc0, card.transfer(0xff));  // High capacity
+
+    assert_eq!(0x00, sd_command(&mut card, 9, 0));
+    let csd = sd_data(&mut card, 16);
+    assert_eq!(0x40, csd[0]);
+    assert_eq!(1, u32::from_be_bytes([0, csd[7], csd[8], csd[9]]));
+
+    assert_eq!(0x04, sd_command(&mut card, 1, 0));
+    card.select(false);
+    assert_eq!(0xff, card.transfer(0x40));
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_sdcard_read_and_write() {
+    let mut image = vec![0u8; 64 * 512];
+    image[3 * 512] = 0x55;
+    image[4 * 512 - 1] = 0xaa;
+    image[4 * 512] = 0x66;
+    let mut spi = Spi::new(SdCard::new(std::io::Cursor::new(image)).unwrap());
+    spi.select(true);
+    let card = &mut spi.device;
+
+    assert_eq!(0x01, sd_command(card, 0, 0));
+    assert_eq!(0x01, sd_command(card, 55, 0));
+    assert_eq!(0x00, sd_command(card, 41, 0));
+
+    assert_eq!(0x00, sd_command(card, 17, 3));
+    let block = sd_data(card, 514);
+    assert_eq!(0x55, block[0]);
+    assert_eq!(0xaa, block[511]);
+    assert_eq!(0x20, sd_command(card, 17, 64));
+
+    // Multiple blocks, stopped with CMD12
+    assert_eq!(0x00, sd_command(card, 18, 3));
+    assert_eq!(0x55, sd_data(card, 514)[0]);
+    assert_eq!(0x66, sd_data(card, 514)[0]);
+    assert_eq!(0x00, sd_command(card, 12, 0));
+    while card.transfer(0xff) != 0xff {}
+
+    // Write the block 5
+    assert_eq!(0x00, sd_command(card, 24, 5));
+    card.transfer(0xff);
+    card.transfer(0xfe);
+    for i in 0..512 {
+        card.transfer(i as u8);
+    }
+    card.transfer(0xff);
+    card.transfer(0xff);
+    assert_eq!(0x05, card.transfer(0xff) & 0x1f);
+    while card.transfer(0xff) != 0xff {}
+    assert_eq!(0x00, sd_command(card, 17, 5));
+    assert_eq!((0..512).map(|i| i as u8).collect::<Vec<u8>>(), sd_data(card, 512));
+
+    // Through the SPI controller
+    spi.port_out(0xba, 0x30);
+    spi.port_out(0xbc, 0xff);
+    spi.tick(spi.byte_cycles());
+    assert_eq!(0x80, spi.port_in(0xbb).unwrap());
+
+    let image = spi.device.into_inner().into_inner();
+    assert_eq!(0x7f, image[5 * 512 + 0x7f]);
+}
+
+#[test]
+fn test_rtc_counting() {
+    let mut rtc = Rtc::new(1000);
+    rtc.time = RtcTime::from_unix(1_709_251_199);
+    assert_eq!(RtcTime { year: 2024, month: 2, day: 29, weekday: 5, hour: 23, minute: 59, second: 59 }, rtc.time);
+
+    rtc.tick(999);
+    assert_eq!(59, rtc.port_in(0xe0).unwrap());
+    rtc.tick(1);
+    let counters: Vec<u8> = (0xe0..0xe8).map(|port| rtc.port_in(port).unwrap()).collect();
+    assert_eq!(vec![0, 0, 0, 6, 1, 3, 24, 20], counters);
+
+    // BCD
+    rtc.port_out(0xed, 0x20);  // RTC_CTRL: BCD_EN
+    rtc.tick(12_000);
+    assert_eq!(0x12, rtc.port_in(0xe0).unwrap());
+    assert_eq!(0x24, rtc.port_in(0xe6).unwrap());
+
+    // Locked, the counters can't be written
+    rtc.port_out(0xe1, 0x30);
+    assert_eq!(0x00, rtc.port_in(0xe1).unwrap());
+    rtc.port_out(0xed, 0x21);  // RTC_UNLOCK
+    rtc.port_out(0xe1, 0x30);
+    rtc.port_out(0xe7, 0x19);
+    rtc.tick(5000);
+    rtc.port_out(0xed, 0x20);
+    assert_eq!(0x30, rtc.port_in(0xe1).unwrap());
+    assert_eq!(0x12, rtc.port_in(0xe0).unwrap());
+    assert_eq!(1924, rtc.time.year);
+}
+
+#[test]
+fn test_rtc_alarm() {
+    let mut rtc = Rtc::new(100);
+    rtc.port_out(0xe8, 5);  // RTC_ASEC
+    rtc.port_out(0xe9, 1);  // RTC_AMIN
+    rtc.port_out(0xec, 0x03);  // RTC_ACTRL: seconds and minutes
+    rtc.port_out(0xed, 0x40);  // RTC_CTRL: INT_EN
+    rtc.tick(100 * 64);
+    assert_eq!(None, rtc.interrupt());
+    rtc.tick(100);
+    assert_eq!(Some(0x16), rtc.interrupt());
+    assert_eq!(0xc0, rtc.port_in(0xed).unwrap());
+    assert_eq!(None, rtc.interrupt());
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_rtc_host_time() {
+    let mut rtc = Rtc::new(18_432_000);
+    rtc.set_host_time();
+    assert!(rtc.time.year >= 2024);
+    assert!((1..=7).contains(&rtc.time.weekday));
+}
+
+#[test]
+fn test_watchdog_restart_and_nmi() {
+    let mut wdt = Watchdog::new(1_000_000);
+    wdt.port_out(0x93, 0xc3);  // WDT_CTL: enabled, NMI, 2^18 cycles
+    assert_eq!(1 << 18, wdt.period_cycles());
+    // Locked while enabled
+    wdt.port_out(0x93, 0x00);
+    assert!(wdt.is_enabled());
+
+    wdt.tick(200_000);
+    wdt.port_out(0x94, 0xa5);
+    wdt.port_out(0x94, 0x5a);
+    wdt.tick(200_000);
+    assert_eq!(None, wdt.timeout());
+    // An incomplete sequence does not restart the count
+    wdt.port_out(0x94, 0x5a);
+    wdt.tick(100_000);
+    assert_eq!(Some(WatchdogTimeout::Nmi), wdt.timeout());
+    assert!(wdt.is_enabled());
+
+    // RTC oscillator
+    let mut wdt = Watchdog::new(32768 * 4);
+    wdt.port_out(0x93, 0x07);
+    assert_eq!(4 << 18, wdt.period_cycles());
+}
+
+#[test]
+fn test_watchdog_reset() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new_ez80();
+    sys.poke(0x0000, 0x76);  // HALT
+    sys.poke(0x0066, 0x76);  // HALT
+    cpu.execute_instruction(&mut sys);
+    assert!(cpu.is_halted());
+
+    let mut wdt = Watchdog::new(1_000_000);
+    wdt.port_out(0x93, 0x43);  // NMI
+    assert_eq!(None, wdt.signal(&mut cpu));
+    wdt.port_out(0x93, 0xc3);
+    wdt.tick(1 << 18);
+    assert_eq!(Some(WatchdogTimeout::Nmi), wdt.signal(&mut cpu));
+    cpu.execute_instruction(&mut sys);
+    assert_eq!(0x0067, cpu.state.pc());  // After the HALT at the NMI entry
+
+    let mut wdt = Watchdog::new(1_000_000);
+    wdt.port_out(0x93, 0x83);  // Reset
+    wdt.tick(1 << 18);
+    assert_eq!(Some(WatchdogTimeout::Reset), wdt.signal(&mut cpu));
+    cpu.execute_instruction(&mut sys);
+    assert_eq!(0x0001, cpu.state.pc());  // After the HALT at 0
+    assert!(!wdt.is_enabled());
+    assert_eq!(0x20, wdt.port_in(0x93).unwrap());
+    assert_eq!(0x00, wdt.port_in(0x93).unwrap());
+}
+
+#[test]
+fn test_memory_controller_decoding() {
+    let mut mc = MemoryController::new(PlainMachine::new());
+    mc.load_flash(0x0000, &[0x12, 0x34]);
+    // CS0 on $000000 to $03FFFF, the memory of PlainMachine
+    mc.port_out(0xa9, 0x03);
+    mc.bus.poke(0x020000, 0x56);
+    mc.bus.set_elapsed_cycles(0);
+
+    // The flash with 4 wait states, the chip select with 7
+    assert_eq!(0x12, mc.peek(0x000000));
+    assert_eq!(5, mc.bus.get_elapsed_cycles());
+    assert_eq!(0x56, mc.peek(0x020000));
+    assert_eq!(5 + 8, mc.bus.get_elapsed_cycles());
+    // Not decoded
+    assert_eq!(0xff, mc.peek(0x050000));
+
+    // Flash and RAM relocated
+    let generation = mc.memory_generation();
+    mc.port_out(0xf7, 0x01);  // FLASH_ADDR_U
+    mc.port_out(0xb5, 0x02);  // RAM_ADDR_U
+    assert!(mc.memory_generation() > generation);
+    assert_eq!(0x34, mc.peek_debug(0x010001));
+    assert_eq!(0x00, mc.peek_debug(0x000000));
+    mc.poke(0x02e000, 0x78);
+    assert_eq!(0x78, mc.ram[0]);
+    assert_eq!(0x00, mc.bus.peek_debug(0x02e000));
+    // The flash is read only
+    mc.poke(0x010000, 0x00);
+    assert_eq!(0x12, mc.peek_debug(0x010000));
+
+    // Disabled
+    mc.port_out(0xb4, 0x00);  // RAM_CTL
+    mc.port_out(0xf8, 0x00);  // FLASH_CTRL
+    assert_eq!(0x00, mc.peek_debug(0x02e000));
+    assert_eq!(0x00, mc.peek_debug(0x010000));
+    assert_eq!(0x00, mc.port_in(0xf8));
+}
+
+#[test]
+fn test_memory_controller_chip_selects() {
+    let mut mc = MemoryController::new(PlainMachine::new());
+    mc.port_out(0xaa, 0x00);  // CS0 disabled
+    mc.port_out(0xae, 0x01);  // CS2_LBR
+    mc.port_out(0xaf, 0x02);  // CS2_UBR
+    mc.port_out(0xb0, 0x28);  // CS2_CTL: 1 wait state
+    mc.port_out(0xab, 0x40);  // CS1_LBR
+    mc.port_out(0xad, 0x78);  // CS1_CTL: I/O, 3 wait states
+    assert!(mc.cs[2].selects_memory(0x02ffff));
+    assert!(!mc.cs[2].selects_memory(0x030000));
+    assert!(mc.cs[1].selects_io(0x4012));
+
+    mc.bus.set_elapsed_cycles(0);
+    mc.poke(0x020000, 0x9a);
+    assert_eq!(2, mc.bus.get_elapsed_cycles());
+    mc.port_out(0x4012, 0x01);
+    assert_eq!(2 + 4, mc.bus.get_elapsed_cycles());
+    mc.port_out(0x5012, 0x01);
+    assert_eq!(2 + 4 + 1, mc.bus.get_elapsed_cycles());
+    assert_eq!(0x9a, mc.bus.peek_debug(0x020000));
+}
+
+#[test]
+fn test_memory_controller_program() {
+    let mut mc = MemoryController::new(PlainMachine::new());
+    let mut cpu = Cpu::new_ez80();
+    cpu.set_adl(true);
+    mc.load_flash(0x0000, &[
+        0x3e, 0x03,              // LD A, $03
+        0xed, 0x39, 0xb5,        // OUT0 ($B5), A ; RAM_ADDR_U
+        0x3e, 0x03,              // LD A, $03
+        0xed, 0x39, 0xa9,        // OUT0 ($A9), A ; CS0_UBR
+        0x3e, 0x55,              // LD A, $55
+        0x32, 0x00, 0xe0, 0x03,  // LD ($03E000), A
+        0x3e, 0x66,              // LD A, $66
+        0x32, 0x00, 0x00, 0x02,  // LD ($020000), A
+        0x32, 0x00, 0x00, 0x00,  // LD ($000000), A
+        0x76,                    // HALT
+    ]);
+    for _ in 0..10 {
+        cpu.execute_instruction(&mut mc);
+    }
+    assert!(cpu.is_halted());
+    assert_eq!(0x55, mc.ram[0]);
+    assert_eq!(0x66, mc.bus.peek_debug(0x020000));
+    assert_eq!(0x3e, mc.peek_debug(0x000000));
+}
+
+#[test]
+fn test_interrupt_controller_priority() {
+    let mut ic = InterruptController::new();
+    assert_eq!(None, ic.interrupt());
+    ic.raise(vectors::UART0);
+    ic.raise(vectors::PB0 + 4);
+    ic.raise(vectors::PRT3);
+    assert_eq!(Some(vectors::PRT3), ic.interrupt());
+
+    // PB2 on high priority
+    ic.port_out(0x0d, 0x04);  // INT_P3
+    assert_eq!(0x04, ic.port_in(0x0d).unwrap());
+    assert!(ic.is_high_priority(0x34));
+    assert_eq!(Some(0x34), ic.interrupt());
+    assert_eq!(vec![0x34, vectors::PRT3, vectors::UART0], ic.pending());
+
+    ic.set_line(0x34, false);
+    ic.clear(vectors::PRT3);
+    assert_eq!(vec![vectors::UART0], ic.pending());
+    assert_eq!(None, ic.port_in(0x10));
+
+    // Sampled from the peripherals
+    let mut timers = Timers::new();
+    timers.port_out(0x80, 0x41);  // TMR0_CTL: IRQ_EN, enabled
+    timers.tick(4 * 0x10000);
+    ic.sample(&[&timers]);
+    assert_eq!(vec![vectors::PRT0], ic.pending());
+}
+
+#[test]
+fn test_interrupt_controller_madl() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new_ez80();
+    let mut ic = InterruptController::new();
+    let code: &[u8] = &[
+        0xed, 0x7d,        // STMIX
+        0xed, 0x5e,        // IM 2
+        0x3e, 0x01,        // LD A, $01
+        0xed, 0x47,        // LD I, A
+        0xfb,              // EI
+        0x76,              // HALT
+        0x18, 0xfd,        // JR $0009
+    ];
+    let isr: &[u8] = &[
+        0x04,              // INC B
+        0xfb,              // EI
+        0x5b, 0xed, 0x4d,  // RETI.L
+    ];
+    for (i, b) in code.iter().enumerate() {
+        sys.poke(i as u32, *b);
+    }
+    for (i, b) in isr.iter().enumerate() {
+        sys.poke(0x0200 + i as u32, *b);
+    }
+    sys.poke(0x010c, 0x00);
+    sys.poke(0x010d, 0x02);  // PRT1 vector
+    cpu.registers().set16(Reg16::SP, 0x8000);
+    cpu.registers().set24(Reg24::SPL, 0x9000);
+
+    // Not accepted with the interrupts disabled
+    ic.raise(vectors::PRT1);
+    cpu.execute_instruction(&mut sys);
+    assert_eq!(None, ic.deliver(&mut cpu, &mut sys));
+    for _ in 0..5 {
+        cpu.execute_instruction(&mut sys);
+    }
+    assert!(cpu.is_halted());
+
+    // Accepted on ADL mode
+    assert_eq!(Some(vectors::PRT1), ic.deliver(&mut cpu, &mut sys));
+    assert_eq!(&[vectors::PRT1], ic.active());
+    assert!(cpu.registers().adl);
+    assert_eq!(0x0200, cpu.state.pc());
+    ic.clear(vectors::PRT1);
+
+    for _ in 0..3 {
+        cpu.execute_instruction(&mut sys);
+    }
+    assert_eq!(None, ic.deliver(&mut cpu, &mut sys));
+    assert!(ic.active().is_empty());
+    assert!(!cpu.registers().adl);
+    assert_eq!(0x000a, cpu.state.pc());
+    assert_eq!(1, cpu.registers().get8(Reg8::B));
+}
@@ -1,3 +1,6 @@
+// The lint exceptions keep the test as written upstream
+#![allow(clippy::bool_assert_comparison)]
+
 use ez80::*;
 
 #[test]
@@ -15,7 +18,8 @@ fn test_ez80_z80() {
     assert_eq!(0x3456, cpu.registers().get16(Reg16::BC));
     assert_eq!(0x3, cpu.state.pc());
 
-    //assert_eq!(0x123456, cpu.registers().get24(Reg24::BC));
+    // The 16 bit loads clear the upper byte
+    assert_eq!(0x003456, cpu.registers().get24(Reg24::BC));
 }
 
 #[test]
@@ -28,10 +32,10 @@ fn test_ez80_adl() {
     sys.poke(0x0001, 0x56); 
     sys.poke(0x0002, 0x34); 
     sys.poke(0x0003, 0x12); 
-    cpu.registers().set24(Reg16::BC, 0x0000);
+    cpu.registers().set24(Reg24::BC, 0x0000);
 
     cpu.execute_instruction(&mut sys);
-    assert_eq!(0x123456, cpu.registers().get24(Reg16::BC));
+    assert_eq!(0x123456, cpu.registers().get24(Reg24::BC));
     assert_eq!(0x4, cpu.state.pc());
 }
 
@@ -47,16 +51,16 @@ fn test_ez80_mem_wrap() {
 
     cpu.execute_instruction(&mut sys);
     assert_eq!(0x0001, cpu.registers().get16(Reg16::SP));
-    assert_eq!(0x00c1fe, cpu.registers().get24(Reg16::BC));
+    assert_eq!(0x00c1fe, cpu.registers().get24(Reg24::BC));
 
     cpu.set_adl(true);
     cpu.state.set_pc(0);
-    cpu.registers().set24(Reg16::BC, 0);
-    cpu.registers().set24(Reg16::SP, 0xFFFF);
+    cpu.registers().set24(Reg24::BC, 0);
+    cpu.registers().set24(Reg24::SPL, 0xFFFF);
 
     cpu.execute_instruction(&mut sys);
-    assert_eq!(0x10002, cpu.registers().get24(Reg16::SP));
-    assert_eq!(0xcafe, cpu.registers().get24(Reg16::BC));
+    assert_eq!(0x10002, cpu.registers().get24(Reg24::SPL));
+    assert_eq!(0xcafe, cpu.registers().get24(Reg24::BC));
 }
 
 #[test]
@@ -99,10 +103,10 @@ fn test_size_suffixes() {
         cpu.set_adl(adl);
 
         cpu.state.set_pc(0);
-        cpu.registers().set24(Reg16::DE, 0);
-        cpu.registers().set24(Reg16::HL, 0);
-        cpu.registers().set24(Reg16::IX, 0);
-        cpu.registers().set24(Reg16::IY, 0);
+        cpu.registers().set24(Reg24::DE, 0);
+        cpu.registers().set24(Reg24::HL, 0);
+        cpu.registers().set24(Reg24::IX, 0);
+        cpu.registers().set24(Reg24::IY, 0);
 
         sys.poke(0x0000, 0x5b); // .LIL
         sys.poke(0x0001, 0x21); // ld.lil hl, $123456
@@ -132,12 +136,12 @@ fn test_size_suffixes() {
         cpu.execute_instruction(&mut sys);
 
         assert_eq!(0x0014, cpu.state.pc());
-        assert_eq!(0x123456, cpu.registers().get24(Reg16::HL));
+        assert_eq!(0x123456, cpu.registers().get24(Reg24::HL));
         // note we are assuming the top byte of the register, when
         // using .s prefix, is zero. the actual spec says "undefined"
-        assert_eq!(0x789a, cpu.registers().get24(Reg16::DE));
-        assert_eq!(0x1234, cpu.registers().get24(Reg16::IX));
-        assert_eq!(0x9abc, cpu.registers().get24(Reg16::IY));
+        assert_eq!(0x789a, cpu.registers().get24(Reg24::DE));
+        assert_eq!(0x1234, cpu.registers().get24(Reg24::IX));
+        assert_eq!(0x9abc, cpu.registers().get24(Reg24::IY));
     }
 }
 
@@ -191,7 +195,7 @@ fn test_tst_a_hl() {
     sys.poke(0x0001, 0x34);
 
     cpu.state.reg.set8(Reg8::A, 0);
-    cpu.state.reg.set24(Reg16::HL, 0);
+    cpu.state.reg.set24(Reg24::HL, 0);
     cpu.state.set_pc(0);
 
     cpu.execute_instruction(&mut sys);
@@ -203,7 +207,7 @@ fn test_tst_a_hl() {
     assert_eq!(false, cpu.registers().get_flag(Flag::S));
 
     cpu.state.reg.set8(Reg8::A, 0xff);
-    cpu.state.reg.set24(Reg16::HL, 0);
+    cpu.state.reg.set24(Reg24::HL, 0);
     cpu.state.set_pc(0);
 
     cpu.execute_instruction(&mut sys);
@@ -291,8 +295,8 @@ fn test_pea() {
     sys.poke(0x0001, 0x65);
     sys.poke(0x0002, 0x12);
 
-    cpu.state.reg.set24(Reg16::IX, 0xabcdef);
-    cpu.state.reg.set24(Reg16::SP, 0x100);
+    cpu.state.reg.set24(Reg24::IX, 0xabcdef);
+    cpu.state.reg.set24(Reg24::SPL, 0x100);
 
     cpu.execute_instruction(&mut sys);
 
@@ -323,7 +327,7 @@ fn test_alu_ixh_ihl() {
     cpu.execute_instruction(&mut sys);
     cpu.execute_instruction(&mut sys);
 
-    assert_eq!(0xcafeba, cpu.state.reg.get24(Reg16::IX));
+    assert_eq!(0xcafeba, cpu.state.reg.get24(Reg24::IX));
     assert_eq!(0x0, cpu.state.reg.a());
 
     cpu.execute_instruction(&mut sys);
@@ -340,7 +344,7 @@ fn test_alu_ld_ixh_ixl() {
     let mut sys = PlainMachine::new();
     let mut cpu = Cpu::new_ez80();
     cpu.set_adl(true);
-    cpu.state.reg.set24(Reg16::IX, 0xcafeba);
+    cpu.state.reg.set24(Reg24::IX, 0xcafeba);
 
     sys.poke(0x0000, 0xdd); // ld ixh,$de
     sys.poke(0x0001, 0x26);
@@ -354,13 +358,13 @@ fn test_alu_ld_ixh_ixl() {
     sys.poke(0x0009, 0x24);
 
     cpu.execute_instruction(&mut sys);
-    assert_eq!(0xcadeba, cpu.state.reg.get24(Reg16::IX));
+    assert_eq!(0xcadeba, cpu.state.reg.get24(Reg24::IX));
     cpu.execute_instruction(&mut sys);
-    assert_eq!(0xcade89, cpu.state.reg.get24(Reg16::IX));
+    assert_eq!(0xcade89, cpu.state.reg.get24(Reg24::IX));
     cpu.execute_instruction(&mut sys);
-    assert_eq!(0xcadd89, cpu.state.reg.get24(Reg16::IX));
+    assert_eq!(0xcadd89, cpu.state.reg.get24(Reg24::IX));
     cpu.execute_instruction(&mut sys);
-    assert_eq!(0xcade89, cpu.state.reg.get24(Reg16::IX));
+    assert_eq!(0xcade89, cpu.state.reg.get24(Reg24::IX));
 }
 
 #[test]
@@ -377,8 +381,8 @@ fn test_24bit_alu_flags() {
     cpu.set_adl(true);
     cpu.state.reg.pc = 0;
     cpu.state.reg.set8(Reg8::F, 0);
-    cpu.state.reg.set24(Reg16::HL, 0xffffff);
-    cpu.state.reg.set24(Reg16::DE, 0x000001);
+    cpu.state.reg.set24(Reg24::HL, 0xffffff);
+    cpu.state.reg.set24(Reg24::DE, 0x000001);
     cpu.execute_instruction(&mut sys);
     cpu.execute_instruction(&mut sys);
     cpu.execute_instruction(&mut sys);
@@ -388,8 +392,8 @@ fn test_24bit_alu_flags() {
     cpu.set_adl(false);
     cpu.state.reg.pc = 0;
     cpu.state.reg.set8(Reg8::F, 0);
-    cpu.state.reg.set24(Reg16::HL, 0xffffff);
-    cpu.state.reg.set24(Reg16::DE, 0x000001);
+    cpu.state.reg.set24(Reg24::HL, 0xffffff);
+    cpu.state.reg.set24(Reg24::DE, 0x000001);
     cpu.execute_instruction(&mut sys);
     cpu.execute_instruction(&mut sys);
     cpu.execute_instruction(&mut sys);
@@ -399,8 +403,8 @@ fn test_24bit_alu_flags() {
     cpu.set_adl(true);
     cpu.state.reg.pc = 0;
     cpu.state.reg.set8(Reg8::F, 0);
-    cpu.state.reg.set24(Reg16::HL, 0xfffffe);
-    cpu.state.reg.set24(Reg16::DE, 0xffffff);
+    cpu.state.reg.set24(Reg24::HL, 0xfffffe);
+    cpu.state.reg.set24(Reg24::DE, 0xffffff);
     cpu.execute_instruction(&mut sys);
     cpu.execute_instruction(&mut sys);
     cpu.execute_instruction(&mut sys);
@@ -410,8 +414,8 @@ fn test_24bit_alu_flags() {
     cpu.set_adl(false);
     cpu.state.reg.pc = 0;
     cpu.state.reg.set8(Reg8::F, 0);
-    cpu.state.reg.set24(Reg16::HL, 0xfffffe);
-    cpu.state.reg.set24(Reg16::DE, 0xffffff);
+    cpu.state.reg.set24(Reg24::HL, 0xfffffe);
+    cpu.state.reg.set24(Reg24::DE, 0xffffff);
     cpu.execute_instruction(&mut sys);
     cpu.execute_instruction(&mut sys);
     cpu.execute_instruction(&mut sys);
@@ -421,8 +425,8 @@ fn test_24bit_alu_flags() {
     cpu.set_adl(true);
     cpu.state.reg.pc = 0;
     cpu.state.reg.set8(Reg8::F, 0);
-    cpu.state.reg.set24(Reg16::HL, 0xfffffe);
-    cpu.state.reg.set24(Reg16::DE, 0xfffffe);
+    cpu.state.reg.set24(Reg24::HL, 0xfffffe);
+    cpu.state.reg.set24(Reg24::DE, 0xfffffe);
     cpu.execute_instruction(&mut sys);
     cpu.execute_instruction(&mut sys);
     cpu.execute_instruction(&mut sys);
@@ -432,8 +436,8 @@ fn test_24bit_alu_flags() {
     cpu.set_adl(false);
     cpu.state.reg.pc = 0;
     cpu.state.reg.set8(Reg8::F, 0);
-    cpu.state.reg.set24(Reg16::HL, 0xfffffe);
-    cpu.state.reg.set24(Reg16::DE, 0xfffffe);
+    cpu.state.reg.set24(Reg24::HL, 0xfffffe);
+    cpu.state.reg.set24(Reg24::DE, 0xfffffe);
     cpu.execute_instruction(&mut sys);
     cpu.execute_instruction(&mut sys);
     cpu.execute_instruction(&mut sys);
@@ -457,7 +461,7 @@ fn test_ld_sil() {
     cpu.state.reg.pc = 0x20000;
     cpu.state.reg.mbase = 1;
     cpu.state.reg.set8(Reg8::F, 0);
-    cpu.state.reg.set24(Reg16::HL, 0xcafeba);
+    cpu.state.reg.set24(Reg24::HL, 0xcafeba);
     cpu.execute_instruction(&mut sys);
 
     assert_eq!(sys.peek(0x20005), 0);
@@ -481,9 +485,9 @@ fn test_ldir_cycles() {
     cpu.set_adl(true);
     cpu.state.reg.pc = 0x10000;
     cpu.state.reg.set8(Reg8::F, 0);
-    cpu.state.reg.set24(Reg16::BC, 3);
-    cpu.state.reg.set24(Reg16::DE, 0xff);
-    cpu.state.reg.set24(Reg16::HL, 0x100);
+    cpu.state.reg.set24(Reg24::BC, 3);
+    cpu.state.reg.set24(Reg24::DE, 0xff);
+    cpu.state.reg.set24(Reg24::HL, 0x100);
     cpu.execute_instruction(&mut sys);
     cpu.execute_instruction(&mut sys);
     cpu.execute_instruction(&mut sys);
@@ -505,9 +509,9 @@ fn test_ldir_lil_cycles() {
     cpu.set_adl(true);
     cpu.state.reg.pc = 0x0;
     cpu.state.reg.set8(Reg8::F, 0);
-    cpu.state.reg.set24(Reg16::BC, 3);
-    cpu.state.reg.set24(Reg16::DE, 0xff);
-    cpu.state.reg.set24(Reg16::HL, 0x100);
+    cpu.state.reg.set24(Reg24::BC, 3);
+    cpu.state.reg.set24(Reg24::DE, 0xff);
+    cpu.state.reg.set24(Reg24::HL, 0x100);
     cpu.execute_instruction(&mut sys);
     cpu.execute_instruction(&mut sys);
     cpu.execute_instruction(&mut sys);
@@ -531,9 +535,9 @@ fn test_otirx() {
     cpu.set_adl(true);
     cpu.state.reg.pc = 0x0;
     cpu.state.reg.set8(Reg8::F, 0);
-    cpu.state.reg.set24(Reg16::BC, 3);
-    cpu.state.reg.set24(Reg16::DE, 0xabcd);
-    cpu.state.reg.set24(Reg16::HL, 0x100);
+    cpu.state.reg.set24(Reg24::BC, 3);
+    cpu.state.reg.set24(Reg24::DE, 0xabcd);
+    cpu.state.reg.set24(Reg24::HL, 0x100);
     cpu.execute_instruction(&mut sys);
     cpu.execute_instruction(&mut sys);
     cpu.execute_instruction(&mut sys);
@@ -541,3 +545,54 @@ fn test_otirx() {
     assert_eq!(cpu.state.reg.pc, 0x2);
     assert_eq!(sys.get_elapsed_cycles(), 2 + 3 * 3);
 }
+
+#[test]
+fn test_ez80_sps_and_spl() {
+    let mut cpu = Cpu::new_ez80();
+    cpu.registers().set16(Reg16::SPS, 0x1234);
+    cpu.registers().set24(Reg24::SPL, 0x56789a);
+    assert_eq!(0x1234, cpu.registers().get16(Reg16::SP));
+    assert_eq!(0x56789a, cpu.registers().get24(Reg24::SPL));
+}
+
+#[test]
+fn test_ez80_register_file() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new_ez80();
+    let file = RegisterFile {
+        af: 0x12d7,
+        bc: 0x010203,
+        de: 0x040506,
+        hl: 0x070809,
+        af_shadow: 0x3400,
+        bc_shadow: 0x111111,
+        de_shadow: 0x222222,
+        hl_shadow: 0x333333,
+        ix: 0x0a0b0c,
+        iy: 0x0d0e0f,
+        sps: 0x8000,
+        spl: 0x0a0000,
+        pc: 0x001000,
+        i: 0x40,
+        r: 0x05,
+        iff1: true,
+        iff2: true,
+        im: 2,
+        adl: true,
+        madl: false,
+        mbase: 0x0a,
+    };
+    cpu.registers().set_all(&file);
+    assert_eq!(file, cpu.registers().get_all());
+
+    sys.poke(0x001000, 0xd9);  // EXX
+    sys.poke(0x001001, 0x08);  // EX AF, AF'
+    cpu.execute_instruction(&mut sys);
+    cpu.execute_instruction(&mut sys);
+    let swapped = cpu.registers().get_all();
+    assert_eq!(0x111111, swapped.bc);
+    assert_eq!(0x070809, swapped.hl_shadow);
+    assert_eq!(0x3400, swapped.af);
+    assert_eq!(0x12d7, swapped.af_shadow);
+    assert_eq!(0x001002, swapped.pc);
+}
@@ -0,0 +1,47 @@
+use ez80::*;
+
+#[test]
+fn test_flags_accessors() {
+    let mut flags = Flags::from(0x00);
+    flags.insert(Flags::S | Flags::C);
+    flags.set(Flags::Y, true);
+    assert!(flags.s() && flags.c() && flags.y());
+    assert!(!flags.z() && !flags.h() && !flags.x() && !flags.pv() && !flags.n());
+    assert_eq!(0xa1, u8::from(flags));
+    flags.remove(Flags::S);
+    assert_eq!(Flags::from(Flag::C) | Flags::Y, flags);
+    assert_eq!("--5----C", flags.to_string());
+}
+
+#[test]
+fn test_flags_of_registers() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new();
+    sys.poke(0x0000, 0x3e);  // LD A, $80
+    sys.poke(0x0001, 0x80);
+    sys.poke(0x0002, 0xd6);  // SUB $01
+    sys.poke(0x0003, 0x01);
+    cpu.execute_instruction(&mut sys);
+    cpu.execute_instruction(&mut sys);
+    let flags = cpu.registers().flags();
+    assert!(flags.pv() && flags.n() && flags.h() && flags.y() && flags.x());
+    assert_eq!("--5H3PN-", cpu.registers().flags_display().to_string());
+
+    cpu.registers().set_flags(Flags::Z);
+    assert!(cpu.registers().get_flag(Flag::Z));
+    assert_eq!(0x40, cpu.registers().get8(Reg8::F));
+}
+
+#[test]
+fn test_flags_8080_layouts() {
+    let mut cpu = Cpu::new_8080();
+    cpu.registers().set16(Reg16::AF, 0x00ff);
+    assert_eq!(FlagsLayout::I8080, cpu.registers().flags_layout());
+    assert_eq!("SZ0A0P1C", cpu.registers().flags_display().to_string());
+    cpu.registers().set16(Reg16::AF, 0x0000);
+    assert_eq!("--0-0-1-", cpu.registers().flags_display().to_string());
+
+    let mut cpu = Cpu::new_8085();
+    cpu.registers().set16(Reg16::AF, 0x0022);
+    assert_eq!("--K-0-V-", cpu.registers().flags_display().to_string());
+}
@@ -0,0 +1,67 @@
+use ez80::*;
+
+#[test]
+fn test_step_back_restores_registers_and_memory() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new();
+    cpu.set_history(10);
+
+    sys.poke(0x0000, 0x21);  // LD HL, $1234
+    sys.poke(0x0001, 0x34);
+    sys.poke(0x0002, 0x12);
+    sys.poke(0x0003, 0xe5);  // PUSH HL
+    sys.poke(0x0004, 0x36);  // LD (HL), $aa
+    sys.poke(0x0005, 0xaa);
+    sys.poke(0x1234, 0x55);
+    cpu.registers().set16(Reg16::SP, 0x8000);
+
+    cpu.execute_instruction(&mut sys);
+    cpu.execute_instruction(&mut sys);
+    cpu.execute_instruction(&mut sys);
+    assert_eq!(0xaa, sys.peek(0x1234));
+    assert_eq!(0x1234, sys._peek16(0x7ffe));
+    assert_eq!(3, cpu.history().unwrap().len());
+
+    assert!(cpu.step_back(&mut sys));
+    assert_eq!(0x55, sys.peek(0x1234));
+    assert_eq!(0x0004, cpu.state.pc());
+    assert_eq!(2, cpu.state.instructions_executed);
+
+    assert!(cpu.step_back(&mut sys));
+    assert_eq!(0x0000, sys._peek16(0x7ffe));
+    assert_eq!(0x8000, cpu.registers().get16(Reg16::SP));
+
+    assert!(cpu.step_back(&mut sys));
+    assert_eq!(0x0000, cpu.registers().get16(Reg16::HL));
+    assert_eq!(0x0000, cpu.state.pc());
+
+    assert!(!cpu.step_back(&mut sys));
+}
+
+#[test]
+fn test_history_capacity_and_last_writer() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new();
+    cpu.set_history(4);
+
+    sys.poke(0x0000, 0x32);  // LD ($0100), A
+    sys.poke(0x0001, 0x00);
+    sys.poke(0x0002, 0x01);
+    // NOPs follow
+
+    for _ in 0..4 {
+        cpu.execute_instruction(&mut sys);
+    }
+    let history = cpu.history().unwrap();
+    let pcs: Vec<u32> = history.entries().map(|e| e.pc).collect();
+    assert_eq!(vec![0, 3, 4, 5], pcs);
+    let writer = history.last_writer(0x0100).unwrap();
+    assert_eq!(0x0000, writer.pc);
+    assert_eq!(0, writer.instructions_executed());
+    assert!(history.last_writer(0x0101).is_none());
+
+    cpu.execute_instruction(&mut sys);
+    let history = cpu.history().unwrap();
+    assert_eq!(4, history.len());
+    assert!(history.last_writer(0x0100).is_none());
+}
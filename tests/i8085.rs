@@ -0,0 +1,196 @@
+use ez80::*;
+
+fn machine(code: &[u8]) -> PlainMachine {
+    let mut sys = PlainMachine::new();
+    for (i, b) in code.iter().enumerate() {
+        sys.poke(i as u32, *b);
+    }
+    sys.set_elapsed_cycles(0);
+    sys
+}
+
+fn peek16(sys: &PlainMachine, address: u32) -> u16 {
+    sys.peek(address) as u16 + ((sys.peek(address + 1) as u16) << 8)
+}
+
+#[test]
+fn test_8085_undocumented_16_bit() {
+    let code = [
+        0x08,        // DSUB
+        0x10,        // ARHL
+        0x18,        // RDEL
+        0x28, 0x10,  // LDHI $10
+        0xd9,        // SHLX
+        0x38, 0x02,  // LDSI $02
+        0xed,        // LHLX
+        0x76,        // HALT
+    ];
+    let mut sys = machine(&code);
+    let mut cpu = Cpu::new_8085();
+    cpu.registers().set16(Reg16::HL, 0x1000);
+    cpu.registers().set16(Reg16::BC, 0x1002);
+    cpu.registers().set16(Reg16::DE, 0x4001);
+    cpu.registers().set16(Reg16::SP, 0x3000);
+    sys.poke(0x3002, 0x34);
+    sys.poke(0x3003, 0x12);
+
+    cpu.execute_instruction(&mut sys);
+    // $1000 - $1002
+    assert_eq!(0xfffe, cpu.registers().get16(Reg16::HL));
+    assert!(cpu.registers().get_flag(Flag::C));
+    assert!(cpu.registers().get_flag(Flag::S));
+
+    cpu.execute_instruction(&mut sys);
+    assert_eq!(0xffff, cpu.registers().get16(Reg16::HL));
+    assert!(!cpu.registers().get_flag(Flag::C));
+
+    cpu.execute_instruction(&mut sys);
+    assert_eq!(0x8002, cpu.registers().get16(Reg16::DE));
+    assert!(!cpu.registers().get_flag(Flag::C));
+    assert!(cpu.registers().get_flag(Flag::V));
+
+    cpu.execute_instruction(&mut sys);
+    assert_eq!(0x000f, cpu.registers().get16(Reg16::DE));
+
+    cpu.execute_instruction(&mut sys);
+    assert_eq!(0xff, sys.peek(0x000f));
+    assert_eq!(0xff, sys.peek(0x0010));
+
+    cpu.execute_instruction(&mut sys);
+    assert_eq!(0x3002, cpu.registers().get16(Reg16::DE));
+
+    cpu.execute_instruction(&mut sys);
+    assert_eq!(0x1234, cpu.registers().get16(Reg16::HL));
+}
+
+#[test]
+fn test_8085_k_and_v_flags() {
+    let code = [
+        0x3e, 0x70,  // MVI A, $70
+        0xc6, 0x20,  // ADI $20
+        0xcb,        // RSTV
+        0x76,        // HALT
+    ];
+    let mut sys = machine(&code);
+    sys.poke(0x0040, 0x03);  // INX B
+    sys.poke(0x0041, 0xfd);  // JK $0050
+    sys.poke(0x0042, 0x50);
+    sys.poke(0x0043, 0x00);
+    sys.poke(0x0044, 0x76);  // HALT
+    sys.poke(0x0050, 0x76);  // HALT
+    let mut cpu = Cpu::new_8085();
+    cpu.registers().set16(Reg16::SP, 0x8000);
+    cpu.registers().set16(Reg16::BC, 0xffff);
+
+    cpu.execute_instruction(&mut sys);
+    cpu.execute_instruction(&mut sys);
+    assert_eq!(0x90, cpu.registers().a());
+    assert!(cpu.registers().get_flag(Flag::V));
+    assert!(!cpu.registers().get_flag(Flag::K));
+
+    cpu.execute_instructions(&mut sys, 10);
+    assert!(cpu.is_halted());
+    assert_eq!(0x0051, cpu.state.pc());
+    assert_eq!(0x0000, cpu.registers().get16(Reg16::BC));
+    assert_eq!(0x0005, peek16(&sys, 0x7ffe));
+
+    cpu.state.set_pc(0x0041);
+    assert_eq!("JP K, $50", cpu.disasm_instruction(&mut sys));
+
+    // K and V are kept in F, bit 3 is always 0
+    cpu.registers().set16(Reg16::AF, 0x00ff);
+    assert_eq!(0x00f7, cpu.registers().get16(Reg16::AF));
+}
+
+#[test]
+fn test_8085_rim_sim() {
+    let code = [
+        0x3e, 0xda,  // MVI A, $da ; SOD=1, SDE, MSE, mask 6.5
+        0x30,        // SIM
+        0xfb,        // EI
+        0x20,        // RIM
+        0x76,        // HALT
+    ];
+    let mut sys = machine(&code);
+    let mut cpu = Cpu::new_8085();
+    cpu.state.i8085.as_mut().unwrap().sid = true;
+    cpu.state.i8085.as_mut().unwrap().rst65 = true;
+
+    cpu.execute_instructions(&mut sys, 10);
+    let i8085 = cpu.state.i8085.unwrap();
+    assert!(i8085.sod);
+    assert_eq!(0x02, i8085.mask);
+    assert_eq!(0xaa, cpu.registers().a());
+}
+
+#[test]
+fn test_8085_interrupts() {
+    let code = [
+        0x3e, 0x08,  // MVI A, $08 ; unmask all
+        0x30,        // SIM
+        0xfb,        // EI
+        0x76,        // HALT
+    ];
+    let mut sys = machine(&code);
+    sys.poke(0x0024, 0x76);  // TRAP: HALT
+    sys.poke(0x002c, 0x76);  // RST 5.5: HALT
+    sys.poke(0x003c, 0x76);  // RST 7.5: HALT
+    let mut cpu = Cpu::new_8085();
+    cpu.registers().set16(Reg16::SP, 0x8000);
+
+    // Accepted after SIM and EI
+    cpu.state.i8085.as_mut().unwrap().rst55 = true;
+    cpu.execute_instructions(&mut sys, 10);
+    assert_eq!(0x002d, cpu.state.pc());
+    assert!(!cpu.registers().get_iff1());
+
+    // RST 7.5 has priority and is ignored with the interrupts disabled
+    cpu.state.i8085.as_mut().unwrap().rst75 = true;
+    assert!(cpu.is_halted());
+    cpu.registers().iff1 = true;
+    assert!(!cpu.is_halted());
+    cpu.execute_instructions(&mut sys, 10);
+    assert_eq!(0x003d, cpu.state.pc());
+    assert!(!cpu.state.i8085.unwrap().rst75);
+
+    // RST 5.5 is still requested
+    cpu.registers().iff1 = true;
+    cpu.execute_instruction(&mut sys);
+    assert_eq!(0x002d, cpu.state.pc());
+
+    // TRAP is not maskable
+    cpu.state.i8085.as_mut().unwrap().trap = true;
+    cpu.execute_instruction(&mut sys);
+    assert_eq!(0x0025, cpu.state.pc());
+    assert!(!cpu.state.i8085.unwrap().trap);
+    assert_eq!(0x002d, peek16(&sys, 0x7ff8));
+}
+
+#[test]
+fn test_8085_timings() {
+    let code = [
+        0x00,              // NOP          4
+        0x06, 0x02,        // MVI B, $02   7
+        0x03,              // INX B        6
+        0xc5,              // PUSH B       12
+        0xc1,              // POP B        10
+        0x05,              // DCR B        4
+        0xc2, 0x04, 0x00,  // JNZ $0004    10 taken, 7 not taken
+        0xcd, 0x10, 0x00,  // CALL $0010   18
+        0x76,              // HALT         5
+    ];
+    let expected = 4 + 7 + 6 + (12 + 10 + 4 + 10) + (12 + 10 + 4 + 7) + 18 + (4 + 12) + 5;
+    for mode in 0..3 {
+        let mut sys = machine(&code);
+        sys.poke(0x0010, 0x20);  // RIM   4
+        sys.poke(0x0011, 0xd0);  // RNC   12 taken
+        sys.set_elapsed_cycles(0);
+        let mut cpu = Cpu::new_8085();
+        cpu.set_decode_cache(mode == 1);
+        cpu.set_block_cache(mode == 2);
+        cpu.registers().set16(Reg16::SP, 0x8000);
+        cpu.registers().clear_flag(Flag::C);
+        cpu.execute_instructions(&mut sys, 100);
+        assert_eq!(expected, sys.get_elapsed_cycles(), "mode {}", mode);
+    }
+}
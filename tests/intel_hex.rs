@@ -0,0 +1,33 @@
+use ez80::*;
+use ez80::z80_mem_tools::*;
+
+#[test]
+fn test_load_intel_hex() {
+    let mut sys = PlainMachine::new();
+    let hex = "\
+:0300300002337A1E
+:020000040001F9
+:02000000ABCD86
+:0400000500000100F6
+:00000001FF
+";
+    let start = load_intel_hex(&mut sys, hex).unwrap();
+    assert_eq!(Some(0x100), start);
+    assert_eq!(0x02, sys.peek(0x0030));
+    assert_eq!(0x33, sys.peek(0x0031));
+    assert_eq!(0x7a, sys.peek(0x0032));
+    assert_eq!(0xab, sys.peek(0x10000));
+    assert_eq!(0xcd, sys.peek(0x10001));
+}
+
+#[test]
+fn test_load_intel_hex_bad_checksum() {
+    let mut sys = PlainMachine::new();
+    assert!(load_intel_hex(&mut sys, ":0300300002337A1F\n").is_err());
+}
+
+#[test]
+fn test_load_intel_hex_non_ascii() {
+    let mut sys = PlainMachine::new();
+    assert!(load_intel_hex(&mut sys, ":03003000é2337A1E\n").is_err());
+}
@@ -0,0 +1,38 @@
+use ez80::*;
+
+#[test]
+fn test_address_mode_wrap() {
+    assert_eq!(0x000001, AddressMode::Adl.wrap(0xffffff, 2));
+    assert_eq!(0x010000, AddressMode::Adl.wrap(0x00ffff, 1));
+    assert_eq!(0x030000, AddressMode::Z80(0x03).wrap(0x00ffff, 1));
+    assert_eq!(0x03ffff, AddressMode::Z80(0x03).wrap(0x000000, -1));
+    assert_eq!(0x031234, AddressMode::Z80(0x03).wrap(0x011234, 0));
+}
+
+#[test]
+fn test_machine_accessors_with_mbase() {
+    let mut sys = PlainMachine::new();
+    sys.poke24_mode(0xfffe, 0x563412, AddressMode::Z80(0x02));
+    assert_eq!(0x12, sys.peek(0x02fffe));
+    assert_eq!(0x34, sys.peek(0x02ffff));
+    assert_eq!(0x56, sys.peek(0x020000));
+    assert_eq!(0x3412, sys.peek16_mode(0xfffe, AddressMode::Z80(0x02)));
+    assert_eq!(0x563412, sys.peek24_mode(0x02fffe, AddressMode::page_of(0x02fffe)));
+    assert_eq!(0x0034, sys.peek16_mode(0x02ffff, AddressMode::Adl));
+
+    // The legacy helpers wrap on the page for words and at 24 bits otherwise
+    sys._poke16(0x01ffff, 0xbeef);
+    assert_eq!(0xef, sys.peek(0x01ffff));
+    assert_eq!(0xbe, sys.peek(0x010000));
+    assert_eq!(0xbeef, sys._peek16(0x01ffff));
+    assert_eq!(0x0056ef, sys._peek24(0x01ffff));
+}
+
+#[test]
+fn test_registers_address_mode() {
+    let mut cpu = Cpu::new_ez80();
+    cpu.registers().mbase = 0x02;
+    assert_eq!(AddressMode::Z80(0x02), cpu.registers().address_mode());
+    cpu.registers().adl = true;
+    assert_eq!(AddressMode::Adl, cpu.registers().address_mode());
+}
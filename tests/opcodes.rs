@@ -1,5 +1,8 @@
 use ez80::*;
 
+mod common;
+use common::DiagnosticMachine;
+
 #[test]
 fn test_two_opcodes() {
     let mut sys = PlainMachine::new();
@@ -33,3 +36,40 @@ fn test_push_pop_rr() {
     assert_eq!(0x1234, cpu.registers().get16(Reg16::BC));
     assert_eq!(0x1234, cpu.registers().get16(Reg16::AF));
 }
+
+#[test]
+fn test_generic_cpu() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = GenericCpu::<PlainMachine>::new_ez80();
+
+    sys.poke(0x0000, 0x06);  // LD B, $34
+    sys.poke(0x0001, 0x34);
+    sys.poke(0x0002, 0x78);  // LD A, B
+    sys.poke(0x0003, 0x32);  // LD ($1000), A
+    sys.poke(0x0004, 0x00);
+    sys.poke(0x0005, 0x10);
+
+    cpu.execute_instruction(&mut sys);
+    assert_eq!("LD A, B", cpu.disasm_instruction(&mut sys));
+    cpu.state.set_pc(0x0002);
+    cpu.set_history(4);
+    cpu.execute_instruction(&mut sys);
+    cpu.execute_instruction(&mut sys);
+    assert_eq!(0x34, cpu.registers().a());
+    assert_eq!(0x34, sys.peek(0x1000));
+
+    assert!(cpu.step_back(&mut sys));
+    assert_eq!(0x00, sys.peek(0x1000));
+}
+
+#[test]
+fn test_diagnostic_hook() {
+    let mut sys = DiagnosticMachine::new();
+    let mut cpu = Cpu::new_ez80();
+
+    sys.poke(0x0000, 0xed);  // SLP
+    sys.poke(0x0001, 0x76);
+
+    cpu.execute_instruction(&mut sys);
+    assert_eq!(vec!["Unimplemented opcode: SLP".to_string()], sys.messages.into_inner());
+}
@@ -1,3 +1,6 @@
+// The lint exceptions keep the test as written upstream
+#![allow(clippy::bool_assert_comparison)]
+
 use ez80::*;
 
 #[test]
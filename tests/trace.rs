@@ -0,0 +1,108 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use ez80::*;
+use ez80::trace::*;
+
+#[test]
+fn test_trace_record_accesses() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new();
+    let ring = Rc::new(RefCell::new(RingTrace::new(10)));
+    cpu.set_trace_sink(Some(Box::new(ring.clone())));
+
+    sys.poke(0x0000, 0xed);  // LD ($1234), BC
+    sys.poke(0x0001, 0x43);
+    sys.poke(0x0002, 0x34);
+    sys.poke(0x0003, 0x12);
+    sys.poke(0x0004, 0xd3);  // OUT ($20), A
+    sys.poke(0x0005, 0x20);
+    cpu.registers().set16(Reg16::BC, 0xabcd);
+    cpu.registers().set_a(0x55);
+
+    cpu.execute_instruction(&mut sys);
+    cpu.execute_instruction(&mut sys);
+
+    let ring = ring.borrow();
+    assert_eq!(2, ring.len());
+    let records: Vec<&TraceRecord> = ring.records().collect();
+
+    let ld = records[0];
+    assert_eq!(0x0000, ld.pc);
+    assert_eq!(0x0004, ld.next_pc);
+    assert_eq!(vec![0xed, 0x43, 0x34, 0x12], ld.bytes);
+    assert_eq!("LD ($1234), BC", ld.instruction);
+    assert_eq!(0xabcd, ld.after.get16(Reg16::BC));
+    assert!(ld.accesses.contains(&BusAccess { kind: AccessKind::MemoryWrite, address: 0x1234, value: 0xcd }));
+    assert!(ld.accesses.contains(&BusAccess { kind: AccessKind::MemoryWrite, address: 0x1235, value: 0xab }));
+    assert_eq!(ld.accesses.len() as i32, ld.cycles);
+
+    let out = records[1];
+    assert_eq!(vec![0xd3, 0x20], out.bytes);
+    assert_eq!(Some(&BusAccess { kind: AccessKind::PortOut, address: 0x5520, value: 0x55 }), out.accesses.last());
+    assert_eq!(2, out.instructions_executed);
+}
+
+#[test]
+fn test_trace_ring_keeps_last() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new();
+    let ring = Rc::new(RefCell::new(RingTrace::new(3)));
+    cpu.set_trace_sink(Some(Box::new(ring.clone())));
+
+    // NOPs
+    for _ in 0..5 {
+        cpu.execute_instruction(&mut sys);
+    }
+
+    let pcs: Vec<u32> = ring.borrow().records().map(|r| r.pc).collect();
+    assert_eq!(vec![2, 3, 4], pcs);
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_trace_text() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new();
+    let text = Rc::new(RefCell::new(TextTrace::new(Vec::new())));
+    cpu.set_trace_sink(Some(Box::new(text.clone())));
+
+    sys.poke(0x0000, 0x3e);  // LD A, $42
+    sys.poke(0x0001, 0x42);
+    cpu.execute_instruction(&mut sys);
+    cpu.set_trace_sink(None);
+    cpu.execute_instruction(&mut sys);
+
+    let text = Rc::try_unwrap(text).ok().unwrap().into_inner().into_inner();
+    let text = String::from_utf8(text).unwrap();
+    assert_eq!(1, text.lines().count());
+    assert!(text.starts_with("==> 000000: LD A, $42"));
+    assert!(text.contains(" PC:000002 AF:42"));
+    assert!(text.trim_end().ends_with("[3e 42]"));
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_trace_binary() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new();
+    let binary = Rc::new(RefCell::new(BinaryTrace::new(Vec::new())));
+    cpu.set_trace_sink(Some(Box::new(binary.clone())));
+
+    sys.poke(0x0000, 0x3c);  // INC A
+    cpu.registers().set_a(0x10);
+    cpu.execute_instruction(&mut sys);
+    cpu.take_trace_sink();
+
+    let data = Rc::try_unwrap(binary).ok().unwrap().into_inner().into_inner();
+    assert_eq!(b"EZ80TRC1", &data[0..8]);
+    let record = &data[8..];
+    assert_eq!(&[0x00, 0x00, 0x00, 1, 0x3c], &record[0..5]);
+    // A before and after, in AF
+    assert_eq!(0x10, record[5 + 1]);
+    assert_eq!(0x11, record[5 + 29 + 1]);
+    // One access, the fetch, and one cycle
+    let accesses = 5 + 58;
+    assert_eq!(&[1, 0, 0, 0, 0, 0, 0x3c], &record[accesses..accesses + 7]);
+    assert_eq!(&[1, 0, 0, 0], &record[accesses + 7..]);
+}
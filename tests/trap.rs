@@ -0,0 +1,308 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use ez80::*;
+
+fn load(sys: &mut PlainMachine, address: u32, code: &[u8]) {
+    for (i, b) in code.iter().enumerate() {
+        sys.poke(address + i as u32, *b);
+    }
+}
+
+#[test]
+fn test_rst_trap_skip() {
+    for block_cache in [false, true] {
+        let mut sys = PlainMachine::new();
+        let mut cpu = Cpu::new_z80();
+        cpu.set_block_cache(block_cache);
+        load(&mut sys, 0x0000, &[
+            0x3e, 0x05, // LD A, 5
+            0xcf,       // RST 08h
+            0x3c,       // INC A
+            0x76,       // HALT
+        ]);
+        load(&mut sys, 0x0008, &[0x76]); // HALT
+        cpu.set_trap(Trap::Rst(0x08), Some(Box::new(|reg, _| {
+            assert_eq!(0x0002, reg.pc);
+            reg.set_a(reg.a() * 2);
+            TrapAction::Skip
+        })));
+
+        cpu.execute_instructions(&mut sys, 100);
+        assert!(cpu.is_halted());
+        assert_eq!(0x0005, cpu.state.pc());
+        assert_eq!(11, cpu.registers().a());
+    }
+}
+
+#[test]
+fn test_rst_trap_execute() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new_z80();
+    load(&mut sys, 0x0000, &[
+        0x31, 0x00, 0x80, // LD SP, $8000
+        0xaf,             // XOR A
+        0xd7,             // RST 10h
+        0xd7,             // RST 10h
+        0x76,             // HALT
+    ]);
+    load(&mut sys, 0x0010, &[0x3c, 0xc9]); // INC A, RET
+    let calls = Rc::new(Cell::new(0));
+    let counter = Rc::clone(&calls);
+    cpu.set_trap(Trap::Rst(0x10), Some(Box::new(move |_, _| {
+        counter.set(counter.get() + 1);
+        TrapAction::Execute
+    })));
+
+    // Executed one by one
+    for _ in 0..5 {
+        cpu.execute_instruction(&mut sys);
+    }
+    assert_eq!(1, calls.get());
+    assert_eq!(1, cpu.registers().a());
+
+    // Removed
+    cpu.set_trap(Trap::Rst(0x10), None);
+    cpu.execute_instructions(&mut sys, 100);
+    assert_eq!(1, calls.get());
+    assert_eq!(2, cpu.registers().a());
+}
+
+#[test]
+fn test_rst_trap_with_suffix() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new_ez80();
+    load(&mut sys, 0x0000, &[
+        0x5b, 0xcf, // RST.LIL 08h
+        0x76,       // HALT
+    ]);
+    cpu.set_trap(Trap::Rst(0x08), Some(Box::new(|reg, sys| {
+        sys.poke(0x1000, 0xaa);
+        reg.set_a(0x55);
+        TrapAction::Skip
+    })));
+
+    cpu.execute_instructions(&mut sys, 100);
+    assert!(cpu.is_halted());
+    assert_eq!(0x0003, cpu.state.pc());
+    assert_eq!(0x55, cpu.registers().a());
+    assert_eq!(0xaa, sys.peek(0x1000));
+}
+
+fn load_self_modifying(sys: &mut PlainMachine) {
+    load(sys, 0x0000, &[
+        0x31, 0x00, 0x80, // LD SP, $8000
+        0xaf,             // XOR A
+        0xcd, 0x00, 0x01, // CALL $0100
+        0x47,             // LD B, A
+        0xcf,             // RST 08h
+        0xcd, 0x00, 0x01, // CALL $0100
+        0x76,             // HALT
+    ]);
+    load(sys, 0x0100, &[0x3c, 0xc9]); // INC A, RET
+}
+
+fn set_patching_trap(cpu: &mut Cpu) {
+    cpu.set_trap(Trap::Rst(0x08), Some(Box::new(|_, sys| {
+        sys.poke(0x0100, 0x3d); // DEC A
+        TrapAction::Skip
+    })));
+}
+
+#[test]
+fn test_rst_trap_writes_cached_code() {
+    for (decode_cache, block_cache) in [(true, false), (false, true), (true, true)] {
+        let mut sys = PlainMachine::new();
+        let mut cpu = Cpu::new_z80();
+        cpu.set_decode_cache(decode_cache);
+        cpu.set_block_cache(block_cache);
+        load_self_modifying(&mut sys);
+        set_patching_trap(&mut cpu);
+
+        cpu.execute_instructions(&mut sys, 100);
+        assert!(cpu.is_halted());
+        assert_eq!(1, cpu.registers().get8(Reg8::B));
+        assert_eq!(0, cpu.registers().a());
+    }
+}
+
+#[test]
+fn test_rst_trap_writes_undone() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new_z80();
+    cpu.set_decode_cache(true);
+    cpu.set_history(16);
+    load_self_modifying(&mut sys);
+    set_patching_trap(&mut cpu);
+
+    cpu.execute_instructions(&mut sys, 100);
+    assert!(cpu.is_halted());
+    assert_eq!(0x3d, sys.peek(0x0100));
+
+    // The write of the handler is undone with the trap
+    while cpu.state.pc() != 0x0008 {
+        assert!(cpu.step_back(&mut sys));
+    }
+    assert_eq!(0x3c, sys.peek(0x0100));
+    assert_eq!(1, cpu.registers().a());
+}
+
+#[test]
+fn test_address_trap_return() {
+    for block_cache in [false, true] {
+        let mut sys = PlainMachine::new();
+        let mut cpu = Cpu::new_z80();
+        cpu.set_block_cache(block_cache);
+        load(&mut sys, 0x0000, &[
+            0x31, 0x00, 0x80, // LD SP, $8000
+            0x0e, 0x02,       // LD C, 2
+            0xcd, 0x00, 0x01, // CALL $0100
+            0x0e, 0x03,       // LD C, 3
+            0xcd, 0x00, 0x01, // CALL $0100
+            0x76,             // HALT
+        ]);
+        let output = Rc::new(Cell::new(0u32));
+        let sum = Rc::clone(&output);
+        cpu.set_trap(Trap::Address { address: 0x0100, adl: None }, Some(Box::new(move |reg, _| {
+            sum.set(sum.get() + reg.get8(Reg8::C) as u32);
+            TrapAction::Return
+        })));
+
+        cpu.execute_instructions(&mut sys, 100);
+        assert!(cpu.is_halted());
+        assert_eq!(5, output.get());
+        assert_eq!(0x000e, cpu.state.pc());
+        assert_eq!(0x8000, cpu.registers().get16(Reg16::SP));
+    }
+}
+
+#[test]
+fn test_address_trap_return_counted() {
+    // The trapped RET runs as the RET at $0100 without the trap
+    for new_cpu in [Cpu::new_z80, Cpu::new_z180, Cpu::new_8085] {
+        let mut results = Vec::new();
+        for trap in [false, true] {
+            let mut sys = PlainMachine::new();
+            let mut cpu = new_cpu();
+            load(&mut sys, 0x0000, &[
+                0x31, 0x00, 0x80, // LD SP, $8000
+                0xcd, 0x00, 0x01, // CALL $0100
+                0x76,             // HALT
+            ]);
+            load(&mut sys, 0x0100, &[0xc9]); // RET
+            sys.set_elapsed_cycles(0);
+            if trap {
+                cpu.set_trap(Trap::Address { address: 0x0100, adl: None },
+                    Some(Box::new(|_, _| TrapAction::Return)));
+            }
+
+            cpu.execute_instructions(&mut sys, 100);
+            assert!(cpu.is_halted());
+            results.push((cpu.state.instructions_executed, cpu.registers().get8(Reg8::R),
+                sys.get_elapsed_cycles()));
+        }
+        assert_eq!(results[0], results[1]);
+    }
+}
+
+#[test]
+fn test_address_trap_skip_with_mbase() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new_ez80();
+    cpu.state.reg.mbase = 0x01;
+    load(&mut sys, 0x010000, &[
+        0x21, 0x34, 0x12, // LD HL, $1234
+        0x76,             // HALT
+    ]);
+    // The address includes MBASE
+    cpu.set_trap(Trap::Address { address: 0x0000, adl: None }, Some(Box::new(|_, _| panic!("Trap on MBASE 0"))));
+    cpu.set_trap(Trap::Address { address: 0x010000, adl: None }, Some(Box::new(|_, _| TrapAction::Skip)));
+
+    cpu.execute_instructions(&mut sys, 100);
+    assert!(cpu.is_halted());
+    assert_eq!(0x010004, cpu.state.pc());
+    assert_eq!(0x0000, cpu.registers().get16(Reg16::HL));
+}
+
+#[test]
+fn test_address_trap_adl() {
+    for adl in [false, true] {
+        let mut sys = PlainMachine::new();
+        let mut cpu = Cpu::new_ez80();
+        cpu.state.reg.mbase = 0x01;
+        if adl {
+            cpu.set_adl(true);
+            cpu.state.set_pc(0x010000);
+        }
+        load(&mut sys, 0x010000, &[
+            0x21, 0x34, 0x12, 0x00, // LD HL, $1234 or LD HL, $001234
+            0x76,                   // HALT
+        ]);
+        // The same address on both modes
+        cpu.set_trap(Trap::Address { address: 0x010000, adl: Some(true) }, Some(Box::new(|reg, _| {
+            reg.set_a(1);
+            TrapAction::Skip
+        })));
+        cpu.set_trap(Trap::Address { address: 0x010000, adl: Some(false) }, Some(Box::new(|reg, _| {
+            reg.set_a(2);
+            TrapAction::Skip
+        })));
+
+        cpu.execute_instruction(&mut sys);
+        assert_eq!(if adl { 1 } else { 2 }, cpu.registers().a());
+        // Skipped with the length of the instruction in the mode
+        assert_eq!(if adl { 0x010004 } else { 0x010003 }, cpu.state.pc());
+        assert_eq!(0x0000, cpu.registers().get16(Reg16::HL));
+    }
+}
+
+#[test]
+fn test_address_trap_without_side_effects() {
+    for action in [TrapAction::Execute, TrapAction::Skip] {
+        let mut sys = PlainMachine::new();
+        let mut cpu = Cpu::new_z80();
+        load(&mut sys, 0x0000, &[
+            0xdd, 0x21, 0x34, 0x12, // LD IX, $1234
+            0x76,                   // HALT
+        ]);
+        sys.set_elapsed_cycles(0);
+        cpu.set_trap(Trap::Address { address: 0x0000, adl: None }, Some(Box::new(move |_, _| action)));
+
+        cpu.execute_instruction(&mut sys);
+        assert_eq!(0x0004, cpu.state.pc());
+        if action == TrapAction::Skip {
+            // Nothing read from the Machine to skip the instruction
+            assert_eq!(0, sys.get_elapsed_cycles());
+            assert_eq!(0x0000, cpu.registers().get16(Reg16::IX));
+        } else {
+            // The same reads as without the trap
+            let mut plain = PlainMachine::new();
+            load(&mut plain, 0x0000, &[0xdd, 0x21, 0x34, 0x12]);
+            plain.set_elapsed_cycles(0);
+            Cpu::new_z80().execute_instruction(&mut plain);
+            assert_eq!(plain.get_elapsed_cycles(), sys.get_elapsed_cycles());
+            assert_eq!(0x1234, cpu.registers().get16(Reg16::IX));
+        }
+    }
+}
+
+#[test]
+fn test_ed_trap_emulated() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new_z80();
+    load(&mut sys, 0x0000, &[
+        0xed, 0xfe, // Emulator trap
+        0x76,       // HALT
+    ]);
+    load(&mut sys, 0x0100, &[0x76]); // HALT
+    cpu.set_trap(Trap::Ed(0xfe), Some(Box::new(|reg, sys| {
+        // Jumps to the address on memory
+        reg.pc = sys.peek(0x0200) as u32 + 0x100;
+        TrapAction::Emulated
+    })));
+
+    cpu.execute_instruction(&mut sys);
+    assert_eq!(0x0100, cpu.state.pc());
+    cpu.execute_instruction(&mut sys);
+    assert!(cpu.is_halted());
+}
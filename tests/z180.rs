@@ -0,0 +1,231 @@
+use std::cell::Cell;
+
+use ez80::*;
+
+/// 1 MiB of memory, with a cycle per access like PlainMachine
+struct Z180Machine {
+    mem: Vec<u8>,
+    ports: Vec<(u16, u8)>,
+    cycles: Cell<i64>,
+}
+
+impl Z180Machine {
+    fn new(code: &[u8]) -> Z180Machine {
+        let mut mem = vec![0; 0x100000];
+        mem[..code.len()].copy_from_slice(code);
+        Z180Machine {
+            mem,
+            ports: Vec::new(),
+            cycles: Cell::new(0),
+        }
+    }
+}
+
+impl Machine for Z180Machine {
+    fn peek(&self, address: u32) -> u8 {
+        self.use_cycles(1);
+        self.mem[address as usize]
+    }
+
+    fn poke(&mut self, address: u32, value: u8) {
+        self.use_cycles(1);
+        self.mem[address as usize] = value;
+    }
+
+    fn use_cycles(&self, cycles: i32) {
+        self.cycles.set(self.cycles.get() + cycles as i64);
+    }
+
+    fn port_in(&mut self, address: u16) -> u8 {
+        self.use_cycles(1);
+        address as u8
+    }
+
+    fn port_out(&mut self, address: u16, value: u8) {
+        self.use_cycles(1);
+        self.ports.push((address, value));
+    }
+}
+
+#[test]
+fn test_z180_mmu() {
+    let code = [
+        0x3e, 0x48,        // LD A, $48
+        0xed, 0x39, 0x3a,  // OUT0 ($3a), A ; CBAR: bank area at $8000, common 1 at $4000
+        0x3e, 0x10,        // LD A, $10
+        0xed, 0x39, 0x39,  // OUT0 ($39), A ; BBR
+        0x3e, 0x20,        // LD A, $20
+        0xed, 0x39, 0x38,  // OUT0 ($38), A ; CBR
+        0x3e, 0x55,        // LD A, $55
+        0x32, 0x34, 0x12,  // LD ($1234), A
+        0x32, 0x34, 0x56,  // LD ($5634), A
+        0x76,              // HALT
+    ];
+    let mut sys = Z180Machine::new(&code);
+    let mut cpu = Cpu::new_z180();
+
+    while !cpu.is_halted() {
+        cpu.execute_instruction(&mut sys);
+    }
+    let z180 = cpu.state.z180.unwrap();
+    assert_eq!(0x48, z180.cbar);
+    assert_eq!(0x10, z180.bbr);
+    assert_eq!(0x20, z180.cbr);
+    assert!(sys.ports.is_empty());
+    // Common area 0 is not translated
+    assert_eq!(0x55, sys.mem[0x01234]);
+    // $5634 is in the common area 1, as CA is below BA
+    assert_eq!(0x55, sys.mem[0x25634]);
+
+    assert_eq!(0x28634, z180.translate(0x8634));
+    cpu.state.z180.as_mut().unwrap().cbar = 0x84;
+    assert_eq!(0x15634, cpu.state.z180.unwrap().translate(0x5634));
+    assert_eq!(0x28634, cpu.state.z180.unwrap().translate(0x8634));
+}
+
+#[test]
+fn test_z180_code_in_bank() {
+    let code = [
+        0x3e, 0x81,        // LD A, $81
+        0xed, 0x39, 0x3a,  // OUT0 ($3a), A ; bank area at $1000
+        0x3e, 0x40,        // LD A, $40
+        0xed, 0x39, 0x39,  // OUT0 ($39), A ; BBR: $1000 at $41000
+        0xc3, 0x00, 0x10,  // JP $1000
+    ];
+    let mut sys = Z180Machine::new(&code);
+    sys.mem[0x41000] = 0x3c;  // INC A
+    sys.mem[0x41001] = 0x76;  // HALT
+    let mut cpu = Cpu::new_z180();
+    cpu.set_block_cache(true);
+
+    cpu.execute_instructions(&mut sys, 100);
+    assert!(cpu.is_halted());
+    assert_eq!(0x41, cpu.registers().a());
+    assert_eq!(0x1002, cpu.state.pc());
+}
+
+#[test]
+fn test_z180_icr_relocation() {
+    let code = [
+        0x3e, 0x40,        // LD A, $40
+        0xed, 0x39, 0x3f,  // OUT0 ($3f), A ; internal registers at $40
+        0xed, 0x39, 0x38,  // OUT0 ($38), A ; to the Machine
+        0xed, 0x39, 0x78,  // OUT0 ($78), A ; CBR
+        0xed, 0x38, 0x7f,  // IN0 A, ($7f)  ; ICR
+        0xed, 0x08, 0x04,  // IN0 C, ($04)  ; to the Machine
+        0x76,              // HALT
+    ];
+    let mut sys = Z180Machine::new(&code);
+    let mut cpu = Cpu::new_z180();
+
+    while !cpu.is_halted() {
+        cpu.execute_instruction(&mut sys);
+    }
+    assert_eq!(vec![(0x0038, 0x40)], sys.ports);
+    assert_eq!(0x40, cpu.state.z180.unwrap().cbr);
+    assert_eq!(0x5f, cpu.registers().a());
+    assert_eq!(0x04, cpu.registers().get8(Reg8::C));
+}
+
+#[test]
+fn test_z180_trap_second_byte() {
+    let mut sys = Z180Machine::new(&[]);
+    let mut cpu = Cpu::new_z180();
+    sys.mem[0x0100] = 0xed;  // Undefined
+    sys.mem[0x0101] = 0x77;
+    cpu.state.set_pc(0x0100);
+    cpu.registers().set16(Reg16::SP, 0x8000);
+
+    cpu.execute_instruction(&mut sys);
+    assert_eq!(0x0000, cpu.state.pc());
+    assert_eq!(0x7ffe, cpu.registers().get16(Reg16::SP));
+    assert_eq!(0x0101, sys.mem[0x7ffe] as u16 + ((sys.mem[0x7fff] as u16) << 8));
+    assert_eq!(0x80, cpu.state.z180.unwrap().itc & 0xc0);
+}
+
+#[test]
+fn test_z180_trap_third_byte() {
+    let mut sys = Z180Machine::new(&[]);
+    let mut cpu = Cpu::new_z180();
+    sys.mem[0x0100] = 0xdd;  // RLC (IX+1), B is undefined
+    sys.mem[0x0101] = 0xcb;
+    sys.mem[0x0102] = 0x01;
+    sys.mem[0x0103] = 0x00;
+    cpu.state.set_pc(0x0100);
+    cpu.registers().set16(Reg16::SP, 0x8000);
+
+    cpu.execute_instruction(&mut sys);
+    assert_eq!(0x0000, cpu.state.pc());
+    assert_eq!(0x0102, sys.mem[0x7ffe] as u16 + ((sys.mem[0x7fff] as u16) << 8));
+    assert_eq!(0xc0, cpu.state.z180.unwrap().itc & 0xc0);
+}
+
+#[test]
+fn test_z180_undocumented_opcodes_trap() {
+    for code in [&[0xdd, 0x7c][..], &[0xfd, 0x00], &[0xcb, 0x30], &[0xed, 0x4e], &[0xdd, 0xed, 0x44]] {
+        let mut sys = Z180Machine::new(code);
+        let mut cpu = Cpu::new_z180();
+        cpu.state.set_pc(0x0000);
+        cpu.registers().set16(Reg16::SP, 0x8000);
+        cpu.execute_instruction(&mut sys);
+        assert_eq!(0x7ffe, cpu.registers().get16(Reg16::SP), "{:02x?}", code);
+        assert_eq!(0x80, cpu.state.z180.unwrap().itc & 0x80, "{:02x?}", code);
+    }
+}
+
+#[test]
+fn test_z180_timings() {
+    let code = [
+        0x00,              // NOP             3
+        0x3e, 0x02,        // LD A, $02       6
+        0xed, 0x4c,        // MLT BC          17
+        0xdd, 0x7e, 0x01,  // LD A, (IX+1)    14
+        0xc5,              // PUSH BC         11
+        0xc1,              // POP BC          9
+        0x3d,              // DEC A           4
+        0x20, 0xfd,        // JR NZ, -3       8 taken, 6 not taken
+        0xed, 0x38, 0x00,  // IN0 A, ($00)    12
+        0x76,              // HALT            3
+    ];
+    let expected = 3 + 6 + 17 + 14 + 11 + 9 + (4 + 8) + (4 + 6) + 12 + 3;
+    for mode in 0..3 {
+        let mut sys = Z180Machine::new(&code);
+        sys.mem[0x2001] = 0x02;
+        let mut cpu = Cpu::new_z180();
+        cpu.set_decode_cache(mode == 1);
+        cpu.set_block_cache(mode == 2);
+        cpu.registers().set16(Reg16::SP, 0x8000);
+        cpu.registers().set16(Reg16::IX, 0x2000);
+        cpu.execute_instructions(&mut sys, 100);
+        assert_eq!(expected, sys.cycles.get(), "mode {}", mode);
+    }
+}
+
+#[test]
+fn test_z180_otimr() {
+    let code = [
+        0xed, 0x93,  // OTIMR
+        0x76,        // HALT
+    ];
+    let mut sys = Z180Machine::new(&code);
+    sys.mem[0x1000..0x1003].copy_from_slice(&[0x11, 0x22, 0x33]);
+    let mut cpu = Cpu::new_z180();
+    cpu.registers().set16(Reg16::HL, 0x1000);
+    cpu.registers().set16(Reg16::BC, 0x03a0);
+
+    cpu.execute_instructions(&mut sys, 100);
+    assert_eq!(vec![(0x00a0, 0x11), (0x00a1, 0x22), (0x00a2, 0x33)], sys.ports);
+    assert_eq!(0x00a3, cpu.registers().get16(Reg16::BC));
+    assert_eq!(0x1003, cpu.registers().get16(Reg16::HL));
+    assert!(cpu.registers().get_flag(Flag::Z));
+}
+
+#[test]
+fn test_z180_slp() {
+    let mut sys = Z180Machine::new(&[0xed, 0x76]);  // SLP
+    let mut cpu = Cpu::new_z180();
+    assert_eq!("SLP", cpu.disasm_instruction(&mut sys));
+    cpu.state.set_pc(0x0000);
+    cpu.execute_instruction(&mut sys);
+    assert!(cpu.is_halted());
+}
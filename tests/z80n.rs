@@ -0,0 +1,265 @@
+use ez80::*;
+
+/// Records the NEXTREG writes
+struct NextMachine {
+    plain: PlainMachine,
+    registers: [u8; 256],
+}
+
+impl Machine for NextMachine {
+    fn peek(&self, address: u32) -> u8 { self.plain.peek(address) }
+    fn poke(&mut self, address: u32, value: u8) { self.plain.poke(address, value) }
+    fn use_cycles(&self, cycles: i32) { self.plain.use_cycles(cycles) }
+    fn port_in(&mut self, address: u16) -> u8 { self.plain.port_in(address) }
+    fn port_out(&mut self, address: u16, value: u8) { self.plain.port_out(address, value) }
+
+    fn nextreg(&mut self, register: u8, value: u8) {
+        self.registers[register as usize] = value;
+    }
+}
+
+fn run(code: &[u8], cpu: &mut Cpu, sys: &mut dyn Machine) {
+    for (i, b) in code.iter().enumerate() {
+        sys.poke(i as u32, *b);
+    }
+    sys.poke(code.len() as u32, 0x76); // HALT
+    cpu.execute_instructions(sys, 1000);
+    assert!(cpu.is_halted());
+}
+
+#[test]
+fn test_z80n_a_operations() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new_z80n();
+    cpu.registers().set_a(0x3c);
+    cpu.registers().set8(Reg8::E, 0x05);
+    run(&[
+        0xed, 0x23,        // SWAPNIB
+        0x47,              // LD B, A
+        0xed, 0x24,        // MIRROR A
+        0x4f,              // LD C, A
+        0xed, 0x27, 0x0f,  // TEST $0F
+        0xed, 0x95,        // SETAE
+    ], &mut cpu, &mut sys);
+    assert_eq!(0xc3, cpu.registers().get8(Reg8::B));
+    assert_eq!(0xc3, cpu.registers().get8(Reg8::C));
+    assert!(!cpu.registers().get_flag(Flag::Z));
+    assert_eq!(0x04, cpu.registers().a());
+}
+
+#[test]
+fn test_z80n_barrel_shifts() {
+    for (opcode, de, b, expected) in [
+        (0x28, 0x8421, 4, 0x4210),  // BSLA
+        (0x29, 0x8421, 4, 0xf842),  // BSRA
+        (0x2a, 0x8421, 4, 0x0842),  // BSRL
+        (0x2b, 0x0421, 4, 0xf042),  // BSRF
+        (0x2c, 0x8421, 4, 0x4218),  // BRLC
+        (0x28, 0x8421, 0x20, 0x8421),
+        (0x2c, 0x8421, 0x13, 0x210c),
+    ] {
+        let mut sys = PlainMachine::new();
+        let mut cpu = Cpu::new_z80n();
+        cpu.registers().set16(Reg16::DE, de);
+        cpu.registers().set8(Reg8::B, b);
+        run(&[0xed, opcode], &mut cpu, &mut sys);
+        assert_eq!(expected, cpu.registers().get16(Reg16::DE), "{:02x} {:x}", opcode, b);
+    }
+}
+
+#[test]
+fn test_z80n_arithmetic() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new_z80n();
+    cpu.registers().set16(Reg16::DE, 0x0c0b);
+    cpu.registers().set16(Reg16::HL, 0xfff0);
+    cpu.registers().set16(Reg16::SP, 0x8000);
+    cpu.registers().set_a(0x20);
+    run(&[
+        0xed, 0x30,              // MUL D, E
+        0xed, 0x31,              // ADD HL, A
+        0xed, 0x33,              // ADD BC, A
+        0xed, 0x35, 0x00, 0x10,  // ADD DE, $1000
+        0xed, 0x8a, 0x12, 0x34,  // PUSH $1234
+    ], &mut cpu, &mut sys);
+    assert_eq!(0x1084, cpu.registers().get16(Reg16::DE));
+    assert_eq!(0x0010, cpu.registers().get16(Reg16::HL));
+    assert_eq!(0x0020, cpu.registers().get16(Reg16::BC));
+    assert_eq!(0x7ffe, cpu.registers().get16(Reg16::SP));
+    assert_eq!(0x34, sys.peek(0x7ffe));
+    assert_eq!(0x12, sys.peek(0x7fff));
+}
+
+#[test]
+fn test_z80n_pixelad() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new_z80n();
+    cpu.registers().set16(Reg16::DE, 0x4f21);  // y=$4f, x=$21
+    run(&[
+        0xed, 0x94,  // PIXELAD
+        0xed, 0x93,  // PIXELDN
+    ], &mut cpu, &mut sys);
+    // Line $4f is on the third of $0800, char row 1, pixel row 7
+    // Line $50 is on the third of $0800, char row 2, pixel row 0
+    assert_eq!(0x4844, cpu.registers().get16(Reg16::HL));
+}
+
+#[test]
+fn test_z80n_pixeldn() {
+    for (hl, expected) in [(0x4000, 0x4100), (0x4700, 0x4020), (0x47e0, 0x4800)] {
+        let mut sys = PlainMachine::new();
+        let mut cpu = Cpu::new_z80n();
+        cpu.registers().set16(Reg16::HL, hl);
+        run(&[0xed, 0x93], &mut cpu, &mut sys);
+        assert_eq!(expected, cpu.registers().get16(Reg16::HL));
+    }
+}
+
+#[test]
+fn test_z80n_ldirx() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new_z80n();
+    for (i, b) in [0x01, 0xe3, 0x02, 0xe3].iter().enumerate() {
+        sys.poke(0x1000 + i as u32, *b);
+    }
+    cpu.registers().set16(Reg16::HL, 0x1000);
+    cpu.registers().set16(Reg16::DE, 0x2000);
+    cpu.registers().set16(Reg16::BC, 0x0004);
+    cpu.registers().set_a(0xe3);
+    run(&[0xed, 0xb4], &mut cpu, &mut sys);  // LDIRX
+    assert_eq!([0x01, 0x00, 0x02, 0x00], [sys.peek(0x2000), sys.peek(0x2001), sys.peek(0x2002), sys.peek(0x2003)]);
+    assert_eq!(0x1004, cpu.registers().get16(Reg16::HL));
+    assert_eq!(0x2004, cpu.registers().get16(Reg16::DE));
+    assert_eq!(0x0000, cpu.registers().get16(Reg16::BC));
+}
+
+#[test]
+fn test_z80n_lddx() {
+    // LDDX moves HL down and DE up
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new_z80n();
+    sys.poke(0x1003, 0x55);
+    cpu.registers().set16(Reg16::HL, 0x1003);
+    cpu.registers().set16(Reg16::DE, 0x2000);
+    cpu.registers().set16(Reg16::BC, 0x0001);
+    run(&[0xed, 0xac], &mut cpu, &mut sys);
+    assert_eq!(0x55, sys.peek(0x2000));
+    assert_eq!(0x1002, cpu.registers().get16(Reg16::HL));
+    assert_eq!(0x2001, cpu.registers().get16(Reg16::DE));
+}
+
+#[test]
+fn test_z80n_ldpirx() {
+    // Copies from a pattern of 8 bytes
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new_z80n();
+    for i in 0..8 {
+        sys.poke(0x1008 + i, 0x10 + i as u8);
+    }
+    cpu.registers().set16(Reg16::HL, 0x100b);
+    cpu.registers().set16(Reg16::DE, 0x2006);
+    cpu.registers().set16(Reg16::BC, 0x0003);
+    cpu.registers().set_a(0x17);
+    run(&[0xed, 0xb7], &mut cpu, &mut sys);
+    assert_eq!([0x16, 0x00, 0x10], [sys.peek(0x2006), sys.peek(0x2007), sys.peek(0x2008)]);
+    assert_eq!(0x2009, cpu.registers().get16(Reg16::DE));
+    assert_eq!(0x100b, cpu.registers().get16(Reg16::HL));
+}
+
+#[test]
+fn test_z80n_ldws() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new_z80n();
+    sys.poke(0x10ff, 0x99);
+    cpu.registers().set16(Reg16::HL, 0x10ff);
+    cpu.registers().set16(Reg16::DE, 0x7f00);
+    run(&[0xed, 0xa5], &mut cpu, &mut sys);
+    assert_eq!(0x99, sys.peek(0x7f00));
+    assert_eq!(0x1000, cpu.registers().get16(Reg16::HL));
+    assert_eq!(0x8000, cpu.registers().get16(Reg16::DE));
+    assert!(cpu.registers().get_flag(Flag::P));
+}
+
+#[test]
+fn test_z80n_io() {
+    // Also with the history, that executes on a TracingMachine
+    for history in [0, 16] {
+        let mut sys = NextMachine {
+            plain: PlainMachine::new(),
+            registers: [0; 256],
+        };
+        let mut cpu = Cpu::new_z80n();
+        cpu.set_history(history);
+        sys.poke(0x1000, 0xaa);
+        cpu.registers().set16(Reg16::HL, 0x1000);
+        cpu.registers().set16(Reg16::BC, 0x00fe);
+        cpu.registers().set_a(0x42);
+        run(&[
+            0xed, 0x91, 0x07, 0x03,  // NEXTREG $07, $03
+            0xed, 0x92, 0x15,        // NEXTREG $15, A
+            0xed, 0x90,              // OUTINB
+        ], &mut cpu, &mut sys);
+        assert_eq!(0x03, sys.registers[0x07]);
+        assert_eq!(0x42, sys.registers[0x15]);
+        assert_eq!(0x00, sys.port_in(0x243b));
+        assert_eq!(0xaa, sys.port_in(0x00fe));
+        assert_eq!(0x1001, cpu.registers().get16(Reg16::HL));
+        assert_eq!(0x00fe, cpu.registers().get16(Reg16::BC));
+    }
+}
+
+#[test]
+fn test_z80n_nextreg_ports() {
+    // The default goes to the register select and data ports
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new_z80n();
+    run(&[0xed, 0x91, 0x07, 0x03], &mut cpu, &mut sys);
+    assert_eq!(0x07, sys.port_in(0x243b));
+    assert_eq!(0x03, sys.port_in(0x253b));
+}
+
+#[test]
+fn test_z80n_jp_c() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new_z80n();
+    sys.port_out(0x1234, 0x21);
+    sys.poke(0x4000, 0xed);  // JP (C)
+    sys.poke(0x4001, 0x98);
+    sys.poke(0x4840, 0x76);  // HALT
+    cpu.registers().set16(Reg16::BC, 0x1234);
+    cpu.state.set_pc(0x4000);
+    cpu.execute_instructions(&mut sys, 10);
+    assert_eq!(0x4841, cpu.state.pc());
+}
+
+#[test]
+fn test_z80n_disasm() {
+    let mut sys = PlainMachine::new();
+    let mut cpu = Cpu::new_z80n();
+    let code = [
+        0xed, 0x8a, 0x12, 0x34,  // PUSH $1234
+        0xed, 0x91, 0x07, 0x03,  // NEXTREG $07, $03
+        0xed, 0x34, 0x00, 0x10,  // ADD HL, $1000
+        0xed, 0x28,              // BSLA DE, B
+    ];
+    for (i, b) in code.iter().enumerate() {
+        sys.poke(i as u32, *b);
+    }
+    assert_eq!("PUSH $1234", cpu.disasm_instruction(&mut sys));
+    assert_eq!("NEXTREG $7, $3", cpu.disasm_instruction(&mut sys));
+    assert_eq!("ADD HL, $1000", cpu.disasm_instruction(&mut sys));
+    assert_eq!("BSLA DE, B", cpu.disasm_instruction(&mut sys));
+
+    // The decode cache reads the operands of PUSH and NEXTREG
+    let mut cpu = Cpu::new_z80n();
+    cpu.set_decode_cache(true);
+    cpu.registers().set16(Reg16::SP, 0x8000);
+    for _ in 0..2 {
+        cpu.state.set_pc(0x0000);
+        cpu.execute_instruction(&mut sys);
+        cpu.execute_instruction(&mut sys);
+        assert_eq!(0x0008, cpu.state.pc());
+    }
+    assert_eq!(0x7ffc, cpu.registers().get16(Reg16::SP));
+    assert_eq!([0x34, 0x12, 0x34, 0x12], [sys.peek(0x7ffc), sys.peek(0x7ffd), sys.peek(0x7ffe), sys.peek(0x7fff)]);
+}
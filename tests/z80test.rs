@@ -1,3 +1,8 @@
+// The lint exceptions keep the test as written upstream
+#![allow(clippy::redundant_static_lifetimes, clippy::needless_range_loop,
+    clippy::println_empty_string, clippy::if_same_then_else,
+    clippy::bool_assert_comparison)]
+
 use ez80::*;
 
 // From https://github.com/raxoft/z80test